@@ -0,0 +1,30 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate fastq_set;
+extern crate flate2;
+
+use fastq_set::read_pair::ReadPair;
+use fastq_set::read_pair_iter::ReadPairIter;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Cursor, Write};
+
+fn parse(data: Vec<u8>) -> Option<Vec<ReadPair>> {
+    let readers = [Some(Cursor::new(data)), None, None, None];
+    let iter = ReadPairIter::from_readers(readers, false).ok()?;
+    iter.collect::<Result<Vec<_>, _>>().ok()
+}
+
+// Gzip the input and check that parsing the compressed stream gives the same
+// result as parsing the plain stream.
+fuzz_target!(|data: &[u8]| {
+    if data.first() != Some(&b'@') {
+        return;
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    assert_eq!(parse(data.to_vec()), parse(compressed));
+});
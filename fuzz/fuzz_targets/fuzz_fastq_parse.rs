@@ -0,0 +1,27 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate fastq_set;
+
+use fastq_set::read_pair::{ReadPart, WhichRead};
+use fastq_set::read_pair_iter::ReadPairIter;
+use std::io::Cursor;
+
+// Parse arbitrary bytes as an interleaved R1/R2 FASTQ. The compression is
+// detected from the magic bytes, so this also exercises the gzip and lz4 paths.
+fuzz_target!(|data: &[u8]| {
+    let readers = [Some(Cursor::new(data.to_vec())), None, None, None];
+    if let Ok(iter) = ReadPairIter::from_readers(readers, true) {
+        for read_pair in iter {
+            match read_pair {
+                Ok(rp) => {
+                    for &which in &[WhichRead::R1, WhichRead::R2] {
+                        let seq = rp.get(which, ReadPart::Seq).unwrap();
+                        let qual = rp.get(which, ReadPart::Qual).unwrap();
+                        assert_eq!(seq.len(), qual.len());
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+});
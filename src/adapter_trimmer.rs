@@ -8,6 +8,9 @@
 //! adapter trimming
 //! * Linked adapters are not supported as of now
 //! * Allowed error rate for the adapter is 10%
+//! * Homopolymer tails (e.g. poly(A)) are trimmed using `PolyTailTrimmer`
+//! * `CutadaptTrimmer` reproduces the alignment of cutadapt 1.x exactly, for
+//!   validating against cutadapt-based pipelines
 //!
 //! # Algorithm
 //!
@@ -17,7 +20,8 @@
 //! A 5' adapter can be treated as a 3' adapter with
 //! the read reversed. Perhaps it makes sense to use
 //! this symmetry in the algorithm?
-use crate::read_pair::WhichRead;
+use crate::metric_utils::ILLUMINA_QUAL_OFFSET;
+use crate::read_pair::{RpRange, WhichRead};
 use crate::WhichEnd;
 use bio::alignment::pairwise::{self, MatchParams, Scoring};
 use bio::alignment::sparse;
@@ -483,6 +487,116 @@ pub struct TrimResult {
     pub score: i32,
 }
 
+impl TrimResult {
+    /// The `trim_range` as a `RpRange` on read `which`, e.g. to record the
+    /// trimming in the `TrimProvenance` of a `TrimmedReadPair`.
+    pub fn trim_rp_range(&self, which: WhichRead) -> RpRange {
+        RpRange::new(which, self.trim_range.start, Some(self.trim_range.len()))
+    }
+}
+
+/// Detects and trims homopolymer tails such as the poly(A) tail at the 3' end
+/// of an mRNA read, or the poly(T) stretch at the 5' end of a read sequenced
+/// from the other strand. Unlike an `Adapter` with a homopolymer sequence, the
+/// tail is allowed to extend all the way to the end of the read and its length
+/// is reported directly.
+///
+/// Starting from `end` of the read, the tail is extended base by base for as long
+/// as it contains at most `max_mismatches` mismatches. The tail always begins
+/// (on the inner side) with a matching base, and is reported only if it is at
+/// least `min_len` bases long.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::adapter_trimmer::PolyTailTrimmer;
+/// let trimmer = PolyTailTrimmer::poly_a(5, 1);
+/// let read = b"ACGTCCGTAAAAGAAAAA";
+/// let result = trimmer.find(read).unwrap();
+/// assert_eq!(result.trim_range, 8..read.len());
+/// assert_eq!(result.retain_range, 0..8);
+/// assert_eq!(trimmer.tail_len(read), 10);
+/// assert_eq!(trimmer.tail_len(b"ACGTCCGTAAAA"), 0);
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PolyTailTrimmer {
+    pub base: u8,
+    pub end: WhichEnd,
+    pub min_len: usize,
+    pub max_mismatches: usize,
+}
+
+impl PolyTailTrimmer {
+    /// Create a new `PolyTailTrimmer` for runs of `base` at the given `end` of the read.
+    pub fn new(base: u8, end: WhichEnd, min_len: usize, max_mismatches: usize) -> Self {
+        PolyTailTrimmer {
+            base: base.to_ascii_uppercase(),
+            end,
+            min_len,
+            max_mismatches,
+        }
+    }
+
+    /// Trimmer for a poly(A) tail at the 3' end of the read
+    pub fn poly_a(min_len: usize, max_mismatches: usize) -> Self {
+        PolyTailTrimmer::new(b'A', WhichEnd::ThreePrime, min_len, max_mismatches)
+    }
+
+    /// Trimmer for a poly(T) stretch at the 5' end of the read
+    pub fn poly_t(min_len: usize, max_mismatches: usize) -> Self {
+        PolyTailTrimmer::new(b'T', WhichEnd::FivePrime, min_len, max_mismatches)
+    }
+
+    /// Length of the tail in the read, 0 if there is no tail of at least `min_len` bases.
+    pub fn tail_len(&self, read: &[u8]) -> usize {
+        self.find(read).map_or(0, |r| r.trim_range.len())
+    }
+
+    /// Search for the tail in the read. The `adapter_range` and `trim_range` of
+    /// the result both cover the tail.
+    pub fn find(&self, read: &[u8]) -> Option<TrimResult> {
+        let is_match = |b: &u8| b.to_ascii_uppercase() == self.base;
+        let scan: Box<dyn Iterator<Item = &u8>> = match self.end {
+            WhichEnd::ThreePrime => Box::new(read.iter().rev()),
+            WhichEnd::FivePrime => Box::new(read.iter()),
+        };
+
+        let mut tail_len = 0;
+        let mut score = 0;
+        let mut best_score = 0;
+        let mut mismatches = 0;
+        for (i, b) in scan.enumerate() {
+            if is_match(b) {
+                score += MATCH_SCORE;
+                tail_len = i + 1;
+                best_score = score;
+            } else {
+                mismatches += 1;
+                if mismatches > self.max_mismatches {
+                    break;
+                }
+                score += EDIT_SCORE;
+            }
+        }
+
+        if tail_len == 0 || tail_len < self.min_len {
+            return None;
+        }
+        let (trim_range, retain_range) = match self.end {
+            WhichEnd::ThreePrime => {
+                let start = read.len() - tail_len;
+                (start..read.len(), 0..start)
+            }
+            WhichEnd::FivePrime => (0..tail_len, tail_len..read.len()),
+        };
+        Some(TrimResult {
+            adapter_range: trim_range.clone(),
+            trim_range,
+            retain_range,
+            score: best_score,
+        })
+    }
+}
+
 /// A function to compute the intersection of two `Range<usize>`. I would
 /// have liked this to be part of the std library. Takes two ranges as input
 /// and computes the intersection. This is a useful function when you want to
@@ -498,6 +612,382 @@ pub fn intersect_ranges(a: &Range<usize>, b: &Range<usize>) -> Range<usize> {
     Range { start, end }
 }
 
+/// Find the low quality tail at the 3' end of a read using the algorithm
+/// from BWA (also used by cutadapt): the read is trimmed at the position
+/// which maximizes the sum of `min_qual - q` over the trimmed bases.
+/// `qual` holds the phred+33 encoded quality string. Returns `None` if no
+/// bases need to be trimmed.
+///
+/// Like the adapter search, this only suggests ranges and leaves the read
+/// untouched, so the result can either be used to hard trim the read or be
+/// converted into a `SoftClip` for an aligner.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::adapter_trimmer::quality_trim;
+/// //           42 bases of Q40, followed by Q2, Q30, Q2, Q2
+/// let qual = [&[b'I'; 42][..], b"#?##"].concat();
+/// let result = quality_trim(&qual, 20).unwrap();
+/// assert_eq!(result.trim_range, 42..46);
+/// assert_eq!(result.retain_range, 0..42);
+/// ```
+pub fn quality_trim(qual: &[u8], min_qual: u8) -> Option<TrimResult> {
+    let mut sum = 0;
+    let mut best_sum = 0;
+    let mut best_start = qual.len();
+    for (i, &q) in qual.iter().enumerate().rev() {
+        sum += i32::from(min_qual) - (i32::from(q) - i32::from(ILLUMINA_QUAL_OFFSET));
+        if sum < 0 {
+            break;
+        }
+        if sum > best_sum {
+            best_sum = sum;
+            best_start = i;
+        }
+    }
+    if best_start == qual.len() {
+        return None;
+    }
+    Some(TrimResult {
+        adapter_range: best_start..qual.len(),
+        trim_range: best_start..qual.len(),
+        retain_range: 0..best_start,
+        score: best_sum,
+    })
+}
+
+/// Number of bases to soft clip at either end of a read, suggested by
+/// trimming instead of removing the bases from the read.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SoftClip {
+    /// Bases to clip at the start of the read
+    pub left: usize,
+    /// Bases to clip at the end of the read
+    pub right: usize,
+}
+
+impl SoftClip {
+    /// Soft clips leaving only the bases in `retain_range`, typically the
+    /// intersection of the `retain_range`s of several `TrimResult`s
+    /// (see [`intersect_ranges`](fn.intersect_ranges.html)). An empty
+    /// `retain_range` clips the whole read.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::adapter_trimmer::SoftClip;
+    /// let clip = SoftClip::from_retain_range(&(5..90), 100);
+    /// assert_eq!(clip, SoftClip { left: 5, right: 10 });
+    /// ```
+    pub fn from_retain_range(retain_range: &Range<usize>, read_len: usize) -> Self {
+        let end = min(retain_range.end, read_len);
+        let start = min(retain_range.start, end);
+        if start == end {
+            return SoftClip {
+                left: read_len,
+                right: 0,
+            };
+        }
+        SoftClip {
+            left: start,
+            right: read_len - end,
+        }
+    }
+
+    /// Range of the read that is not clipped
+    pub fn retain_range(&self, read_len: usize) -> Range<usize> {
+        let start = min(self.left, read_len);
+        start..max(start, read_len.saturating_sub(self.right))
+    }
+
+    /// True if no bases are clipped
+    pub fn is_empty(&self) -> bool {
+        self.left == 0 && self.right == 0
+    }
+}
+
+// Flags of the cutadapt aligner, selecting which ends of the adapter (seq1) and
+// the read (seq2) may be skipped without penalty
+const START_WITHIN_ADAPTER: u8 = 1;
+const START_WITHIN_READ: u8 = 2;
+const STOP_WITHIN_ADAPTER: u8 = 4;
+const STOP_WITHIN_READ: u8 = 8;
+
+// Base encoding of the cutadapt aligner. An `N` in the adapter matches any of
+// A, C, G or T in the read, while an `N` in the read matches nothing.
+fn cutadapt_base(b: u8, wildcard: bool) -> u8 {
+    match b.to_ascii_uppercase() {
+        b'A' => 1,
+        b'C' => 2,
+        b'G' => 4,
+        b'T' | b'U' => 8,
+        b'N' if wildcard => 15,
+        _ => 128,
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct CutadaptEntry {
+    cost: usize,
+    matches: usize,
+    // Start of the alignment: a position in the read if >= 0, or minus a
+    // position in the adapter otherwise
+    origin: isize,
+}
+
+/// Adapter trimming reproducing the semantics of [cutadapt](https://cutadapt.readthedocs.io/)
+/// 1.x, for pipelines that need results identical to cutadapt rather than the
+/// faster heuristic alignment of `AdapterTrimmer`.
+///
+/// The adapter is aligned with unit cost edit distance. An alignment is accepted
+/// if the aligned part of the adapter is at least `min_overlap` bases long and has
+/// at most `error_rate` errors per base, and the alignment with the most matching
+/// bases is reported. The `AdapterLoc` of the adapter maps to the cutadapt adapter
+/// types as follows:
+///
+/// | `AdapterLoc`  | 3' adapter     | 5' adapter      |
+/// |---------------|----------------|-----------------|
+/// | `Anywhere`    | `-a ADAPTER`   | `-g ADAPTER`    |
+/// | `NonInternal` | `-a ADAPTERX`  | `-g XADAPTER`   |
+/// | `Anchored`    | `-a ADAPTER$`  | `-g ^ADAPTER`   |
+///
+/// `N`s in the adapter match any base. The defaults are the cutadapt defaults:
+/// an error rate of 0.1, a minimum overlap of 3 and indels allowed.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::adapter_trimmer::{Adapter, AdapterLoc, CutadaptTrimmer};
+/// use fastq_set::WhichEnd;
+/// let adapter = Adapter::new("truseq", WhichEnd::ThreePrime, AdapterLoc::Anywhere, "AGATCGGAAGAGC");
+/// let trimmer = CutadaptTrimmer::new(&adapter).min_overlap(5);
+/// // Partial adapter at the end of the read
+/// let result = trimmer.find(b"ACGTTTGACCAGTACAGATCGG").unwrap();
+/// assert_eq!(result.retain_range, 0..15);
+/// assert_eq!(result.adapter_range, 15..22);
+/// // Too short to be accepted
+/// assert!(trimmer.find(b"ACGTTTGACCAGTACAGAT").is_none());
+/// ```
+#[derive(Debug, Clone)]
+pub struct CutadaptTrimmer<'a> {
+    pub adapter: &'a Adapter,
+    seq: Vec<u8>,
+    flags: u8,
+    error_rate: f64,
+    min_overlap: usize,
+    indel_cost: usize,
+    wildcards: bool,
+}
+
+impl<'a> CutadaptTrimmer<'a> {
+    pub fn new(adapter: &'a Adapter) -> Self {
+        use self::AdapterLoc::{Anchored, Anywhere, NonInternal};
+        use crate::WhichEnd::{FivePrime, ThreePrime};
+
+        let flags = match (adapter.end, adapter.location) {
+            (ThreePrime, Anywhere) => START_WITHIN_READ | STOP_WITHIN_READ | STOP_WITHIN_ADAPTER,
+            (ThreePrime, NonInternal) => START_WITHIN_READ | STOP_WITHIN_ADAPTER,
+            (ThreePrime, Anchored) => START_WITHIN_READ,
+            (FivePrime, Anywhere) => START_WITHIN_READ | STOP_WITHIN_READ | START_WITHIN_ADAPTER,
+            (FivePrime, NonInternal) => START_WITHIN_ADAPTER | STOP_WITHIN_READ,
+            (FivePrime, Anchored) => STOP_WITHIN_READ,
+        };
+        let seq = adapter.seq.to_ascii_uppercase().into_bytes();
+        // Like cutadapt, only use wildcard matching if the adapter has an `N`
+        let wildcards = seq.iter().any(|&b| !b"ACGTU".contains(&b));
+        CutadaptTrimmer {
+            adapter,
+            seq: seq.iter().map(|&b| cutadapt_base(b, true)).collect(),
+            flags,
+            error_rate: 0.1,
+            min_overlap: 3,
+            indel_cost: 1,
+            wildcards,
+        }
+    }
+
+    /// Maximum number of errors per aligned adapter base (`-e`)
+    pub fn error_rate(mut self, error_rate: f64) -> Self {
+        self.error_rate = error_rate;
+        self
+    }
+
+    /// Minimum length of the aligned part of the adapter (`-O`, `--overlap`)
+    pub fn min_overlap(mut self, min_overlap: usize) -> Self {
+        self.min_overlap = min_overlap;
+        self
+    }
+
+    /// Allow insertions and deletions in the alignment (cutadapt `--no-indels`
+    /// corresponds to `false`)
+    pub fn indels(mut self, indels: bool) -> Self {
+        self.indel_cost = if indels { 1 } else { 100_000 };
+        self
+    }
+
+    /// Search for the adapter in the read. The `score` of the result is the
+    /// number of matching bases of the alignment minus the number of errors.
+    pub fn find(&self, read: &[u8]) -> Option<TrimResult> {
+        let (read_start, read_stop, matches, errors) = self.locate(read)?;
+        let (trim_range, retain_range) = match self.adapter.end {
+            WhichEnd::ThreePrime => (read_start..read.len(), 0..read_start),
+            WhichEnd::FivePrime => (0..read_stop, read_stop..read.len()),
+        };
+        Some(TrimResult {
+            adapter_range: read_start..read_stop,
+            trim_range,
+            retain_range,
+            score: matches as i32 - errors as i32,
+        })
+    }
+
+    // Port of `Aligner.locate` of cutadapt 1.18, including its column pruning,
+    // so that ties between alignments are broken in the same way. Returns the
+    // aligned range of the read, the number of matches and the number of errors.
+    fn locate(&self, read: &[u8]) -> Option<(usize, usize, usize, usize)> {
+        let s1 = &self.seq;
+        let s2: Vec<u8> = read.iter().map(|&b| cutadapt_base(b, false)).collect();
+        let m = s1.len();
+        let n = s2.len();
+        let start_in_adapter = self.flags & START_WITHIN_ADAPTER != 0;
+        let start_in_read = self.flags & START_WITHIN_READ != 0;
+        let stop_in_adapter = self.flags & STOP_WITHIN_ADAPTER != 0;
+        let stop_in_read = self.flags & STOP_WITHIN_READ != 0;
+        let indel = self.indel_cost;
+        let max_error_rate = self.error_rate;
+
+        let k = (max_error_rate * m as f64) as usize;
+        let max_n = if start_in_read { n } else { n.min(m + k) };
+        let min_n = if stop_in_read {
+            0
+        } else {
+            n.saturating_sub(m + k)
+        };
+
+        let mut column: Vec<CutadaptEntry> = (0..=m)
+            .map(|i| {
+                let (cost, origin) = match (start_in_adapter, start_in_read) {
+                    (false, false) => (i.max(min_n) * indel, 0),
+                    (true, false) => (min_n * indel, (min_n as isize - i as isize).min(0)),
+                    (false, true) => (i * indel, (min_n as isize - i as isize).max(0)),
+                    (true, true) => (i.min(min_n) * indel, min_n as isize - i as isize),
+                };
+                CutadaptEntry {
+                    cost,
+                    matches: 0,
+                    origin,
+                }
+            })
+            .collect();
+
+        let mut last: isize = if start_in_adapter {
+            m as isize
+        } else {
+            m.min(k + 1) as isize
+        };
+
+        let mut best = CutadaptEntry {
+            cost: m + n + 1,
+            matches: 0,
+            origin: 0,
+        };
+        let mut best_stop = (0, 0);
+        let acceptable = |length: isize, e: &CutadaptEntry, best: &CutadaptEntry| {
+            length >= self.min_overlap as isize
+                && e.cost as f64 <= length as f64 * max_error_rate
+                && (e.matches > best.matches || (e.matches == best.matches && e.cost < best.cost))
+        };
+
+        for j in min_n + 1..=max_n {
+            let mut tmp = column[0];
+            if start_in_read {
+                column[0].origin = j as isize;
+            } else {
+                column[0].cost = j * indel;
+            }
+            for i in 1..=last.max(0) as usize {
+                let equal = if self.wildcards {
+                    s1[i - 1] & s2[j - 1] != 0
+                } else {
+                    s1[i - 1] == s2[j - 1]
+                };
+                let entry = if equal {
+                    CutadaptEntry {
+                        cost: tmp.cost,
+                        matches: tmp.matches + 1,
+                        origin: tmp.origin,
+                    }
+                } else {
+                    let cost_diag = tmp.cost + 1;
+                    let cost_deletion = column[i].cost + indel;
+                    let cost_insertion = column[i - 1].cost + indel;
+                    if cost_diag <= cost_deletion && cost_diag <= cost_insertion {
+                        CutadaptEntry {
+                            cost: cost_diag,
+                            ..tmp
+                        }
+                    } else if cost_insertion <= cost_deletion {
+                        CutadaptEntry {
+                            cost: cost_insertion,
+                            ..column[i - 1]
+                        }
+                    } else {
+                        CutadaptEntry {
+                            cost: cost_deletion,
+                            ..column[i]
+                        }
+                    }
+                };
+                tmp = column[i];
+                column[i] = entry;
+            }
+
+            while last >= 0 && column[last as usize].cost > k {
+                last -= 1;
+            }
+            if last < m as isize {
+                last += 1;
+            } else if stop_in_read {
+                let e = column[m];
+                let length = m as isize + e.origin.min(0);
+                if acceptable(length, &e, &best) {
+                    best = e;
+                    best_stop = (m, j);
+                    if e.cost == 0 && e.matches == m {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if max_n == n {
+            let first_i = if stop_in_adapter { 0 } else { m };
+            for (i, &e) in column.iter().enumerate().skip(first_i) {
+                let length = i as isize + e.origin.min(0);
+                if acceptable(length, &e, &best) {
+                    best = e;
+                    best_stop = (i, n);
+                }
+            }
+        }
+
+        if best.cost == m + n + 1 {
+            return None;
+        }
+        let (adapter_start, read_start) = if best.origin >= 0 {
+            (0, best.origin as usize)
+        } else {
+            ((-best.origin) as usize, 0)
+        };
+        let (adapter_stop, read_stop) = best_stop;
+
+        // The final check of `Adapter.match_to`
+        let size = adapter_stop - adapter_start;
+        if size < self.min_overlap || best.cost as f64 / size as f64 > max_error_rate {
+            return None;
+        }
+        Some((read_start, read_stop, best.matches, best.cost))
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 struct CutScores {
     match_score: i32,
@@ -775,6 +1265,51 @@ mod tests {
     use super::*;
     use std::path::Path;
 
+    #[test]
+    fn test_poly_tail_trimmer() {
+        let poly_a = PolyTailTrimmer::poly_a(4, 1);
+        assert_eq!(poly_a.tail_len(b"ACGTAAAA"), 4);
+        assert_eq!(poly_a.tail_len(b"ACGTAAA"), 0);
+        assert_eq!(poly_a.tail_len(b"ACGTaaaa"), 4);
+        // The mismatch at the inner edge is not part of the tail
+        assert_eq!(poly_a.tail_len(b"ACGCGAAAAA"), 5);
+        // A single mismatch within the tail is allowed
+        assert_eq!(poly_a.tail_len(b"ACGTACAAAAA"), 7);
+        assert_eq!(poly_a.tail_len(b"AAAAAAAA"), 8);
+        assert_eq!(poly_a.tail_len(b""), 0);
+        assert_eq!(poly_a.tail_len(b"AAAACGCG"), 0);
+        let result = poly_a.find(b"ACGTACAAAAA").unwrap();
+        assert_eq!(result.adapter_range, 4..11);
+        assert_eq!(result.retain_range, 0..4);
+        assert_eq!(result.score, 6 * MATCH_SCORE + EDIT_SCORE);
+
+        let poly_t = PolyTailTrimmer::poly_t(4, 0);
+        let result = poly_t.find(b"TTTTTGACGT").unwrap();
+        assert_eq!(result.trim_range, 0..5);
+        assert_eq!(result.retain_range, 5..10);
+        assert_eq!(poly_t.tail_len(b"TTTGTTTT"), 0);
+    }
+
+    #[test]
+    fn test_quality_trim() {
+        assert!(quality_trim(b"", 20).is_none());
+        assert!(quality_trim(b"IIIIIIII", 20).is_none());
+        // The Q30 base is outweighed by the low quality bases surrounding it
+        let result = quality_trim(b"IIII#?##", 20).unwrap();
+        assert_eq!(result.trim_range, 4..8);
+        assert_eq!(result.retain_range, 0..4);
+        assert_eq!(quality_trim(b"####", 20).unwrap().retain_range, 0..0);
+
+        let clip = SoftClip::from_retain_range(&result.retain_range, 8);
+        assert_eq!(clip, SoftClip { left: 0, right: 4 });
+        assert_eq!(clip.retain_range(8), 0..4);
+        assert!(!clip.is_empty());
+        assert!(SoftClip::from_retain_range(&(0..8), 8).is_empty());
+        let all = SoftClip::from_retain_range(&intersect_ranges(&(0..3), &(6..8)), 8);
+        assert_eq!(all, SoftClip { left: 8, right: 0 });
+        assert_eq!(all.retain_range(8), 8..8);
+    }
+
     #[test]
     fn test_anywhere_3p() {
         test_helper(
@@ -1089,6 +1624,64 @@ mod tests {
         catalog.push_trimmer(WhichRead::R2, AdapterTrimmer::new(&adapter_2));
     }
 
+    // Fraction of the reads for which `CutadaptTrimmer` retains exactly the same
+    // sequence as cutadapt, using the settings of `tests/run_cutadapt.sh`
+    fn cutadapt_parity(
+        adapter_seq: &str,
+        end: WhichEnd,
+        loc: AdapterLoc,
+        input_path: &str,
+        cutadapt_output_path: &str,
+    ) -> f64 {
+        use bio::io::fasta::Reader;
+
+        let adapter = Adapter::new("primer", end, loc, adapter_seq);
+        let trimmer = CutadaptTrimmer::new(&adapter).min_overlap(5);
+        let input = Reader::from_file(input_path).unwrap();
+        let cutadapt_output = Reader::from_file(cutadapt_output_path).unwrap();
+
+        let mut total = 0;
+        let mut identical = 0;
+        for (r_i, r_o) in input.records().zip(cutadapt_output.records()) {
+            let (r_i, r_o) = (r_i.unwrap(), r_o.unwrap());
+            let seq = r_i.seq();
+            let retained = match trimmer.find(seq) {
+                Some(r) => &seq[r.retain_range],
+                None => seq,
+            };
+            total += 1;
+            if retained == r_o.seq() {
+                identical += 1;
+            }
+        }
+        identical as f64 / total as f64
+    }
+
+    #[test]
+    fn test_cutadapt_parity() {
+        use self::AdapterLoc::{Anchored, Anywhere, NonInternal};
+        use crate::WhichEnd::{FivePrime, ThreePrime};
+        let adapter = "AGATCGGAAGAGCACACGTCTGAACTCCAGTCAC";
+        let poly_a = "AAAAAAAAAAAAAAAAAAAA";
+        for &(end, loc, name) in &[
+            (ThreePrime, Anywhere, "anywhere_3p"),
+            (ThreePrime, NonInternal, "non_internal_3p"),
+            (ThreePrime, Anchored, "anchored_3p"),
+            (FivePrime, Anywhere, "anywhere_5p"),
+            (FivePrime, NonInternal, "non_internal_5p"),
+            (FivePrime, Anchored, "anchored_5p"),
+        ] {
+            for &(seq, input, suffix) in &[
+                (adapter, "tests/input.fa", ""),
+                (poly_a, "tests/input_polyA.fa", "_polyA"),
+            ] {
+                let output = format!("tests/cutadapt_v1_18_{}{}.fa", name, suffix);
+                let parity = cutadapt_parity(seq, end, loc, input, &output);
+                assert_eq!(parity, 1.0, "{}", output);
+            }
+        }
+    }
+
     #[test]
     fn test_intersect_ranges() {
         assert_eq!(intersect_ranges(&(0..10), &(5..15)), 5..10);
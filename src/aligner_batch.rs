@@ -0,0 +1,431 @@
+//! Batches of alignable reads packed for aligner bindings, such as minimap2 or
+//! bwa-mem2 wrappers, so that processed reads can be aligned without writing an
+//! intermediate FASTQ file.
+//!
+//! The name, comment, sequence and quality of every record are stored in a single
+//! buffer, each followed by a NUL byte, so a pointer to any of them can be passed
+//! as a C string to `bseq1_t`-style structs. The comment holds the SAM tags of the
+//! read (e.g. `CB:Z:...\tUB:Z:...`), which aligners copy to the SAM record when
+//! asked to append comments (`bwa mem -C`, `minimap2 -y`). Paired reads produce
+//! two consecutive records with the same name, R1 first, as in an interleaved FASTQ.
+//! A read with an empty R2 sequence is unpaired, and a batch holds either paired or
+//! unpaired reads, never both, so that records of a paired batch always alternate
+//! between mates.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::aligner_batch::AlignerBatch;
+//! use fastq_set::read_pair::{AlignableRanges, ReadPair, ReadPart, WhichRead};
+//! use fastq_set::{AlignableReadPair, OwnedRecord};
+//!
+//! struct Read(ReadPair);
+//! impl AlignableReadPair for Read {
+//!     fn header(&self) -> &[u8] {
+//!         self.0.get(WhichRead::R1, ReadPart::Header).unwrap()
+//!     }
+//!     fn alignable_sequence(&self) -> (&[u8], &[u8]) {
+//!         self.0.alignable_sequence(AlignableRanges::full(false))
+//!     }
+//!     fn alignable_quals(&self) -> (&[u8], &[u8]) {
+//!         self.0.alignable_quals(AlignableRanges::full(false))
+//!     }
+//! }
+//!
+//! let rec = OwnedRecord {
+//!     head: b"read1 1:N:0:0".to_vec(),
+//!     seq: b"ACGTACGT".to_vec(),
+//!     qual: b"IIIIIIII".to_vec(),
+//!     sep: None,
+//! };
+//! let read = Read(ReadPair::new([Some(rec), None, None, None]));
+//! let mut batch = AlignerBatch::new();
+//! batch.push(&read, &[(*b"CB", b"AAAA-1")]).unwrap();
+//! let record = batch.get(0).unwrap();
+//! assert_eq!(record.name, b"read1");
+//! assert_eq!(record.comment, b"CB:Z:AAAA-1");
+//! assert_eq!(record.seq, b"ACGTACGT");
+//! assert_eq!(record.mate, 0);
+//! ```
+
+use crate::{AlignableReadPair, HasBamTags};
+use failure::{format_err, Error};
+use std::iter::Peekable;
+
+/// Position of a NUL terminated string in the buffer of a batch
+#[derive(Clone, Copy, Debug, Default)]
+struct Span {
+    start: usize,
+    len: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    name: Span,
+    comment: Span,
+    seq: Span,
+    qual: Span,
+    mate: u8,
+}
+
+/// One record of an `AlignerBatch`. Each slice is followed by a NUL byte in the
+/// buffer of the batch, so `as_ptr()` gives a C string valid for the life of the batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlignerRecord<'a> {
+    /// The read name: the header up to the first space or tab
+    pub name: &'a [u8],
+    /// Tab separated SAM tags
+    pub comment: &'a [u8],
+    pub seq: &'a [u8],
+    /// Phred+33 quality scores. Empty if the read has no quality scores.
+    pub qual: &'a [u8],
+    /// 0 for an unpaired read or R1, 1 for R2
+    pub mate: u8,
+}
+
+/// A batch of alignable records sharing a single buffer.
+#[derive(Clone, Debug, Default)]
+pub struct AlignerBatch {
+    data: Vec<u8>,
+    entries: Vec<Entry>,
+    pairs: usize,
+    paired: Option<bool>,
+}
+
+impl AlignerBatch {
+    pub fn new() -> Self {
+        AlignerBatch::default()
+    }
+
+    fn push_str(&mut self, s: &[u8]) -> Span {
+        let span = Span {
+            start: self.data.len(),
+            len: s.len(),
+        };
+        self.data.extend_from_slice(s);
+        self.data.push(0);
+        span
+    }
+
+    /// True if `read` can be added to the batch: the batch is empty, or `read` is
+    /// paired if and only if the reads already in the batch are.
+    pub fn accepts(&self, read: &impl AlignableReadPair) -> bool {
+        let paired = !read.alignable_sequence().1.is_empty();
+        self.paired.is_none() || self.paired == Some(paired)
+    }
+
+    /// Add the alignable sequence of `read`, with `tags` as SAM tags of type `Z`.
+    /// A read with an empty R2 sequence is added as an unpaired read.
+    ///
+    /// Returns an error, leaving the batch unchanged, if the batch holds paired
+    /// reads and `read` is unpaired, or the other way around.
+    pub fn push(
+        &mut self,
+        read: &impl AlignableReadPair,
+        tags: &[([u8; 2], &[u8])],
+    ) -> Result<(), Error> {
+        let (seq1, seq2) = read.alignable_sequence();
+        let paired = !seq2.is_empty();
+        if !self.accepts(read) {
+            return Err(format_err!(
+                "Cannot add {} read {} to a batch of {} reads",
+                if paired { "a paired" } else { "an unpaired" },
+                String::from_utf8_lossy(read.header()),
+                if paired { "unpaired" } else { "paired" },
+            ));
+        }
+        self.paired = Some(paired);
+
+        let header = read.header();
+        let name_end = header
+            .iter()
+            .position(|&c| c == b' ' || c == b'\t')
+            .unwrap_or(header.len());
+        let name = self.push_str(&header[..name_end]);
+
+        let mut comment = Vec::new();
+        for (tag, value) in tags {
+            if !comment.is_empty() {
+                comment.push(b'\t');
+            }
+            comment.extend_from_slice(tag);
+            comment.extend_from_slice(b":Z:");
+            comment.extend_from_slice(value);
+        }
+        let comment = self.push_str(&comment);
+
+        let (qual1, qual2) = read.alignable_quals();
+        let mates = if !paired {
+            vec![(seq1, qual1)]
+        } else {
+            vec![(seq1, qual1), (seq2, qual2)]
+        };
+        for (mate, (seq, qual)) in mates.into_iter().enumerate() {
+            let seq = self.push_str(seq);
+            let qual = self.push_str(qual);
+            self.entries.push(Entry {
+                name,
+                comment,
+                seq,
+                qual,
+                mate: mate as u8,
+            });
+        }
+        self.pairs += 1;
+        Ok(())
+    }
+
+    /// Add `read` with the tags given by its `HasBamTags` implementation
+    pub fn push_tagged<R: AlignableReadPair + HasBamTags>(
+        &mut self,
+        read: &R,
+    ) -> Result<(), Error> {
+        self.push(read, &read.tags())
+    }
+
+    /// Number of records, counting both mates of paired reads
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of reads added, counting paired reads once
+    pub fn num_pairs(&self) -> usize {
+        self.pairs
+    }
+
+    /// True if the batch has paired reads, whose records alternate between mates
+    pub fn is_paired(&self) -> bool {
+        self.paired == Some(true)
+    }
+
+    fn slice(&self, span: Span) -> &[u8] {
+        &self.data[span.start..span.start + span.len]
+    }
+
+    pub fn get(&self, i: usize) -> Option<AlignerRecord<'_>> {
+        self.entries.get(i).map(|e| AlignerRecord {
+            name: self.slice(e.name),
+            comment: self.slice(e.comment),
+            seq: self.slice(e.seq),
+            qual: self.slice(e.qual),
+            mate: e.mate,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = AlignerRecord<'_>> {
+        (0..self.len()).map(move |i| self.get(i).unwrap())
+    }
+
+    /// The buffer holding all the NUL terminated strings of the batch
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Remove all the records, keeping the allocated memory for the next batch
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.entries.clear();
+        self.pairs = 0;
+        self.paired = None;
+    }
+}
+
+/// Iterator grouping tagged reads into batches of at most `pairs_per_batch` reads.
+/// Created by [`batches`](fn.batches.html).
+pub struct AlignerBatches<I: Iterator> {
+    reads: Peekable<I>,
+    pairs_per_batch: usize,
+}
+
+/// Group `reads` into batches of at most `pairs_per_batch` reads, tagged with
+/// their `HasBamTags` tags. A batch ends early when a paired read follows an
+/// unpaired one or the other way around, so every batch is either paired or unpaired.
+///
+/// # Panics
+/// * If `pairs_per_batch` is 0
+pub fn batches<I, R>(reads: I, pairs_per_batch: usize) -> AlignerBatches<I::IntoIter>
+where
+    I: IntoIterator<Item = R>,
+    R: AlignableReadPair + HasBamTags,
+{
+    assert!(pairs_per_batch > 0, "Batches must hold at least one read");
+    AlignerBatches {
+        reads: reads.into_iter().peekable(),
+        pairs_per_batch,
+    }
+}
+
+impl<I, R> Iterator for AlignerBatches<I>
+where
+    I: Iterator<Item = R>,
+    R: AlignableReadPair + HasBamTags,
+{
+    type Item = AlignerBatch;
+
+    fn next(&mut self) -> Option<AlignerBatch> {
+        let mut batch = AlignerBatch::new();
+        while batch.num_pairs() < self.pairs_per_batch {
+            match self.reads.peek() {
+                Some(read) if batch.accepts(read) => {}
+                _ => break,
+            }
+            let read = self.reads.next().unwrap();
+            batch.push_tagged(&read).unwrap();
+        }
+        if batch.is_empty() {
+            None
+        } else {
+            Some(batch)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair::{AlignableRanges, ReadPair, ReadPart, RpRange, WhichRead};
+    use crate::read_pair_iter::ReadPairIter;
+
+    struct Read {
+        read: ReadPair,
+        paired: bool,
+    }
+
+    impl Read {
+        fn alignable_ranges(&self) -> AlignableRanges {
+            if self.paired {
+                AlignableRanges {
+                    r1: Some(RpRange::new(WhichRead::R1, 26, None)),
+                    r2: Some(RpRange::new(WhichRead::R2, 0, None)),
+                }
+            } else {
+                AlignableRanges::full(false)
+            }
+        }
+    }
+
+    impl AlignableReadPair for Read {
+        fn header(&self) -> &[u8] {
+            self.read.get(WhichRead::R1, ReadPart::Header).unwrap()
+        }
+
+        fn alignable_sequence(&self) -> (&[u8], &[u8]) {
+            self.read.alignable_sequence(self.alignable_ranges())
+        }
+
+        fn alignable_quals(&self) -> (&[u8], &[u8]) {
+            self.read.alignable_quals(self.alignable_ranges())
+        }
+    }
+
+    impl HasBamTags for Read {
+        fn tags(&self) -> Vec<([u8; 2], &[u8])> {
+            let bc = self
+                .read
+                .get_range(RpRange::new(WhichRead::R1, 0, Some(16)), ReadPart::Seq)
+                .unwrap();
+            vec![(*b"CR", bc), (*b"UR", b"ACGT")]
+        }
+    }
+
+    fn reads(paired: bool) -> Vec<Read> {
+        ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap()
+        .map(|r| Read {
+            read: r.unwrap(),
+            paired,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_aligner_batch() {
+        let reads = reads(true);
+        let batches: Vec<_> = batches(self::reads(true), 3).collect();
+        assert_eq!(
+            batches.iter().map(|b| b.num_pairs()).collect::<Vec<_>>(),
+            vec![3, 3, 2]
+        );
+
+        let records: Vec<_> = batches.iter().flat_map(|b| b.iter()).collect();
+        assert_eq!(records.len(), 2 * reads.len());
+        for (read, pair) in reads.iter().zip(records.chunks(2)) {
+            let (seq1, seq2) = read.alignable_sequence();
+            let (qual1, qual2) = read.alignable_quals();
+            assert_eq!((pair[0].seq, pair[0].qual, pair[0].mate), (seq1, qual1, 0));
+            assert_eq!((pair[1].seq, pair[1].qual, pair[1].mate), (seq2, qual2, 1));
+            assert_eq!(pair[0].name, pair[1].name);
+            assert!(read.header().starts_with(pair[0].name));
+            assert!(!pair[0].name.contains(&b' '));
+
+            let bc = &read.read.get(WhichRead::R1, ReadPart::Seq).unwrap()[..16];
+            let comment = [&b"CR:Z:"[..], bc, b"\tUR:Z:ACGT"].concat();
+            assert_eq!(pair[0].comment, &comment[..]);
+        }
+        assert!(batches[0].is_paired());
+
+        // Every string is NUL terminated in the batch buffer
+        let batch = &batches[0];
+        let base = batch.as_bytes().as_ptr() as usize;
+        for record in batch.iter() {
+            for s in &[record.name, record.comment, record.seq, record.qual] {
+                let end = s.as_ptr() as usize - base + s.len();
+                assert_eq!(batch.as_bytes()[end], 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unpaired_batch() {
+        let reads = reads(false);
+        let mut batch = AlignerBatch::new();
+        for read in &reads {
+            batch.push(read, &[]).unwrap();
+        }
+        assert_eq!(batch.len(), reads.len());
+        assert_eq!(batch.num_pairs(), reads.len());
+        assert!(!batch.is_paired());
+        assert!(batch.iter().all(|r| r.mate == 0 && r.comment.is_empty()));
+        assert_eq!(batch.get(reads.len()), None);
+
+        batch.clear();
+        assert!(batch.is_empty());
+        assert_eq!(batch.num_pairs(), 0);
+        assert!(batches(Vec::<Read>::new(), 10).next().is_none());
+    }
+
+    #[test]
+    fn test_mixed_batch() {
+        let paired = reads(true);
+        let unpaired = reads(false);
+        let mut batch = AlignerBatch::new();
+        batch.push(&paired[0], &[]).unwrap();
+        assert!(!batch.accepts(&unpaired[0]));
+        assert!(batch.push(&unpaired[0], &[]).is_err());
+        assert_eq!(batch.len(), 2);
+        assert!(batch.is_paired());
+
+        // A change of pairing starts a new batch
+        let mixed = paired
+            .into_iter()
+            .take(2)
+            .chain(unpaired.into_iter().take(3));
+        let batches: Vec<_> = batches(mixed, 4).collect();
+        assert_eq!(
+            batches
+                .iter()
+                .map(|b| (b.num_pairs(), b.len(), b.is_paired()))
+                .collect::<Vec<_>>(),
+            vec![(2, 4, true), (3, 3, false)]
+        );
+    }
+}
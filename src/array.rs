@@ -11,6 +11,55 @@ pub trait ArrayContent {
     fn expected_contents() -> &'static str;
 }
 
+/// Define a unit struct implementing `ArrayContent` whose valid contents are the
+/// bytes of a fixed alphabet. This avoids re-implementing the validation for every
+/// custom `ByteArray` alphabet (color-space reads, protein sequences, barcodes
+/// with separators, etc.). `validate_bytes()` panics with the position of the
+/// first byte that is not in the alphabet.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::array::ByteArray;
+/// use fastq_set::byte_array_alphabet;
+///
+/// byte_array_alphabet! {
+///     /// Color-space calls, with '.' for a missing call
+///     pub struct ColorSpaceContents = b"0123.", "A [0123.]* string";
+/// }
+/// pub type ColorSeq = ByteArray<ColorSpaceContents, 32>;
+///
+/// let seq = ColorSeq::from_bytes(b"0.1230");
+/// assert_eq!(seq.as_str(), "0.1230");
+/// assert!(std::panic::catch_unwind(|| ColorSeq::from_bytes(b"0124")).is_err());
+/// ```
+#[macro_export]
+macro_rules! byte_array_alphabet {
+    ($(#[$meta:meta])* $vis:vis struct $name:ident = $alphabet:expr, $description:expr;) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Debug)]
+        $vis struct $name;
+
+        impl $crate::array::ArrayContent for $name {
+            fn validate_bytes(bytes: &[u8]) {
+                let alphabet: &[u8] = $alphabet;
+                for (i, &c) in bytes.iter().enumerate() {
+                    if !alphabet.contains(&c) {
+                        panic!(
+                            "Invalid character {} at position {}. Expected {}",
+                            c,
+                            i,
+                            <$name as $crate::array::ArrayContent>::expected_contents()
+                        );
+                    }
+                }
+            }
+            fn expected_contents() -> &'static str {
+                $description
+            }
+        }
+    };
+}
+
 /// Fixed-sized container for a short DNA sequence or quality.
 /// The capacity is determined by the type `N` and the contents are validates based on type `T`
 /// Typically used as a convenient container for barcode or UMI sequences or quality.
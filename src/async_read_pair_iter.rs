@@ -0,0 +1,462 @@
+//! Asynchronous counterpart of `ReadPairIter`, reading FASTQ data from files or
+//! `tokio` readers, such as streams from network storage, as a `Stream` of read
+//! pairs. Requires the `async` feature.
+//!
+//! The read pairs are produced by a `ReadPairIter` running on the blocking thread
+//! pool of the `tokio` runtime, in batches so that the tasks polling the stream
+//! never block on decompression or parsing. The input is therefore read exactly
+//! like `ReadPairIter` reads it: any of the supported compressions, and all of
+//! its settings, applied with [`configure`](struct.AsyncReadPairIter.html#method.configure).
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::async_read_pair_iter::AsyncReadPairIter;
+//! use fastq_set::read_pair::WhichRead;
+//! use fastq_set::read_pair_iter::InputFastqs;
+//! use futures::StreamExt;
+//!
+//! let input = InputFastqs {
+//!     r1: "tests/read_pair_iter/good-RA.fastq".to_string(),
+//!     r2: None,
+//!     i1: Some("tests/read_pair_iter/good-I1.fastq".to_string()),
+//!     i2: None,
+//!     r1_interleaved: true,
+//! };
+//! let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+//! let n = rt.block_on(async {
+//!     let reads = AsyncReadPairIter::from_fastq_files(&input)
+//!         .await
+//!         .unwrap()
+//!         .configure(|iter| Ok(iter.trim_length(WhichRead::R1, Some(10))));
+//!     reads.filter(|r| futures::future::ready(r.is_ok())).count().await
+//! });
+//! assert_eq!(n, 8);
+//! ```
+
+use crate::read_pair::ReadPair;
+use crate::read_pair_iter::{FastqError, InputFastqs, ReadPairIter};
+use futures_core::Stream;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::runtime::Handle;
+use tokio::task::{JoinError, JoinHandle};
+
+// Number of read pairs read by each blocking task
+const ASYNC_BATCH_SIZE: usize = 256;
+
+type Opener = Box<dyn FnOnce() -> Result<ReadPairIter, FastqError> + Send>;
+type Configure = Box<dyn FnOnce(ReadPairIter) -> Result<ReadPairIter, FastqError> + Send>;
+
+/// Where the next batch of read pairs comes from
+enum Source {
+    /// The iterator is opened by the first blocking task
+    Open(Opener),
+    Iter(Box<ReadPairIter>),
+}
+
+enum State {
+    Idle(Source),
+    Reading(JoinHandle<Batch>),
+    Done,
+}
+
+struct Batch {
+    /// The iterator, unless it ended or returned an error
+    iter: Option<Box<ReadPairIter>>,
+    reads: Vec<Result<ReadPair, FastqError>>,
+}
+
+/// Open or configure the iterator if needed, and read the next batch of read pairs
+fn read_batch(source: Source, configure: Vec<Configure>) -> Batch {
+    let iter = match source {
+        Source::Open(open) => open(),
+        Source::Iter(iter) => Ok(*iter),
+    };
+    let mut iter = match configure.into_iter().fold(iter, |iter, f| iter.and_then(f)) {
+        Ok(iter) => iter,
+        Err(e) => {
+            return Batch {
+                iter: None,
+                reads: vec![Err(e)],
+            }
+        }
+    };
+
+    let mut reads = Vec::with_capacity(ASYNC_BATCH_SIZE);
+    while reads.len() < ASYNC_BATCH_SIZE {
+        match iter.next() {
+            Some(Ok(read_pair)) => reads.push(Ok(read_pair)),
+            Some(Err(e)) => {
+                reads.push(Err(e));
+                return Batch { iter: None, reads };
+            }
+            None => return Batch { iter: None, reads },
+        }
+    }
+    Batch {
+        iter: Some(Box::new(iter)),
+        reads,
+    }
+}
+
+/// Propagate the panic of a blocking task
+fn task_panic(e: JoinError) -> ! {
+    match e.try_into_panic() {
+        Ok(panic) => std::panic::resume_unwind(panic),
+        Err(e) => panic!("FASTQ reading task failed: {}", e),
+    }
+}
+
+/// Synchronous `Read` over an `AsyncRead`, used from a blocking thread of the runtime
+struct BlockingReader {
+    reader: Pin<Box<dyn AsyncRead + Send>>,
+    handle: Handle,
+}
+
+impl Read for BlockingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = ReadFuture {
+            reader: self.reader.as_mut(),
+            buf,
+        };
+        self.handle.block_on(read)
+    }
+}
+
+struct ReadFuture<'a> {
+    reader: Pin<&'a mut (dyn AsyncRead + Send)>,
+    buf: &'a mut [u8],
+}
+
+impl Future for ReadFuture<'_> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut buf = ReadBuf::new(this.buf);
+        match this.reader.as_mut().poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A `Stream` of `ReadPair`s from a parallel set of FASTQ streams, read by a
+/// `ReadPairIter` on the blocking thread pool of the `tokio` runtime polling the
+/// stream. The stream ends after the first error.
+pub struct AsyncReadPairIter {
+    state: State,
+    configure: Vec<Configure>,
+    reads: VecDeque<Result<ReadPair, FastqError>>,
+}
+
+impl AsyncReadPairIter {
+    /// Stream the read pairs of `iter`, e.g. a `ReadPairIter` opened and configured
+    /// outside of the runtime.
+    pub fn new(iter: ReadPairIter) -> Self {
+        Self::with_source(Source::Iter(Box::new(iter)))
+    }
+
+    /// Stream the read pairs from readers supplying FASTQ data for the available
+    /// read components, in the order R1, R2, I1, I2, as read by
+    /// `ReadPairIter::from_readers`. Errors refer to the readers by the name of the
+    /// read component (e.g. `read1`). For interleaved R1/R2 data, set
+    /// `readers[1] = None`, and set `r1_interleaved = true`.
+    pub fn from_readers<R: AsyncRead + Send + 'static>(
+        mut readers: [Option<R>; 4],
+        r1_interleaved: bool,
+    ) -> Self {
+        let mut readers: [Option<Pin<Box<dyn AsyncRead + Send>>>; 4] = [
+            readers[0].take().map(|r| Box::pin(r) as _),
+            readers[1].take().map(|r| Box::pin(r) as _),
+            readers[2].take().map(|r| Box::pin(r) as _),
+            readers[3].take().map(|r| Box::pin(r) as _),
+        ];
+        let open = move || {
+            // Runs on a blocking thread, within the context of the runtime
+            let handle = Handle::current();
+            let mut blocking = [None, None, None, None];
+            for (idx, reader) in readers.iter_mut().enumerate() {
+                blocking[idx] = reader.take().map(|reader| BlockingReader {
+                    reader,
+                    handle: handle.clone(),
+                });
+            }
+            ReadPairIter::from_readers(blocking, r1_interleaved)
+        };
+        Self::with_source(Source::Open(Box::new(open)))
+    }
+
+    /// Open the FASTQ files of `input_fastqs` with `ReadPairIter::from_fastq_files`,
+    /// on a blocking thread.
+    pub async fn from_fastq_files(input_fastqs: &InputFastqs) -> Result<Self, FastqError> {
+        let input_fastqs = input_fastqs.clone();
+        let iter =
+            tokio::task::spawn_blocking(move || ReadPairIter::from_fastq_files(&input_fastqs))
+                .await
+                .unwrap_or_else(|e| task_panic(e))?;
+        Ok(Self::new(iter))
+    }
+
+    /// Apply the settings of `f` to the `ReadPairIter` before it reads any read pair,
+    /// e.g. `configure(|iter| iter.quality_offsets([64; 4]))`. The first read pair
+    /// of the stream is the error returned by `f`, if any. Must be called before the
+    /// stream is first polled.
+    pub fn configure<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(ReadPairIter) -> Result<ReadPairIter, FastqError> + Send + 'static,
+    {
+        self.configure.push(Box::new(f));
+        self
+    }
+
+    fn with_source(source: Source) -> Self {
+        AsyncReadPairIter {
+            state: State::Idle(source),
+            configure: Vec::new(),
+            reads: VecDeque::new(),
+        }
+    }
+}
+
+impl Stream for AsyncReadPairIter {
+    type Item = Result<ReadPair, FastqError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(read_pair) = this.reads.pop_front() {
+                return Poll::Ready(Some(read_pair));
+            }
+            match std::mem::replace(&mut this.state, State::Done) {
+                State::Done => return Poll::Ready(None),
+                State::Idle(source) => {
+                    let configure = std::mem::take(&mut this.configure);
+                    this.state = State::Reading(tokio::task::spawn_blocking(move || {
+                        read_batch(source, configure)
+                    }));
+                }
+                State::Reading(mut task) => match Pin::new(&mut task).poll(cx) {
+                    Poll::Pending => {
+                        this.state = State::Reading(task);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Ok(batch)) => {
+                        this.reads.extend(batch.reads);
+                        if let Some(iter) = batch.iter {
+                            // Read the next batch while this one is consumed
+                            this.state = State::Reading(tokio::task::spawn_blocking(move || {
+                                read_batch(Source::Iter(iter), Vec::new())
+                            }));
+                        }
+                    }
+                    Poll::Ready(Err(e)) => task_panic(e),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair::{ReadPart, WhichRead};
+    use futures::StreamExt;
+    use std::io::Read;
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    fn collect(data: Vec<u8>, interleaved: bool) -> Vec<Result<ReadPair, FastqError>> {
+        let stream = AsyncReadPairIter::from_readers(
+            [Some(std::io::Cursor::new(data)), None, None, None],
+            interleaved,
+        );
+        block_on(stream.collect())
+    }
+
+    fn expected() -> Vec<ReadPair> {
+        ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_async_compression() {
+        let expected = expected();
+        let ra = std::fs::read("tests/read_pair_iter/good-RA.fastq").unwrap();
+        let gz = std::fs::read("tests/read_pair_iter/good-gzipped-RA.fastq.gz").unwrap();
+        let zst = zstd::encode_all(&ra[..], 3).unwrap();
+        let mut xz = Vec::new();
+        xz2::read::XzEncoder::new(&ra[..], 6)
+            .read_to_end(&mut xz)
+            .unwrap();
+
+        for data in &[ra, gz, zst, xz] {
+            let reads: Vec<ReadPair> = collect(data.clone(), true)
+                .into_iter()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(reads, expected);
+
+            // Concatenated streams
+            let reads: Vec<ReadPair> = collect([&data[..], &data[..]].concat(), true)
+                .into_iter()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(reads, [&expected[..], &expected[..]].concat());
+        }
+
+        let lz4 = std::fs::read("tests/read_pair_iter/good-lz4-RA.fastq.lz4").unwrap();
+        let reads: Vec<ReadPair> = collect(lz4, true)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(reads, expected);
+    }
+
+    #[test]
+    fn test_async_files() {
+        let input = InputFastqs {
+            r1: "tests/read_pair_iter/good-RA.fastq".to_string(),
+            r2: None,
+            i1: Some("tests/read_pair_iter/good-I1.fastq".to_string()),
+            i2: Some("tests/read_pair_iter/good-I2.fastq".to_string()),
+            r1_interleaved: true,
+        };
+        let expected: Vec<ReadPair> = ReadPairIter::from_fastq_files(&input)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let reads: Vec<ReadPair> = block_on(async {
+            AsyncReadPairIter::from_fastq_files(&input)
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await
+        })
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .unwrap();
+        assert_eq!(reads, expected);
+
+        let missing = InputFastqs {
+            r1: "tests/read_pair_iter/missing.fastq".to_string(),
+            ..input
+        };
+        assert!(block_on(AsyncReadPairIter::from_fastq_files(&missing)).is_err());
+    }
+
+    #[test]
+    fn test_async_configure() {
+        // More read pairs than a batch, read with the settings of the sync iterator
+        let path = "tests/read_pair_iter/vdj_micro_50k.fastq";
+        let configure = |iter: ReadPairIter| {
+            Ok(iter
+                .trim_length(WhichRead::R1, Some(20))
+                .subsample_rate(0.5)
+                .seed(7))
+        };
+        let expected: Vec<ReadPair> =
+            configure(ReadPairIter::new(Some(path), None, None, None, true).unwrap())
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert!(expected.len() > ASYNC_BATCH_SIZE);
+
+        let data = std::fs::read(path).unwrap();
+        let stream = AsyncReadPairIter::from_readers(
+            [Some(std::io::Cursor::new(data)), None, None, None],
+            true,
+        )
+        .configure(configure);
+        let reads: Vec<ReadPair> = block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(reads, expected);
+        assert!(reads
+            .iter()
+            .all(|r| r.get(WhichRead::R1, ReadPart::Seq).unwrap().len() <= 20));
+
+        let bad_offsets =
+            AsyncReadPairIter::new(ReadPairIter::new(Some(path), None, None, None, true).unwrap())
+                .configure(|iter| iter.quality_offsets([20; 4]));
+        let res: Vec<_> = block_on(bad_offsets.collect());
+        assert_eq!(res.len(), 1);
+        assert!(res[0].is_err());
+    }
+
+    #[test]
+    fn test_async_errors() {
+        let ra = std::fs::read("tests/read_pair_iter/good-RA.fastq").unwrap();
+
+        // Odd number of interleaved records
+        let lines: Vec<_> = ra.split_inclusive(|&c| c == b'\n').collect();
+        let res = collect(lines[..12].concat(), true);
+        assert!(res.last().unwrap().is_err());
+
+        // Truncated record
+        let res = collect(ra[..ra.len() - 10].to_vec(), false);
+        assert!(res.last().unwrap().is_err());
+
+        // No trailing newline, which the fastq parser of ReadPairIter reports as a
+        // possibly truncated file
+        let data = ra[..ra.len() - 1].to_vec();
+        let reads = collect(data.clone(), true);
+        let sync_ok =
+            ReadPairIter::from_readers([Some(std::io::Cursor::new(data)), None, None, None], true)
+                .unwrap()
+                .take_while(|r| r.is_ok())
+                .count();
+        assert!(sync_ok < 8);
+        assert_eq!(reads.len(), sync_ok + 1);
+        assert!(reads.last().unwrap().is_err());
+
+        let bad_char = b"@read\nACGX\n+\nIIII\n".to_vec();
+        assert!(collect(bad_char, false)[0].is_err());
+        let bad_len = b"@read\nACGT\n+\nIII\n".to_vec();
+        assert!(collect(bad_len, false)[0].is_err());
+        assert!(collect(b"garbage".to_vec(), false)[0].is_err());
+        assert!(collect(Vec::new(), false).is_empty());
+        let empty = AsyncReadPairIter::from_readers(
+            [Some(std::io::Cursor::new(Vec::new())), None, None, None],
+            false,
+        )
+        .configure(|iter| Ok(iter.reject_empty(true)));
+        let res: Vec<_> = block_on(empty.collect());
+        assert_eq!(res.len(), 1);
+        assert!(res[0].is_err());
+
+        // Inputs of different lengths
+        let stream = AsyncReadPairIter::from_readers(
+            [
+                Some(std::io::Cursor::new(ra.clone())),
+                None,
+                Some(std::io::Cursor::new(
+                    std::fs::read("tests/read_pair_iter/short-I1.fastq").unwrap(),
+                )),
+                None,
+            ],
+            true,
+        );
+        let res: Vec<_> = block_on(stream.collect());
+        assert!(res.last().unwrap().is_err());
+    }
+}
@@ -1,12 +1,74 @@
+use serde::{Deserialize, Serialize};
 use std;
-use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::Arc;
 use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Buffering of a `BackgroundIterator`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackgroundConfig {
+    /// Number of batches the worker thread can work ahead of the consumer
+    pub depth: usize,
+    /// Number of items sent to the consumer at a time. Larger batches reduce the
+    /// synchronization between the threads.
+    pub batch_size: usize,
+}
+
+impl BackgroundConfig {
+    pub fn new(depth: usize, batch_size: usize) -> Self {
+        BackgroundConfig { depth, batch_size }
+    }
+}
+
+/// Occupancy of the queue between the worker thread and the consumer of a
+/// `BackgroundIterator`, reported by
+/// [`BackgroundIterator::queue_stats`](struct.BackgroundIterator.html#method.queue_stats).
+/// Frequent consumer stalls with a low mean occupancy indicate that the worker
+/// thread can't keep up, e.g. on a slow filesystem, while frequent producer stalls
+/// indicate that the consumer is the bottleneck.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    /// Batches received by the consumer
+    pub batches: u64,
+    /// Sum over the received batches of the number of batches queued when the
+    /// batch was received, including it
+    pub occupancy_sum: u64,
+    /// Batches the consumer had to wait for, because the queue was empty
+    pub consumer_stalls: u64,
+    /// Time the consumer spent waiting on an empty queue
+    pub consumer_wait: Duration,
+    /// Batches the worker thread had to wait to send, because the queue was full
+    pub producer_stalls: u64,
+}
+
+impl QueueStats {
+    /// Mean number of batches queued when the consumer received a batch
+    pub fn mean_occupancy(&self) -> f64 {
+        if self.batches == 0 {
+            0.0
+        } else {
+            self.occupancy_sum as f64 / self.batches as f64
+        }
+    }
+}
+
+/// Counters shared with the worker thread
+#[derive(Default)]
+struct Shared {
+    queued: AtomicI64,
+    producer_stalls: AtomicU64,
+}
 
 /// Execute an iterator on a worker thread, which can work ahead a configurable number of items.
 pub struct BackgroundIterator<T> {
-    rx: Receiver<Option<T>>,
+    rx: Receiver<Option<Vec<T>>>,
+    batch: std::vec::IntoIter<T>,
     done: bool,
     handle: Option<JoinHandle<()>>,
+    shared: Arc<Shared>,
+    stats: QueueStats,
 }
 
 impl<T> BackgroundIterator<T> {
@@ -28,29 +90,63 @@ impl<T> BackgroundIterator<T> {
             }
         }
     }
+
+    /// Occupancy of the queue so far
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            producer_stalls: self.shared.producer_stalls.load(Ordering::Relaxed),
+            ..self.stats
+        }
+    }
+
+    /// Receive the next batch, tracking the occupancy of the queue
+    fn recv(&mut self) -> Option<Option<Vec<T>>> {
+        let msg = match self.rx.try_recv() {
+            Ok(msg) => Some(msg),
+            Err(TryRecvError::Empty) => {
+                self.stats.consumer_stalls += 1;
+                let start = Instant::now();
+                let msg = self.rx.recv().ok();
+                self.stats.consumer_wait += start.elapsed();
+                msg
+            }
+            Err(TryRecvError::Disconnected) => None,
+        };
+        if let Some(Some(_)) = msg {
+            let queued = self.shared.queued.fetch_sub(1, Ordering::Relaxed);
+            self.stats.batches += 1;
+            self.stats.occupancy_sum += queued.max(1) as u64;
+        }
+        msg
+    }
 }
 
 impl<T: Send> Iterator for BackgroundIterator<T> {
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
-        if self.done {
-            return None;
-        }
-
-        match self.rx.recv() {
-            Ok(Some(v)) => Some(v),
-            Ok(None) => {
-                self.done = true;
-                self.join_and_propagate_panic();
-                None
+        loop {
+            if let Some(v) = self.batch.next() {
+                return Some(v);
+            }
+            if self.done {
+                return None;
             }
-            // if the producer thread dies, then the sender thread must have panicked
-            // propagate the panic, otherwise we will silently continue with likely incomplete results
-            Err(_) => {
-                self.done = true;
-                self.join_and_propagate_panic();
-                None
+
+            match self.recv() {
+                Some(Some(batch)) => self.batch = batch.into_iter(),
+                Some(None) => {
+                    self.done = true;
+                    self.join_and_propagate_panic();
+                    return None;
+                }
+                // if the producer thread dies, then the sender thread must have panicked
+                // propagate the panic, otherwise we will silently continue with likely incomplete results
+                None => {
+                    self.done = true;
+                    self.join_and_propagate_panic();
+                    return None;
+                }
             }
         }
     }
@@ -64,12 +160,34 @@ impl<T: 'static + Send> BackgroundIterator<T> {
         itr: I,
         max_read_ahead: usize,
     ) -> BackgroundIterator<T> {
-        let (tx, rx) = sync_channel::<Option<T>>(max_read_ahead);
+        Self::with_config(itr, BackgroundConfig::new(max_read_ahead, 1))
+    }
+
+    /// Iterate through `itr` on a newly created thread, sending the items to the consumer
+    /// in batches of `config.batch_size` items. The worker thread will continue to produce
+    /// batches until it is `config.depth` batches ahead of the consumer iterator.
+    pub fn with_config<I: 'static + Send + Iterator<Item = T>>(
+        itr: I,
+        config: BackgroundConfig,
+    ) -> BackgroundIterator<T> {
+        let batch_size = config.batch_size.max(1);
+        let (tx, rx) = sync_channel::<Option<Vec<T>>>(config.depth);
+        let shared = Arc::new(Shared::default());
+        let worker_shared = shared.clone();
         let handle = spawn(move || {
+            let send = |batch: Vec<T>| send_batch(&tx, &worker_shared, batch);
+            let mut batch = Vec::with_capacity(batch_size);
             for item in itr {
-                if tx.send(Some(item)).is_err() {
-                    return;
-                };
+                batch.push(item);
+                if batch.len() == batch_size {
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                    if send(full).is_err() {
+                        return;
+                    }
+                }
+            }
+            if !batch.is_empty() && send(batch).is_err() {
+                return;
             }
 
             tx.send(None).unwrap();
@@ -77,10 +195,34 @@ impl<T: 'static + Send> BackgroundIterator<T> {
 
         BackgroundIterator {
             rx,
+            batch: Vec::new().into_iter(),
             handle: Some(handle),
             done: false,
+            shared,
+            stats: QueueStats::default(),
+        }
+    }
+}
+
+/// Send `batch`, counting a producer stall if the queue is full. Fails if the
+/// consumer is gone.
+fn send_batch<T>(
+    tx: &SyncSender<Option<Vec<T>>>,
+    shared: &Shared,
+    batch: Vec<T>,
+) -> Result<(), ()> {
+    let sent = match tx.try_send(Some(batch)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(batch)) => {
+            shared.producer_stalls.fetch_add(1, Ordering::Relaxed);
+            tx.send(batch).map_err(|_| ())
         }
+        Err(TrySendError::Disconnected(_)) => Err(()),
+    };
+    if sent.is_ok() {
+        shared.queued.fetch_add(1, Ordering::Relaxed);
     }
+    sent
 }
 
 #[cfg(test)]
@@ -148,4 +290,30 @@ mod test {
 
         assert_eq!(n_send, n_read);
     }
+
+    #[test]
+    fn batched_queue_stats() {
+        for &(depth, batch_size) in &[(0, 1), (2, 7), (4, 1000)] {
+            let config = BackgroundConfig::new(depth, batch_size);
+            let mut bg_iter = BackgroundIterator::with_config(0..100usize, config);
+            let items: Vec<_> = bg_iter.by_ref().collect();
+            assert_eq!(items, (0..100).collect::<Vec<_>>());
+
+            let stats = bg_iter.queue_stats();
+            assert_eq!(stats.batches, (0..100).step_by(batch_size).count() as u64);
+            assert!(stats.consumer_stalls <= stats.batches + 1);
+            assert!(stats.mean_occupancy() >= 1.0);
+            assert!(stats.mean_occupancy() <= (depth + 1) as f64);
+        }
+
+        // A slow consumer leaves the worker thread waiting on a full queue
+        let config = BackgroundConfig::new(1, 4);
+        let mut bg_iter = BackgroundIterator::with_config(0..40usize, config);
+        for _ in bg_iter.by_ref() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let stats = bg_iter.queue_stats();
+        assert_eq!(stats.batches, 10);
+        assert!(stats.producer_stalls > 0);
+    }
 }
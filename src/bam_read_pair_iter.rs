@@ -0,0 +1,864 @@
+//! Read pairs from an unaligned BAM file, or from an aligned BAM file sorted by
+//! read name, so that data distributed only as BAM can be passed to
+//! `FastqProcessor::process_read` like reads from FASTQ files.
+//!
+//! Paired records (flag `0x1`) are combined with their mate, which must be the
+//! next primary record, into R1 and R2. Unpaired records go to a single read slot,
+//! R1 by default. Secondary and supplementary alignments are skipped, and reads
+//! aligned to the reverse strand are reverse complemented back to their sequenced
+//! orientation. The other read slots are filled from SAM tags, by default I1 and I2
+//! from the sample index tags `BC` and `QT`. See
+//! [`BamReadPairIter::cellranger`](struct.BamReadPairIter.html#method.cellranger)
+//! for the layout of Cell Ranger BAM files.
+//!
+//! CRAM files are decoded to BAM by `samtools view`, which must be installed. The
+//! `samtools` executable is taken from the `SAMTOOLS` environment variable if set,
+//! and looked up in the `PATH` otherwise. The reference sequence of aligned CRAM
+//! files is found as `samtools` finds it, or given with
+//! [`BamReadPairIter::from_cram`](struct.BamReadPairIter.html#method.from_cram).
+
+use crate::block_gz::BgzfReader;
+use crate::read_pair::{ReadPair, WhichRead, MAX_PHRED_QUAL};
+use failure::{format_err, Error};
+use fastq::OwnedRecord;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread;
+
+/// Number of threads decompressing the BAM file
+const BGZF_THREADS: usize = 2;
+
+const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+const CRAM_MAGIC: &[u8; 4] = b"CRAM";
+
+const FLAG_PAIRED: u16 = 0x1;
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_READ1: u16 = 0x40;
+const FLAG_READ2: u16 = 0x80;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// Quality score of bases without a quality in the BAM file (Phred 40)
+const MISSING_QUAL: u8 = b'I';
+
+/// Quality value of bases without a quality in the BAM encoding
+const BAM_MISSING_QUAL: u8 = 0xff;
+
+/// Part of a read stored in a SAM tag, e.g. the cell barcode in `CR`, with its
+/// qualities in `CY`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagSegment {
+    pub seq_tag: [u8; 2],
+    /// Tag holding the qualities of the sequence. Missing qualities are set to Phred 40.
+    pub qual_tag: Option<[u8; 2]>,
+    /// Which of the `-` separated fields of the sequence tag to use, and of the
+    /// ` ` separated fields of the quality tag, e.g. 1 for the i5 index in `BC`.
+    pub field: usize,
+}
+
+impl TagSegment {
+    pub fn new(seq_tag: [u8; 2], qual_tag: Option<[u8; 2]>) -> Self {
+        TagSegment {
+            seq_tag,
+            qual_tag,
+            field: 0,
+        }
+    }
+
+    pub fn field(mut self, field: usize) -> Self {
+        self.field = field;
+        self
+    }
+}
+
+/// One record of a BAM file
+struct BamRecord {
+    name: Vec<u8>,
+    flag: u16,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+    aux: Vec<u8>,
+}
+
+impl BamRecord {
+    fn parse(data: &[u8]) -> Option<BamRecord> {
+        let u16_at = |i: usize| Some(u16::from_le_bytes([*data.get(i)?, *data.get(i + 1)?]));
+        let l_read_name = *data.get(8)? as usize;
+        let n_cigar_op = u16_at(12)? as usize;
+        let flag = u16_at(14)?;
+        let l_seq = u32::from_le_bytes([
+            *data.get(16)?,
+            *data.get(17)?,
+            *data.get(18)?,
+            *data.get(19)?,
+        ]) as usize;
+
+        let name_start = 32;
+        let seq_start = name_start + l_read_name + 4 * n_cigar_op;
+        let qual_start = seq_start + l_seq / 2 + l_seq % 2;
+        let aux_start = qual_start + l_seq;
+        if l_read_name == 0 || aux_start > data.len() {
+            return None;
+        }
+
+        const BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+        let packed = &data[seq_start..qual_start];
+        let seq = (0..l_seq)
+            .map(|i| {
+                let b = packed[i / 2];
+                BASES[if i % 2 == 0 { b >> 4 } else { b & 0xf } as usize]
+            })
+            .collect();
+        // Qualities are stored without the +33 offset, and are all 0xff if missing.
+        // Qualities above the range of phred+33 are capped.
+        let qual = data[qual_start..aux_start]
+            .iter()
+            .map(|&q| match q {
+                BAM_MISSING_QUAL => MISSING_QUAL,
+                q => q.min(MAX_PHRED_QUAL) + 33,
+            })
+            .collect();
+
+        let mut rec = BamRecord {
+            name: data[name_start..name_start + l_read_name - 1].to_vec(),
+            flag,
+            seq,
+            qual,
+            aux: data[aux_start..].to_vec(),
+        };
+        if flag & FLAG_REVERSE != 0 {
+            rec.seq = bio::alphabets::dna::revcomp(&rec.seq);
+            rec.qual.reverse();
+        }
+        Some(rec)
+    }
+
+    /// The value of the string tag `tag`. Returns `Err` if the tags are malformed.
+    fn tag(&self, tag: [u8; 2]) -> Result<Option<&[u8]>, ()> {
+        let aux = &self.aux;
+        let mut i = 0;
+        while i + 3 <= aux.len() {
+            let typ = aux[i + 2];
+            let start = i + 3;
+            let len = match typ {
+                b'A' | b'c' | b'C' => 1,
+                b's' | b'S' => 2,
+                b'i' | b'I' | b'f' => 4,
+                b'Z' | b'H' => {
+                    let len = aux[start..].iter().position(|&c| c == 0).ok_or(())?;
+                    if aux[i..i + 2] == tag {
+                        return Ok(Some(&aux[start..start + len]));
+                    }
+                    len + 1
+                }
+                b'B' => {
+                    let size = match aux.get(start) {
+                        Some(b'c') | Some(b'C') => 1,
+                        Some(b's') | Some(b'S') => 2,
+                        Some(b'i') | Some(b'I') | Some(b'f') => 4,
+                        _ => return Err(()),
+                    };
+                    let count = aux.get(start + 1..start + 5).ok_or(())?;
+                    let count = u32::from_le_bytes([count[0], count[1], count[2], count[3]]);
+                    5 + size * count as usize
+                }
+                _ => return Err(()),
+            };
+            i = start + len;
+        }
+        if i == aux.len() {
+            Ok(None)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// Iterator over the read pairs of a BAM file.
+///
+/// # Example
+/// ```rust,no_run
+/// use fastq_set::bam_read_pair_iter::BamReadPairIter;
+/// use fastq_set::read_pair::{ReadPart, WhichRead};
+/// for rp in BamReadPairIter::new("possorted_genome_bam.bam").unwrap().cellranger() {
+///     let rp = rp.unwrap();
+///     let barcode_umi = rp.get(WhichRead::R1, ReadPart::Seq).unwrap();
+///     let insert = rp.get(WhichRead::R2, ReadPart::Seq).unwrap();
+/// }
+/// ```
+pub struct BamReadPairIter {
+    reader: Box<dyn Read + Send>,
+    path: PathBuf,
+    unpaired_read: WhichRead,
+    tag_reads: [Option<Vec<TagSegment>>; 4],
+    peeked: Option<BamRecord>,
+    done: bool,
+}
+
+impl BamReadPairIter {
+    /// Open the BAM or CRAM file at `path`
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut file = File::open(path)
+            .map_err(|e| format_err!("Error opening BAM file {:?}: {}", path, e))?;
+        let mut start = Vec::with_capacity(CRAM_MAGIC.len());
+        (&mut file)
+            .take(CRAM_MAGIC.len() as u64)
+            .read_to_end(&mut start)
+            .map_err(|e| format_err!("Error reading BAM file {:?}: {}", path, e))?;
+        if start == CRAM_MAGIC {
+            return Self::from_cram(path, None);
+        }
+        let reader = io::Cursor::new(start).chain(BufReader::new(file));
+        Self::from_reader(reader, path)
+    }
+
+    /// Open the CRAM file at `path`, decoded by `samtools view`, with the reference
+    /// sequence of its alignments in the FASTA file `reference` if given.
+    pub fn from_cram(path: impl AsRef<Path>, reference: Option<&Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let reader = SamtoolsReader::spawn(path, reference, None)
+            .map_err(|e| format_err!("Error decoding CRAM file {:?}: {}", path, e))?;
+        Self::from_bam(reader, path.to_path_buf())
+    }
+
+    /// Read BAM or CRAM data from `reader`. `path` is used in error messages.
+    /// CRAM data is piped through `samtools view`.
+    pub fn from_reader<R: Read + Send + 'static>(
+        mut reader: R,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut start = [0u8; 4];
+        reader
+            .read_exact(&mut start)
+            .map_err(|e| format_err!("Error reading BAM file {:?}: {}", path, e))?;
+        let reader = io::Cursor::new(start).chain(reader);
+        if &start == CRAM_MAGIC {
+            let cram = SamtoolsReader::spawn(Path::new("-"), None, Some(Box::new(reader)))
+                .map_err(|e| format_err!("Error decoding CRAM file {:?}: {}", path, e))?;
+            return Self::from_bam(cram, path);
+        }
+        Self::from_bam(reader, path)
+    }
+
+    fn from_bam<R: Read + Send + 'static>(reader: R, path: PathBuf) -> Result<Self, Error> {
+        let mut iter = BamReadPairIter {
+            reader: Box::new(BgzfReader::new(reader, BGZF_THREADS)),
+            path,
+            unpaired_read: WhichRead::R1,
+            tag_reads: [None, None, None, None],
+            peeked: None,
+            done: false,
+        };
+        iter.read_header()?;
+        Ok(iter
+            .tag_read(WhichRead::I1, vec![TagSegment::new(*b"BC", Some(*b"QT"))])
+            .tag_read(
+                WhichRead::I2,
+                vec![TagSegment::new(*b"BC", Some(*b"QT")).field(1)],
+            ))
+    }
+
+    /// Put the sequence of unpaired records in read `which` instead of R1.
+    pub fn unpaired_read(mut self, which: WhichRead) -> Self {
+        self.unpaired_read = which;
+        self
+    }
+
+    /// Fill read `which` with the concatenation of `segments`, taken from the tags of
+    /// the record, or of R1 for paired records. This replaces the sequence of the
+    /// record for that read. The read is left empty if a tag is missing.
+    /// An empty `segments` removes the mapping of `which`.
+    pub fn tag_read(mut self, which: WhichRead, segments: Vec<TagSegment>) -> Self {
+        self.tag_reads[which as usize] = if segments.is_empty() {
+            None
+        } else {
+            Some(segments)
+        };
+        self
+    }
+
+    /// The layout of Cell Ranger BAM files, where each unpaired record holds R2, and
+    /// R1 is the raw cell barcode `CR` followed by the raw UMI `UR`.
+    pub fn cellranger(self) -> Self {
+        self.unpaired_read(WhichRead::R2).tag_read(
+            WhichRead::R1,
+            vec![
+                TagSegment::new(*b"CR", Some(*b"CY")),
+                TagSegment::new(*b"UR", Some(*b"UY")),
+            ],
+        )
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut self.reader.by_ref().take(len), &mut io::sink())?;
+        if skipped < len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> Result<(), Error> {
+        let mut header = || -> io::Result<bool> {
+            let mut magic = [0u8; 4];
+            self.reader.read_exact(&mut magic)?;
+            if &magic != BAM_MAGIC {
+                return Ok(false);
+            }
+            let l_text = self.read_u32()?;
+            self.skip(l_text as u64)?;
+            for _ in 0..self.read_u32()? {
+                let l_name = self.read_u32()?;
+                self.skip(l_name as u64 + 4)?;
+            }
+            Ok(true)
+        };
+        match header() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format_err!("{:?} is not a BAM file", self.path)),
+            Err(e) => Err(format_err!(
+                "Error reading header of BAM file {:?}: {}",
+                self.path,
+                e
+            )),
+        }
+    }
+
+    /// The next primary record
+    fn next_record(&mut self) -> Result<Option<BamRecord>, Error> {
+        if let Some(rec) = self.peeked.take() {
+            return Ok(Some(rec));
+        }
+        loop {
+            let block_size = match self.read_u32() {
+                Ok(n) => n as usize,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(format_err!("Error reading BAM file {:?}: {}", self.path, e)),
+            };
+            let mut data = vec![0; block_size];
+            self.reader
+                .read_exact(&mut data)
+                .map_err(|e| format_err!("Error reading BAM file {:?}: {}", self.path, e))?;
+            let rec = BamRecord::parse(&data)
+                .ok_or_else(|| format_err!("Malformed record in BAM file {:?}", self.path))?;
+            if rec.flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) == 0 {
+                return Ok(Some(rec));
+            }
+        }
+    }
+
+    fn read_pair(&mut self, first: BamRecord) -> Result<ReadPair, Error> {
+        let mut records: [Option<OwnedRecord>; 4] = [None, None, None, None];
+        let source = if first.flag & FLAG_PAIRED == 0 {
+            records[self.unpaired_read as usize] = Some(owned(&first, &first.seq, &first.qual));
+            first
+        } else {
+            let second = match self.next_record()? {
+                Some(rec) if rec.name == first.name && rec.flag & FLAG_PAIRED != 0 => rec,
+                other => {
+                    self.peeked = other;
+                    return Err(format_err!(
+                        "Mate of read {} not found in BAM file {:?}. Paired BAM files must be unaligned or sorted by read name.",
+                        String::from_utf8_lossy(&first.name),
+                        self.path
+                    ));
+                }
+            };
+            for rec in &[&first, &second] {
+                let which = match rec.flag & (FLAG_READ1 | FLAG_READ2) {
+                    FLAG_READ1 => WhichRead::R1,
+                    FLAG_READ2 => WhichRead::R2,
+                    _ => {
+                        return Err(format_err!(
+                            "Read {} in BAM file {:?} is paired, but not flagged as first or last segment",
+                            String::from_utf8_lossy(&rec.name),
+                            self.path
+                        ))
+                    }
+                };
+                records[which as usize] = Some(owned(rec, &rec.seq, &rec.qual));
+            }
+            if records[0].is_none() || records[1].is_none() {
+                return Err(format_err!(
+                    "Mates of read {} in BAM file {:?} are both flagged as the same segment",
+                    String::from_utf8_lossy(&first.name),
+                    self.path
+                ));
+            }
+            if first.flag & FLAG_READ1 != 0 {
+                first
+            } else {
+                second
+            }
+        };
+
+        for (idx, segments) in self.tag_reads.iter().enumerate() {
+            if let Some(segments) = segments {
+                records[idx] = self.tag_record(&source, segments)?;
+            }
+        }
+        Ok(ReadPair::new(records))
+    }
+
+    /// The read made of the tag segments of `rec`, or `None` if a tag is missing
+    fn tag_record(
+        &self,
+        rec: &BamRecord,
+        segments: &[TagSegment],
+    ) -> Result<Option<OwnedRecord>, Error> {
+        let malformed = |_| format_err!("Malformed tags in BAM file {:?}", self.path);
+        let mut seq = Vec::new();
+        let mut qual = Vec::new();
+        for segment in segments {
+            let value = match rec.tag(segment.seq_tag).map_err(malformed)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            let field = match value.split(|&c| c == b'-').nth(segment.field) {
+                Some(field) => field,
+                None => return Ok(None),
+            };
+            let field_qual = match segment.qual_tag {
+                Some(tag) => rec
+                    .tag(tag)
+                    .map_err(malformed)?
+                    .and_then(|q| q.split(|&c| c == b' ').nth(segment.field)),
+                None => None,
+            };
+            seq.extend_from_slice(field);
+            match field_qual {
+                Some(q) if q.len() == field.len() => qual.extend_from_slice(q),
+                _ => qual.resize(seq.len(), MISSING_QUAL),
+            }
+        }
+        Ok(Some(owned(rec, &seq, &qual)))
+    }
+}
+
+fn owned(rec: &BamRecord, seq: &[u8], qual: &[u8]) -> OwnedRecord {
+    OwnedRecord {
+        head: rec.name.clone(),
+        seq: seq.to_vec(),
+        sep: None,
+        qual: qual.to_vec(),
+    }
+}
+
+/// BAM data decoded from CRAM data by `samtools view`. Reading past the end of the
+/// data returns the error reported by `samtools`, if it failed.
+struct SamtoolsReader {
+    child: Child,
+    stdout: ChildStdout,
+    // Collects the error messages of samtools, until it is waited for
+    stderr: Option<thread::JoinHandle<Vec<u8>>>,
+}
+
+impl SamtoolsReader {
+    /// Decode the CRAM file at `path`, or the CRAM data of `input` if `path` is `-`
+    fn spawn(
+        path: &Path,
+        reference: Option<&Path>,
+        input: Option<Box<dyn Read + Send>>,
+    ) -> io::Result<SamtoolsReader> {
+        let samtools = std::env::var_os("SAMTOOLS").unwrap_or_else(|| "samtools".into());
+        let mut cmd = Command::new(&samtools);
+        // Uncompressed BAM output, which is still BGZF framed
+        cmd.arg("view").arg("-u");
+        if let Some(reference) = reference {
+            cmd.arg("-T").arg(reference);
+        }
+        cmd.arg(path)
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = cmd.spawn().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("could not run {:?}: {}", Path::new(&samtools), e),
+            )
+        })?;
+
+        if let (Some(mut input), Some(mut stdin)) = (input, child.stdin.take()) {
+            // Errors writing the input show up as errors of samtools
+            thread::spawn(move || {
+                let _ = io::copy(&mut input, &mut stdin).and_then(|_| stdin.flush());
+            });
+        }
+        let mut stderr = child.stderr.take().unwrap();
+        let stderr = thread::spawn(move || {
+            let mut msg = Vec::new();
+            let _ = stderr.read_to_end(&mut msg);
+            msg
+        });
+        Ok(SamtoolsReader {
+            stdout: child.stdout.take().unwrap(),
+            child,
+            stderr: Some(stderr),
+        })
+    }
+
+    /// Wait for samtools to exit, and return its error if it failed
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(stderr) = self.stderr.take() {
+            let status = self.child.wait()?;
+            let msg = stderr.join().unwrap_or_default();
+            if !status.success() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "samtools view failed ({}): {}",
+                        status,
+                        String::from_utf8_lossy(&msg).trim()
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for SamtoolsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.finish()?;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for SamtoolsReader {
+    fn drop(&mut self) {
+        if self.stderr.is_some() {
+            // Stopped reading before the end of the data
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+impl Iterator for BamReadPairIter {
+    type Item = Result<ReadPair, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = match self.next_record() {
+            Ok(Some(rec)) => self.read_pair(rec),
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            self.done = true;
+        }
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair::ReadPart;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    /// Encode a BAM record without alignment
+    fn record(
+        name: &str,
+        flag: u16,
+        seq: &[u8],
+        qual: Option<&[u8]>,
+        tags: &[(&str, &str)],
+    ) -> Vec<u8> {
+        let mut r = Vec::new();
+        r.extend_from_slice(&(-1i32).to_le_bytes());
+        r.extend_from_slice(&(-1i32).to_le_bytes());
+        r.push(name.len() as u8 + 1);
+        r.push(255);
+        r.extend_from_slice(&4680u16.to_le_bytes());
+        r.extend_from_slice(&0u16.to_le_bytes());
+        r.extend_from_slice(&flag.to_le_bytes());
+        r.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+        r.extend_from_slice(&(-1i32).to_le_bytes());
+        r.extend_from_slice(&(-1i32).to_le_bytes());
+        r.extend_from_slice(&0i32.to_le_bytes());
+        r.extend_from_slice(name.as_bytes());
+        r.push(0);
+        let code = |c: u8| b"=ACMGRSVTWYHKDBN".iter().position(|&b| b == c).unwrap() as u8;
+        for pair in seq.chunks(2) {
+            r.push(code(pair[0]) << 4 | pair.get(1).map_or(0, |&c| code(c)));
+        }
+        match qual {
+            Some(q) => r.extend(q.iter().map(|c| c - 33)),
+            None => r.extend(vec![0xff; seq.len()]),
+        }
+        // A numeric tag and an array tag before the string tags
+        r.extend_from_slice(b"NMC\x00ZBBs\x02\x00\x00\x00\x01\x00\x02\x00");
+        for (tag, value) in tags {
+            r.extend_from_slice(tag.as_bytes());
+            r.push(b'Z');
+            r.extend_from_slice(value.as_bytes());
+            r.push(0);
+        }
+        let mut out = (r.len() as u32).to_le_bytes().to_vec();
+        out.extend(r);
+        out
+    }
+
+    fn bam(records: &[Vec<u8>]) -> Vec<u8> {
+        let text = b"@HD\tVN:1.6\tSO:unsorted\n";
+        let mut data = BAM_MAGIC.to_vec();
+        data.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        data.extend_from_slice(text);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&5u32.to_le_bytes());
+        data.extend_from_slice(b"chr1\x00");
+        data.extend_from_slice(&1000u32.to_le_bytes());
+        for r in records {
+            data.extend_from_slice(r);
+        }
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(&data).unwrap();
+        gz.finish().unwrap()
+    }
+
+    fn read_pairs(data: Vec<u8>, cellranger: bool) -> Vec<Result<ReadPair, Error>> {
+        let iter = BamReadPairIter::from_reader(io::Cursor::new(data), "test.bam").unwrap();
+        if cellranger {
+            iter.cellranger().collect()
+        } else {
+            iter.collect()
+        }
+    }
+
+    fn get(rp: &ReadPair, which: WhichRead, part: ReadPart) -> Option<&[u8]> {
+        rp.get(which, part)
+    }
+
+    #[test]
+    fn test_paired_bam() {
+        let data = bam(&[
+            // R2 before R1, aligned to the reverse strand
+            record("a", 0x1 | 0x80 | 0x10, b"AACGG", Some(b"ABCDE"), &[]),
+            record(
+                "a",
+                0x1 | 0x40,
+                b"ACGTN",
+                Some(b"IIII#"),
+                &[("BC", "ACGT-TTGG"), ("QT", "FFFF ::::")],
+            ),
+            // Secondary alignment
+            record("a", 0x1 | 0x40 | 0x100, b"ACGT", None, &[]),
+            record("b", 0x1 | 0x40, b"GGG", None, &[("BC", "CCCC")]),
+            record("b", 0x1 | 0x80 | 0x800, b"TTT", None, &[]),
+            record("b", 0x1 | 0x80, b"TTTT", None, &[]),
+        ]);
+        let reads: Vec<ReadPair> = read_pairs(data, false)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(reads.len(), 2);
+
+        let a = &reads[0];
+        assert_eq!(get(a, WhichRead::R1, ReadPart::Seq), Some(&b"ACGTN"[..]));
+        assert_eq!(get(a, WhichRead::R1, ReadPart::Qual), Some(&b"IIII#"[..]));
+        assert_eq!(get(a, WhichRead::R2, ReadPart::Seq), Some(&b"CCGTT"[..]));
+        assert_eq!(get(a, WhichRead::R2, ReadPart::Qual), Some(&b"EDCBA"[..]));
+        assert_eq!(get(a, WhichRead::R2, ReadPart::Header), Some(&b"a"[..]));
+        assert_eq!(get(a, WhichRead::I1, ReadPart::Seq), Some(&b"ACGT"[..]));
+        assert_eq!(get(a, WhichRead::I1, ReadPart::Qual), Some(&b"FFFF"[..]));
+        assert_eq!(get(a, WhichRead::I2, ReadPart::Seq), Some(&b"TTGG"[..]));
+        assert_eq!(get(a, WhichRead::I2, ReadPart::Qual), Some(&b"::::"[..]));
+
+        let b = &reads[1];
+        assert_eq!(get(b, WhichRead::R1, ReadPart::Qual), Some(&b"III"[..]));
+        assert_eq!(get(b, WhichRead::R2, ReadPart::Seq), Some(&b"TTTT"[..]));
+        assert_eq!(get(b, WhichRead::I1, ReadPart::Seq), Some(&b"CCCC"[..]));
+        assert_eq!(get(b, WhichRead::I1, ReadPart::Qual), Some(&b"IIII"[..]));
+        assert_eq!(get(b, WhichRead::I2, ReadPart::Seq), None);
+    }
+
+    #[test]
+    fn test_cellranger_bam() {
+        let data = bam(&[record(
+            "a",
+            0,
+            b"GGGTTT",
+            Some(b"FFFFFF"),
+            &[
+                ("CR", "ACGT"),
+                ("CY", "IIII"),
+                ("CB", "ACGT-1"),
+                ("UR", "TTA"),
+                ("UY", "FF:"),
+            ],
+        )]);
+        let reads = read_pairs(data, true);
+        let rp = reads[0].as_ref().unwrap();
+        assert_eq!(get(rp, WhichRead::R1, ReadPart::Seq), Some(&b"ACGTTTA"[..]));
+        assert_eq!(
+            get(rp, WhichRead::R1, ReadPart::Qual),
+            Some(&b"IIIIFF:"[..])
+        );
+        assert_eq!(get(rp, WhichRead::R2, ReadPart::Seq), Some(&b"GGGTTT"[..]));
+        assert_eq!(get(rp, WhichRead::I1, ReadPart::Seq), None);
+
+        // Corrected barcode, without the GEM well suffix
+        let iter = BamReadPairIter::from_reader(
+            io::Cursor::new(bam(&[record("a", 0, b"GGG", None, &[("CB", "ACGT-1")])])),
+            "test.bam",
+        )
+        .unwrap()
+        .tag_read(WhichRead::I1, vec![])
+        .tag_read(WhichRead::R2, vec![TagSegment::new(*b"CB", None)]);
+        let rp = iter.map(Result::unwrap).next().unwrap();
+        assert_eq!(get(&rp, WhichRead::R1, ReadPart::Seq), Some(&b"GGG"[..]));
+        assert_eq!(get(&rp, WhichRead::R2, ReadPart::Seq), Some(&b"ACGT"[..]));
+    }
+
+    #[test]
+    fn test_bam_qualities() {
+        // Qualities above phred 93, and a single missing quality
+        let mut rec = record("a", 0, b"ACG", Some(b"\x88I!"), &[]);
+        let qual_start = 4 + 32 + 2 + 2;
+        assert_eq!(&rec[qual_start..qual_start + 3], &[103, 40, 0]);
+        rec[qual_start + 2] = 0xff;
+        let reads = read_pairs(bam(&[rec, record("b", 0, b"AC", None, &[])]), false);
+        let a = reads[0].as_ref().unwrap();
+        assert_eq!(get(a, WhichRead::R1, ReadPart::Qual), Some(&b"~II"[..]));
+        let b = reads[1].as_ref().unwrap();
+        assert_eq!(get(b, WhichRead::R1, ReadPart::Qual), Some(&b"II"[..]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cram() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A stand-in for samtools that outputs a BAM file for any input, and fails
+        // for inputs named fail.cram
+        let bam_path = "tests/test_cram.bam";
+        let script = "tests/test_cram_samtools.sh";
+        std::fs::write(
+            bam_path,
+            bam(&[record("a", 0, b"ACGT", Some(b"IIII"), &[("BC", "GGTT")])]),
+        )
+        .unwrap();
+        std::fs::write(
+            script,
+            format!(
+                "#!/bin/sh\n\
+                 echo \"$@\" > {args}\n\
+                 for last; do :; done\n\
+                 if [ \"$last\" = - ]; then cat > /dev/null; fi\n\
+                 case \"$last\" in *fail.cram) echo 'no reference found' >&2; exit 1;; esac\n\
+                 cat {bam}\n",
+                args = "tests/test_cram_args.txt",
+                bam = bam_path
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write("tests/test_cram.cram", b"CRAM\x03\x00").unwrap();
+        std::env::set_var("SAMTOOLS", script);
+
+        let from_path: Vec<_> = BamReadPairIter::new("tests/test_cram.cram")
+            .unwrap()
+            .collect();
+        let args_path = std::fs::read_to_string("tests/test_cram_args.txt").unwrap();
+        let with_reference: Vec<_> =
+            BamReadPairIter::from_cram("tests/test_cram.cram", Some(Path::new("ref.fa")))
+                .unwrap()
+                .collect();
+        let args_reference = std::fs::read_to_string("tests/test_cram_args.txt").unwrap();
+        let cram = b"CRAM\x03\x00".to_vec();
+        let from_reader: Vec<_> = BamReadPairIter::from_reader(io::Cursor::new(cram), "test.cram")
+            .unwrap()
+            .collect();
+        let failed = BamReadPairIter::new("tests/fail.cram").is_err();
+        std::fs::write("tests/fail.cram", b"CRAM\x03\x00").unwrap();
+        let fail = BamReadPairIter::from_cram("tests/fail.cram", None).map(|_| ());
+
+        std::env::set_var("SAMTOOLS", "tests/missing_samtools");
+        let missing = BamReadPairIter::from_cram("tests/test_cram.cram", None).map(|_| ());
+        std::env::remove_var("SAMTOOLS");
+        for path in &[
+            bam_path,
+            script,
+            "tests/test_cram.cram",
+            "tests/test_cram_args.txt",
+            "tests/fail.cram",
+        ] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        for reads in &[from_path, with_reference, from_reader] {
+            assert_eq!(reads.len(), 1);
+            let rp = reads[0].as_ref().unwrap();
+            assert_eq!(get(rp, WhichRead::R1, ReadPart::Seq), Some(&b"ACGT"[..]));
+            assert_eq!(get(rp, WhichRead::I1, ReadPart::Seq), Some(&b"GGTT"[..]));
+        }
+        assert_eq!(args_path.trim(), "view -u tests/test_cram.cram");
+        assert_eq!(
+            args_reference.trim(),
+            "view -u -T ref.fa tests/test_cram.cram"
+        );
+        // Missing file
+        assert!(failed);
+        let msg = fail.unwrap_err().to_string();
+        assert!(msg.contains("no reference found"), "{}", msg);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_bam_errors() {
+        // Missing mate
+        let data = bam(&[
+            record("a", 0x1 | 0x40, b"ACGT", None, &[]),
+            record("b", 0x1 | 0x80, b"ACGT", None, &[]),
+        ]);
+        let reads = read_pairs(data, false);
+        assert_eq!(reads.len(), 1);
+        assert!(reads[0].is_err());
+
+        // Both mates flagged as R1
+        let data = bam(&[
+            record("a", 0x1 | 0x40, b"ACGT", None, &[]),
+            record("a", 0x1 | 0x40, b"ACGT", None, &[]),
+        ]);
+        assert!(read_pairs(data, false)[0].is_err());
+
+        let fastq = std::fs::read("tests/read_pair_iter/good-gzipped-RA.fastq.gz").unwrap();
+        assert!(BamReadPairIter::from_reader(io::Cursor::new(fastq), "test.bam").is_err());
+        assert!(BamReadPairIter::new("tests/missing.bam").is_err());
+
+        // Truncated record
+        let mut data = bam(&[record("a", 0, b"ACGT", None, &[])]);
+        let mut plain = Vec::new();
+        flate2::read::GzDecoder::new(&data[..])
+            .read_to_end(&mut plain)
+            .unwrap();
+        plain.truncate(plain.len() - 3);
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(&plain).unwrap();
+        data = gz.finish().unwrap();
+        assert!(read_pairs(data, false)[0].is_err());
+    }
+}
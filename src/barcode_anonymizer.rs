@@ -0,0 +1,345 @@
+//! Replace cell barcodes with synthetic barcodes, so that datasets can be shared
+//! for debugging tools without exposing the original barcodes.
+//!
+//! Each barcode of the whitelist is mapped to a synthetic barcode of the same length
+//! derived from a keyed hash of the barcode. The synthetic barcodes are distinct and
+//! never on the original whitelist, so the mapping is a keyed one-to-one relabeling
+//! of the whitelist onto a synthetic whitelist, which can be written out for the
+//! tools consuming the anonymized reads. The mapping only depends on the salt and
+//! the whitelist, so the same salt gives the same synthetic barcodes across files,
+//! chunks and runs.
+//!
+//! Barcodes that are not on the whitelist (e.g. with sequencing errors) are replaced
+//! with a keyed hash of the barcode of the same length, which is never one of the
+//! synthetic whitelist barcodes. Lowercase bases are treated as uppercase, and
+//! other bases than `ACGT` are kept in place, so no-calls stay no-calls.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::barcode_anonymizer::BarcodeAnonymizer;
+//! use fastq_set::read_pair::{RpRange, WhichRead};
+//! let whitelist = vec![b"AAAACCCC".to_vec(), b"GGGGTTTT".to_vec()];
+//! let anonymizer = BarcodeAnonymizer::new(
+//!     b"my secret salt",
+//!     RpRange::new(WhichRead::R1, 0, Some(8)),
+//!     whitelist,
+//! )
+//! .unwrap();
+//! let synthetic = anonymizer.anonymize(b"AAAACCCC");
+//! assert_eq!(synthetic.len(), 8);
+//! assert_ne!(synthetic, b"AAAACCCC");
+//! assert!(anonymizer.synthetic_whitelist().contains(&&synthetic[..]));
+//! ```
+
+use crate::read_names::siphash;
+use crate::read_pair::{ReadPair, ReadPart, RpRange};
+use crate::utils;
+use failure::{format_err, Error, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+
+// Number of hashes tried for a whitelist barcode before giving up, which only
+// happens if the synthetic barcodes nearly exhaust the barcodes of that length
+const MAX_ATTEMPTS: u32 = 1000;
+
+/// Maps the barcodes in a range of the read pairs to synthetic barcodes
+pub struct BarcodeAnonymizer {
+    range: RpRange,
+    keys: (u64, u64),
+    // Whitelist barcode -> synthetic barcode
+    mapping: HashMap<Vec<u8>, Vec<u8>>,
+    synthetic: HashSet<Vec<u8>>,
+}
+
+impl BarcodeAnonymizer {
+    /// Anonymize the barcodes found at `range` of the read pairs, mapping the
+    /// barcodes of `whitelist` to a synthetic whitelist keyed with `salt`. Returns
+    /// an error if a synthetic barcode cannot be found for a whitelist barcode,
+    /// which only happens for short barcodes with a whitelist covering most of
+    /// the barcodes of their length.
+    pub fn new(
+        salt: &[u8],
+        range: RpRange,
+        whitelist: impl IntoIterator<Item = Vec<u8>>,
+    ) -> Result<Self, Error> {
+        // Keys distinct from those of the read names for the same salt
+        let keys = (siphash((1, 0), salt), siphash((1, 1), salt));
+
+        // Assign the synthetic barcodes in sorted order, so that the mapping does
+        // not depend on the order of the whitelist
+        let mut whitelist: Vec<_> = whitelist
+            .into_iter()
+            .map(|bc| bc.to_ascii_uppercase())
+            .collect();
+        whitelist.sort();
+        whitelist.dedup();
+        let original: HashSet<&[u8]> = whitelist.iter().map(|bc| &bc[..]).collect();
+
+        let mut mapping = HashMap::with_capacity(whitelist.len());
+        let mut used = HashSet::with_capacity(whitelist.len());
+        for bc in &whitelist {
+            let synthetic = (0..MAX_ATTEMPTS)
+                .map(|attempt| hash_bases(keys, bc, attempt))
+                .find(|s| !original.contains(&s[..]) && !used.contains(s))
+                .ok_or_else(|| {
+                    format_err!(
+                        "Unable to find a synthetic barcode for {}: the whitelist has too \
+                         many barcodes of length {}",
+                        String::from_utf8_lossy(bc),
+                        bc.len()
+                    )
+                })?;
+            used.insert(synthetic.clone());
+            mapping.insert(bc.clone(), synthetic);
+        }
+
+        Ok(BarcodeAnonymizer {
+            range,
+            keys,
+            mapping,
+            synthetic: used,
+        })
+    }
+
+    /// The range of the read pairs holding the barcode
+    pub fn range(&self) -> RpRange {
+        self.range
+    }
+
+    /// The synthetic barcode replacing `barcode`, in uppercase
+    pub fn anonymize(&self, barcode: &[u8]) -> Vec<u8> {
+        let barcode = barcode.to_ascii_uppercase();
+        match self.mapping.get(&barcode) {
+            Some(synthetic) => synthetic.clone(),
+            // Rehash until the barcode is off the synthetic whitelist, which takes
+            // few attempts as the synthetic and original whitelists are disjoint,
+            // so the synthetic whitelist holds at most half of the barcodes
+            None => (0..)
+                .map(|attempt| hash_bases(self.keys, &barcode, attempt))
+                .find(|s| !self.synthetic.contains(s))
+                .unwrap(),
+        }
+    }
+
+    /// The synthetic whitelist, sorted
+    pub fn synthetic_whitelist(&self) -> Vec<&[u8]> {
+        let mut whitelist: Vec<_> = self.mapping.values().map(|bc| &bc[..]).collect();
+        whitelist.sort();
+        whitelist
+    }
+
+    /// Write the synthetic whitelist to `path`, one barcode per line, gzip
+    /// compressed if the file name ends in `.gz`
+    pub fn write_whitelist(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut writer = utils::write_with_gz(&path)?;
+        for bc in self.synthetic_whitelist() {
+            writer
+                .write_all(&[bc, b"\n"].concat())
+                .with_context(|_| format!("error writing whitelist {:?}", path.as_ref()))?;
+        }
+        writer
+            .finish()
+            .with_context(|_| format!("error writing whitelist {:?}", path.as_ref()))?;
+        Ok(())
+    }
+
+    /// Replace the barcode of `rp` with its synthetic barcode. If the read is
+    /// shorter than the range, the bases that are present are replaced. Read
+    /// pairs without the read of the range are left unchanged.
+    pub fn anonymize_read_pair(&self, rp: &mut ReadPair) -> Result<(), Error> {
+        let which = self.range.read();
+        let seq = match rp.get(which, ReadPart::Seq) {
+            Some(seq) => seq,
+            None => return Ok(()),
+        };
+        let start = self.range.offset().min(seq.len());
+        let end = match self.range.len() {
+            Some(len) => (start + len).min(seq.len()),
+            None => seq.len(),
+        };
+        let synthetic = self.anonymize(&seq[start..end]);
+        rp.edit_read(which, |seq, _| seq[start..end].copy_from_slice(&synthetic))
+    }
+
+    /// Anonymize the barcodes of the read pairs of `reads` as they are iterated
+    pub fn anonymize_all<I, E>(self, reads: I) -> AnonymizeBarcodes<I::IntoIter>
+    where
+        I: IntoIterator<Item = Result<ReadPair, E>>,
+        Error: From<E>,
+    {
+        AnonymizeBarcodes {
+            reads: reads.into_iter(),
+            anonymizer: self,
+        }
+    }
+}
+
+/// Iterator over read pairs with anonymized barcodes, created by
+/// `BarcodeAnonymizer::anonymize_all`
+pub struct AnonymizeBarcodes<I> {
+    reads: I,
+    anonymizer: BarcodeAnonymizer,
+}
+
+impl<I> AnonymizeBarcodes<I> {
+    /// The anonymizer, e.g. to write the synthetic whitelist
+    pub fn anonymizer(&self) -> &BarcodeAnonymizer {
+        &self.anonymizer
+    }
+}
+
+impl<I, E> Iterator for AnonymizeBarcodes<I>
+where
+    I: Iterator<Item = Result<ReadPair, E>>,
+    Error: From<E>,
+{
+    type Item = Result<ReadPair, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reads.next().map(|rp| {
+            let mut rp = rp?;
+            self.anonymizer.anonymize_read_pair(&mut rp)?;
+            Ok(rp)
+        })
+    }
+}
+
+/// Bases of the same length as `barcode` drawn from keyed hashes of the barcode
+/// and `attempt`, keeping the bases of `barcode` that are not `ACGT`
+fn hash_bases(keys: (u64, u64), barcode: &[u8], attempt: u32) -> Vec<u8> {
+    let mut data = barcode.to_vec();
+    data.extend_from_slice(&attempt.to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    let block_pos = data.len() - 4;
+
+    let mut bases = Vec::with_capacity(barcode.len());
+    for (block, chunk) in barcode.chunks(32).enumerate() {
+        data[block_pos..].copy_from_slice(&(block as u32).to_le_bytes());
+        let hash = siphash(keys, &data);
+        for (i, &b) in chunk.iter().enumerate() {
+            bases.push(match b {
+                b'A' | b'C' | b'G' | b'T' => b"ACGT"[(hash >> (2 * i) & 3) as usize],
+                _ => b,
+            });
+        }
+    }
+    bases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair::WhichRead;
+    use crate::read_pair_iter::ReadPairIter;
+
+    fn whitelist() -> Vec<Vec<u8>> {
+        vec![
+            b"AAAACCCC".to_vec(),
+            b"GGGGTTTT".to_vec(),
+            b"ACGTACGT".to_vec(),
+        ]
+    }
+
+    #[test]
+    fn test_synthetic_whitelist() {
+        let range = RpRange::new(WhichRead::R1, 0, Some(8));
+        let anonymizer = BarcodeAnonymizer::new(b"salt", range, whitelist()).unwrap();
+        let synthetic = anonymizer.synthetic_whitelist();
+        assert_eq!(synthetic.len(), 3);
+        for bc in whitelist() {
+            let s = anonymizer.anonymize(&bc);
+            assert_eq!(s.len(), 8);
+            assert!(synthetic.contains(&&s[..]));
+            assert!(!whitelist().contains(&s));
+        }
+
+        // The mapping only depends on the salt and the whitelist
+        let mut reversed = whitelist();
+        reversed.reverse();
+        let same = BarcodeAnonymizer::new(b"salt", range, reversed).unwrap();
+        let other = BarcodeAnonymizer::new(b"pepper", range, whitelist()).unwrap();
+        assert_eq!(same.synthetic_whitelist(), synthetic);
+        assert_ne!(other.synthetic_whitelist(), synthetic);
+
+        // Barcodes off the whitelist keep their no-calls
+        let s = anonymizer.anonymize(b"AANACCCC");
+        assert_eq!(s[2], b'N');
+        assert_eq!(s, anonymizer.anonymize(b"AANACCCC"));
+
+        // Lowercase bases are anonymized like uppercase bases
+        assert_eq!(
+            anonymizer.anonymize(b"aaaacccc"),
+            anonymizer.anonymize(b"AAAACCCC")
+        );
+        let s = anonymizer.anonymize(b"aanacccc");
+        assert_eq!(s, anonymizer.anonymize(b"AANACCCC"));
+        assert!(s.iter().all(|b| b"ACGTN".contains(b)));
+
+        // Barcodes off the whitelist never map to a synthetic barcode, even when
+        // the synthetic whitelist covers many of the barcodes
+        let short: Vec<_> = [&b"AA"[..], b"AC", b"AG", b"AT", b"CA", b"CC", b"CG", b"CT"]
+            .iter()
+            .map(|bc| bc.to_vec())
+            .collect();
+        let short_range = RpRange::new(WhichRead::R1, 0, Some(2));
+        let anonymizer = BarcodeAnonymizer::new(b"salt", short_range, short.clone()).unwrap();
+        let synthetic = anonymizer.synthetic_whitelist();
+        for a in b"ACGT" {
+            for b in b"ACGT" {
+                let bc = [*a, *b];
+                if !short.contains(&bc.to_vec()) {
+                    assert!(!synthetic.contains(&&anonymizer.anonymize(&bc)[..]));
+                }
+            }
+        }
+
+        // All the barcodes of length 1 cannot be mapped
+        let full = vec![b"A".to_vec(), b"C".to_vec(), b"G".to_vec(), b"T".to_vec()];
+        assert!(BarcodeAnonymizer::new(b"salt", range, full).is_err());
+    }
+
+    #[test]
+    fn test_anonymize_read_pairs() {
+        let range = RpRange::new(WhichRead::R1, 2, Some(8));
+        let reads: Vec<_> = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+        let whitelist = vec![reads[0].get_range(range, ReadPart::Seq).unwrap().to_vec()];
+        let anonymizer = BarcodeAnonymizer::new(b"salt", range, whitelist.clone()).unwrap();
+
+        let anonymized: Vec<_> = anonymizer
+            .anonymize_all(reads.clone().into_iter().map(Ok::<_, Error>))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let anonymizer = BarcodeAnonymizer::new(b"salt", range, whitelist).unwrap();
+        assert_eq!(
+            anonymized[0].get_range(range, ReadPart::Seq).unwrap(),
+            anonymizer.synthetic_whitelist()[0]
+        );
+
+        for (rp, anon) in reads.iter().zip(&anonymized) {
+            let seq = rp.get(WhichRead::R1, ReadPart::Seq).unwrap();
+            let anon_seq = anon.get(WhichRead::R1, ReadPart::Seq).unwrap();
+            assert_eq!(seq.len(), anon_seq.len());
+            assert_eq!(seq[..2], anon_seq[..2]);
+            assert_eq!(seq[10..], anon_seq[10..]);
+            assert_eq!(&anonymizer.anonymize(&seq[2..10])[..], &anon_seq[2..10]);
+            assert_eq!(
+                rp.get(WhichRead::R1, ReadPart::Qual),
+                anon.get(WhichRead::R1, ReadPart::Qual)
+            );
+            assert_eq!(
+                rp.get(WhichRead::R2, ReadPart::Seq),
+                anon.get(WhichRead::R2, ReadPart::Seq)
+            );
+        }
+    }
+}
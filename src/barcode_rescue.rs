@@ -0,0 +1,240 @@
+//! Rescue of cell barcodes with a single no-call. A barcode read with exactly one
+//! `N`, at a cycle with a low base quality, differs from its true barcode only at
+//! that position, so if exactly one of the four substitutions of the `N` is on the
+//! barcode whitelist, that barcode can be used instead of discarding the read.
+//! The whitelist is given as the barcode sequences, as for
+//! [`UndeterminedRescue`](../undetermined_rescue/struct.UndeterminedRescue.html).
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::barcode_rescue::NRescue;
+//! use fastq_set::read_pair::{ReadPair, RpRange, WhichRead};
+//! use fastq_set::OwnedRecord;
+//! let read = |seq: &[u8], qual: &[u8]| {
+//!     let rec = OwnedRecord {
+//!         head: b"read".to_vec(),
+//!         seq: seq.to_vec(),
+//!         qual: qual.to_vec(),
+//!         sep: None,
+//!     };
+//!     ReadPair::new([Some(rec), None, None, None])
+//! };
+//!
+//! let whitelist = vec![b"AAAACCCC".to_vec(), b"GGGGTTTT".to_vec()];
+//! let mut rescue = NRescue::new(RpRange::new(WhichRead::R1, 0, Some(8)), whitelist);
+//! let rescued = rescue.correct(&read(b"AAAANCCCTT", b"IIII#IIIII"));
+//! assert_eq!(rescued.as_deref(), Some(&b"AAAACCCC"[..]));
+//! // The N has a high quality, so the read is left alone
+//! assert_eq!(rescue.correct(&read(b"AAAANCCCTT", b"IIIIIIIIII")), None);
+//! assert_eq!(rescue.num_checked(), 2);
+//! assert_eq!(rescue.num_rescued(), 1);
+//! ```
+
+use crate::metric_utils::ILLUMINA_QUAL_OFFSET;
+use crate::read_pair::{ReadPair, ReadPart, RpRange};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Highest phred quality of an `N` considered for rescue, unless set with
+/// `max_qual`. Sequencers report no-calls with a quality of 2.
+const DEFAULT_MAX_QUAL: u8 = 10;
+
+/// Barcode correction stage rescuing barcodes with a single low-quality `N`. It
+/// keeps count of the reads it has checked so that the number of rescued
+/// barcodes can be reported as a metric.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NRescue {
+    barcode: RpRange,
+    whitelist: HashSet<Vec<u8>>,
+    max_qual: u8,
+    num_checked: u64,
+    num_candidates: u64,
+    num_rescued: u64,
+    num_ambiguous: u64,
+}
+
+impl NRescue {
+    /// Rescue the barcodes found at `barcode` of the read pairs against the
+    /// barcodes of `whitelist`.
+    pub fn new(barcode: RpRange, whitelist: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        NRescue {
+            barcode,
+            whitelist: whitelist
+                .into_iter()
+                .map(|bc| bc.to_ascii_uppercase())
+                .collect(),
+            max_qual: DEFAULT_MAX_QUAL,
+            num_checked: 0,
+            num_candidates: 0,
+            num_rescued: 0,
+            num_ambiguous: 0,
+        }
+    }
+
+    /// Only rescue an `N` with a phred quality of at most `max_qual`.
+    pub fn max_qual(mut self, max_qual: u8) -> Self {
+        self.max_qual = max_qual;
+        self
+    }
+
+    /// The barcode to use for `read` if its barcode has a single low-quality `N`
+    /// with a unique substitution on the whitelist, and updates the counts.
+    /// Returns `None` if the barcode can't be rescued, including barcodes
+    /// without an `N`, which need no rescue.
+    pub fn correct(&mut self, read: &ReadPair) -> Option<Vec<u8>> {
+        self.num_checked += 1;
+        let seq = read.get_range(self.barcode, ReadPart::Seq)?;
+        let qual = read.get_range(self.barcode, ReadPart::Qual)?;
+
+        let mut ns = seq
+            .iter()
+            .enumerate()
+            .filter(|(_, &b)| b == b'N' || b == b'n');
+        let pos = match (ns.next(), ns.next()) {
+            (Some((pos, _)), None) => pos,
+            _ => return None,
+        };
+        match qual.get(pos) {
+            Some(&q) if q.saturating_sub(ILLUMINA_QUAL_OFFSET) <= self.max_qual => {}
+            _ => return None,
+        }
+        self.num_candidates += 1;
+
+        let mut candidate = seq.to_ascii_uppercase();
+        let mut hit = None;
+        for &base in b"ACGT" {
+            candidate[pos] = base;
+            if self.whitelist.contains(&candidate) {
+                if hit.is_some() {
+                    self.num_ambiguous += 1;
+                    return None;
+                }
+                hit = Some(base);
+            }
+        }
+        let base = hit?;
+        candidate[pos] = base;
+        self.num_rescued += 1;
+        Some(candidate)
+    }
+
+    /// Combine the counts from another chunk using the same settings.
+    ///
+    /// # Panics
+    /// * If `other` uses a different barcode range or maximum quality
+    pub fn merge(&mut self, other: &NRescue) {
+        assert_eq!(
+            (self.barcode, self.max_qual),
+            (other.barcode, other.max_qual),
+            "Cannot merge barcode rescues with different settings"
+        );
+        self.num_checked += other.num_checked;
+        self.num_candidates += other.num_candidates;
+        self.num_rescued += other.num_rescued;
+        self.num_ambiguous += other.num_ambiguous;
+    }
+
+    pub fn num_checked(&self) -> u64 {
+        self.num_checked
+    }
+
+    /// Number of barcodes with a single low-quality `N`
+    pub fn num_candidates(&self) -> u64 {
+        self.num_candidates
+    }
+
+    /// Number of barcodes with a single substitution on the whitelist
+    pub fn num_rescued(&self) -> u64 {
+        self.num_rescued
+    }
+
+    /// Number of barcodes with several substitutions on the whitelist
+    pub fn num_ambiguous(&self) -> u64 {
+        self.num_ambiguous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair::WhichRead;
+    use crate::OwnedRecord;
+
+    fn read(seq: &[u8], qual: &[u8]) -> ReadPair {
+        let rec = OwnedRecord {
+            head: b"read".to_vec(),
+            seq: seq.to_vec(),
+            qual: qual.to_vec(),
+            sep: None,
+        };
+        ReadPair::new([Some(rec), None, None, None])
+    }
+
+    #[test]
+    fn test_correct() {
+        let whitelist = vec![
+            b"AAAACCCC".to_vec(),
+            b"aaaaGGGG".to_vec(),
+            b"AAAATGGG".to_vec(),
+        ];
+        let range = RpRange::new(WhichRead::R1, 2, Some(8));
+        let mut rescue = NRescue::new(range, whitelist);
+
+        let correct =
+            |rescue: &mut NRescue, seq: &[u8], qual: &[u8]| rescue.correct(&read(seq, qual));
+        assert_eq!(
+            correct(&mut rescue, b"TTAAAANCCC", b"IIIIII#III"),
+            Some(b"AAAACCCC".to_vec())
+        );
+        // Lowercase bases and whitelist barcodes are compared in uppercase
+        assert_eq!(
+            correct(&mut rescue, b"TTaaaaGGGn", b"IIIIIIIII#"),
+            Some(b"AAAAGGGG".to_vec())
+        );
+        // Two substitutions are on the whitelist
+        assert_eq!(correct(&mut rescue, b"TTAAAANGGG", b"IIIIII#III"), None);
+        // No substitution is on the whitelist
+        assert_eq!(correct(&mut rescue, b"TTCAAANCCC", b"IIIIII#III"), None);
+        // No N, two Ns, or a high quality N
+        assert_eq!(correct(&mut rescue, b"TTAAAACCCC", b"IIIIIIIIII"), None);
+        assert_eq!(correct(&mut rescue, b"TTAAAANNCC", b"IIIIII##II"), None);
+        assert_eq!(correct(&mut rescue, b"TTAAAANCCC", b"IIIIII5III"), None);
+        // The barcode is missing
+        assert_eq!(correct(&mut rescue, b"TTAAAAN", b"IIIIII#"), None);
+
+        assert_eq!(rescue.num_checked(), 8);
+        assert_eq!(rescue.num_candidates(), 4);
+        assert_eq!(rescue.num_rescued(), 2);
+        assert_eq!(rescue.num_ambiguous(), 1);
+
+        // A higher quality threshold rescues the high quality N
+        let mut lenient = NRescue::new(range, vec![b"AAAACCCC".to_vec()]).max_qual(20);
+        assert_eq!(
+            correct(&mut lenient, b"TTAAAANCCC", b"IIIIII5III"),
+            Some(b"AAAACCCC".to_vec())
+        );
+        let mut strict = NRescue::new(range, vec![b"AAAACCCC".to_vec()]).max_qual(1);
+        assert_eq!(correct(&mut strict, b"TTAAAANCCC", b"IIIIII#III"), None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let range = RpRange::new(WhichRead::R1, 0, Some(4));
+        let mut a = NRescue::new(range, vec![b"ACGT".to_vec()]);
+        a.correct(&read(b"ACGN", b"III#"));
+        let mut b = a.clone();
+        b.correct(&read(b"ACNN", b"II##"));
+        a.merge(&b);
+        assert_eq!(a.num_checked(), 3);
+        assert_eq!(a.num_candidates(), 2);
+        assert_eq!(a.num_rescued(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_merge_different_settings() {
+        let range = RpRange::new(WhichRead::R1, 0, Some(4));
+        let mut a = NRescue::new(range, vec![]);
+        a.merge(&NRescue::new(range, vec![]).max_qual(20));
+    }
+}
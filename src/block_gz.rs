@@ -0,0 +1,785 @@
+//! Gzip output split into independently compressed blocks holding a fixed number
+//! of read pairs, along with a sidecar index of the blocks. Each block is one or
+//! more complete gzip members, so the output is a valid gzip file that any gzip
+//! reader can decompress, while a later stage can use the index to seek to a block
+//! and decompress it on its own, e.g. to process the blocks in parallel.
+//!
+//! With `BlockFormat::Bgzf` the members follow the BGZF format used by `samtools`
+//! and `bgzip`, splitting each block into members of at most 64KB.
+//!
+//! BGZF input, e.g. from `bcl-convert`, can be decompressed on several threads
+//! with `BgzfReader`, which `ReadPairIter` uses for BGZF files.
+
+use crate::utils::FinishWrite;
+use failure::{format_err, Error};
+use flate2::read::{GzDecoder, MultiGzDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Crc;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom, Take, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Maximum uncompressed size of a BGZF member, which guarantees that the
+/// compressed member fits in the 64KB limit of the format.
+const BGZF_MAX_DATA: usize = 0xff00;
+
+/// Length of the header of a BGZF member, up to the end of the block size field
+pub(crate) const BGZF_HEADER_LEN: usize = 18;
+
+/// Number of BGZF members decompressed by a thread of a `BgzfReader` at a time
+const BGZF_MEMBERS_PER_BATCH: usize = 16;
+
+/// Empty BGZF member marking the end of a BGZF file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// How the blocks are encoded
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockFormat {
+    /// One gzip member per block
+    MultiMember,
+    /// BGZF members of at most 64KB, with a BGZF end-of-file marker
+    Bgzf,
+}
+
+/// Settings of a block gzip output
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockConfig {
+    pub format: BlockFormat,
+    /// Number of read pairs in each block, except for the last one
+    pub read_pairs_per_block: usize,
+    /// Gzip compression level, from 0 to 9
+    pub level: u32,
+}
+
+impl BlockConfig {
+    pub fn new(format: BlockFormat, read_pairs_per_block: usize) -> Self {
+        BlockConfig {
+            format,
+            read_pairs_per_block,
+            level: flate2::Compression::fast().level(),
+        }
+    }
+}
+
+/// Location of one block in a block gzip file
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockIndexEntry {
+    /// Index of the first read pair of the block
+    pub first_read_pair: u64,
+    pub read_pairs: u64,
+    pub compressed_offset: u64,
+    pub compressed_len: u64,
+    pub uncompressed_offset: u64,
+    pub uncompressed_len: u64,
+}
+
+/// Sidecar index of a block gzip file, stored as JSON next to the file.
+/// See [`BlockIndex::path_for`](#method.path_for).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockIndex {
+    pub config: BlockConfig,
+    pub blocks: Vec<BlockIndexEntry>,
+}
+
+impl BlockIndex {
+    /// Path of the index of the block gzip file at `path`: the path with `.blocks.json` appended.
+    pub fn path_for(path: impl AsRef<Path>) -> PathBuf {
+        let mut p = path.as_ref().as_os_str().to_owned();
+        p.push(".blocks.json");
+        PathBuf::from(p)
+    }
+
+    /// Load the index of the block gzip file at `path`
+    pub fn read(path: impl AsRef<Path>) -> Result<BlockIndex, Error> {
+        let index_path = Self::path_for(path);
+        let file = File::open(&index_path)
+            .map_err(|e| format_err!("Error opening block index {:?}: {}", index_path, e))?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(Self::path_for(path))?;
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+
+    /// Total number of read pairs in the file
+    pub fn read_pairs(&self) -> u64 {
+        self.blocks.iter().map(|b| b.read_pairs).sum()
+    }
+
+    /// Open the compressed data of block `block` of the file at `path`, which can be
+    /// passed to `ReadPairIter::from_readers()` to read the block on its own.
+    ///
+    /// # Panics
+    /// * If `block` is not a valid block index
+    pub fn block_reader(&self, path: impl AsRef<Path>, block: usize) -> io::Result<Take<File>> {
+        self.blocks_reader(path, block..block + 1)
+    }
+
+    /// Open the compressed data of the consecutive blocks `blocks` of the file at `path`.
+    ///
+    /// # Panics
+    /// * If `blocks` is empty or out of bounds
+    pub fn blocks_reader(
+        &self,
+        path: impl AsRef<Path>,
+        blocks: Range<usize>,
+    ) -> io::Result<Take<File>> {
+        let first = &self.blocks[blocks.start];
+        let last = &self.blocks[blocks.end - 1];
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(first.compressed_offset))?;
+        Ok(file.take(last.compressed_offset + last.compressed_len - first.compressed_offset))
+    }
+}
+
+/// Writer splitting FASTQ data into gzip blocks of a fixed number of read pairs.
+/// Blocks are cut at line boundaries, so the writer must receive whole records.
+/// The last block and the index are written by `finish()`, or when the writer is
+/// dropped, in which case errors are ignored.
+pub struct BlockGzWriter<W: Write> {
+    inner: Option<W>,
+    config: BlockConfig,
+    lines_per_read_pair: usize,
+    index_path: Option<PathBuf>,
+    index: BlockIndex,
+    buffer: Vec<u8>,
+    lines: usize,
+    compressed_offset: u64,
+    uncompressed_offset: u64,
+}
+
+impl BlockGzWriter<File> {
+    /// Create a block gzip file at `path`, with its index at `BlockIndex::path_for(path)`.
+    /// Each read pair consists of `records_per_read_pair` FASTQ records, e.g. 2 for
+    /// an interleaved file.
+    pub fn create(
+        path: impl AsRef<Path>,
+        config: BlockConfig,
+        records_per_read_pair: usize,
+    ) -> Result<Self, Error> {
+        let mut writer =
+            BlockGzWriter::new(File::create(path.as_ref())?, config, records_per_read_pair)?;
+        writer.index_path = Some(path.as_ref().to_path_buf());
+        Ok(writer)
+    }
+}
+
+impl<W: Write> BlockGzWriter<W> {
+    /// Write blocks to `inner`, without writing the index to a file.
+    pub fn new(inner: W, config: BlockConfig, records_per_read_pair: usize) -> Result<Self, Error> {
+        if config.level > 9 {
+            return Err(format_err!(
+                "Invalid gzip compression level {}, must be between 0 and 9",
+                config.level
+            ));
+        }
+        if config.read_pairs_per_block == 0 || records_per_read_pair == 0 {
+            return Err(format_err!(
+                "Blocks must contain at least one record: {:?}",
+                config
+            ));
+        }
+        Ok(BlockGzWriter {
+            inner: Some(inner),
+            config,
+            lines_per_read_pair: 4 * records_per_read_pair,
+            index_path: None,
+            index: BlockIndex {
+                config,
+                blocks: Vec::new(),
+            },
+            buffer: Vec::new(),
+            lines: 0,
+            compressed_offset: 0,
+            uncompressed_offset: 0,
+        })
+    }
+
+    /// The index of the blocks written so far
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+
+    /// Write the last block, the BGZF end-of-file marker and the index, and return
+    /// the underlying writer along with the index.
+    pub fn finish(mut self) -> Result<(W, BlockIndex), Error> {
+        self.finish_blocks()?;
+        let index = self.index.clone();
+        Ok((self.inner.take().unwrap(), index))
+    }
+
+    fn finish_blocks(&mut self) -> io::Result<()> {
+        self.end_block()?;
+        let inner = self.inner.as_mut().unwrap();
+        if self.config.format == BlockFormat::Bgzf {
+            inner.write_all(&BGZF_EOF)?;
+        }
+        inner.flush()?;
+        if let Some(ref p) = self.index_path {
+            self.index.write(p)?;
+        }
+        Ok(())
+    }
+
+    fn end_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let level = flate2::Compression::new(self.config.level);
+        let inner = self.inner.as_mut().unwrap();
+        let mut compressed_len = 0;
+        match self.config.format {
+            BlockFormat::MultiMember => {
+                let mut gz = GzEncoder::new(Vec::new(), level);
+                gz.write_all(&self.buffer)?;
+                let member = gz.finish()?;
+                inner.write_all(&member)?;
+                compressed_len += member.len();
+            }
+            BlockFormat::Bgzf => {
+                for chunk in self.buffer.chunks(BGZF_MAX_DATA) {
+                    let member = bgzf_member(chunk, level)?;
+                    inner.write_all(&member)?;
+                    compressed_len += member.len();
+                }
+            }
+        }
+
+        let read_pairs = self.lines / self.lines_per_read_pair;
+        self.index.blocks.push(BlockIndexEntry {
+            first_read_pair: self.index.read_pairs(),
+            read_pairs: read_pairs as u64,
+            compressed_offset: self.compressed_offset,
+            compressed_len: compressed_len as u64,
+            uncompressed_offset: self.uncompressed_offset,
+            uncompressed_len: self.buffer.len() as u64,
+        });
+        self.compressed_offset += compressed_len as u64;
+        self.uncompressed_offset += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.lines = 0;
+        Ok(())
+    }
+}
+
+/// Compress `data` into a single BGZF member
+fn bgzf_member(data: &[u8], level: flate2::Compression) -> io::Result<Vec<u8>> {
+    let mut deflate = DeflateEncoder::new(Vec::new(), level);
+    deflate.write_all(data)?;
+    let cdata = deflate.finish()?;
+    let mut crc = Crc::new();
+    crc.update(data);
+
+    // header (18 bytes) + compressed data + CRC32 and input size (8 bytes)
+    let block_size = 18 + cdata.len() + 8;
+    assert!(block_size <= 1 << 16);
+    let mut member = Vec::with_capacity(block_size);
+    member.extend_from_slice(&[
+        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, b'B', b'C', 0x02,
+        0x00,
+    ]);
+    member.extend_from_slice(&((block_size - 1) as u16).to_le_bytes());
+    member.extend_from_slice(&cdata);
+    member.extend_from_slice(&crc.sum().to_le_bytes());
+    member.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(member)
+}
+
+/// Reader of BGZF compressed data, decompressing batches of members on a pool of
+/// threads. The members of a BGZF stream record their compressed size, so they can
+/// be split up without decompressing them. The pool is started on the first read and
+/// kept for the life of the reader, and decompresses the next batches while the
+/// current one is read. With a single thread, the members are decompressed on the
+/// calling thread instead. If a member without the BGZF size field
+/// is found, e.g. in a regular gzip file appended to a BGZF file, the rest of the
+/// stream is decompressed as multi-member gzip on the calling thread.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::block_gz::{BgzfReader, BlockConfig, BlockFormat, BlockGzWriter};
+/// use std::io::{Cursor, Read, Write};
+/// let mut writer = BlockGzWriter::new(Vec::new(), BlockConfig::new(BlockFormat::Bgzf, 1), 4).unwrap();
+/// writer.write_all(b"@read\nACGT\n+\nIIII\n").unwrap();
+/// let (data, _) = writer.finish().unwrap();
+/// assert!(BgzfReader::<&[u8]>::is_bgzf(&data));
+///
+/// let mut fastq = String::new();
+/// BgzfReader::new(Cursor::new(data), 4).read_to_string(&mut fastq).unwrap();
+/// assert_eq!(fastq, "@read\nACGT\n+\nIIII\n");
+/// ```
+pub struct BgzfReader<R> {
+    inner: Option<R>,
+    fallback: Option<Box<dyn Read + Send>>,
+    threads: Arc<AtomicUsize>,
+    pool: Option<DecompressPool>,
+    // Batches submitted to the pool, in the order of the stream
+    pending: VecDeque<mpsc::Receiver<io::Result<Vec<u8>>>>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+type DecompressJob = (Vec<Vec<u8>>, mpsc::SyncSender<io::Result<Vec<u8>>>);
+
+/// Threads decompressing batches of BGZF members for a `BgzfReader`
+struct DecompressPool {
+    jobs: Option<mpsc::Sender<DecompressJob>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl DecompressPool {
+    fn new(threads: usize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<DecompressJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    // The lock is released before decompressing
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok((members, result)) => {
+                            let _ = result.send(decompress_members(&members));
+                        }
+                        // The reader was dropped
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        DecompressPool {
+            jobs: Some(jobs),
+            workers,
+        }
+    }
+
+    /// Queue `members` for decompression, returning the receiver of the result
+    fn submit(&self, members: Vec<Vec<u8>>) -> mpsc::Receiver<io::Result<Vec<u8>>> {
+        let (result, receiver) = mpsc::sync_channel(1);
+        // The workers only stop once `jobs` is dropped
+        self.jobs.as_ref().unwrap().send((members, result)).unwrap();
+        receiver
+    }
+}
+
+impl Drop for DecompressPool {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+enum BgzfMember {
+    Bgzf(Vec<u8>),
+    // The bytes read from a member that is not BGZF
+    Other(Vec<u8>),
+    Eof,
+}
+
+impl<R: Read + Send + 'static> BgzfReader<R> {
+    /// Decompress `inner` on `threads` threads
+    pub fn new(inner: R, threads: usize) -> Self {
+        Self::with_shared_threads(inner, Arc::new(AtomicUsize::new(threads)))
+    }
+
+    /// Decompress `inner` on the number of threads in `threads`, which can be
+    /// changed while reading
+    pub(crate) fn with_shared_threads(inner: R, threads: Arc<AtomicUsize>) -> Self {
+        BgzfReader {
+            inner: Some(inner),
+            fallback: None,
+            threads,
+            pool: None,
+            pending: VecDeque::new(),
+            buffer: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// True if `buf` starts with the header of a BGZF member
+    pub fn is_bgzf(buf: &[u8]) -> bool {
+        buf.len() >= BGZF_HEADER_LEN
+            && buf[0..4] == [0x1f, 0x8b, 0x08, 0x04]
+            && buf[12..16] == [b'B', b'C', 0x02, 0x00]
+    }
+
+    fn read_member(inner: &mut R) -> io::Result<BgzfMember> {
+        let mut header = [0u8; BGZF_HEADER_LEN];
+        let mut len = 0;
+        while len < header.len() {
+            match inner.read(&mut header[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if len == 0 {
+            return Ok(BgzfMember::Eof);
+        }
+        if !Self::is_bgzf(&header[..len]) {
+            return Ok(BgzfMember::Other(header[..len].to_vec()));
+        }
+
+        let block_size = u16::from_le_bytes([header[16], header[17]]) as usize + 1;
+        if block_size < BGZF_HEADER_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid BGZF block size {}", block_size),
+            ));
+        }
+        let mut member = vec![0; block_size];
+        member[..BGZF_HEADER_LEN].copy_from_slice(&header);
+        inner.read_exact(&mut member[BGZF_HEADER_LEN..])?;
+        Ok(BgzfMember::Bgzf(member))
+    }
+
+    /// Read the next batch of members, or `None` after the last BGZF member
+    fn read_batch(&mut self) -> io::Result<Option<Vec<Vec<u8>>>> {
+        let inner = match self.inner.as_mut() {
+            Some(inner) => inner,
+            None => return Ok(None),
+        };
+        let mut members = Vec::new();
+        let mut end = None;
+        while members.len() < BGZF_MEMBERS_PER_BATCH {
+            match Self::read_member(inner)? {
+                BgzfMember::Bgzf(member) => members.push(member),
+                other => {
+                    end = Some(other);
+                    break;
+                }
+            }
+        }
+        match end {
+            Some(BgzfMember::Other(start)) => {
+                let rest = Cursor::new(start).chain(self.inner.take().unwrap());
+                self.fallback = Some(Box::new(MultiGzDecoder::new(rest)));
+            }
+            Some(_) => self.inner = None,
+            None => {}
+        }
+        Ok(if members.is_empty() {
+            None
+        } else {
+            Some(members)
+        })
+    }
+
+    /// Decompress the next batch of members into the buffer
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        self.pos = 0;
+        let threads = self.threads.load(Ordering::Relaxed).max(1);
+        if self.pending.is_empty() {
+            if threads == 1 {
+                self.pool = None;
+                if let Some(members) = self.read_batch()? {
+                    self.buffer = decompress_members(&members)?;
+                }
+                return Ok(());
+            }
+            let resize = match self.pool {
+                Some(ref pool) => pool.workers.len() != threads,
+                None => true,
+            };
+            if resize {
+                self.pool = Some(DecompressPool::new(threads));
+            }
+        }
+
+        // Keep two batches per thread in flight, so that the threads decompress the
+        // next batches while the current one is read
+        while self.pending.len() < 2 * threads {
+            match self.read_batch()? {
+                Some(members) => {
+                    let batch = self.pool.as_ref().unwrap().submit(members);
+                    self.pending.push_back(batch);
+                }
+                None => break,
+            }
+        }
+        if let Some(batch) = self.pending.pop_front() {
+            self.buffer = batch.recv().expect("BGZF decompression thread panicked")?;
+        }
+        Ok(())
+    }
+}
+
+/// Position `reader`, at the start of a BGZF file, at the start of the member holding
+/// the uncompressed byte `offset`, using the sizes in the member headers and footers
+/// without decompressing the members. Returns the number of uncompressed bytes of the
+/// member to skip to get to `offset`.
+pub(crate) fn bgzf_seek<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<u64> {
+    let mut uncompressed = 0;
+    loop {
+        let start = reader.stream_position()?;
+        let mut header = [0u8; BGZF_HEADER_LEN];
+        let len = reader.read(&mut header)?;
+        if len == 0 {
+            return Ok(offset - uncompressed);
+        }
+        reader.read_exact(&mut header[len..])?;
+        if !BgzfReader::<io::Empty>::is_bgzf(&header) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("member at offset {} is not a BGZF member", start),
+            ));
+        }
+
+        let block_size = u64::from(u16::from_le_bytes([header[16], header[17]])) + 1;
+        let mut isize = [0u8; 4];
+        reader.seek(SeekFrom::Start(start + block_size - 4))?;
+        reader.read_exact(&mut isize)?;
+        let member_len = u64::from(u32::from_le_bytes(isize));
+        if uncompressed + member_len > offset {
+            reader.seek(SeekFrom::Start(start))?;
+            return Ok(offset - uncompressed);
+        }
+        uncompressed += member_len;
+    }
+}
+
+/// Decompress complete gzip members
+fn decompress_members(members: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for member in members {
+        GzDecoder::new(&member[..]).read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+impl<R: Read + Send + 'static> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buffer.len() {
+                let n = buf.len().min(self.buffer.len() - self.pos);
+                buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if self.inner.is_some() || !self.pending.is_empty() {
+                self.fill_buffer()?;
+                continue;
+            }
+            return match self.fallback.as_mut() {
+                Some(fallback) => fallback.read(buf),
+                None => Ok(0),
+            };
+        }
+    }
+}
+
+impl<W: Write> Write for BlockGzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let lines_per_block = self.lines_per_read_pair * self.config.read_pairs_per_block;
+        let mut rest = buf;
+        while let Some(pos) = rest.iter().position(|&b| b == b'\n') {
+            self.buffer.extend_from_slice(&rest[..=pos]);
+            rest = &rest[pos + 1..];
+            self.lines += 1;
+            if self.lines == lines_per_block {
+                self.end_block()?;
+            }
+        }
+        self.buffer.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    /// Flushes the blocks written so far. The current block is not cut short, so
+    /// its data is only written once the block is complete.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> FinishWrite for BlockGzWriter<W> {
+    /// Write the last block, the BGZF end-of-file marker and the index. The
+    /// underlying writer is dropped.
+    fn finish(&mut self) -> io::Result<()> {
+        if self.inner.is_some() {
+            self.finish_blocks()?;
+            self.inner = None;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for BlockGzWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.finish_blocks();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair::ReadPair;
+    use crate::read_pair_iter::{InputFastqs, ReadPairIter};
+    use crate::read_pair_writer::ReadPairWriter;
+    use crate::utils::Compression;
+
+    #[test]
+    fn test_block_gz_round_trip() -> Result<(), Error> {
+        let input = InputFastqs {
+            r1: "tests/read_pair_iter/vdj_micro_50k.fastq".to_string(),
+            r2: None,
+            i1: None,
+            i2: None,
+            r1_interleaved: true,
+        };
+        let reads: Vec<ReadPair> =
+            ReadPairIter::from_fastq_files(&input)?.collect::<Result<_, _>>()?;
+
+        for &format in &[BlockFormat::MultiMember, BlockFormat::Bgzf] {
+            let output = InputFastqs {
+                r1: format!("tests/block_gz_{:?}_RA.fastq.gz", format),
+                ..input.clone()
+            };
+            let config = BlockConfig::new(format, 1000);
+            {
+                let mut writer = ReadPairWriter::with_blocks(&output, config)?;
+                for rp in &reads {
+                    writer.write(rp)?;
+                }
+            }
+
+            let index = BlockIndex::read(&output.r1)?;
+            let written: Vec<ReadPair> =
+                ReadPairIter::from_fastq_files(&output)?.collect::<Result<_, _>>()?;
+            assert_eq!(Compression::detect(&output.r1)?, Compression::Gzip);
+            assert_eq!(written, reads);
+            assert_eq!(index.config, config);
+            assert_eq!(index.read_pairs(), reads.len() as u64);
+            assert!(index.blocks[..index.blocks.len() - 1]
+                .iter()
+                .all(|b| b.read_pairs == 1000));
+
+            // each block can be read on its own
+            for (i, block) in index.blocks.iter().enumerate() {
+                let rdr = index.block_reader(&output.r1, i)?;
+                let block_reads: Vec<ReadPair> =
+                    ReadPairIter::from_readers([Some(rdr), None, None, None], true)?
+                        .collect::<Result<_, _>>()?;
+                let start = block.first_read_pair as usize;
+                assert_eq!(block_reads.len() as u64, block.read_pairs);
+                assert_eq!(&block_reads[..], &reads[start..start + block_reads.len()]);
+            }
+
+            let file_len = std::fs::metadata(&output.r1)?.len();
+            let last = index.blocks.last().unwrap();
+            let eof_len = if format == BlockFormat::Bgzf { 28 } else { 0 };
+            assert_eq!(
+                last.compressed_offset + last.compressed_len + eof_len,
+                file_len
+            );
+            std::fs::remove_file(&output.r1)?;
+            std::fs::remove_file(BlockIndex::path_for(&output.r1))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bgzf_member_size() -> Result<(), Error> {
+        // A large incompressible block is split into BGZF members of at most 64KB
+        let mut seq = Vec::new();
+        let mut x = 1u32;
+        for _ in 0..200_000 {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            seq.push(b"ACGT"[(x >> 16) as usize % 4]);
+        }
+        let mut record = b"@r\n".to_vec();
+        record.extend_from_slice(&seq);
+        record.extend_from_slice(b"\n+\n");
+        record.extend_from_slice(&seq);
+        record.push(b'\n');
+
+        let mut writer = BlockGzWriter::new(Vec::new(), BlockConfig::new(BlockFormat::Bgzf, 1), 1)?;
+        writer.write_all(&record)?;
+        writer.write_all(&record)?;
+        let (data, index) = writer.finish()?;
+        assert_eq!(index.blocks.len(), 2);
+        assert!(data.ends_with(&BGZF_EOF));
+
+        let mut offset = 0;
+        let mut members = 0;
+        while offset < data.len() {
+            assert_eq!(&data[offset + 12..offset + 14], b"BC");
+            let bsize = u16::from_le_bytes([data[offset + 16], data[offset + 17]]) as usize + 1;
+            offset += bsize;
+            members += 1;
+        }
+        assert_eq!(offset, data.len());
+        assert!(members > 2 * (record.len() / BGZF_MAX_DATA));
+
+        let mut decoded = Vec::new();
+        flate2::read::MultiGzDecoder::new(&data[..]).read_to_end(&mut decoded)?;
+        assert_eq!(decoded, [&record[..], &record[..]].concat());
+
+        assert!(BlockGzWriter::new(Vec::new(), BlockConfig::new(BlockFormat::Bgzf, 0), 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bgzf_reader() -> Result<(), Error> {
+        let mut fastq = Vec::new();
+        for i in 0..20_000 {
+            fastq.extend_from_slice(format!("@read{}\nACGTACGTAC\n+\nIIIIIIIIII\n", i).as_bytes());
+        }
+        let mut writer =
+            BlockGzWriter::new(Vec::new(), BlockConfig::new(BlockFormat::Bgzf, 5000), 1)?;
+        writer.write_all(&fastq)?;
+        let (bgzf, _) = writer.finish()?;
+        assert!(BgzfReader::<&[u8]>::is_bgzf(&bgzf));
+
+        let read_all = |data: Vec<u8>, threads| -> io::Result<Vec<u8>> {
+            let mut decoded = Vec::new();
+            BgzfReader::new(Cursor::new(data), threads).read_to_end(&mut decoded)?;
+            Ok(decoded)
+        };
+        for &threads in &[1, 3, 8] {
+            assert_eq!(read_all(bgzf.clone(), threads)?, fastq);
+        }
+
+        // Regular gzip members appended to the BGZF data
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(b"@extra\nACGT\n+\nIIII\n")?;
+        let gz = gz.finish()?;
+        let concat = [&bgzf[..], &gz, &bgzf].concat();
+        let expected = [&fastq[..], b"@extra\nACGT\n+\nIIII\n", &fastq].concat();
+        assert_eq!(read_all(concat, 4)?, expected);
+
+        // The number of threads can change while reading
+        let threads = Arc::new(AtomicUsize::new(1));
+        let mut reader =
+            BgzfReader::with_shared_threads(Cursor::new(bgzf.clone()), threads.clone());
+        let mut decoded = vec![0; 1000];
+        reader.read_exact(&mut decoded[..500])?;
+        threads.store(4, Ordering::Relaxed);
+        reader.read_exact(&mut decoded[500..])?;
+        threads.store(2, Ordering::Relaxed);
+        reader.read_to_end(&mut decoded)?;
+        assert_eq!(decoded, fastq);
+
+        assert_eq!(read_all(Vec::new(), 4)?, b"");
+        assert!(read_all(bgzf[..bgzf.len() / 2].to_vec(), 4).is_err());
+        let mut corrupt = bgzf.clone();
+        corrupt[30] ^= 0xff;
+        assert!(read_all(corrupt, 4).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+//! Lightweight k-mer screen of reads against a small set of reference
+//! sequences, such as PhiX, adapters or common rRNA/mitochondrial sequences.
+//! Screening a sample of the reads of each chunk gives an early estimate of
+//! the contamination of a library before running the full processing.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::contaminant_screen::ContaminantScreen;
+//! let mut screen = ContaminantScreen::new(8, 2);
+//! screen.add_reference("adapter", b"AGATCGGAAGAGCACACGTCTGAACTCCAGTCAC");
+//! let reads: Vec<&[u8]> = vec![
+//!     b"TTGCATCGAGATCGGAAGAGCACACG",
+//!     b"CCATGCATGCATTTGACGATCGATCG",
+//! ];
+//! let report = screen.screen(reads);
+//! assert_eq!(report.num_reads(), 2);
+//! assert_eq!(report.fraction("adapter"), 0.5);
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// Marks k-mers shared by more than one reference
+const SHARED_KMER: usize = usize::MAX;
+
+/// A set of reference sequences to screen reads against. Reads are assigned
+/// to the reference sharing the most k-mers with them, if they share at least
+/// `min_hits` k-mers. K-mers are compared in both orientations and k-mers
+/// present in more than one reference are ignored.
+pub struct ContaminantScreen {
+    k: usize,
+    min_hits: usize,
+    names: Vec<String>,
+    kmers: HashMap<u64, usize>,
+}
+
+impl ContaminantScreen {
+    /// Create an empty screen using k-mers of length `k`.
+    ///
+    /// # Panics
+    /// * If `k` is 0 or larger than 32
+    pub fn new(k: usize, min_hits: usize) -> Self {
+        assert!(
+            k > 0 && k <= 32,
+            "k-mer length must be between 1 and 32. Found {}",
+            k
+        );
+        ContaminantScreen {
+            k,
+            min_hits: min_hits.max(1),
+            names: Vec::new(),
+            kmers: HashMap::new(),
+        }
+    }
+
+    /// Add the k-mers of a reference sequence to the screen. Adding several
+    /// sequences with the same name (e.g. multiple rRNA genes) pools their k-mers.
+    pub fn add_reference(&mut self, name: impl ToString, seq: &[u8]) {
+        let name = name.to_string();
+        let index = match self.names.iter().position(|n| *n == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(name);
+                self.names.len() - 1
+            }
+        };
+        for kmer in canonical_kmers(seq, self.k) {
+            let entry = self.kmers.entry(kmer).or_insert(index);
+            if *entry != index {
+                *entry = SHARED_KMER;
+            }
+        }
+    }
+
+    /// Names of the references in the screen
+    pub fn references(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The reference `seq` most likely comes from, if any.
+    pub fn classify(&self, seq: &[u8]) -> Option<&str> {
+        let mut hits = vec![0; self.names.len()];
+        for kmer in canonical_kmers(seq, self.k) {
+            match self.kmers.get(&kmer) {
+                Some(&index) if index != SHARED_KMER => hits[index] += 1,
+                _ => {}
+            }
+        }
+        let (best, &best_hits) = hits.iter().enumerate().max_by_key(|&(i, h)| (h, !i))?;
+        if best_hits >= self.min_hits {
+            Some(&self.names[best])
+        } else {
+            None
+        }
+    }
+
+    /// Screen a sample of read sequences.
+    pub fn screen<'a>(&self, seqs: impl IntoIterator<Item = &'a [u8]>) -> ContaminationReport {
+        let mut report = ContaminationReport::default();
+        for seq in seqs {
+            report.num_reads += 1;
+            if let Some(name) = self.classify(seq) {
+                *report.hits.entry(name.to_string()).or_insert(0) += 1;
+            }
+        }
+        report
+    }
+}
+
+/// Number of screened reads assigned to each reference.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ContaminationReport {
+    num_reads: u64,
+    hits: BTreeMap<String, u64>,
+}
+
+impl ContaminationReport {
+    /// Combine the report from another chunk
+    pub fn merge(&mut self, other: &ContaminationReport) {
+        self.num_reads += other.num_reads;
+        for (name, &count) in &other.hits {
+            *self.hits.entry(name.clone()).or_insert(0) += count;
+        }
+    }
+
+    /// Total number of reads screened
+    pub fn num_reads(&self) -> u64 {
+        self.num_reads
+    }
+
+    /// Number of reads assigned to the reference `name`
+    pub fn hits(&self, name: &str) -> u64 {
+        self.hits.get(name).cloned().unwrap_or(0)
+    }
+
+    /// Fraction of the screened reads assigned to the reference `name`
+    pub fn fraction(&self, name: &str) -> f64 {
+        if self.num_reads == 0 {
+            0.0
+        } else {
+            self.hits(name) as f64 / self.num_reads as f64
+        }
+    }
+
+    /// Fraction of the screened reads assigned to each reference with at least one hit
+    pub fn fractions(&self) -> BTreeMap<&str, f64> {
+        self.hits
+            .keys()
+            .map(|name| (name.as_str(), self.fraction(name)))
+            .collect()
+    }
+}
+
+fn encode_base(base: u8) -> Option<u64> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// 2-bit encoded canonical k-mers of `seq`, skipping k-mers containing non-ACGT bases.
+fn canonical_kmers(seq: &[u8], k: usize) -> impl Iterator<Item = u64> + '_ {
+    let mask = if k == 32 {
+        u64::MAX
+    } else {
+        (1 << (2 * k)) - 1
+    };
+    let shift = 2 * (k as u64 - 1);
+    let mut fwd = 0u64;
+    let mut rev = 0u64;
+    let mut valid = 0;
+    seq.iter().filter_map(move |&base| match encode_base(base) {
+        Some(code) => {
+            fwd = ((fwd << 2) | code) & mask;
+            rev = (rev >> 2) | ((3 - code) << shift);
+            valid += 1;
+            if valid >= k {
+                Some(fwd.min(rev))
+            } else {
+                None
+            }
+        }
+        None => {
+            valid = 0;
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revcomp(seq: &[u8]) -> Vec<u8> {
+        seq.iter()
+            .rev()
+            .map(|&b| match b {
+                b'A' => b'T',
+                b'C' => b'G',
+                b'G' => b'C',
+                b'T' => b'A',
+                _ => b'N',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_canonical_kmers() {
+        let seq = b"ACGTTGCANGGCAT";
+        let fwd: Vec<_> = canonical_kmers(seq, 4).collect();
+        let mut rev: Vec<_> = canonical_kmers(&revcomp(seq), 4).collect();
+        rev.reverse();
+        assert_eq!(fwd.len(), 5 + 2);
+        assert_eq!(fwd, rev);
+        assert_eq!(
+            canonical_kmers(b"ACGTACGTACGTACGTACGTACGTACGTACGTA", 32).count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_contaminant_screen() {
+        let phix = b"GAGTTTTATCGCTTCCATGACGCAGAAGTTAACACTTTCGGATATTTCTGATGAGTCGAAAAATTATCTTGATAAAGCAGGAATTACTACTGCTTGTTTACGAATTAAATCGAAGTGGACTGCTGGCGGAAAATGAGAAAATTCGACCTATCCTTGCGCAGCTCGAGAAGCTCTTACTTTGCGACCTTTCGCCATCAACTAACGATTCTGTCAAAAACTGACGCGTTGGATGAGGAGAAGTGGCTTAATATGCTTGGCACGTTCGTCAAGGACTGGTTTAGATATGAGTCACATTTTGTTCATGGTAGAGATTCTCTTGTTGACATTTTAAAAGAGCGTGGATTACTATCTGAGTCCGATGCTGTTCAACCACTAATAGGTAAGAAATCATGAGTCAAGTTACTGAACAATCCGTACGTTTCCAGACCGCTTTGGCCTCTATTAAGCTCATTCAGGCTTCTGCCGTTTTGGATTTAACCGAAGATGATTTCGATTTTCTGACGAGTAACAAAGTTTGGATTGCTACTGACCGCTCTCGTGCTCGTCGCTGCGTTGAGGCTTGCGTTTATGGTACGCTGGACTTTGTGGGATACCCTCGCTTTCCTGCTCCTGTTGAGTTTATTGCTGCCGTCATTGCTTATTATGTTCATCCCGTCAACATTCAAACGGCCTGTCTCATCATGGAAGGCGCTGAATTTACGGAAAACATTATTAATGGCGTCGAGCGTCCGGTTAAAGCCGCTGAATTGTTCGCGTTTACCTTGCGTGTACGCGCAGGAAACACTGACGTTCTTACTGACGCAGAAGAAAACGTGCGTCAAAAATTACGTGCGGAAGGAGTGATGTAATGTCTAAAGGTAAAAAACGTTCTGGCGCTCGCCCTGGTCGTCCGCAGCCGTTGCGAGGTACTAAAGGCAAGCGTAAAGGCGCTCGTCTTTGGTATGTAGGTGGTCAACAATTTTAATTGCAGGGGCTTCGGCCCCTTACTTGAGGATAAATTATGTCTAATATTCAAACTGGCGCCGAGCGTATGCCGCATGACCTTTCCCATCTTGGCTTCCTTGCTGGTCAGATTGGTCGTCTTATTACCATTTCAACTACTCCGGTTATCGCTGGCGACTCCTTCGAGATGGACGCCGTTGGCGCTCTCCGTCTTTCTCCATTGCGTCGTGGCCTTGCTATTGACTCTACTGTAGACATTTTTACTTTTTATGTCCCTCATCGTCACGTTTATGGTGAACAGTGGATTAAGTTCATGAAGGATGGTGTTAATGCCACTCCTCTCCCGACTGTTAACACTACTGGTTATATTGACCATGCCGCTTTTCTTGGCACGATTAACCCTGATACCAATAAAATCCCTAAGCATTTGTTTCAGGGTTATTTGAATATCTATAACAACTATTTTAAAGCGCCGTGGATGCCTGACCGTACCGAGGCTAACCCTAATGAGCTTAATCAAGATGATGCTCGTTATGGTTTCCGTTGCTGCCATCTCAAAAACATTTGGACTGCTCCGCTTCCTCCTGAGACTGAGCTTTCTCGCCAAATGACGACTTCTACCACATCTATTGACATTATGGGTCTGCAAGCTGCTTATGCTAATTTGCATACTGACCAAGAACGTGATTACTTCATGCAGCGTTACCATGATGTTATTTCTTCATTTGGAGGTAAAACCTCATATGACGCTGACAACCGTCCTTTACTTGTCATGCGCTCTAATCTCTGGGCATCTGGCTATGATGTTGATGGAACTGACCAAACGTCGTTAGGCCAGTTTTCTGGTCGTGTTCAACAGACCTATAAACATTCTGTGCCGCGTTTCTTTGTTCCTGAGCATGGCACTATGTTTACTCTTGCGCTTGTTCGTTTTCCGCCTACTGCGACTAAAGAGATTCAGTACCTTAACGCTAAAGGTGCTTTGACTTATACCGATATTGCTGGCGACCCTGTTTTGTATGGCAACTTGCCGCCGCGTGAAATTTCTATGAAGGATGTTTTCCGTTCTGGTGATTCGTCTAAGAAGTTTAAGATTGCTGAGGGTCAGTGGTATCGTTATGCGCCTTCGTATGTTTCTCCTGCTTATCACCTTCTTGAAGGCTTCCCATTCATTCAGGAACCGCCTTCTGGTGATTTGCAAGAACGCGCGAAAATGCGTGTTGCTGCGGCGTTCTCTGCGGGATTGAACCTGACTGAGCCACGTGGGCCTCTGGCTTGGCAACGTTTAATCTAC";
+        let adapter = b"AGATCGGAAGAGCACACGTCTGAACTCCAGTCAC";
+        let mut screen = ContaminantScreen::new(11, 3);
+        screen.add_reference("phix", &phix[..600]);
+        screen.add_reference("phix", &phix[600..]);
+        screen.add_reference("adapter", adapter);
+        assert_eq!(
+            screen.references(),
+            &["phix".to_string(), "adapter".to_string()]
+        );
+
+        let phix_rc = revcomp(&phix[100..180]);
+        let reads: Vec<&[u8]> = vec![
+            &phix[10..90],
+            &phix[1000..1080],
+            &phix_rc,
+            &adapter[..],
+            b"ACGACTAGCTAGCTACGACTAGCATCGACTAGCATTTTATATAT",
+            // Too few k-mers to be classified
+            &phix[200..212],
+        ];
+        assert_eq!(screen.classify(reads[2]), Some("phix"));
+        let mut report = screen.screen(reads.iter().cloned());
+        assert_eq!(report.num_reads(), 6);
+        assert_eq!(report.hits("phix"), 3);
+        assert_eq!(report.hits("adapter"), 1);
+        assert_eq!(report.hits("rrna"), 0);
+
+        report.merge(&screen.screen(reads[3..5].iter().cloned()));
+        assert_eq!(report.num_reads(), 8);
+        let fractions = report.fractions();
+        assert_eq!(fractions.len(), 2);
+        assert_eq!(fractions["phix"], 3.0 / 8.0);
+        assert_eq!(fractions["adapter"], 2.0 / 8.0);
+        assert_eq!(ContaminationReport::default().fraction("phix"), 0.0);
+    }
+}
@@ -0,0 +1,456 @@
+//! Read filtering reproducing the filters of [fastp](https://github.com/OpenGene/fastp),
+//! to ease the migration of pipelines running fastp before this crate.
+//!
+//! A `FastpFilter` holds the fastp settings for the quality, N base, length and low
+//! complexity filters, as well as the adapter and poly-G trimming applied before
+//! filtering. It can be created from a `FastpPreset` reproducing common fastp command
+//! lines, and is serializable so that it can be part of a pipeline configuration.
+//! As in fastp, a read pair is filtered if either R1 or R2 fails a filter, and index
+//! reads are not filtered.
+//!
+//! Adapters are found with a `CutadaptTrimmer` allowing one mismatch per 8 bases and
+//! no indels, like the fastp adapter search. fastp adapter auto-detection and the
+//! overlap analysis of paired reads are not reproduced, so the adapter sequences
+//! have to be given. The presets use the Illumina TruSeq adapters.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::fastp_filter::{FastpFilter, FastpPreset, FilterFailure};
+//! use fastq_set::read_pair::ReadPair;
+//! use fastq_set::OwnedRecord;
+//! let rec = |seq: &[u8], qual: u8| OwnedRecord {
+//!     head: b"read".to_vec(),
+//!     seq: seq.to_vec(),
+//!     qual: vec![qual; seq.len()],
+//!     sep: None,
+//! };
+//! let filter = FastpFilter::preset(FastpPreset::Default);
+//! let good = ReadPair::new([Some(rec(b"ACGTTGCAACGTTGCAACGT", b'I')), None, None, None]);
+//! let low_qual = ReadPair::new([Some(rec(b"ACGTTGCAACGTTGCAACGT", b'#')), None, None, None]);
+//! let short = ReadPair::new([Some(rec(b"ACGTTGCA", b'I')), None, None, None]);
+//! assert_eq!(filter.check(&good), None);
+//! assert_eq!(filter.check(&low_qual), Some(FilterFailure::LowQuality));
+//! assert_eq!(filter.check(&short), Some(FilterFailure::TooShort));
+//! ```
+
+use crate::adapter_trimmer::{Adapter, AdapterLoc, CutadaptTrimmer, TrimResult};
+use crate::metric_utils::ILLUMINA_QUAL_OFFSET;
+use crate::read_pair::{ReadPair, ReadPart, RpRange, TrimmedReadPair, WhichRead};
+use crate::WhichEnd;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Illumina TruSeq adapter of R1
+pub const TRUSEQ_ADAPTER_R1: &str = "AGATCGGAAGAGCACACGTCTGAACTCCAGTCA";
+/// Illumina TruSeq adapter of R2
+pub const TRUSEQ_ADAPTER_R2: &str = "AGATCGGAAGAGCGTCGTGTAGGGAAAGAGTGT";
+
+/// Common fastp command lines
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FastpPreset {
+    /// fastp without options: quality, N base and length filters, and adapter trimming
+    #[serde(rename = "default")]
+    Default,
+    /// fastp defaults for NextSeq and NovaSeq data, which add poly-G trimming (`-g`)
+    #[serde(rename = "two_color")]
+    TwoColor,
+    /// fastp defaults with the low complexity filter (`-y`)
+    #[serde(rename = "low_complexity")]
+    LowComplexity,
+    /// Nothing trimmed or filtered (`-A -Q -L`)
+    #[serde(rename = "disabled")]
+    Disabled,
+}
+
+/// Reason for a read pair to be filtered, named after the fastp filtering results
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterFailure {
+    /// Too many bases below the qualified quality, or a low mean quality
+    LowQuality,
+    /// More N bases than the limit
+    TooManyN,
+    /// Shorter than the required length after trimming
+    TooShort,
+    /// Longer than the length limit
+    TooLong,
+    /// Too few bases that differ from the next base
+    LowComplexity,
+}
+
+/// Filtering settings, named after the corresponding fastp options
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FastpFilter {
+    /// Enable the quality and N base filters (disabled by `-Q`)
+    pub quality_filter: bool,
+    /// `-q`: minimum phred quality of a qualified base
+    pub qualified_quality_phred: u8,
+    /// `-u`: maximum percentage of unqualified bases
+    pub unqualified_percent_limit: u8,
+    /// `-n`: maximum number of N bases
+    pub n_base_limit: usize,
+    /// `-e`: minimum mean phred quality, 0 for no requirement
+    pub average_qual: u8,
+    /// Enable the length filter (disabled by `-L`)
+    pub length_filter: bool,
+    /// `-l`: minimum length after trimming
+    pub length_required: usize,
+    /// `--length_limit`: maximum length, 0 for no limit
+    pub length_limit: usize,
+    /// `-Y`: minimum percentage of bases that differ from the next base, if the low
+    /// complexity filter is enabled (`-y`)
+    pub complexity_threshold: Option<u8>,
+    /// `--adapter_sequence` and `--adapter_sequence_r2`: 3' adapters of R1 and R2
+    pub adapters: [Option<Adapter>; 2],
+    /// `--poly_g_min_len`: minimum length of a poly-G tail, if poly-G trimming is
+    /// enabled (`-g`)
+    pub poly_g_min_len: Option<usize>,
+}
+
+impl FastpFilter {
+    /// The settings of a fastp preset
+    pub fn preset(preset: FastpPreset) -> Self {
+        let adapter = |name, seq| {
+            Some(Adapter::new(
+                name,
+                WhichEnd::ThreePrime,
+                AdapterLoc::Anywhere,
+                seq,
+            ))
+        };
+        let default = FastpFilter {
+            quality_filter: true,
+            qualified_quality_phred: 15,
+            unqualified_percent_limit: 40,
+            n_base_limit: 5,
+            average_qual: 0,
+            length_filter: true,
+            length_required: 15,
+            length_limit: 0,
+            complexity_threshold: None,
+            adapters: [
+                adapter("truseq_r1", TRUSEQ_ADAPTER_R1),
+                adapter("truseq_r2", TRUSEQ_ADAPTER_R2),
+            ],
+            poly_g_min_len: None,
+        };
+        match preset {
+            FastpPreset::Default => default,
+            FastpPreset::TwoColor => FastpFilter {
+                poly_g_min_len: Some(10),
+                ..default
+            },
+            FastpPreset::LowComplexity => FastpFilter {
+                complexity_threshold: Some(30),
+                ..default
+            },
+            FastpPreset::Disabled => FastpFilter {
+                quality_filter: false,
+                length_filter: false,
+                adapters: [None, None],
+                ..default
+            },
+        }
+    }
+
+    /// The bases of read `which` kept after poly-G and adapter trimming, or `None`
+    /// if the read is not present
+    pub fn retain_range(&self, rp: &ReadPair, which: WhichRead) -> Option<Range<usize>> {
+        let (end, adapter) = self.find_trims(rp, which)?;
+        match adapter {
+            Some(adapter) => Some(adapter.retain_range),
+            None => Some(0..end),
+        }
+    }
+
+    /// The start of the poly-G tail of read `which`, or its length if there is no
+    /// tail, and the adapter found before the tail
+    fn find_trims(&self, rp: &ReadPair, which: WhichRead) -> Option<(usize, Option<TrimResult>)> {
+        let seq = rp.get(which, ReadPart::Seq)?;
+        let mut end = seq.len();
+        if let Some(min_len) = self.poly_g_min_len {
+            if let Some(tail) = rp.detect_polyg_suffix(which, min_len) {
+                end = tail.offset();
+            }
+        }
+        let adapter = match which {
+            WhichRead::R1 => self.adapters[0].as_ref(),
+            WhichRead::R2 => self.adapters[1].as_ref(),
+            WhichRead::I1 | WhichRead::I2 => None,
+        };
+        if let Some(adapter) = adapter {
+            let trimmer = CutadaptTrimmer::new(adapter)
+                .error_rate(0.125)
+                .min_overlap(4)
+                .indels(false);
+            return Some((end, trimmer.find(&seq[..end])));
+        }
+        Some((end, None))
+    }
+
+    /// Check the trimmed R1 and R2 of `rp`, returning the first filter they fail,
+    /// or `None` if the read pair passes
+    pub fn check(&self, rp: &ReadPair) -> Option<FilterFailure> {
+        [WhichRead::R1, WhichRead::R2].iter().find_map(|&which| {
+            let range = self.retain_range(rp, which)?;
+            let seq = &rp.get(which, ReadPart::Seq).unwrap()[range.clone()];
+            let qual = rp.get(which, ReadPart::Qual).map(|q| &q[range]);
+            self.check_read(seq, qual)
+        })
+    }
+
+    /// Record the poly-G and adapter trimming of R1 and R2 in the trim provenance
+    /// of `rp`, and check the read pair as `check` does. The reads themselves are
+    /// left untrimmed, and the retained bases are given by `retain_range`.
+    pub fn record_trims(&self, rp: &mut TrimmedReadPair) -> Option<FilterFailure> {
+        for &which in [WhichRead::R1, WhichRead::R2].iter() {
+            let read_pair = &rp.read_pair;
+            if let (Some((end, adapter)), Some(len)) =
+                (self.find_trims(read_pair, which), read_pair.len(which))
+            {
+                let trim = rp.trim_mut(which);
+                if end < len {
+                    trim.poly_g = Some(RpRange::new(which, end, Some(len - end)));
+                }
+                if let Some(adapter) = adapter {
+                    trim.adapter = Some(adapter.trim_rp_range(which));
+                }
+            }
+        }
+        self.check(&rp.read_pair)
+    }
+
+    /// The fastp filters of a single read, in the order fastp applies them
+    fn check_read(&self, seq: &[u8], qual: Option<&[u8]>) -> Option<FilterFailure> {
+        let len = seq.len();
+        if self.quality_filter {
+            if let Some(qual) = qual {
+                let min_qual = ILLUMINA_QUAL_OFFSET + self.qualified_quality_phred;
+                let low_qual = qual.iter().filter(|&&q| q < min_qual).count();
+                let total: usize = qual
+                    .iter()
+                    .map(|&q| q.saturating_sub(ILLUMINA_QUAL_OFFSET) as usize)
+                    .sum();
+                if low_qual as f64 > f64::from(self.unqualified_percent_limit) * len as f64 / 100.0
+                    || (self.average_qual > 0
+                        && len > 0
+                        && total / len < self.average_qual as usize)
+                {
+                    return Some(FilterFailure::LowQuality);
+                }
+            }
+            let n_bases = seq.iter().filter(|&&b| b == b'N' || b == b'n').count();
+            if n_bases > self.n_base_limit {
+                return Some(FilterFailure::TooManyN);
+            }
+        }
+        if self.length_filter {
+            if len < self.length_required {
+                return Some(FilterFailure::TooShort);
+            }
+            if self.length_limit > 0 && len > self.length_limit {
+                return Some(FilterFailure::TooLong);
+            }
+        }
+        if let Some(threshold) = self.complexity_threshold {
+            let diff = seq.windows(2).filter(|w| w[0] != w[1]).count();
+            if len < 2 || (diff * 100) < usize::from(threshold) * (len - 1) {
+                return Some(FilterFailure::LowComplexity);
+            }
+        }
+        None
+    }
+}
+
+/// Number of R1 and R2 reads passing and failing each filter, with the field names
+/// of the `filtering_result` of a fastp JSON report
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FilterReport {
+    pub passed_filter_reads: u64,
+    pub low_quality_reads: u64,
+    #[serde(rename = "too_many_N_reads")]
+    pub too_many_n_reads: u64,
+    pub too_short_reads: u64,
+    pub too_long_reads: u64,
+    pub low_complexity_reads: u64,
+}
+
+impl FilterReport {
+    /// Count the R1 and R2 reads of `rp`, given the result of checking it
+    pub fn observe(&mut self, rp: &ReadPair, result: Option<FilterFailure>) {
+        let reads = [WhichRead::R1, WhichRead::R2]
+            .iter()
+            .filter(|&&which| rp.len(which).is_some())
+            .count() as u64;
+        let count = match result {
+            None => &mut self.passed_filter_reads,
+            Some(FilterFailure::LowQuality) => &mut self.low_quality_reads,
+            Some(FilterFailure::TooManyN) => &mut self.too_many_n_reads,
+            Some(FilterFailure::TooShort) => &mut self.too_short_reads,
+            Some(FilterFailure::TooLong) => &mut self.too_long_reads,
+            Some(FilterFailure::LowComplexity) => &mut self.low_complexity_reads,
+        };
+        *count += reads;
+    }
+
+    /// Combine the report from another chunk
+    pub fn merge(&mut self, other: &FilterReport) {
+        self.passed_filter_reads += other.passed_filter_reads;
+        self.low_quality_reads += other.low_quality_reads;
+        self.too_many_n_reads += other.too_many_n_reads;
+        self.too_short_reads += other.too_short_reads;
+        self.too_long_reads += other.too_long_reads;
+        self.low_complexity_reads += other.low_complexity_reads;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OwnedRecord;
+
+    fn rec(seq: &[u8], qual: &[u8]) -> OwnedRecord {
+        OwnedRecord {
+            head: b"read".to_vec(),
+            seq: seq.to_vec(),
+            qual: qual.to_vec(),
+            sep: None,
+        }
+    }
+
+    fn pair(r1: &[u8], r2: &[u8]) -> ReadPair {
+        ReadPair::new([
+            Some(rec(r1, &vec![b'I'; r1.len()])),
+            Some(rec(r2, &vec![b'I'; r2.len()])),
+            None,
+            None,
+        ])
+    }
+
+    #[test]
+    fn test_filters() {
+        let filter = FastpFilter::preset(FastpPreset::Default);
+        let seq = b"ACGTTGCAACGTTGCAACGTTGCA";
+        assert_eq!(filter.check(&pair(seq, seq)), None);
+
+        // 40% unqualified bases pass, more fail
+        let mut qual = vec![b'I'; 20];
+        for q in qual.iter_mut().take(8) {
+            *q = b'#';
+        }
+        let rp = |qual: &[u8]| ReadPair::new([Some(rec(&seq[..20], qual)), None, None, None]);
+        assert_eq!(filter.check(&rp(&qual)), None);
+        qual[8] = b'/';
+        assert_eq!(filter.check(&rp(&qual)), Some(FilterFailure::LowQuality));
+
+        let n = b"ACGTNNNNNNACGTTGCAACGT";
+        assert_eq!(filter.check(&pair(seq, n)), Some(FilterFailure::TooManyN));
+        assert_eq!(
+            filter.check(&pair(seq, b"ACGT")),
+            Some(FilterFailure::TooShort)
+        );
+
+        let low_complexity = b"AAAAAAAAAAAAAAAAAAAACG";
+        assert_eq!(filter.check(&pair(seq, low_complexity)), None);
+        let filter = FastpFilter::preset(FastpPreset::LowComplexity);
+        assert_eq!(
+            filter.check(&pair(seq, low_complexity)),
+            Some(FilterFailure::LowComplexity)
+        );
+
+        // Empty reads only fail the length filter
+        assert_eq!(filter.check(&pair(seq, b"")), Some(FilterFailure::TooShort));
+
+        let filter = FastpFilter::preset(FastpPreset::Disabled);
+        assert_eq!(filter.check(&pair(n, b"ACGT")), None);
+        assert_eq!(filter.check(&pair(seq, b"")), None);
+        let average = FastpFilter {
+            quality_filter: true,
+            average_qual: 20,
+            ..filter.clone()
+        };
+        assert_eq!(average.check(&pair(seq, b"")), None);
+        let filter = FastpFilter {
+            length_limit: 20,
+            ..filter
+        };
+        assert_eq!(filter.check(&pair(n, b"ACGT")), None);
+        let filter = FastpFilter {
+            length_filter: true,
+            length_required: 0,
+            ..filter
+        };
+        assert_eq!(
+            filter.check(&pair(n, b"ACGT")),
+            Some(FilterFailure::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_trimming() {
+        let insert = b"TTGCAACGTTGCAACGT";
+        let with_adapter = [&insert[..], TRUSEQ_ADAPTER_R1.as_bytes()].concat();
+        let with_polyg = [&insert[..], b"GGGGGGGGGGGGGGG"].concat();
+        let rp = pair(&with_adapter, &with_polyg);
+
+        let filter = FastpFilter::preset(FastpPreset::Default);
+        assert_eq!(filter.retain_range(&rp, WhichRead::R1), Some(0..17));
+        assert_eq!(filter.retain_range(&rp, WhichRead::R2), Some(0..32));
+        assert_eq!(filter.retain_range(&rp, WhichRead::I1), None);
+
+        let filter = FastpFilter::preset(FastpPreset::TwoColor);
+        assert_eq!(filter.retain_range(&rp, WhichRead::R2), Some(0..17));
+        let mut trimmed = TrimmedReadPair::new(rp.clone());
+        assert_eq!(filter.record_trims(&mut trimmed), None);
+        assert_eq!(trimmed.read_pair, rp);
+        let trims = &trimmed.trims;
+        assert_eq!(
+            trims.read(WhichRead::R1).adapter,
+            Some(RpRange::new(
+                WhichRead::R1,
+                17,
+                Some(TRUSEQ_ADAPTER_R1.len())
+            ))
+        );
+        assert_eq!(trims.read(WhichRead::R1).poly_g, None);
+        assert_eq!(trims.read(WhichRead::R2).adapter, None);
+        assert_eq!(
+            trims.read(WhichRead::R2).poly_g,
+            Some(RpRange::new(WhichRead::R2, 17, Some(15)))
+        );
+
+        // An adapter followed by a poly-G tail
+        let both = [&with_adapter[..], b"GGGGGGGGGGGGGGG"].concat();
+        let mut trimmed = TrimmedReadPair::new(pair(&both, insert));
+        assert_eq!(filter.record_trims(&mut trimmed), None);
+        let trim = trimmed.trims.read(WhichRead::R1);
+        assert_eq!(
+            trim.adapter,
+            Some(RpRange::new(
+                WhichRead::R1,
+                17,
+                Some(TRUSEQ_ADAPTER_R1.len())
+            ))
+        );
+        assert_eq!(
+            trim.poly_g,
+            Some(RpRange::new(WhichRead::R1, with_adapter.len(), Some(15)))
+        );
+
+        // Too short once trimmed
+        let filter = FastpFilter {
+            length_required: 20,
+            ..filter
+        };
+        assert_eq!(filter.check(&rp), Some(FilterFailure::TooShort));
+
+        let mut report = FilterReport::default();
+        report.observe(&rp, filter.check(&rp));
+        report.observe(&rp, None);
+        let mut merged = FilterReport::default();
+        merged.merge(&report);
+        assert_eq!(merged.too_short_reads, 2);
+        assert_eq!(merged.passed_filter_reads, 2);
+        let json = serde_json::to_value(&merged).unwrap();
+        assert_eq!(json["too_many_N_reads"], 0);
+    }
+}
@@ -0,0 +1,106 @@
+//! Utilities for finding the FASTQ files written by 10x's `bamtofastq` tool.
+//!
+//! `bamtofastq` writes one directory per read group of the input BAM, named
+//! `<sample>_<library>_<gem group>_<flowcell>`, containing bcl2fastq-style files
+//! such as `bamtofastq_S1_L001_R1_001.fastq.gz`. Since the file names repeat across
+//! directories, each directory has to be grouped separately.
+
+use crate::filenames::bcl2fastq::find_flowcell_fastqs;
+use crate::read_pair_iter::InputFastqs;
+use failure::Error;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+lazy_static! {
+    static ref BAMTOFASTQ_DIR_REGEX: Regex = Regex::new(r"^.+_(\d+)_(\d+)_[A-Za-z0-9]+$").unwrap();
+}
+
+/// The FASTQs from one read group of the original BAM.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
+pub struct BamToFastqGroup {
+    /// Name of the directory holding the FASTQs
+    pub dir_name: String,
+    /// Gem group parsed from the directory name, if it follows the `bamtofastq` convention
+    pub gem_group: Option<u16>,
+    pub fastqs: Vec<InputFastqs>,
+}
+
+/// Find the FASTQ files in each read group directory of a `bamtofastq` output directory.
+/// Directories without any FASTQ files are skipped.
+pub fn find_bamtofastq_fastqs(path: impl AsRef<Path>) -> Result<Vec<BamToFastqGroup>, Error> {
+    let mut res = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?.path();
+        if !entry.is_dir() {
+            continue;
+        }
+        let dir_name = match entry.file_name().and_then(|f| f.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        let fastqs: Vec<_> = find_flowcell_fastqs(&entry)?
+            .into_iter()
+            .map(|(_, fastqs)| fastqs)
+            .collect();
+        if fastqs.is_empty() {
+            continue;
+        }
+
+        let gem_group = BAMTOFASTQ_DIR_REGEX
+            .captures(&dir_name)
+            .and_then(|cap| cap.get(2).unwrap().as_str().parse().ok());
+        res.push(BamToFastqGroup {
+            dir_name,
+            gem_group,
+            fastqs,
+        });
+    }
+    res.sort();
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_bamtofastq() -> Result<(), Error> {
+        let groups = find_bamtofastq_fastqs("tests/filenames/bamtofastq")?;
+        assert_eq!(groups.len(), 3);
+
+        assert_eq!(groups[0].dir_name, "other");
+        assert_eq!(groups[0].gem_group, None);
+        assert_eq!(groups[0].fastqs.len(), 1);
+
+        assert_eq!(groups[1].dir_name, "pbmc_0_1_HAWT7ADXX");
+        assert_eq!(groups[1].gem_group, Some(1));
+        assert_eq!(groups[1].fastqs.len(), 2);
+        assert_eq!(
+            groups[1].fastqs[0],
+            InputFastqs {
+                r1: "tests/filenames/bamtofastq/pbmc_0_1_HAWT7ADXX/bamtofastq_S1_L001_R1_001.fastq.gz"
+                    .to_string(),
+                r2: Some(
+                    "tests/filenames/bamtofastq/pbmc_0_1_HAWT7ADXX/bamtofastq_S1_L001_R2_001.fastq.gz"
+                        .to_string()
+                ),
+                i1: Some(
+                    "tests/filenames/bamtofastq/pbmc_0_1_HAWT7ADXX/bamtofastq_S1_L001_I1_001.fastq.gz"
+                        .to_string()
+                ),
+                i2: None,
+                r1_interleaved: false,
+            }
+        );
+
+        // The same file names in a different directory are kept separate
+        assert_eq!(groups[2].dir_name, "pbmc_0_2_HAWT7ADXX");
+        assert_eq!(groups[2].gem_group, Some(2));
+        assert_eq!(groups[2].fastqs.len(), 1);
+        assert!(groups[2].fastqs[0].r1.contains("pbmc_0_2_HAWT7ADXX"));
+        Ok(())
+    }
+}
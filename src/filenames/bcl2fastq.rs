@@ -14,9 +14,10 @@ use std::path::{Path, PathBuf};
 
 lazy_static! {
     static ref BCL2FASTQ_REGEX: Regex =
-        Regex::new(r"^([\w_-]+)_S(\d+)_L(\d+)_([RI][A123])_(\d+).fastq(.gz|.lz4)?$").unwrap();
+        Regex::new(r"^([\w_-]+)_S(\d+)_L(\d+)_([RI][A123])_(\d+).fastq(.gz|.lz4|.zst|.xz)?$")
+            .unwrap();
     static ref BCL2FASTQ_NO_LANE_SPLIT_REGEX: Regex =
-        Regex::new(r"^([\w_-]+)_S(\d+)_([RI][A123])_(\d+).fastq(.gz|.lz4)?$").unwrap();
+        Regex::new(r"^([\w_-]+)_S(\d+)_([RI][A123])_(\d+).fastq(.gz|.lz4|.zst|.xz)?$").unwrap();
 }
 
 /// Different ways to specify sample names for the `Bcl2FastqDef`
@@ -50,7 +51,7 @@ impl SampleNameSpec {
 /// using the Illumina `bcl2fastq` naming conventions.
 /// The `find_fastqs` method will find FASTQ files
 /// of the form `heart_1k_v3_S1_L002_R2_001.fastq.gz`
-/// with an optional `.gz` suffix.
+/// with an optional `.gz`, `.lz4`, `.zst` or `.xz` suffix.
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
 pub struct Bcl2FastqDef {
     /// The path where to the demulitplexed FASTQ files
@@ -260,6 +261,13 @@ mod test {
         };
 
         assert_eq!(r.unwrap(), expected);
+
+        for &ext in &["", ".lz4", ".zst", ".xz"] {
+            let filename = format!("heart_1k_v3_S1_L002_R2_001.fastq{}", ext);
+            let r = IlmnFastqFile::new(&filename).unwrap();
+            assert_eq!(r.group, expected.group);
+        }
+        assert!(IlmnFastqFile::new("heart_1k_v3_S1_L002_R2_001.fastq.bz2").is_none());
     }
 
     #[test]
@@ -241,7 +241,8 @@ fn try_parse(f: PathBuf) -> Option<(BclProcessorFile, PathBuf)> {
 }
 
 fn try_parse_bclprocessor_file(filename: &str) -> Option<BclProcessorFile> {
-    let re = "^read-([RI][A0-9])_si-([^_]+)_lane-([0-9]+)-chunk-([0-9]+).fastq(.gz|.lz4)?$";
+    let re =
+        "^read-([RI][A0-9])_si-([^_]+)_lane-([0-9]+)-chunk-([0-9]+).fastq(.gz|.lz4|.zst|.xz)?$";
     let re = regex::Regex::new(re).unwrap();
 
     re.captures(filename).map(|caps| BclProcessorFile {
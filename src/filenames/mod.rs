@@ -1,5 +1,6 @@
 //! Utilities for finding groups of FASTQ files on disk.
 
+pub mod bamtofastq;
 pub mod bcl2fastq;
 pub mod bcl_processor;
 pub mod fastq_dir;
@@ -1,7 +1,7 @@
 use failure::{format_err, Error};
 use serde::{Deserialize, Serialize};
 
-use crate::read_pair::{ReadPart, WhichRead};
+use crate::read_pair::{header_comment, ReadPart, WhichRead};
 use crate::read_pair_iter::{InputFastqs, ReadPairIter};
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
@@ -77,6 +77,175 @@ impl InputFastqs {
     }
 }
 
+/// The fields of an Illumina FASTQ header, in the format written by bcl2fastq and
+/// bcl-convert:
+/// `<instrument>:<run number>:<flowcell>:<lane>:<tile>:<x>:<y>[:<UMI>] <read>:<is filtered>:<control number>:<index>`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct IlluminaHeader {
+    pub instrument: String,
+    pub run_number: u32,
+    pub flowcell: String,
+    pub lane: u32,
+    pub tile: u32,
+    pub x: u32,
+    pub y: u32,
+    /// UMI appended to the read name, when UMIs are configured in the sample sheet
+    pub umi: Option<String>,
+    /// The fields of the header comment, if it is in the Illumina format
+    pub comment: Option<IlluminaComment>,
+}
+
+/// The fields of the comment of an Illumina FASTQ header, e.g. `1:N:0:GGCGAGTA+ACGTTTGA`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct IlluminaComment {
+    /// Read number, 1 or 2 (or 3 and 4 for index reads written as reads)
+    pub read: u8,
+    /// Whether the read was filtered out (`Y`)
+    pub is_filtered: bool,
+    pub control_number: u32,
+    /// Sample index sequence, or sample number for older versions of bcl2fastq.
+    /// Both indices of dual indexed runs are separated by `+`.
+    pub index: String,
+}
+
+impl IlluminaHeader {
+    /// Parse a FASTQ header, with or without the leading `@`. Returns `None` if
+    /// the read name is not in the Illumina format. The comment is optional.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::illumina_header_info::IlluminaHeader;
+    /// let header = IlluminaHeader::parse(b"A00228:197:HC7WVDMXX:1:1110:20338:1016 1:N:0:GGCGAGTA").unwrap();
+    /// assert_eq!(header.flowcell, "HC7WVDMXX");
+    /// assert_eq!((header.lane, header.tile, header.x, header.y), (1, 1110, 20338, 1016));
+    /// let comment = header.comment.unwrap();
+    /// assert_eq!(comment.read, 1);
+    /// assert!(!comment.is_filtered);
+    /// assert_eq!(comment.index, "GGCGAGTA");
+    /// ```
+    pub fn parse(header: &[u8]) -> Option<IlluminaHeader> {
+        let header = std::str::from_utf8(header.strip_prefix(b"@").unwrap_or(header)).ok()?;
+        let name = header.split([' ', '\t']).next()?;
+        let fields: Vec<&str> = name.split(':').collect();
+        if fields.len() != 7 && fields.len() != 8 {
+            return None;
+        }
+        let comment = std::str::from_utf8(header_comment(header.as_bytes())).ok()?;
+        Some(IlluminaHeader {
+            instrument: fields[0].to_string(),
+            run_number: fields[1].parse().ok()?,
+            flowcell: fields[2].to_string(),
+            lane: fields[3].parse().ok()?,
+            tile: fields[4].parse().ok()?,
+            x: fields[5].parse().ok()?,
+            y: fields[6].parse().ok()?,
+            umi: fields.get(7).map(|u| u.to_string()),
+            comment: IlluminaComment::parse(comment),
+        })
+    }
+
+    /// The run-level fields of the header
+    pub fn info(&self) -> IlluminaHeaderInfo {
+        IlluminaHeaderInfo {
+            instrument: self.instrument.clone(),
+            run_number: self.run_number,
+            flowcell: self.flowcell.clone(),
+            lane: self.lane,
+        }
+    }
+}
+
+impl IlluminaComment {
+    /// Parse the comment of a FASTQ header. Only the first whitespace-separated
+    /// field is considered, so comments with extra tags are accepted.
+    pub fn parse(comment: &str) -> Option<IlluminaComment> {
+        let field = comment.split([' ', '\t']).next()?;
+        let mut parts = field.splitn(4, ':');
+        let read = parts.next()?.parse().ok()?;
+        let is_filtered = match parts.next()? {
+            "Y" => true,
+            "N" => false,
+            _ => return None,
+        };
+        let control_number = parts.next()?.parse().ok()?;
+        let index = parts.next()?.to_string();
+        Some(IlluminaComment {
+            read,
+            is_filtered,
+            control_number,
+            index,
+        })
+    }
+}
+
+/// Find a SAM-style `TAG:TYPE:VALUE` field in the comment of a FASTQ header, i.e. the
+/// whitespace-separated fields after the read name. Returns the value of the first
+/// field matching `tag`, e.g. `b"BX"`.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::illumina_header_info::header_tag;
+/// let header = b"A00419:42:H7CL3DRXX:1:1101:1000:1000 BX:Z:ACGTACGTACGTACGT-1\tRX:Z:AC";
+/// assert_eq!(header_tag(header, b"BX"), Some(&b"ACGTACGTACGTACGT-1"[..]));
+/// assert_eq!(header_tag(header, b"RX"), Some(&b"AC"[..]));
+/// assert_eq!(header_tag(header, b"MI"), None);
+/// ```
+pub fn header_tag<'a>(header: &'a [u8], tag: &[u8]) -> Option<&'a [u8]> {
+    header
+        .split(|c| *c == b' ' || *c == b'\t')
+        .skip(1)
+        .find_map(|field| {
+            let mut parts = field.splitn(3, |c| *c == b':');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(t), Some(_), Some(value)) if t == tag => Some(value),
+                _ => None,
+            }
+        })
+}
+
+/// A barcode carried in the `BX:Z` field of a FASTQ header comment, as emitted by
+/// longranger basic and bamtofastq. The barcode may carry a `-<gem group>` suffix.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct HeaderBarcode {
+    pub sequence: Vec<u8>,
+    pub gem_group: Option<u16>,
+}
+
+impl HeaderBarcode {
+    /// Parse the `BX:Z` field of `header`, if present. A suffix that is not a valid
+    /// gem group is kept as part of the sequence.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::illumina_header_info::HeaderBarcode;
+    /// let bc = HeaderBarcode::from_header(b"read1 BX:Z:ACGTACGT-2").unwrap();
+    /// assert_eq!(bc.sequence, b"ACGTACGT".to_vec());
+    /// assert_eq!(bc.gem_group, Some(2));
+    /// ```
+    pub fn from_header(header: &[u8]) -> Option<HeaderBarcode> {
+        header_tag(header, b"BX").map(HeaderBarcode::from_bx)
+    }
+
+    /// Parse the value of a `BX` tag
+    pub fn from_bx(bx: &[u8]) -> HeaderBarcode {
+        if let Some(pos) = bx.iter().rposition(|c| *c == b'-') {
+            let gem_group = std::str::from_utf8(&bx[pos + 1..])
+                .ok()
+                .and_then(|g| g.parse().ok());
+            if gem_group.is_some() {
+                return HeaderBarcode {
+                    sequence: bx[..pos].to_vec(),
+                    gem_group,
+                };
+            }
+        }
+        HeaderBarcode {
+            sequence: bx.to_vec(),
+            gem_group: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -166,4 +335,85 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_illumina_header() {
+        let h = IlluminaHeader::parse(
+            b"@M00123:7:000000000-A1B2C:1:2104:15343:1970:ACGTACGT 2:Y:18:ACGT+GGTT BX:Z:AC",
+        )
+        .unwrap();
+        assert_eq!(h.instrument, "M00123");
+        assert_eq!(h.run_number, 7);
+        assert_eq!(h.flowcell, "000000000-A1B2C");
+        assert_eq!(h.umi.as_deref(), Some("ACGTACGT"));
+        assert_eq!(
+            h.comment,
+            Some(IlluminaComment {
+                read: 2,
+                is_filtered: true,
+                control_number: 18,
+                index: "ACGT+GGTT".to_string(),
+            })
+        );
+        assert_eq!(h.info().lane, 1);
+
+        let h = IlluminaHeader::parse(b"A00228:197:HC7WVDMXX:1:1110:20338:1016").unwrap();
+        assert_eq!((h.umi, h.comment), (None, None));
+        let h = IlluminaHeader::parse(b"A00228:197:HC7WVDMXX:1:1110:20338:1016 BX:Z:AC").unwrap();
+        assert_eq!(h.comment, None);
+
+        // Not in the Illumina format
+        assert_eq!(
+            IlluminaHeader::parse(b"3:1101:1597:1000 1:N:0:GGCGAGTA"),
+            None
+        );
+        assert_eq!(IlluminaHeader::parse(b"A:x:FC:1:1:1:1"), None);
+        assert_eq!(IlluminaHeader::parse(b"SRR1234.1 1/1"), None);
+
+        // Header and comment of the reads
+        let rp = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+        let header = rp.get(WhichRead::R1, ReadPart::Header).unwrap();
+        let comment = rp.get(WhichRead::R1, ReadPart::HeaderComment).unwrap();
+        assert_eq!(comment, b"1:N:0:0");
+        assert!(header.ends_with(comment));
+        let h = IlluminaHeader::parse(header).unwrap();
+        assert_eq!(h.comment.unwrap().index, "0");
+        assert_eq!(
+            rp.get(WhichRead::R2, ReadPart::HeaderComment).unwrap(),
+            b"4:N:0:0"
+        );
+    }
+
+    #[test]
+    fn test_header_barcode() {
+        assert_eq!(header_tag(b"BX:Z:ACGT", b"BX"), None);
+        assert_eq!(header_tag(b"read BX:ACGT", b"BX"), None);
+        assert_eq!(
+            header_tag(b"read 1:N:0:1 BX:Z:A:C", b"BX"),
+            Some(&b"A:C"[..])
+        );
+        assert_eq!(HeaderBarcode::from_header(b"read 1:N:0:1"), None);
+
+        let bc = HeaderBarcode::from_header(b"read 1:N:0:1 BX:Z:ACGT").unwrap();
+        assert_eq!(bc.sequence, b"ACGT".to_vec());
+        assert_eq!(bc.gem_group, None);
+
+        let bc = HeaderBarcode::from_bx(b"ACGT-GG");
+        assert_eq!(bc.sequence, b"ACGT-GG".to_vec());
+        assert_eq!(bc.gem_group, None);
+
+        let bc = HeaderBarcode::from_bx(b"AC-GT-12");
+        assert_eq!(bc.sequence, b"AC-GT".to_vec());
+        assert_eq!(bc.gem_group, Some(12));
+    }
 }
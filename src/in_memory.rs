@@ -0,0 +1,252 @@
+//! In-memory collection of a bounded number of read pairs, indexed by read name
+//! and optionally by barcode. Intended for tests and for exploring small datasets,
+//! not for processing full runs.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::in_memory::FastqSet;
+//! use fastq_set::read_pair::{ReadPart, RpRange, WhichRead};
+//! use fastq_set::read_pair_iter::InputFastqs;
+//! let input = InputFastqs {
+//!     r1: "tests/read_pair_iter/good-RA.fastq".to_string(),
+//!     r2: None,
+//!     i1: None,
+//!     i2: None,
+//!     r1_interleaved: true,
+//! };
+//! let set = FastqSet::new()
+//!     .barcode_range(RpRange::new(WhichRead::R1, 0, Some(16)))
+//!     .load(&input, 10)
+//!     .unwrap();
+//! assert_eq!(set.len(), 8);
+//! let read = set.by_name(b"A00228:197:HC7WVDMXX:1:1110:20338:1016").unwrap();
+//! assert_eq!(&read.get(WhichRead::R1, ReadPart::Seq).unwrap()[..4], b"GNCC");
+//! assert_eq!(set.by_barcode(b"GNCCTGGTGGCAAGCA").len(), 1);
+//! assert_eq!(set.reads()[1..3].len(), 2);
+//! ```
+
+use crate::read_names;
+use crate::read_pair::{ReadPair, ReadPart, RpRange, WhichRead};
+use crate::read_pair_iter::{InputFastqs, ReadPairIter};
+use failure::Error;
+use std::collections::HashMap;
+use std::ops::Index;
+
+/// Read pairs held in memory, in the order they were added.
+#[derive(Clone, Debug, Default)]
+pub struct FastqSet {
+    reads: Vec<ReadPair>,
+    barcode: Option<RpRange>,
+    by_name: HashMap<Vec<u8>, usize>,
+    by_barcode: HashMap<Vec<u8>, Vec<usize>>,
+}
+
+impl FastqSet {
+    /// An empty set, indexing the reads by name only
+    pub fn new() -> Self {
+        FastqSet::default()
+    }
+
+    /// Also index the reads by the sequence in `range`, e.g. the cell barcode.
+    ///
+    /// # Panics
+    /// * If the set already holds reads
+    pub fn barcode_range(mut self, range: RpRange) -> Self {
+        assert!(
+            self.reads.is_empty(),
+            "The barcode range must be set before adding reads"
+        );
+        self.barcode = Some(range);
+        self
+    }
+
+    /// Load the first `max_reads` read pairs of `input` into the set.
+    pub fn load(mut self, input: &InputFastqs, max_reads: usize) -> Result<Self, Error> {
+        self.extend_from(ReadPairIter::from_fastq_files(input)?, max_reads)?;
+        Ok(self)
+    }
+
+    /// Add the read pairs of `reads` until the set holds `max_reads` read pairs.
+    /// Returns the number of read pairs added.
+    pub fn extend_from<I, E>(&mut self, reads: I, max_reads: usize) -> Result<usize, Error>
+    where
+        I: IntoIterator<Item = Result<ReadPair, E>>,
+        Error: From<E>,
+    {
+        let start = self.len();
+        for read in reads.into_iter().take(max_reads.saturating_sub(start)) {
+            self.push(read?);
+        }
+        Ok(self.len() - start)
+    }
+
+    /// Add a read pair to the set. A read pair with the same name as an earlier
+    /// one replaces it in the name index.
+    pub fn push(&mut self, read: ReadPair) {
+        let i = self.reads.len();
+        if let Some(name) = read_name(&read) {
+            self.by_name.insert(name.to_vec(), i);
+        }
+        if let Some(barcode) = self.barcode.and_then(|r| read.get_range(r, ReadPart::Seq)) {
+            self.by_barcode.entry(barcode.to_vec()).or_default().push(i);
+        }
+        self.reads.push(read);
+    }
+
+    pub fn len(&self) -> usize {
+        self.reads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty()
+    }
+
+    /// All the read pairs, in the order they were added
+    pub fn reads(&self) -> &[ReadPair] {
+        &self.reads
+    }
+
+    pub fn get(&self, i: usize) -> Option<&ReadPair> {
+        self.reads.get(i)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ReadPair> {
+        self.reads.iter()
+    }
+
+    /// The read pair named `name`: the header up to the first space, without a
+    /// trailing `/1` or `/2`.
+    pub fn by_name(&self, name: &[u8]) -> Option<&ReadPair> {
+        self.by_name.get(name).map(|&i| &self.reads[i])
+    }
+
+    /// The read pairs with the sequence `barcode` in the barcode range. Empty if
+    /// no barcode range was set.
+    pub fn by_barcode(&self, barcode: &[u8]) -> Vec<&ReadPair> {
+        self.by_barcode
+            .get(barcode)
+            .map(|reads| reads.iter().map(|&i| &self.reads[i]).collect())
+            .unwrap_or_default()
+    }
+
+    /// The distinct barcodes of the read pairs, in arbitrary order
+    pub fn barcodes(&self) -> impl Iterator<Item = &[u8]> {
+        self.by_barcode.keys().map(|b| b.as_slice())
+    }
+}
+
+impl Index<usize> for FastqSet {
+    type Output = ReadPair;
+
+    fn index(&self, i: usize) -> &ReadPair {
+        &self.reads[i]
+    }
+}
+
+impl<'a> IntoIterator for &'a FastqSet {
+    type Item = &'a ReadPair;
+    type IntoIter = std::slice::Iter<'a, ReadPair>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.reads.iter()
+    }
+}
+
+/// Name of a read pair, from the header of its first read
+fn read_name(read: &ReadPair) -> Option<&[u8]> {
+    WhichRead::read_types()
+        .iter()
+        .find_map(|&which| read.get(which, ReadPart::Header))
+        .map(read_names::read_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(r1: &str, r2: Option<&str>) -> InputFastqs {
+        InputFastqs {
+            r1: r1.to_string(),
+            r2: r2.map(String::from),
+            i1: None,
+            i2: None,
+            r1_interleaved: r2.is_none(),
+        }
+    }
+
+    #[test]
+    fn test_fastq_set() -> Result<(), Error> {
+        let all: Vec<ReadPair> = ReadPairIter::from_fastq_files(&input(
+            "tests/read_pair_iter/vdj_micro_50k.fastq",
+            None,
+        ))?
+        .collect::<Result<_, _>>()?;
+
+        let barcode = RpRange::new(WhichRead::R1, 0, Some(16));
+        let set = FastqSet::new().barcode_range(barcode).load(
+            &input("tests/read_pair_iter/vdj_micro_50k.fastq", None),
+            1000,
+        )?;
+        assert_eq!(set.len(), 1000);
+        assert_eq!(set.reads(), &all[..1000]);
+        assert_eq!(set[10], all[10]);
+        assert_eq!(set.get(1000), None);
+        assert_eq!((&set).into_iter().count(), 1000);
+
+        for read in set.iter() {
+            let name = read_name(read).unwrap();
+            assert_eq!(set.by_name(name), Some(read));
+            let bc = read.get_range(barcode, ReadPart::Seq).unwrap();
+            assert!(set.by_barcode(bc).contains(&read));
+        }
+        let total: usize = set.barcodes().map(|bc| set.by_barcode(bc).len()).sum();
+        assert_eq!(total, set.len());
+        assert!(set.by_name(b"no-such-read").is_none());
+        assert!(set.by_barcode(b"NNNNNNNNNNNNNNNN").is_empty());
+
+        // Reads beyond the limit are not loaded
+        let mut set = FastqSet::new();
+        let it = ReadPairIter::from_fastq_files(&input(
+            "tests/read_pair_iter/vdj_micro_50k.fastq",
+            None,
+        ))?;
+        assert_eq!(set.extend_from(it, 5)?, 5);
+        assert_eq!(
+            set.extend_from(all.iter().cloned().map(Ok::<_, Error>), 5)?,
+            0
+        );
+        assert_eq!(set.barcodes().count(), 0);
+        assert!(set.by_barcode(b"").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_name() -> Result<(), Error> {
+        // "/1" and "/2" suffixes are stripped
+        let set = FastqSet::new().load(
+            &input(
+                "tests/read_pair_iter/slash1.fastq",
+                Some("tests/read_pair_iter/slash2.fastq"),
+            ),
+            1,
+        )?;
+        assert!(set
+            .by_name(b"A00228:197:HC7WVDMXX:1:1110:20338:1016")
+            .is_some());
+        assert!(FastqSet::new().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_barcode_range_after_reads() {
+        let mut set = FastqSet::new();
+        set.extend_from(
+            ReadPairIter::from_fastq_files(&input("tests/read_pair_iter/good-RA.fastq", None))
+                .unwrap(),
+            1,
+        )
+        .unwrap();
+        set.barcode_range(RpRange::new(WhichRead::R1, 0, Some(16)));
+    }
+}
@@ -8,6 +8,9 @@
 //! * Containers for FASTQ read-pairs (along with index reads), providing access to 'technical' read components like cell barcode and
 //! UMI sequences.
 //! * Flexible read trimming inspired by `cutadapt`
+//! * Barcode whitelist checks: single-N barcode rescue, probe barcode assignment, R1/R2 swap
+//! detection, Undetermined read rescue and barcode anonymization. The whitelist is supplied by
+//! the caller as barcode sequences.
 
 #![deny(warnings)]
 // Allowed clippy lints
@@ -18,20 +21,40 @@
 )]
 
 pub mod adapter_trimmer;
+pub mod aligner_batch;
 pub mod array;
+#[cfg(feature = "async")]
+pub mod async_read_pair_iter;
 pub mod background_iterator;
+pub mod bam_read_pair_iter;
+pub mod barcode_anonymizer;
+pub mod barcode_rescue;
+pub mod block_gz;
+pub mod contaminant_screen;
+pub mod fastp_filter;
 pub mod filenames;
 pub mod illumina_header_info;
+pub mod in_memory;
+pub mod manifest;
+pub mod memory_tracker;
 pub mod metric_utils;
+pub mod probe_barcodes;
+pub mod read_names;
 pub mod read_pair;
 pub mod read_pair_iter;
 pub mod read_pair_writer;
+pub mod read_sink;
+pub mod rechunk;
+pub mod regex_extract;
 pub mod sample_index_map;
+pub mod shuffle;
 pub mod squality;
 pub mod sseq;
+pub mod swap_check;
+pub mod undetermined_rescue;
 pub mod utils;
 
-use crate::read_pair_iter::{AnyReadPairIter, InputFastqs, ReadPairIter};
+use crate::read_pair_iter::{AnyReadPairIter, InputFastqs, IoBytes, ReadPairIter};
 pub use crate::squality::SQuality;
 pub use crate::sseq::SSeq;
 use failure::Error;
@@ -39,6 +62,7 @@ pub use fastq::OwnedRecord;
 pub use fastq::Record;
 pub use read_pair::WhichRead;
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
 
 /// A trait for objects that carry alignable sequence data.
 pub trait AlignableReadPair {
@@ -87,6 +111,22 @@ pub trait FastqProcessor {
     fn illumina_r1_trim_length(&self) -> Option<usize>;
     fn illumina_r2_trim_length(&self) -> Option<usize>;
 
+    /// Number of bases of read `which` to keep, or `None` to keep the full read.
+    /// Defaults to the Illumina trim lengths for R1 and R2.
+    fn trim_length(&self, which: WhichRead) -> Option<usize> {
+        match which {
+            WhichRead::R1 => self.illumina_r1_trim_length(),
+            WhichRead::R2 => self.illumina_r2_trim_length(),
+            WhichRead::I1 | WhichRead::I2 => None,
+        }
+    }
+
+    /// Treat a chunk without any read pair as an error. Defaults to `false`, so that
+    /// empty chunks yield no reads and empty metrics.
+    fn reject_empty_chunks(&self) -> bool {
+        false
+    }
+
     fn iter(&self) -> Result<FastqProcessorIter<'_, Self>, Error>
     where
         Self: Sized,
@@ -101,6 +141,20 @@ pub trait FastqProcessor {
         FastqProcessorIter::new_background(self, read_ahead)
     }
 
+    /// Read and process the reads on a background thread buffering `config.depth`
+    /// batches of `config.batch_size` read pairs, decompressing BGZF input on
+    /// `decompression_threads` threads.
+    fn iter_background_with_config(
+        &self,
+        config: background_iterator::BackgroundConfig,
+        decompression_threads: usize,
+    ) -> Result<FastqProcessorIter<'_, Self>, Error>
+    where
+        Self: Sized,
+    {
+        FastqProcessorIter::with_background_config(self, config, decompression_threads)
+    }
+
     fn iter_with_storage(
         &self,
         storage: read_pair::ReadPairStorage,
@@ -138,6 +192,10 @@ where
 {
     read_pair_iter: AnyReadPairIter,
     processor: &'a Processor,
+    io: IoBytes,
+    start: Instant,
+    start_cpu: Option<Duration>,
+    records: u64,
 }
 
 impl<'a, Processor> FastqProcessorIter<'a, Processor>
@@ -145,63 +203,74 @@ where
     Processor: FastqProcessor,
 {
     fn make_read_pair_iter(processor: &'a Processor) -> Result<ReadPairIter, Error> {
-        let read_pair_iter = ReadPairIter::from_fastq_files(&processor.fastq_files())?
-            .illumina_r1_trim_length(processor.illumina_r1_trim_length())
-            .illumina_r2_trim_length(processor.illumina_r2_trim_length())
+        let mut read_pair_iter = ReadPairIter::from_fastq_files(&processor.fastq_files())?
             .subsample_rate(processor.read_subsample_rate());
+        for &which in WhichRead::read_types().iter() {
+            read_pair_iter = read_pair_iter.trim_length(which, processor.trim_length(which));
+        }
+        read_pair_iter = read_pair_iter.reject_empty(processor.reject_empty_chunks());
 
         Ok(read_pair_iter)
     }
 
-    pub fn new(processor: &'a Processor) -> Result<Self, Error> {
-        let iter = Self::make_read_pair_iter(processor)?;
-        let read_pair_iter = AnyReadPairIter::Direct(iter);
-        Ok(FastqProcessorIter {
+    fn from_iter(read_pair_iter: AnyReadPairIter, io: IoBytes, processor: &'a Processor) -> Self {
+        FastqProcessorIter {
             read_pair_iter,
             processor,
-        })
+            io,
+            start: Instant::now(),
+            start_cpu: utils::thread_cpu_time(),
+            records: 0,
+        }
+    }
+
+    pub fn new(processor: &'a Processor) -> Result<Self, Error> {
+        let iter = Self::make_read_pair_iter(processor)?;
+        let io = iter.io_bytes();
+        let read_pair_iter = AnyReadPairIter::Direct(Box::new(iter));
+        Ok(Self::from_iter(read_pair_iter, io, processor))
     }
 
     pub fn new_background(processor: &'a Processor, readahead: usize) -> Result<Self, Error> {
         let iter = Self::make_read_pair_iter(processor)?;
+        let io = iter.io_bytes();
 
         let bg_iter = background_iterator::BackgroundIterator::new(iter, readahead);
         let read_pair_iter = AnyReadPairIter::Background(bg_iter);
-        Ok(FastqProcessorIter {
-            read_pair_iter,
-            processor,
-        })
+        Ok(Self::from_iter(read_pair_iter, io, processor))
+    }
+
+    pub fn with_background_config(
+        processor: &'a Processor,
+        config: background_iterator::BackgroundConfig,
+        decompression_threads: usize,
+    ) -> Result<Self, Error> {
+        let iter =
+            Self::make_read_pair_iter(processor)?.decompression_threads(decompression_threads);
+        let io = iter.io_bytes();
+
+        let bg_iter = background_iterator::BackgroundIterator::with_config(iter, config);
+        let read_pair_iter = AnyReadPairIter::Background(bg_iter);
+        Ok(Self::from_iter(read_pair_iter, io, processor))
     }
 
     pub fn with_storage(
         processor: &'a Processor,
         storage: read_pair::ReadPairStorage,
     ) -> Result<Self, Error> {
-        let read_pair_iter = ReadPairIter::from_fastq_files(&processor.fastq_files())?
-            .subsample_rate(processor.read_subsample_rate())
-            .illumina_r1_trim_length(processor.illumina_r1_trim_length())
-            .illumina_r2_trim_length(processor.illumina_r2_trim_length())
-            .storage(storage);
+        let read_pair_iter = Self::make_read_pair_iter(processor)?.storage(storage);
+        let io = read_pair_iter.io_bytes();
 
-        let read_pair_iter = AnyReadPairIter::Direct(read_pair_iter);
-        Ok(FastqProcessorIter {
-            read_pair_iter,
-            processor,
-        })
+        let read_pair_iter = AnyReadPairIter::Direct(Box::new(read_pair_iter));
+        Ok(Self::from_iter(read_pair_iter, io, processor))
     }
 
     pub fn with_seed(processor: &'a Processor, seed: u64) -> Result<Self, Error> {
-        let read_pair_iter = ReadPairIter::from_fastq_files(&processor.fastq_files())?
-            .illumina_r1_trim_length(processor.illumina_r1_trim_length())
-            .illumina_r2_trim_length(processor.illumina_r2_trim_length())
-            .subsample_rate(processor.read_subsample_rate())
-            .seed(seed);
-
-        let read_pair_iter = AnyReadPairIter::Direct(read_pair_iter);
-        Ok(FastqProcessorIter {
-            read_pair_iter,
-            processor,
-        })
+        let read_pair_iter = Self::make_read_pair_iter(processor)?.seed(seed);
+        let io = read_pair_iter.io_bytes();
+
+        let read_pair_iter = AnyReadPairIter::Direct(Box::new(read_pair_iter));
+        Ok(Self::from_iter(read_pair_iter, io, processor))
     }
 
     pub fn with_seed_and_storage(
@@ -209,18 +278,43 @@ where
         seed: u64,
         storage: read_pair::ReadPairStorage,
     ) -> Result<Self, Error> {
-        let read_pair_iter = ReadPairIter::from_fastq_files(&processor.fastq_files())?
-            .illumina_r1_trim_length(processor.illumina_r1_trim_length())
-            .illumina_r2_trim_length(processor.illumina_r2_trim_length())
-            .subsample_rate(processor.read_subsample_rate())
+        let read_pair_iter = Self::make_read_pair_iter(processor)?
             .seed(seed)
             .storage(storage);
+        let io = read_pair_iter.io_bytes();
 
-        let read_pair_iter = AnyReadPairIter::Direct(read_pair_iter);
-        Ok(FastqProcessorIter {
-            read_pair_iter,
-            processor,
-        })
+        let read_pair_iter = AnyReadPairIter::Direct(Box::new(read_pair_iter));
+        Ok(Self::from_iter(read_pair_iter, io, processor))
+    }
+
+    /// Occupancy of the queue of the background thread, or `None` if the reads are
+    /// not read on a background thread
+    pub fn queue_stats(&self) -> Option<background_iterator::QueueStats> {
+        match &self.read_pair_iter {
+            AnyReadPairIter::Direct(_) => None,
+            AnyReadPairIter::Background(v) => Some(v.queue_stats()),
+        }
+    }
+
+    /// Timing and throughput of the chunk since the iterator was created, e.g. to
+    /// log once the iterator is exhausted. The CPU time is that of the calling
+    /// thread, so it covers reading and processing for an iterator created and
+    /// consumed on that thread, but not the work of the thread reading ahead for
+    /// `new_background()`. It is only available on Linux.
+    pub fn timing(&self) -> metric_utils::ChunkTiming {
+        let (compressed, decompressed) = self.io.totals();
+        let cpu_time = match (self.start_cpu, utils::thread_cpu_time()) {
+            (Some(start), Some(now)) => now.checked_sub(start),
+            _ => None,
+        };
+        metric_utils::ChunkTiming::new(
+            self.processor.fastq_files(),
+            self.records,
+            compressed,
+            decompressed,
+            self.start.elapsed(),
+            cpu_time,
+        )
     }
 }
 
@@ -233,9 +327,13 @@ where
     /// Iterate over ReadType objects.
     fn next(&mut self) -> Option<Self::Item> {
         match self.read_pair_iter.next() {
-            Some(Ok(read)) => Some(Ok(self.processor.process_read(read))), // Processed Read
-            Some(Err(e)) => Some(Err(e.into())),                           // IO Error
-            None => None,                                                  // End of fastq
+            Some(Ok(read)) => {
+                // Processed Read
+                self.records += 1;
+                Some(Ok(self.processor.process_read(read)))
+            }
+            Some(Err(e)) => Some(Err(e.into())), // IO Error
+            None => None,                        // End of fastq
         }
     }
 }
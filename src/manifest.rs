@@ -0,0 +1,182 @@
+//! Chunk manifests for scatter/gather workflow managers such as Nextflow or CWL.
+//! A manifest is a JSON lines file with one `InputFastqs` chunk per line, along with
+//! an estimate of the number of reads in the chunk and an optional read group. Each
+//! line can be parsed independently, so a scattered job only needs its own line to
+//! reconstruct its inputs.
+//!
+//! ```json
+//! {"r1":"S1_L001_R1_001.fastq.gz","r2":"S1_L001_R2_001.fastq.gz","i1":null,"i2":null,"r1_interleaved":false,"estimated_reads":2093,"read_group":"S1:0:1"}
+//! ```
+
+use crate::read_pair_iter::{
+    CountingReader, FastqError, FileIoError, InputFastqs, IoCounters, ReadPairIter,
+};
+use failure::Error;
+use fastq::Parser;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+// Number of records sampled to estimate the number of reads in a file
+const ESTIMATE_SAMPLE_RECORDS: u64 = 10_000;
+
+/// One line of a chunk manifest.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    #[serde(flatten)]
+    pub fastqs: InputFastqs,
+    /// Estimated number of read pairs in the chunk
+    pub estimated_reads: u64,
+    pub read_group: Option<String>,
+}
+
+impl ManifestEntry {
+    /// Create a manifest entry for `fastqs`, estimating the number of reads
+    /// from the first records of the R1 file (see [`estimate_reads`](fn.estimate_reads.html)).
+    pub fn new(fastqs: InputFastqs, read_group: Option<String>) -> Result<Self, FastqError> {
+        let estimated_reads = estimate_reads(&fastqs)?;
+        Ok(ManifestEntry {
+            fastqs,
+            estimated_reads,
+            read_group,
+        })
+    }
+
+    /// Parse a single line of a manifest
+    pub fn from_line(line: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(line.trim())?)
+    }
+
+    /// Open a `ReadPairIter` over the FASTQs of this entry
+    pub fn read_pair_iter(&self) -> Result<ReadPairIter, FastqError> {
+        ReadPairIter::from_fastq_files(&self.fastqs)
+    }
+}
+
+/// Write a manifest with one line per entry
+pub fn write_manifest(path: impl AsRef<Path>, entries: &[ManifestEntry]) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read all the entries of a manifest, skipping blank lines
+pub fn read_manifest(path: impl AsRef<Path>) -> Result<Vec<ManifestEntry>, Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(ManifestEntry::from_line(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Estimate the number of read pairs in `fastqs` from the on-disk size of the R1
+/// file and the number of (possibly compressed) bytes taken by its first records.
+/// The count is exact for files small enough to be read completely.
+pub fn estimate_reads(fastqs: &InputFastqs) -> Result<u64, FastqError> {
+    let path = Path::new(&fastqs.r1);
+    let file = File::open(path).open_err(path)?;
+    let file_size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+    let counters = Arc::new(IoCounters::default());
+    let counting = CountingReader::raw(file, counters.clone());
+    let mut iter = Parser::new(ReadPairIter::decode_fastq(counting, path)?).ref_iter();
+
+    let mut records = 0;
+    let mut complete = false;
+    while records < ESTIMATE_SAMPLE_RECORDS {
+        iter.advance().fastq_err(path, records as usize)?;
+        if iter.get().is_none() {
+            complete = true;
+            break;
+        }
+        records += 1;
+    }
+
+    let estimate = if complete || records == 0 {
+        records
+    } else {
+        let bytes = counters.raw_bytes().max(1);
+        (records as f64 * file_size as f64 / bytes as f64).round() as u64
+    };
+    Ok(if fastqs.r1_interleaved {
+        estimate / 2
+    } else {
+        estimate
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fastqs(r1: &str, interleaved: bool) -> InputFastqs {
+        InputFastqs {
+            r1: r1.to_string(),
+            r2: None,
+            i1: None,
+            i2: None,
+            r1_interleaved: interleaved,
+        }
+    }
+
+    #[test]
+    fn test_estimate_reads() {
+        for path in &[
+            "tests/read_pair_iter/good-RA.fastq",
+            "tests/read_pair_iter/good-gzipped-RA.fastq.gz",
+            "tests/read_pair_iter/good-lz4-RA.fastq.lz4",
+        ] {
+            let n = ReadPairIter::from_fastq_files(&fastqs(path, true))
+                .unwrap()
+                .count() as u64;
+            assert_eq!(estimate_reads(&fastqs(path, true)).unwrap(), n);
+            assert_eq!(estimate_reads(&fastqs(path, false)).unwrap(), 2 * n);
+        }
+        assert!(estimate_reads(&fastqs("tests/read_pair_iter/missing.fastq", false)).is_err());
+
+        // Empty chunks have no reads
+        let empty = "tests/estimate_reads_empty.fastq";
+        std::fs::write(empty, b"").unwrap();
+        assert_eq!(estimate_reads(&fastqs(empty, true)).unwrap(), 0);
+        std::fs::remove_file(empty).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let path = "tests/manifest_round_trip.jsonl";
+        let entries = vec![
+            ManifestEntry::new(
+                fastqs("tests/read_pair_iter/good-RA.fastq", true),
+                Some("sample:0:1".to_string()),
+            )
+            .unwrap(),
+            ManifestEntry::new(
+                fastqs("tests/read_pair_iter/vdj_micro_50k.fastq", true),
+                None,
+            )
+            .unwrap(),
+        ];
+        write_manifest(path, &entries).unwrap();
+        let loaded = read_manifest(path).unwrap();
+        assert_eq!(loaded, entries);
+
+        let line = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let first = line.lines().next().unwrap();
+        assert!(first.starts_with("{\"r1\":\"tests/read_pair_iter/good-RA.fastq\""));
+        let entry = ManifestEntry::from_line(first).unwrap();
+        let n = entry.read_pair_iter().unwrap().count() as u64;
+        assert_eq!(entry.estimated_reads, n);
+        assert!(ManifestEntry::from_line("{\"r1\": 1}").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! Accounting of the memory held by buffered reads, so that processors buffering
+//! reads (e.g. grouping them by barcode) can apply back-pressure before running
+//! out of memory. The tracker only counts what it is told about: add the
+//! [`ReadPair::heap_bytes`](../read_pair/struct.ReadPair.html#method.heap_bytes)
+//! of each buffered read, and release it when the read is dropped or written out.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::memory_tracker::MemoryTracker;
+//! let tracker = MemoryTracker::new(1000);
+//! let reservation = tracker.try_reserve(600).unwrap();
+//! assert_eq!(tracker.used(), 600);
+//! assert!(tracker.try_reserve(600).is_none());
+//! drop(reservation);
+//! assert_eq!(tracker.used(), 0);
+//! assert_eq!(tracker.peak(), 600);
+//! ```
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug)]
+struct Counters {
+    budget: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// Thread-safe count of bytes in use against a budget. Clones share the same
+/// counts, so a tracker can be handed to each worker thread.
+#[derive(Clone, Debug)]
+pub struct MemoryTracker {
+    counters: Arc<Counters>,
+}
+
+impl MemoryTracker {
+    /// A tracker with a budget of `budget` bytes
+    pub fn new(budget: usize) -> Self {
+        MemoryTracker {
+            counters: Arc::new(Counters {
+                budget,
+                used: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Count `bytes` as used, irrespective of the budget
+    pub fn add(&self, bytes: usize) {
+        let used = self.counters.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.counters.peak.fetch_max(used, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` as no longer used
+    ///
+    /// # Panics
+    /// * If more bytes are released than are in use
+    pub fn release(&self, bytes: usize) {
+        let mut used = self.counters.used.load(Ordering::Relaxed);
+        loop {
+            let new_used = used.checked_sub(bytes).unwrap_or_else(|| {
+                panic!(
+                    "Released {} bytes, but only {} bytes are in use",
+                    bytes, used
+                )
+            });
+            match self.counters.used.compare_exchange_weak(
+                used,
+                new_used,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    /// Count `bytes` as used if that keeps the usage within the budget. The
+    /// bytes are released when the returned reservation is dropped.
+    pub fn try_reserve(&self, bytes: usize) -> Option<MemoryReservation> {
+        let mut used = self.counters.used.load(Ordering::Relaxed);
+        loop {
+            let new_used = used.checked_add(bytes)?;
+            if new_used > self.counters.budget {
+                return None;
+            }
+            match self.counters.used.compare_exchange_weak(
+                used,
+                new_used,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.counters.peak.fetch_max(new_used, Ordering::Relaxed);
+                    return Some(MemoryReservation {
+                        tracker: self.clone(),
+                        bytes,
+                    });
+                }
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    /// Number of bytes in use
+    pub fn used(&self) -> usize {
+        self.counters.used.load(Ordering::Relaxed)
+    }
+
+    /// Largest number of bytes in use at any point
+    pub fn peak(&self) -> usize {
+        self.counters.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn budget(&self) -> usize {
+        self.counters.budget
+    }
+
+    /// Number of bytes left in the budget
+    pub fn available(&self) -> usize {
+        self.budget().saturating_sub(self.used())
+    }
+
+    /// True if more bytes are in use than the budget allows. Buffering
+    /// processors should flush or stop reading until usage drops.
+    pub fn is_over_budget(&self) -> bool {
+        self.used() > self.budget()
+    }
+}
+
+/// Bytes counted by a `MemoryTracker` until the reservation is dropped.
+/// Created by [`MemoryTracker::try_reserve`](struct.MemoryTracker.html#method.try_reserve).
+#[derive(Debug)]
+pub struct MemoryReservation {
+    tracker: MemoryTracker,
+    bytes: usize,
+}
+
+impl MemoryReservation {
+    /// Number of bytes reserved
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.tracker.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair::ReadPair;
+    use crate::read_pair_iter::ReadPairIter;
+
+    #[test]
+    fn test_memory_tracker() {
+        let tracker = MemoryTracker::new(10_000);
+        let reads: Vec<ReadPair> = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+
+        let mut buffered = Vec::new();
+        let mut rejected = 0;
+        for rp in reads.iter().cycle().take(1000) {
+            match tracker.try_reserve(rp.heap_bytes()) {
+                Some(reservation) => buffered.push((rp.clone(), reservation)),
+                None => rejected += 1,
+            }
+        }
+        assert!(rejected > 0);
+        assert!(tracker.used() <= tracker.budget());
+        let total: usize = buffered.iter().map(|(rp, _)| rp.heap_bytes()).sum();
+        assert_eq!(tracker.used(), total);
+        assert_eq!(tracker.available(), tracker.budget() - total);
+
+        // Shared between threads
+        let clone = tracker.clone();
+        std::thread::spawn(move || clone.add(20_000))
+            .join()
+            .unwrap();
+        assert!(tracker.is_over_budget());
+        assert!(tracker.try_reserve(1).is_none());
+        tracker.release(20_000);
+
+        buffered.clear();
+        assert_eq!(tracker.used(), 0);
+        assert_eq!(tracker.peak(), total + 20_000);
+        assert!(tracker.try_reserve(usize::MAX).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_release_too_much() {
+        let tracker = MemoryTracker::new(100);
+        tracker.add(10);
+        tracker.release(11);
+    }
+
+    #[test]
+    fn test_release_too_much_keeps_usage() {
+        let tracker = MemoryTracker::new(100);
+        tracker.add(10);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tracker.release(11)));
+        assert!(res.is_err());
+        assert_eq!(tracker.used(), 10);
+        tracker.release(10);
+        assert_eq!(tracker.used(), 0);
+    }
+}
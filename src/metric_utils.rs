@@ -1,4 +1,9 @@
+use crate::read_pair::{ReadPair, ReadPart, RpRange, WhichRead};
+use crate::read_pair_iter::InputFastqs;
+use crate::AlignableReadPair;
 use bio::pattern_matching;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 pub const ILLUMINA_QUAL_OFFSET: u8 = 33;
 
@@ -17,3 +22,616 @@ impl PatternCheck {
         self.pattern.find_all(read).next().is_some()
     }
 }
+
+/// Per-cycle rate of N bases within a region of the read, such as the cell barcode.
+/// A sequencing cycle with anomalous dropout (e.g. a dark cycle) shows up as an
+/// N rate well above the other cycles of the region, which makes the bases at that
+/// position unreliable for barcode correction.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::metric_utils::CycleNRate;
+/// use fastq_set::read_pair::{ReadPair, RpRange, WhichRead};
+/// use fastq_set::OwnedRecord;
+/// let mut rates = CycleNRate::new(RpRange::new(WhichRead::R1, 0, Some(4)));
+/// for seq in [b"ACNTGG", b"GCNTAA", b"TTATCC", b"ACNAGG"].iter() {
+///     let rec = OwnedRecord {
+///         head: b"read".to_vec(),
+///         seq: seq.to_vec(),
+///         qual: vec![b'I'; seq.len()],
+///         sep: None,
+///     };
+///     rates.observe(&ReadPair::new([Some(rec), None, None, None]));
+/// }
+/// assert_eq!(rates.n_rates(), vec![0.0, 0.0, 0.75, 0.0]);
+/// assert_eq!(rates.unreliable_positions(0.1, 3.0), vec![2]);
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CycleNRate {
+    range: RpRange,
+    n_counts: Vec<u64>,
+    totals: Vec<u64>,
+}
+
+impl CycleNRate {
+    /// Track the N rate of each position of `range`. Positions are reported
+    /// relative to the start of the range.
+    pub fn new(range: RpRange) -> Self {
+        let len = range.len().unwrap_or(0);
+        CycleNRate {
+            range,
+            n_counts: vec![0; len],
+            totals: vec![0; len],
+        }
+    }
+
+    /// The region of the read being tracked.
+    pub fn range(&self) -> RpRange {
+        self.range
+    }
+
+    /// Count the bases of `read` within the tracked region. Reads that do not
+    /// cover the full region are ignored.
+    pub fn observe(&mut self, read: &ReadPair) {
+        if let Some(seq) = read.get_range(self.range, ReadPart::Seq) {
+            if seq.len() > self.totals.len() {
+                self.n_counts.resize(seq.len(), 0);
+                self.totals.resize(seq.len(), 0);
+            }
+            for (i, &base) in seq.iter().enumerate() {
+                self.totals[i] += 1;
+                if base == b'N' || base == b'n' {
+                    self.n_counts[i] += 1;
+                }
+            }
+        }
+    }
+
+    /// Combine the counts from another chunk tracking the same region.
+    ///
+    /// # Panics
+    /// * If `other` tracks a different region
+    pub fn merge(&mut self, other: &CycleNRate) {
+        assert_eq!(
+            self.range, other.range,
+            "Cannot merge N rates computed over different regions"
+        );
+        if other.totals.len() > self.totals.len() {
+            self.n_counts.resize(other.totals.len(), 0);
+            self.totals.resize(other.totals.len(), 0);
+        }
+        for i in 0..other.totals.len() {
+            self.n_counts[i] += other.n_counts[i];
+            self.totals[i] += other.totals[i];
+        }
+    }
+
+    /// Fraction of N bases at each position of the region. Positions
+    /// without any observed bases have a rate of 0.
+    pub fn n_rates(&self) -> Vec<f64> {
+        self.n_counts
+            .iter()
+            .zip(self.totals.iter())
+            .map(|(&n, &total)| {
+                if total == 0 {
+                    0.0
+                } else {
+                    n as f64 / total as f64
+                }
+            })
+            .collect()
+    }
+
+    /// Positions of the region with anomalous dropout, i.e. positions whose N rate
+    /// is at least `min_rate` and at least `fold_over_median` times the median N rate
+    /// of the region.
+    pub fn unreliable_positions(&self, min_rate: f64, fold_over_median: f64) -> Vec<usize> {
+        let rates = self.n_rates();
+        if rates.is_empty() {
+            return Vec::new();
+        }
+        let mut sorted = rates.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = sorted[(sorted.len() - 1) / 2];
+        let threshold = min_rate.max(fold_over_median * median);
+
+        rates
+            .iter()
+            .enumerate()
+            .filter(|(_, &rate)| rate > 0.0 && rate >= threshold)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+/// Filter for reads whose alignable sequence is too short after barcodes, UMIs
+/// and adapters have been trimmed away. Aligners handle reads of only a few bases
+/// poorly, so such reads are better dropped or flagged before alignment. The
+/// filter keeps count of the reads it has checked so that the number of short
+/// reads can be reported as a metric.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::metric_utils::MinAlignableLength;
+/// use fastq_set::AlignableReadPair;
+///
+/// struct Trimmed(Vec<u8>, Vec<u8>);
+/// impl AlignableReadPair for Trimmed {
+///     fn header(&self) -> &[u8] {
+///         b"read"
+///     }
+///     fn alignable_sequence(&self) -> (&[u8], &[u8]) {
+///         (&self.0, &self.1)
+///     }
+///     fn alignable_quals(&self) -> (&[u8], &[u8]) {
+///         (&self.0, &self.1)
+///     }
+/// }
+///
+/// let mut filter = MinAlignableLength::new(5, true);
+/// assert!(filter.check(&Trimmed(b"ACGTACGT".to_vec(), b"ACGTA".to_vec())));
+/// assert!(!filter.check(&Trimmed(b"ACGTACGT".to_vec(), b"AC".to_vec())));
+/// assert_eq!(filter.num_too_short(), 1);
+/// assert_eq!(filter.fraction_too_short(), 0.5);
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MinAlignableLength {
+    min_len: usize,
+    paired: bool,
+    num_checked: u64,
+    num_too_short: u64,
+}
+
+impl MinAlignableLength {
+    /// Require at least `min_len` alignable bases in the first read, and
+    /// also in the second read if `paired` is true.
+    pub fn new(min_len: usize, paired: bool) -> Self {
+        MinAlignableLength {
+            min_len,
+            paired,
+            num_checked: 0,
+            num_too_short: 0,
+        }
+    }
+
+    /// Returns true if the alignable sequence of `read` is long enough, and
+    /// updates the counts.
+    pub fn check(&mut self, read: &impl AlignableReadPair) -> bool {
+        let (r1, r2) = read.alignable_sequence();
+        let pass = r1.len() >= self.min_len && (!self.paired || r2.len() >= self.min_len);
+        self.num_checked += 1;
+        if !pass {
+            self.num_too_short += 1;
+        }
+        pass
+    }
+
+    /// Combine the counts from another chunk using the same filter settings.
+    ///
+    /// # Panics
+    /// * If `other` uses a different minimum length or pairing
+    pub fn merge(&mut self, other: &MinAlignableLength) {
+        assert_eq!(
+            (self.min_len, self.paired),
+            (other.min_len, other.paired),
+            "Cannot merge alignable length filters with different settings"
+        );
+        self.num_checked += other.num_checked;
+        self.num_too_short += other.num_too_short;
+    }
+
+    pub fn min_len(&self) -> usize {
+        self.min_len
+    }
+
+    pub fn num_checked(&self) -> u64 {
+        self.num_checked
+    }
+
+    pub fn num_too_short(&self) -> u64 {
+        self.num_too_short
+    }
+
+    /// Fraction of the checked reads that were too short, 0 if no reads were checked.
+    pub fn fraction_too_short(&self) -> f64 {
+        if self.num_checked == 0 {
+            0.0
+        } else {
+            self.num_too_short as f64 / self.num_checked as f64
+        }
+    }
+}
+
+/// Base quality summary of one read component: the number of bases, the number of
+/// bases with quality of at least 20 and 30, and the sum of the quality values.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReadQualityStats {
+    pub bases: u64,
+    pub q20_bases: u64,
+    pub q30_bases: u64,
+    pub qual_sum: u64,
+}
+
+impl ReadQualityStats {
+    /// Add the bases of a phred+33 encoded quality string. All statistics are
+    /// computed in a single branch-free pass over the bytes, which the compiler
+    /// can vectorize.
+    pub fn observe(&mut self, qual: &[u8]) {
+        // Accumulate in u32 over chunks small enough not to overflow
+        for chunk in qual.chunks(1 << 16) {
+            let mut q20 = 0u32;
+            let mut q30 = 0u32;
+            let mut sum = 0u32;
+            for &q in chunk {
+                q20 += (q >= ILLUMINA_QUAL_OFFSET + 20) as u32;
+                q30 += (q >= ILLUMINA_QUAL_OFFSET + 30) as u32;
+                // Bytes below the offset count as quality 0
+                sum += q.saturating_sub(ILLUMINA_QUAL_OFFSET) as u32;
+            }
+            self.bases += chunk.len() as u64;
+            self.q20_bases += u64::from(q20);
+            self.q30_bases += u64::from(q30);
+            self.qual_sum += u64::from(sum);
+        }
+    }
+
+    pub fn merge(&mut self, other: &ReadQualityStats) {
+        self.bases += other.bases;
+        self.q20_bases += other.q20_bases;
+        self.q30_bases += other.q30_bases;
+        self.qual_sum += other.qual_sum;
+    }
+
+    fn fraction(&self, count: u64) -> f64 {
+        if self.bases == 0 {
+            0.0
+        } else {
+            count as f64 / self.bases as f64
+        }
+    }
+
+    pub fn q20_fraction(&self) -> f64 {
+        self.fraction(self.q20_bases)
+    }
+
+    pub fn q30_fraction(&self) -> f64 {
+        self.fraction(self.q30_bases)
+    }
+
+    pub fn mean_quality(&self) -> f64 {
+        self.fraction(self.qual_sum)
+    }
+}
+
+/// Base quality statistics for each read component (R1, R2, I1, I2) of a set of read pairs.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::metric_utils::QualityStats;
+/// use fastq_set::read_pair::{ReadPair, WhichRead};
+/// use fastq_set::OwnedRecord;
+/// let rec = OwnedRecord {
+///     head: b"read".to_vec(),
+///     seq: b"ACGT".to_vec(),
+///     qual: b"I5+#".to_vec(), // Q40, Q20, Q10, Q2
+///     sep: None,
+/// };
+/// let reads = vec![ReadPair::new([Some(rec), None, None, None])];
+/// let stats = QualityStats::from_reads(&reads);
+/// assert_eq!(stats.get(WhichRead::R1).q30_fraction(), 0.25);
+/// assert_eq!(stats.get(WhichRead::R1).q20_fraction(), 0.5);
+/// assert_eq!(stats.get(WhichRead::R1).mean_quality(), 18.0);
+/// assert_eq!(stats.get(WhichRead::R2).bases, 0);
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct QualityStats {
+    stats: [ReadQualityStats; 4],
+}
+
+impl QualityStats {
+    pub fn new() -> Self {
+        QualityStats::default()
+    }
+
+    /// Compute the statistics over a batch of read pairs
+    pub fn from_reads<'a>(reads: impl IntoIterator<Item = &'a ReadPair>) -> Self {
+        let mut stats = QualityStats::new();
+        for read in reads {
+            stats.observe(read);
+        }
+        stats
+    }
+
+    pub fn observe(&mut self, read: &ReadPair) {
+        for &which in WhichRead::read_types().iter() {
+            if let Some(qual) = read.get(which, ReadPart::Qual) {
+                self.stats[which as usize].observe(qual);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &QualityStats) {
+        for (s, o) in self.stats.iter_mut().zip(other.stats.iter()) {
+            s.merge(o);
+        }
+    }
+
+    /// Statistics of one read component
+    pub fn get(&self, which: WhichRead) -> &ReadQualityStats {
+        &self.stats[which as usize]
+    }
+}
+
+/// Identifier of the JSON schema of `ChunkTiming`, changed whenever a field is
+/// added, removed or changes meaning
+pub const CHUNK_TIMING_SCHEMA: &str = "fastq_set.chunk_timing.v1";
+
+/// Timing and throughput of the processing of one chunk of FASTQ files, as reported
+/// by [`FastqProcessorIter::timing`](../struct.FastqProcessorIter.html#method.timing).
+/// The JSON form carries the `schema` identifier and the version of this crate, so
+/// that reports collected from pipeline logs can be compared across runs and crate
+/// versions.
+///
+/// ```rust
+/// use fastq_set::metric_utils::ChunkTiming;
+/// use fastq_set::read_pair_iter::InputFastqs;
+/// use std::time::Duration;
+/// let fastqs = InputFastqs {
+///     r1: "chunk_R1.fastq.gz".to_string(),
+///     r2: None,
+///     i1: None,
+///     i2: None,
+///     r1_interleaved: true,
+/// };
+/// let timing = ChunkTiming::new(fastqs, 1000, 50_000, 200_000, Duration::from_secs(2), None);
+/// assert_eq!(timing.records_per_sec, 500.0);
+/// assert_eq!(timing.bytes_per_sec, 25_000.0);
+/// let json = serde_json::to_value(&timing).unwrap();
+/// assert_eq!(json["schema"], "fastq_set.chunk_timing.v1");
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChunkTiming {
+    /// `CHUNK_TIMING_SCHEMA`
+    pub schema: String,
+    /// Version of `fastq_set` that processed the chunk
+    pub crate_version: String,
+    pub fastqs: InputFastqs,
+    /// Read pairs returned
+    pub records: u64,
+    /// Bytes read from the input files, before decompression
+    pub compressed_bytes: u64,
+    /// Bytes of FASTQ data after decompression
+    pub decompressed_bytes: u64,
+    pub wall_seconds: f64,
+    /// CPU time of the processing thread, if available on the platform
+    pub cpu_seconds: Option<f64>,
+    pub records_per_sec: f64,
+    /// Bytes read from the input files per second of wall-clock time
+    pub bytes_per_sec: f64,
+}
+
+impl ChunkTiming {
+    pub fn new(
+        fastqs: InputFastqs,
+        records: u64,
+        compressed_bytes: u64,
+        decompressed_bytes: u64,
+        wall_time: Duration,
+        cpu_time: Option<Duration>,
+    ) -> Self {
+        let wall_seconds = wall_time.as_secs_f64();
+        let rate = |n: u64| {
+            if wall_seconds > 0.0 {
+                n as f64 / wall_seconds
+            } else {
+                0.0
+            }
+        };
+        ChunkTiming {
+            schema: CHUNK_TIMING_SCHEMA.to_string(),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            fastqs,
+            records,
+            compressed_bytes,
+            decompressed_bytes,
+            wall_seconds,
+            cpu_seconds: cpu_time.map(|t| t.as_secs_f64()),
+            records_per_sec: rate(records),
+            bytes_per_sec: rate(compressed_bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastq::OwnedRecord;
+
+    fn read_pair(r1: &[u8]) -> ReadPair {
+        let rec = OwnedRecord {
+            head: b"read".to_vec(),
+            seq: r1.to_vec(),
+            qual: vec![b'I'; r1.len()],
+            sep: None,
+        };
+        ReadPair::new([Some(rec), None, None, None])
+    }
+
+    #[test]
+    fn test_cycle_n_rate() {
+        let mut rates = CycleNRate::new(RpRange::new(WhichRead::R1, 2, Some(4)));
+        rates.observe(&read_pair(b"NNACNTGG"));
+        rates.observe(&read_pair(b"NNACGTGG"));
+        // Too short for the range
+        rates.observe(&read_pair(b"NNNN"));
+        assert_eq!(rates.n_rates(), vec![0.0, 0.0, 0.5, 0.0]);
+
+        let mut other = CycleNRate::new(rates.range());
+        other.observe(&read_pair(b"AANANTGG"));
+        other.observe(&read_pair(b"AAACNTGG"));
+        rates.merge(&other);
+        assert_eq!(rates.n_rates(), vec![0.25, 0.0, 0.75, 0.0]);
+        assert_eq!(rates.unreliable_positions(0.1, 2.0), vec![0, 2]);
+        assert_eq!(rates.unreliable_positions(0.5, 2.0), vec![2]);
+    }
+
+    #[test]
+    fn test_cycle_n_rate_open_range() {
+        let mut rates = CycleNRate::new(RpRange::new(WhichRead::R1, 1, None));
+        assert!(rates.unreliable_positions(0.1, 2.0).is_empty());
+        rates.observe(&read_pair(b"ANA"));
+        rates.observe(&read_pair(b"AAAN"));
+        assert_eq!(rates.n_rates(), vec![0.5, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_quality_stats() {
+        let mut stats = ReadQualityStats::default();
+        assert_eq!(stats.mean_quality(), 0.0);
+        let qual: Vec<u8> = (0..100_000).map(|i| b'#' + (i % 40) as u8).collect();
+        stats.observe(&qual);
+        assert_eq!(stats.bases, 100_000);
+        assert_eq!(stats.q20_bases, 100_000 * 22 / 40);
+        assert_eq!(stats.q30_bases, 100_000 * 12 / 40);
+        assert_eq!(
+            stats.qual_sum,
+            (0..100_000u64).map(|i| 2 + i % 40).sum::<u64>()
+        );
+
+        // Bytes below the phred+33 offset count as quality 0
+        let mut low = ReadQualityStats::default();
+        low.observe(b"\x00 !+");
+        assert_eq!(low.bases, 4);
+        assert_eq!(low.qual_sum, 10);
+        assert_eq!(low.mean_quality(), 2.5);
+
+        let rec = |qual: &[u8]| OwnedRecord {
+            head: b"read".to_vec(),
+            seq: vec![b'A'; qual.len()],
+            qual: qual.to_vec(),
+            sep: None,
+        };
+        let rp = ReadPair::new([Some(rec(b"IIII")), Some(rec(b"##")), Some(rec(b"?")), None]);
+        let mut all = QualityStats::from_reads(std::iter::once(&rp));
+        all.merge(&QualityStats::from_reads(std::iter::once(&rp)));
+        assert_eq!(all.get(WhichRead::R1).bases, 8);
+        assert_eq!(all.get(WhichRead::R1).q30_fraction(), 1.0);
+        assert_eq!(all.get(WhichRead::R2).q20_fraction(), 0.0);
+        assert_eq!(all.get(WhichRead::R2).mean_quality(), 2.0);
+        assert_eq!(all.get(WhichRead::I1).q30_fraction(), 1.0);
+        assert_eq!(all.get(WhichRead::I2), &ReadQualityStats::default());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_cycle_n_rate_merge_mismatch() {
+        let mut rates = CycleNRate::new(RpRange::new(WhichRead::R1, 0, Some(4)));
+        rates.merge(&CycleNRate::new(RpRange::new(WhichRead::R2, 0, Some(4))));
+    }
+
+    struct Trimmed(&'static [u8], &'static [u8]);
+
+    impl AlignableReadPair for Trimmed {
+        fn header(&self) -> &[u8] {
+            b"read"
+        }
+        fn alignable_sequence(&self) -> (&[u8], &[u8]) {
+            (self.0, self.1)
+        }
+        fn alignable_quals(&self) -> (&[u8], &[u8]) {
+            (self.0, self.1)
+        }
+    }
+
+    #[test]
+    fn test_min_alignable_length_merge() {
+        let mut filter = MinAlignableLength::new(4, false);
+        assert!(filter.check(&Trimmed(b"ACGT", b"")));
+        let mut other = MinAlignableLength::new(4, false);
+        assert!(!other.check(&Trimmed(b"ACG", b"ACGT")));
+        assert!(other.check(&Trimmed(b"ACGTA", b"")));
+        filter.merge(&other);
+        assert_eq!(filter.num_checked(), 3);
+        assert_eq!(filter.num_too_short(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_min_alignable_length_merge_mismatch() {
+        let mut filter = MinAlignableLength::new(4, false);
+        filter.merge(&MinAlignableLength::new(4, true));
+    }
+
+    struct Chunk;
+
+    impl crate::FastqProcessor for Chunk {
+        type ReadType = ReadPair;
+        fn process_read(&self, read: ReadPair) -> crate::ProcessResult<ReadPair> {
+            crate::ProcessResult::Processed(read)
+        }
+        fn fastq_files(&self) -> InputFastqs {
+            InputFastqs {
+                r1: "tests/read_pair_iter/good-gzipped-RA.fastq.gz".to_string(),
+                r2: None,
+                i1: None,
+                i2: None,
+                r1_interleaved: true,
+            }
+        }
+        fn bc_subsample_rate(&self) -> f64 {
+            1.0
+        }
+        fn read_subsample_rate(&self) -> f64 {
+            1.0
+        }
+        fn illumina_r1_trim_length(&self) -> Option<usize> {
+            None
+        }
+        fn illumina_r2_trim_length(&self) -> Option<usize> {
+            None
+        }
+        fn gem_group(&self) -> u16 {
+            1
+        }
+    }
+
+    #[test]
+    fn test_chunk_timing() {
+        use crate::background_iterator::BackgroundConfig;
+        use crate::FastqProcessor;
+        for mode in 0..3 {
+            let mut iter = match mode {
+                0 => Chunk.iter().unwrap(),
+                1 => Chunk.iter_background(4).unwrap(),
+                _ => Chunk
+                    .iter_background_with_config(BackgroundConfig::new(2, 3), 2)
+                    .unwrap(),
+            };
+            let n = iter.by_ref().count() as u64;
+            let timing = iter.timing();
+            assert_eq!(timing.records, n);
+            assert_eq!(timing.fastqs, Chunk.fastq_files());
+            let file_len = std::fs::metadata(&timing.fastqs.r1).unwrap().len();
+            assert_eq!(timing.compressed_bytes, file_len);
+            assert!(timing.decompressed_bytes > timing.compressed_bytes);
+            assert!(timing.records_per_sec > 0.0);
+            if cfg!(target_os = "linux") {
+                assert!(timing.cpu_seconds.is_some());
+            }
+
+            match iter.queue_stats() {
+                None => assert_eq!(mode, 0),
+                Some(stats) if mode == 2 => {
+                    assert_eq!(stats.batches, (0..n).step_by(3).count() as u64)
+                }
+                Some(stats) => assert_eq!(stats.batches, n),
+            }
+
+            let json = serde_json::to_string(&timing).unwrap();
+            let back: ChunkTiming = serde_json::from_str(&json).unwrap();
+            assert_eq!(back.schema, CHUNK_TIMING_SCHEMA);
+            assert_eq!(back.records, timing.records);
+            assert_eq!(back.compressed_bytes, timing.compressed_bytes);
+        }
+    }
+}
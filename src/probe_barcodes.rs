@@ -0,0 +1,303 @@
+//! Probe barcodes of fixed RNA profiling (Flex) libraries, which identify the
+//! sample a read comes from when several samples are hybridized with distinct
+//! probe sets and pooled. A probe barcode reference lists the probe barcode
+//! sequences with the sample each one is assigned to; each read's probe barcode is
+//! corrected against it with up to one mismatch, so reads can be demultiplexed
+//! by sample. Like the barcode whitelist of
+//! [`UndeterminedRescue`](../undetermined_rescue/struct.UndeterminedRescue.html),
+//! the reference is supplied by the caller.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::probe_barcodes::{ProbeAssignment, ProbeBarcodes};
+//! use fastq_set::read_pair::{ReadPair, RpRange, WhichRead};
+//! use fastq_set::OwnedRecord;
+//! let read = |seq: &[u8]| {
+//!     let rec = OwnedRecord {
+//!         head: b"read".to_vec(),
+//!         seq: seq.to_vec(),
+//!         qual: vec![b'I'; seq.len()],
+//!         sep: None,
+//!     };
+//!     ReadPair::new([None, Some(rec), None, None])
+//! };
+//!
+//! let reference = "# probe barcode, sample\nACTTTAGG,liver\nAACGGGAA,kidney\n";
+//! let probes = ProbeBarcodes::from_reader(
+//!     RpRange::new(WhichRead::R2, 4, Some(8)),
+//!     reference.as_bytes(),
+//! )
+//! .unwrap();
+//! assert_eq!(probes.assign(&read(b"TTTTACTTTAGG")), ProbeAssignment::Exact(0));
+//! assert_eq!(probes.assign(&read(b"TTTTAACGGCAA")), ProbeAssignment::Corrected(1));
+//! assert_eq!(probes.samples(), &["liver".to_string(), "kidney".to_string()]);
+//! ```
+
+use crate::read_pair::{ReadPair, ReadPart, RpRange};
+use failure::{format_err, Error, ResultExt};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+// Marks sequences one mismatch from probe barcodes of more than one sample
+const AMBIGUOUS: usize = usize::MAX;
+
+/// The sample assigned to a read from its probe barcode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeAssignment {
+    /// The probe barcode matches a barcode of the sample at this position in
+    /// `samples()`
+    Exact(usize),
+    /// The probe barcode is one mismatch from a barcode of the sample at this
+    /// position in `samples()`
+    Corrected(usize),
+    /// The probe barcode is one mismatch from barcodes of several samples
+    Ambiguous,
+    /// The probe barcode is more than one mismatch from every barcode
+    NoMatch,
+    /// The read holding the probe barcode is missing or too short
+    MissingRead,
+}
+
+impl ProbeAssignment {
+    /// The position of the assigned sample in `samples()`, if any
+    pub fn sample(self) -> Option<usize> {
+        match self {
+            ProbeAssignment::Exact(sample) | ProbeAssignment::Corrected(sample) => Some(sample),
+            _ => None,
+        }
+    }
+}
+
+/// A probe barcode reference, assigning reads to samples by their probe barcode
+pub struct ProbeBarcodes {
+    range: RpRange,
+    names: Vec<String>,
+    // Probe barcode or one mismatch neighbor -> (mismatches, sample)
+    barcodes: HashMap<Vec<u8>, (usize, usize)>,
+}
+
+impl ProbeBarcodes {
+    /// Assign reads by the probe barcode found at `range` of the read pairs, using
+    /// the `(probe barcode, sample)` pairs of `reference`. Several barcodes can be
+    /// assigned to the same sample. Returns an error if a barcode has other bases
+    /// than `ACGT`, or is assigned to two samples.
+    pub fn new(
+        range: RpRange,
+        reference: impl IntoIterator<Item = (Vec<u8>, String)>,
+    ) -> Result<Self, Error> {
+        let mut probes = ProbeBarcodes {
+            range,
+            names: Vec::new(),
+            barcodes: HashMap::new(),
+        };
+        let mut exact = Vec::new();
+        for (barcode, name) in reference {
+            let barcode = barcode.to_ascii_uppercase();
+            if let Some(&b) = barcode.iter().find(|b| !b"ACGT".contains(b)) {
+                return Err(format_err!(
+                    "Invalid base '{}' in probe barcode {}",
+                    b as char,
+                    String::from_utf8_lossy(&barcode)
+                ));
+            }
+            let sample = match probes.names.iter().position(|n| *n == name) {
+                Some(sample) => sample,
+                None => {
+                    probes.names.push(name);
+                    probes.names.len() - 1
+                }
+            };
+            exact.push((barcode, sample));
+        }
+
+        // Insert the exact matches first, so that conflicting barcodes are found
+        // before their neighbors can shadow them
+        for (barcode, sample) in &exact {
+            if let Some(&(_, other)) = probes.barcodes.get(barcode) {
+                if other != *sample {
+                    return Err(format_err!(
+                        "Probe barcode {} is assigned to samples {} and {}",
+                        String::from_utf8_lossy(barcode),
+                        probes.names[other],
+                        probes.names[*sample]
+                    ));
+                }
+            }
+            probes.barcodes.insert(barcode.clone(), (0, *sample));
+        }
+        for (barcode, sample) in exact {
+            for pos in 0..barcode.len() {
+                for &base in b"ACGTN" {
+                    if base != barcode[pos] {
+                        let mut neighbor = barcode.clone();
+                        neighbor[pos] = base;
+                        probes.insert_neighbor(neighbor, sample);
+                    }
+                }
+            }
+        }
+        Ok(probes)
+    }
+
+    /// Read a probe barcode reference with one probe barcode per line, followed by
+    /// the sample it is assigned to, separated by a comma or a tab. Further
+    /// columns, blank lines and lines starting with `#` are ignored.
+    pub fn from_reader(range: RpRange, reader: impl BufRead) -> Result<Self, Error> {
+        let mut reference = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(&[',', '\t'][..]).map(str::trim);
+            match (fields.next(), fields.next()) {
+                (Some(barcode), Some(sample)) if !barcode.is_empty() && !sample.is_empty() => {
+                    reference.push((barcode.as_bytes().to_vec(), sample.to_string()))
+                }
+                _ => {
+                    return Err(format_err!(
+                        "Expected a probe barcode and a sample on line {} of the probe \
+                         barcode reference, found '{}'",
+                        i + 1,
+                        line
+                    ))
+                }
+            }
+        }
+        ProbeBarcodes::new(range, reference)
+    }
+
+    /// Read a probe barcode reference from the file at `path`, see `from_reader`.
+    pub fn from_file(range: RpRange, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let context = || format!("error reading probe barcode reference {:?}", path);
+        let file = File::open(path).with_context(|_| context())?;
+        Ok(ProbeBarcodes::from_reader(range, BufReader::new(file)).with_context(|_| context())?)
+    }
+
+    fn insert_neighbor(&mut self, seq: Vec<u8>, sample: usize) {
+        match self.barcodes.entry(seq) {
+            Entry::Vacant(e) => {
+                e.insert((1, sample));
+            }
+            Entry::Occupied(mut e) => {
+                let (m, s) = *e.get();
+                if m == 1 && s != sample {
+                    e.insert((1, AMBIGUOUS));
+                }
+            }
+        }
+    }
+
+    /// The range of the read pairs holding the probe barcode
+    pub fn range(&self) -> RpRange {
+        self.range
+    }
+
+    /// Names of the samples, in the order they first appear in the reference
+    pub fn samples(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Assign `read` to a sample by its probe barcode.
+    pub fn assign(&self, read: &ReadPair) -> ProbeAssignment {
+        let barcode = match read.get_range(self.range, ReadPart::Seq) {
+            Some(barcode) => barcode.to_ascii_uppercase(),
+            None => return ProbeAssignment::MissingRead,
+        };
+        match self.barcodes.get(&barcode) {
+            None => ProbeAssignment::NoMatch,
+            Some(&(_, AMBIGUOUS)) => ProbeAssignment::Ambiguous,
+            Some(&(0, sample)) => ProbeAssignment::Exact(sample),
+            Some(&(_, sample)) => ProbeAssignment::Corrected(sample),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair::WhichRead;
+    use crate::OwnedRecord;
+
+    fn read(r2: Option<&[u8]>) -> ReadPair {
+        let rec = |seq: &[u8]| OwnedRecord {
+            head: b"read".to_vec(),
+            seq: seq.to_vec(),
+            qual: vec![b'I'; seq.len()],
+            sep: None,
+        };
+        ReadPair::new([Some(rec(b"ACGT")), r2.map(rec), None, None])
+    }
+
+    #[test]
+    fn test_assign() -> Result<(), Error> {
+        let range = RpRange::new(WhichRead::R2, 2, Some(8));
+        let probes = ProbeBarcodes::new(
+            range,
+            vec![
+                (b"AAAAAAAA".to_vec(), "a".to_string()),
+                (b"ccccCCCC".to_vec(), "b".to_string()),
+                (b"AAAAAACC".to_vec(), "b".to_string()),
+                (b"GGGGGGGG".to_vec(), "a".to_string()),
+            ],
+        )?;
+        assert_eq!(probes.samples(), &["a".to_string(), "b".to_string()]);
+        let assign = |seq: &[u8]| probes.assign(&read(Some(seq)));
+        assert_eq!(assign(b"TTAAAAAAAA"), ProbeAssignment::Exact(0));
+        assert_eq!(assign(b"TTccccccccTT"), ProbeAssignment::Exact(1));
+        assert_eq!(assign(b"TTGGGGNGGG"), ProbeAssignment::Corrected(0));
+        assert_eq!(assign(b"TTCCCCCCCA"), ProbeAssignment::Corrected(1));
+        // One mismatch from barcodes of both samples
+        assert_eq!(assign(b"TTAAAAAACA"), ProbeAssignment::Ambiguous);
+        assert_eq!(assign(b"TTAAAAAAAC"), ProbeAssignment::Ambiguous);
+        assert_eq!(assign(b"TTAAAAAAGG"), ProbeAssignment::NoMatch);
+        assert_eq!(assign(b"TTAAAA"), ProbeAssignment::MissingRead);
+        assert_eq!(probes.assign(&read(None)), ProbeAssignment::MissingRead);
+
+        assert_eq!(ProbeAssignment::Corrected(1).sample(), Some(1));
+        assert_eq!(ProbeAssignment::Ambiguous.sample(), None);
+
+        // A barcode can't belong to two samples, and must have only ACGT bases
+        let conflict = vec![
+            (b"AAAAAAAA".to_vec(), "a".to_string()),
+            (b"AAAAAAAA".to_vec(), "b".to_string()),
+        ];
+        assert!(ProbeBarcodes::new(range, conflict).is_err());
+        let duplicate = vec![
+            (b"AAAAAAAA".to_vec(), "a".to_string()),
+            (b"AAAAAAAA".to_vec(), "a".to_string()),
+        ];
+        assert!(ProbeBarcodes::new(range, duplicate).is_ok());
+        let invalid = vec![(b"AAAANAAA".to_vec(), "a".to_string())];
+        assert!(ProbeBarcodes::new(range, invalid).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_file() -> Result<(), Error> {
+        let range = RpRange::new(WhichRead::R2, 0, Some(8));
+        let probes = ProbeBarcodes::from_file(range, "tests/probe_barcodes/probe-barcodes.txt")?;
+        assert_eq!(
+            probes.samples(),
+            &["liver".to_string(), "kidney".to_string()]
+        );
+        assert_eq!(
+            probes.assign(&read(Some(b"AACGGGAA"))),
+            ProbeAssignment::Exact(1)
+        );
+        assert_eq!(
+            probes.assign(&read(Some(b"AGTCACTG"))),
+            ProbeAssignment::Exact(0)
+        );
+
+        assert!(ProbeBarcodes::from_file(range, "tests/probe_barcodes/missing.txt").is_err());
+        assert!(ProbeBarcodes::from_reader(range, &b"ACTTTAGG\n"[..]).is_err());
+        assert!(ProbeBarcodes::from_reader(range, &b"ACTTTAGG,\n"[..]).is_err());
+        Ok(())
+    }
+}
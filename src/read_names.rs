@@ -0,0 +1,378 @@
+//! Rewrite read names into compact deterministic IDs, to strip the instrument,
+//! run and flowcell metadata they contain before sharing data. All the reads of a
+//! read pair get the same new name, and the comment following the name (e.g.
+//! `1:N:0:ACGTACGT`) is kept.
+//!
+//! Names are either the chunk number and the index of the read pair in the chunk
+//! (`3:1234`), or a keyed hash of the original name. Hashed names only depend on the
+//! original name and the salt, so the mates of a read pair get the same name even
+//! when their files are processed separately, while the names cannot be reversed
+//! without the salt. A mapping file from the new names back to the original names
+//! can optionally be written.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::read_names::NameRewriter;
+//! use fastq_set::read_pair::{ReadPart, WhichRead};
+//! use fastq_set::read_pair_iter::ReadPairIter;
+//! let reads = ReadPairIter::new(
+//!     Some("tests/read_pair_iter/good-RA.fastq"),
+//!     None,
+//!     None,
+//!     None,
+//!     true,
+//! )
+//! .unwrap();
+//! let renamed: Vec<_> = NameRewriter::chunk_index(3)
+//!     .rewrite_all(reads)
+//!     .collect::<Result<_, _>>()
+//!     .unwrap();
+//! assert_eq!(renamed[1].get(WhichRead::R1, ReadPart::Header).unwrap(), b"3:1 1:N:0:0");
+//! assert_eq!(renamed[1].get(WhichRead::R2, ReadPart::Header).unwrap(), b"3:1 4:N:0:0");
+//! ```
+
+use crate::read_pair::{ReadPair, ReadPart, WhichRead};
+use crate::utils;
+use failure::{Error, ResultExt};
+use std::convert::TryInto;
+use std::io::Write;
+use std::path::Path;
+
+/// How the new read names are made
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NameScheme {
+    /// `<chunk>:<index>`, where `index` counts the read pairs from 0
+    ChunkIndex { chunk: u32 },
+    /// 32 hex digits of a hash of the original name, keyed with `salt`
+    SaltedHash { salt: Vec<u8> },
+}
+
+/// Rewrites the names of read pairs, optionally recording the original names.
+pub struct NameRewriter {
+    scheme: NameScheme,
+    // SipHash keys derived from the salt
+    keys: [(u64, u64); 2],
+    next_index: u64,
+    mapping: Option<Box<dyn Write>>,
+}
+
+impl NameRewriter {
+    pub fn new(scheme: NameScheme) -> Self {
+        let keys = match &scheme {
+            NameScheme::SaltedHash { salt } => [
+                (siphash((0, 0), salt), siphash((0, 1), salt)),
+                (siphash((0, 2), salt), siphash((0, 3), salt)),
+            ],
+            NameScheme::ChunkIndex { .. } => [(0, 0); 2],
+        };
+        NameRewriter {
+            scheme,
+            keys,
+            next_index: 0,
+            mapping: None,
+        }
+    }
+
+    /// Name the read pairs `<chunk>:<index>`
+    pub fn chunk_index(chunk: u32) -> Self {
+        Self::new(NameScheme::ChunkIndex { chunk })
+    }
+
+    /// Name the read pairs with a hash of their original name, keyed with `salt`
+    pub fn salted_hash(salt: &[u8]) -> Self {
+        Self::new(NameScheme::SaltedHash {
+            salt: salt.to_vec(),
+        })
+    }
+
+    /// Write the new and original name of each read pair to `writer`, separated by a tab
+    pub fn mapping_writer(mut self, writer: Box<dyn Write>) -> Self {
+        self.mapping = Some(writer);
+        self
+    }
+
+    /// Write the new and original name of each read pair to a TSV file at `path`,
+    /// gzip compressed if the file name ends in `.gz`
+    pub fn mapping_file(self, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let writer = utils::write_with_gz(path)?;
+        Ok(self.mapping_writer(Box::new(writer)))
+    }
+
+    /// The new name of the read pair originally named `name`, advancing the index
+    /// of the `ChunkIndex` scheme
+    pub fn new_name(&mut self, name: &[u8]) -> Vec<u8> {
+        let index = self.next_index;
+        self.next_index += 1;
+        match &self.scheme {
+            NameScheme::ChunkIndex { chunk } => format!("{}:{}", chunk, index).into_bytes(),
+            NameScheme::SaltedHash { .. } => {
+                let hash = format!(
+                    "{:016x}{:016x}",
+                    siphash(self.keys[0], name),
+                    siphash(self.keys[1], name)
+                );
+                hash.into_bytes()
+            }
+        }
+    }
+
+    /// Rename all the reads of `rp`. The original name is the name of the first read,
+    /// up to the first space or tab and without a `/1` or `/2` suffix, which is kept
+    /// on each read.
+    pub fn rewrite(&mut self, rp: &mut ReadPair) -> Result<(), Error> {
+        let first = WhichRead::read_types()
+            .iter()
+            .find_map(|&which| rp.get(which, ReadPart::Header));
+        let original = match first {
+            Some(header) => split_name(header).0.to_vec(),
+            None => return Ok(()),
+        };
+        let name = self.new_name(&original);
+
+        let headers: Vec<Option<Vec<u8>>> = WhichRead::read_types()
+            .iter()
+            .map(|&which| {
+                let header = rp.get(which, ReadPart::Header)?;
+                Some([&name[..], split_name(header).1].concat())
+            })
+            .collect();
+        rp.set_headers([
+            headers[0].as_deref(),
+            headers[1].as_deref(),
+            headers[2].as_deref(),
+            headers[3].as_deref(),
+        ])?;
+
+        if let Some(mapping) = self.mapping.as_mut() {
+            mapping
+                .write_all(&[&name[..], b"\t", &original, b"\n"].concat())
+                .context("error writing read name mapping")?;
+        }
+        Ok(())
+    }
+
+    /// Rename the read pairs of `reads` as they are iterated
+    pub fn rewrite_all<I, E>(self, reads: I) -> RewriteNames<I::IntoIter>
+    where
+        I: IntoIterator<Item = Result<ReadPair, E>>,
+        Error: From<E>,
+    {
+        RewriteNames {
+            reads: reads.into_iter(),
+            rewriter: self,
+        }
+    }
+
+    /// Flush the mapping file
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if let Some(mapping) = self.mapping.as_mut() {
+            mapping
+                .flush()
+                .context("error flushing read name mapping")?;
+        }
+        Ok(())
+    }
+}
+
+/// Split a header into the read name and the rest of the header, which starts with
+/// a `/1` to `/4` read number suffix or with the space or tab before the comment
+pub(crate) fn split_name(header: &[u8]) -> (&[u8], &[u8]) {
+    let end = header
+        .iter()
+        .position(|&c| c == b' ' || c == b'\t')
+        .unwrap_or(header.len());
+    let end = match header[..end] {
+        [.., b'/', b'1'..=b'4'] => end - 2,
+        _ => end,
+    };
+    header.split_at(end)
+}
+
+/// The read name of a header, without the comment and the `/1` to `/4` read
+/// number suffix, which is the same for all the reads of a read pair
+pub(crate) fn read_name(header: &[u8]) -> &[u8] {
+    split_name(header).0
+}
+
+/// Iterator over renamed read pairs, created by
+/// [`NameRewriter::rewrite_all`](struct.NameRewriter.html#method.rewrite_all).
+pub struct RewriteNames<I> {
+    reads: I,
+    rewriter: NameRewriter,
+}
+
+impl<I> RewriteNames<I> {
+    pub fn rewriter(&mut self) -> &mut NameRewriter {
+        &mut self.rewriter
+    }
+}
+
+impl<I, E> Iterator for RewriteNames<I>
+where
+    I: Iterator<Item = Result<ReadPair, E>>,
+    Error: From<E>,
+{
+    type Item = Result<ReadPair, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reads.next() {
+            Some(Ok(mut rp)) => Some(self.rewriter.rewrite(&mut rp).map(|_| rp)),
+            Some(Err(e)) => Some(Err(e.into())),
+            // Flush the mapping, so that it is complete once the reads are exhausted
+            None => self.rewriter.flush().err().map(Err),
+        }
+    }
+}
+
+/// SipHash-2-4 of `data` with the key `(k0, k1)`
+pub(crate) fn siphash((k0, k1): (u64, u64), data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let chunks = data.chunks_exact(8);
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    for chunk in chunks {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+    let m = u64::from_le_bytes(last);
+    v[3] ^= m;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair_iter::ReadPairIter;
+    use std::io::Read;
+
+    fn reads(r1: &str, r2: Option<&str>) -> ReadPairIter {
+        ReadPairIter::new(Some(r1), r2, None, None, r2.is_none()).unwrap()
+    }
+
+    #[test]
+    fn test_siphash() {
+        // Reference vectors of SipHash-2-4, with the key 00 01 .. 0f
+        let key = (0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908);
+        assert_eq!(siphash(key, b""), 0x726f_db47_dd0e_0e31);
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash(key, &data), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn test_salted_hash() -> Result<(), Error> {
+        let renamed: Vec<ReadPair> = NameRewriter::salted_hash(b"secret")
+            .rewrite_all(reads("tests/read_pair_iter/good-RA.fastq", None))
+            .collect::<Result<_, _>>()?;
+        let originals: Vec<ReadPair> =
+            reads("tests/read_pair_iter/good-RA.fastq", None).collect::<Result<_, _>>()?;
+
+        let mut names = std::collections::HashSet::new();
+        for (rp, orig) in renamed.iter().zip(&originals) {
+            let r1 = rp.get(WhichRead::R1, ReadPart::Header).unwrap();
+            let (name, comment) = split_name(r1);
+            assert_eq!(name.len(), 32);
+            assert!(!r1.starts_with(b"A00228"));
+            assert_eq!(comment, b" 1:N:0:0");
+            let r2 = rp.get(WhichRead::R2, ReadPart::Header).unwrap();
+            assert_eq!(split_name(r2), (name, &b" 4:N:0:0"[..]));
+            assert_eq!(
+                rp.get(WhichRead::R2, ReadPart::Seq),
+                orig.get(WhichRead::R2, ReadPart::Seq)
+            );
+            names.insert(name.to_vec());
+        }
+        assert_eq!(names.len(), renamed.len());
+
+        // Mates in separate files with /1 and /2 suffixes get the same names, which
+        // depend on the salt
+        let mut rewriter = NameRewriter::salted_hash(b"secret");
+        let mut other_salt = NameRewriter::salted_hash(b"other");
+        let mut rp = reads(
+            "tests/read_pair_iter/slash1.fastq",
+            Some("tests/read_pair_iter/slash2.fastq"),
+        )
+        .next()
+        .unwrap()?;
+        let mut other = rp.clone();
+        rewriter.rewrite(&mut rp)?;
+        other_salt.rewrite(&mut other)?;
+        let r1 = rp.get(WhichRead::R1, ReadPart::Header).unwrap();
+        let r2 = rp.get(WhichRead::R2, ReadPart::Header).unwrap();
+        assert_eq!(split_name(r1).0, split_name(r2).0);
+        assert!(r1[32..].starts_with(b"/1"));
+        assert!(r2[32..].starts_with(b"/2"));
+        assert_ne!(r1, other.get(WhichRead::R1, ReadPart::Header).unwrap());
+        let name = rewriter.new_name(b"A00228:197:HC7WVDMXX:1:1110:20338:1016");
+        assert_eq!(split_name(r1).0, &name[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_name() {
+        assert_eq!(
+            split_name(b"read/1 1:N:0:0"),
+            (&b"read"[..], &b"/1 1:N:0:0"[..])
+        );
+        assert_eq!(
+            split_name(b"read/4\tcomment"),
+            (&b"read"[..], &b"/4\tcomment"[..])
+        );
+        assert_eq!(read_name(b"read/3"), b"read");
+        assert_eq!(read_name(b"read/5"), b"read/5");
+        assert_eq!(read_name(b"read 1/2"), b"read");
+        assert_eq!(read_name(b"/1"), b"");
+        assert_eq!(read_name(b""), b"");
+    }
+
+    #[test]
+    fn test_mapping_file() -> Result<(), Error> {
+        let path = "tests/read_names_mapping.tsv.gz";
+        let renamed: Vec<ReadPair> = NameRewriter::chunk_index(7)
+            .mapping_file(path)?
+            .rewrite_all(reads("tests/read_pair_iter/good-RA.fastq", None))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(renamed.len(), 8);
+        assert_eq!(
+            renamed[7].get(WhichRead::R2, ReadPart::Header).unwrap(),
+            b"7:7 4:N:0:0"
+        );
+
+        let mut mapping = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(path)?).read_to_string(&mut mapping)?;
+        std::fs::remove_file(path)?;
+        let lines: Vec<_> = mapping.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "7:0\tA00228:197:HC7WVDMXX:1:1110:20338:1016");
+        Ok(())
+    }
+}
@@ -3,9 +3,11 @@
 //! Container for the FASTQ data from a single sequencing 'cluster',
 //! including the primary 'R1' and 'R2' and index 'I1' and 'I2' reads.
 
+use crate::metric_utils::ILLUMINA_QUAL_OFFSET;
+use crate::utils::mask_bases_below;
 use crate::WhichEnd;
 use bytes::{Bytes, BytesMut};
-use failure::{format_err, Error};
+use failure::{format_err, Error, Fail};
 use fastq::{OwnedRecord, Record};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -32,6 +34,17 @@ impl ReadOffset {
             None
         }
     }
+
+    // A quality string that was not retained is marked by a `qual` offset
+    // before the end of the sequence.
+    fn has_qual(&self) -> bool {
+        self.exists && self.qual >= self.seq
+    }
+
+    // End of the data of the read
+    fn end(&self) -> usize {
+        self.qual.max(self.seq) as usize
+    }
 }
 
 /// The possible reads from a Illumina cluster. R1 and R2 are the two
@@ -99,13 +112,25 @@ impl std::str::FromStr for WhichRead {
 }
 
 /// Components of a FASTQ record.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ReadPart {
+    /// The full header line, without the leading `@`
     Header,
+    /// The comment of the header: the text after the first space or tab, e.g.
+    /// `1:N:0:GGCGAGTA` for Illumina reads. Empty if the header has no comment.
+    HeaderComment,
     Seq,
     Qual,
 }
 
+/// The comment of a FASTQ `header`, see `ReadPart::HeaderComment`
+pub(crate) fn header_comment(header: &[u8]) -> &[u8] {
+    match header.iter().position(|&c| c == b' ' || c == b'\t') {
+        Some(pos) => &header[pos + 1..],
+        None => &[],
+    }
+}
+
 /// Compact representation of selected read and an interval in that read.
 /// Supports offsets and lengths up to 32K.
 /// Internally it is stored as a `u32` with the following bit layout
@@ -179,6 +204,35 @@ impl fmt::Debug for RpRange {
     }
 }
 
+/// Error returned by `RpRange::new_checked` for a range that doesn't fit in a read
+#[derive(Fail, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpRangeError {
+    #[fail(
+        display = "Range on {} can't be created because the read is not present.",
+        read
+    )]
+    MissingRead { read: WhichRead },
+    #[fail(
+        display = "Range {}-{} is out of bounds of {}, which is {} bp long.",
+        start, end, read, read_len
+    )]
+    OutOfBounds {
+        read: WhichRead,
+        start: usize,
+        end: usize,
+        read_len: usize,
+    },
+    #[fail(
+        display = "Range on {} with offset {} and length {:?} can't be represented by a RpRange.",
+        read, offset, len
+    )]
+    TooLarge {
+        read: WhichRead,
+        offset: usize,
+        len: Option<usize>,
+    },
+}
+
 #[allow(clippy::len_without_is_empty)]
 impl RpRange {
     /// Create a `RpRange` that represent the interval [`offset`, `offset + len`) in
@@ -225,6 +279,91 @@ impl RpRange {
         RpRange { val }
     }
 
+    /// Create a `RpRange` like `new`, checking that the interval is contained in
+    /// the `read` of `read_pair`, so that slicing the read with the range can't
+    /// fail. An open-ended range (`len` of `None`) only requires `offset` to be
+    /// within the read.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{ReadPair, RpRange, RpRangeError, WhichRead};
+    /// use fastq_set::OwnedRecord;
+    /// let rec = OwnedRecord {
+    ///     head: b"read".to_vec(),
+    ///     seq: b"ACGTACGT".to_vec(),
+    ///     qual: b"IIIIIIII".to_vec(),
+    ///     sep: None,
+    /// };
+    /// let rp = ReadPair::new([Some(rec), None, None, None]);
+    /// let range = RpRange::new_checked(WhichRead::R1, 2, Some(6), &rp).unwrap();
+    /// assert_eq!(range, RpRange::new(WhichRead::R1, 2, Some(6)));
+    /// assert_eq!(
+    ///     RpRange::new_checked(WhichRead::R1, 4, Some(6), &rp),
+    ///     Err(RpRangeError::OutOfBounds { read: WhichRead::R1, start: 4, end: 10, read_len: 8 })
+    /// );
+    /// assert_eq!(
+    ///     RpRange::new_checked(WhichRead::R2, 0, None, &rp),
+    ///     Err(RpRangeError::MissingRead { read: WhichRead::R2 })
+    /// );
+    /// ```
+    pub fn new_checked(
+        read: WhichRead,
+        offset: usize,
+        len: Option<usize>,
+        read_pair: &ReadPair,
+    ) -> Result<RpRange, RpRangeError> {
+        let range = RpRange::try_new(read, offset, len)?;
+        range.check_against(read_pair)?;
+        Ok(range)
+    }
+
+    /// Create a `RpRange` like `new`, returning an error instead of panicking if
+    /// `offset` or `len` can't be represented.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{RpRange, RpRangeError, WhichRead};
+    /// assert_eq!(
+    ///     RpRange::try_new(WhichRead::R2, 5, None),
+    ///     Ok(RpRange::new(WhichRead::R2, 5, None))
+    /// );
+    /// assert_eq!(
+    ///     RpRange::try_new(WhichRead::R2, 1 << 15, None),
+    ///     Err(RpRangeError::TooLarge { read: WhichRead::R2, offset: 1 << 15, len: None })
+    /// );
+    /// ```
+    pub fn try_new(
+        read: WhichRead,
+        offset: usize,
+        len: Option<usize>,
+    ) -> Result<RpRange, RpRangeError> {
+        if offset >= (1 << 15) || matches!(len, Some(l) if l >= 0x7FFF) {
+            Err(RpRangeError::TooLarge { read, offset, len })
+        } else {
+            Ok(RpRange::new(read, offset, len))
+        }
+    }
+
+    /// Check that the range is contained in its read in `read_pair`. Like
+    /// `ReadPair::check_range`, with a typed error.
+    pub fn check_against(self, read_pair: &ReadPair) -> Result<(), RpRangeError> {
+        let read = self.read();
+        let read_len = read_pair
+            .len(read)
+            .ok_or(RpRangeError::MissingRead { read })?;
+        let end = self.offset() + self.len().unwrap_or(0);
+        if end > read_len {
+            Err(RpRangeError::OutOfBounds {
+                read,
+                start: self.offset(),
+                end,
+                read_len,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
     #[inline]
     /// Retrieive the read from the internal representation
     pub fn read(self) -> WhichRead {
@@ -454,6 +593,162 @@ impl RpRange {
             }
         }
     }
+
+    // End of the range, or `None` if it extends to the end of the read
+    fn end(self) -> Option<usize> {
+        self.len().map(|l| self.offset() + l)
+    }
+
+    /// Whether `other` lies entirely within this `RpRange`. An open ended
+    /// range only lies within another open ended range.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{RpRange, WhichRead};
+    /// let read = RpRange::new(WhichRead::R1, 0, None);
+    /// let barcode = RpRange::new(WhichRead::R1, 0, Some(16));
+    /// assert!(read.contains(barcode));
+    /// assert!(!barcode.contains(read));
+    /// assert!(!read.contains(RpRange::new(WhichRead::R2, 0, Some(16))));
+    /// ```
+    pub fn contains(self, other: RpRange) -> bool {
+        self.read() == other.read()
+            && other.offset() >= self.offset()
+            && match (self.end(), other.end()) {
+                (None, _) => true,
+                (Some(_), None) => false,
+                (Some(self_end), Some(other_end)) => other_end <= self_end,
+            }
+    }
+
+    /// Move the `RpRange` by `amount` bases, towards the 3' end if `amount` is positive
+    /// and towards the 5' end if it is negative. The length is unchanged.
+    ///
+    /// # Panics
+    /// * If the new offset is negative or >= `2^15`
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{RpRange, WhichRead};
+    /// let mut umi = RpRange::new(WhichRead::R1, 16, Some(12));
+    /// umi.shift(-6);
+    /// assert_eq!(umi, RpRange::new(WhichRead::R1, 10, Some(12)));
+    /// ```
+    pub fn shift(&mut self, amount: isize) {
+        let new_offset = self.offset() as isize + amount;
+        assert!(
+            new_offset >= 0,
+            "Cannot shift {:?} by {} to a negative offset",
+            self,
+            amount
+        );
+        self.set_offset(new_offset as usize);
+    }
+
+    /// Split the `RpRange` into the ranges before and after position `mid`,
+    /// relative to the start of the range. The second range is open ended
+    /// if this range is.
+    ///
+    /// # Panics
+    /// * If the length is set and `mid` is larger than the length
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{RpRange, WhichRead};
+    /// let (barcode, rest) = RpRange::new(WhichRead::R1, 0, None).split_at(16);
+    /// assert_eq!(barcode, RpRange::new(WhichRead::R1, 0, Some(16)));
+    /// assert_eq!(rest, RpRange::new(WhichRead::R1, 16, None));
+    /// ```
+    pub fn split_at(self, mid: usize) -> (RpRange, RpRange) {
+        let mut first = self;
+        let mut second = self;
+        if let Some(len) = self.len() {
+            assert!(
+                mid <= len,
+                "Split position {} is beyond the end of {:?}",
+                mid,
+                self
+            );
+            second.set_len(len - mid);
+        }
+        first.set_len(mid);
+        second.set_offset(self.offset() + mid);
+        (first, second)
+    }
+
+    /// Remove the positions of `other` from this `RpRange`, returning the
+    /// remaining ranges before and after `other`, or `None` for an empty piece.
+    /// If `other` is on a different read or does not overlap this range,
+    /// the whole range is returned as the first piece.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{RpRange, WhichRead};
+    /// let read = RpRange::new(WhichRead::R1, 0, None);
+    /// let barcode = RpRange::new(WhichRead::R1, 0, Some(16));
+    /// let umi = RpRange::new(WhichRead::R1, 16, Some(12));
+    /// let (before, insert) = read.subtract(barcode);
+    /// assert_eq!(before, None);
+    /// let (before, insert) = insert.unwrap().subtract(umi);
+    /// assert_eq!(before, None);
+    /// assert_eq!(insert, Some(RpRange::new(WhichRead::R1, 28, None)));
+    ///
+    /// let (before, after) = RpRange::new(WhichRead::R1, 0, Some(100))
+    ///     .subtract(RpRange::new(WhichRead::R1, 40, Some(10)));
+    /// assert_eq!(before, Some(RpRange::new(WhichRead::R1, 0, Some(40))));
+    /// assert_eq!(after, Some(RpRange::new(WhichRead::R1, 50, Some(50))));
+    /// ```
+    pub fn subtract(self, other: RpRange) -> (Option<RpRange>, Option<RpRange>) {
+        let before_other = |end: Option<usize>| matches!(end, Some(e) if e <= other.offset());
+        let after_other = |start: usize| matches!(other.end(), Some(e) if e <= start);
+        if self.read() != other.read() || before_other(self.end()) || after_other(self.offset()) {
+            return (Some(self).filter(|r| r.len() != Some(0)), None);
+        }
+
+        let before = if other.offset() > self.offset() {
+            Some(RpRange::new(
+                self.read(),
+                self.offset(),
+                Some(other.offset() - self.offset()),
+            ))
+        } else {
+            None
+        };
+
+        let after = match (other.end(), self.end()) {
+            (None, _) => None,
+            (Some(other_end), None) => Some(RpRange::new(self.read(), other_end, None)),
+            (Some(other_end), Some(self_end)) if self_end > other_end => Some(RpRange::new(
+                self.read(),
+                other_end,
+                Some(self_end - other_end),
+            )),
+            (Some(_), Some(_)) => None,
+        };
+        (before, after)
+    }
+}
+
+/// A single difference between two `ReadPair`s, as reported by `ReadPair::diff()`.
+/// Byte ranges are relative to the start of the `ReadPart`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// The read is present in only one of the two read pairs. `in_self` is true
+    /// if it is present in the read pair `diff()` was called on.
+    Presence { read: WhichRead, in_self: bool },
+    /// The part has a different length in the two read pairs.
+    Length {
+        read: WhichRead,
+        part: ReadPart,
+        self_len: usize,
+        other_len: usize,
+    },
+    /// A maximal run of differing bytes within the length common to both parts.
+    Bytes {
+        read: WhichRead,
+        part: ReadPart,
+        range: ops::Range<usize>,
+    },
 }
 
 /// Storage patterns for a read pair. There are two
@@ -484,6 +779,13 @@ impl Default for ReadPairStorage {
     }
 }
 
+/// Maximum number of bytes of FASTQ data (headers, sequences and qualities of
+/// all the reads) that can be stored in a single `ReadPair`.
+pub const MAX_READ_PAIR_BYTES: usize = u16::MAX as usize;
+
+/// Highest quality score encodable in phred+33, as `~`
+pub(crate) const MAX_PHRED_QUAL: u8 = 93;
+
 /// Helper struct used during construction of a ReadPair. The data for the ReadPair is
 /// accumulated in the buffer bytes::BytesMut. When all the data has been added, call
 /// `freeze()` to convert this into an immutable `ReadPair` object. Multiple `ReadPair` objects
@@ -504,17 +806,34 @@ impl<'a> MutReadPair<'a> {
         }
     }
 
+    /// Store the records `rr` in `buffer`.
+    ///
+    /// # Panics
+    /// * If the records exceed `MAX_READ_PAIR_BYTES`. Use `try_new` to get an
+    ///   error instead.
     pub fn new<R: Record>(buffer: &'a mut BytesMut, rr: &[Option<R>; 4]) -> MutReadPair<'a> {
+        match MutReadPair::try_new(buffer, rr) {
+            Ok(rp) => rp,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Store the records `rr` in `buffer`. Returns an error if the records exceed
+    /// `MAX_READ_PAIR_BYTES`.
+    pub fn try_new<R: Record>(
+        buffer: &'a mut BytesMut,
+        rr: &[Option<R>; 4],
+    ) -> Result<MutReadPair<'a>, Error> {
         let mut rp = MutReadPair::empty(buffer);
 
         for (_rec, which) in rr.iter().zip(WhichRead::read_types().iter()) {
             if let Some(ref rec) = *_rec {
-                rp.push_read(rec, *which)
+                rp.push_read(rec, *which)?;
             }
             // default ReadOffsets is exists = false
         }
 
-        rp
+        Ok(rp)
     }
 
     pub(super) fn storage(mut self, storage: ReadPairStorage) -> Self {
@@ -524,10 +843,22 @@ impl<'a> MutReadPair<'a> {
 
     // FIXME: Should we check that the length of seq and qual agree?
     // If we add that check, modify `prop_test_readpair_get()` test
-    pub(super) fn push_read<R: Record>(&mut self, rec: &R, which: WhichRead) {
+    pub(super) fn push_read<R: Record>(&mut self, rec: &R, which: WhichRead) -> Result<(), Error> {
         assert!(!self.offsets[which as usize].exists);
         //let buf = self.data;
 
+        // Offsets are stored as u16, so the data of all the reads must fit in 64KB
+        let total_len = self.data.len() + rec.head().len() + rec.seq().len() + rec.qual().len();
+        if total_len > MAX_READ_PAIR_BYTES {
+            return Err(format_err!(
+                "FASTQ data of a read pair exceeds the maximum supported size of {} bytes \
+                 (header, sequence and quality of all reads). Got {} bytes after adding {}.",
+                MAX_READ_PAIR_BYTES,
+                total_len,
+                which
+            ));
+        }
+
         let start = self.data.len() as u16;
         self.data.extend_from_slice(rec.head());
         let head = self.data.len() as u16;
@@ -543,6 +874,56 @@ impl<'a> MutReadPair<'a> {
             qual,
         };
         self.offsets[which as usize] = read_offset;
+        Ok(())
+    }
+
+    /// Convert the quality string of read `which` from ASCII offset `offset` (e.g.
+    /// 64 for legacy phred+64 data) to the phred+33 encoding. Returns an error if
+    /// `offset` is below 33, or if a quality value is below `offset` or above the
+    /// highest quality of 93 that phred+33 can encode, which indicates the offset is wrong.
+    pub(super) fn normalize_qual(&mut self, which: WhichRead, offset: u8) -> Result<(), Error> {
+        let w = self.offsets[which as usize];
+        if !w.exists || offset == ILLUMINA_QUAL_OFFSET {
+            return Ok(());
+        }
+        if offset < ILLUMINA_QUAL_OFFSET {
+            return Err(format_err!(
+                "Quality offset {} of {} is below {}.",
+                offset,
+                which,
+                ILLUMINA_QUAL_OFFSET
+            ));
+        }
+        let max = offset.saturating_add(MAX_PHRED_QUAL);
+        let qual = &mut self.data[w.seq as usize..w.qual as usize];
+        if let Some(&q) = qual.iter().find(|&&q| q < offset || q > max) {
+            let bound = if q < offset {
+                "below"
+            } else {
+                "above the range of"
+            };
+            return Err(format_err!(
+                "Quality value '{}' of {} is {} the quality offset of {}.",
+                q as char,
+                which,
+                bound,
+                offset
+            ));
+        }
+        for q in qual.iter_mut() {
+            *q = *q - offset + ILLUMINA_QUAL_OFFSET;
+        }
+        Ok(())
+    }
+
+    /// Discard the quality string of read `which`, which must be the last read pushed
+    pub(super) fn drop_qual(&mut self, which: WhichRead) {
+        let w = &mut self.offsets[which as usize];
+        if w.exists {
+            assert_eq!(w.qual as usize, self.data.len());
+            self.data.truncate(w.seq as usize);
+            w.qual = 0;
+        }
     }
 
     pub fn freeze(self) -> ReadPair {
@@ -564,8 +945,12 @@ impl<'a> MutReadPair<'a> {
             let w = self.offsets[which as usize];
             match part {
                 ReadPart::Header => Some(&self.data[w.start as usize..w.head as usize]),
+                ReadPart::HeaderComment => Some(header_comment(
+                    &self.data[w.start as usize..w.head as usize],
+                )),
                 ReadPart::Seq => Some(&self.data[w.head as usize..w.seq as usize]),
-                ReadPart::Qual => Some(&self.data[w.seq as usize..w.qual as usize]),
+                ReadPart::Qual if w.has_qual() => Some(&self.data[w.seq as usize..w.qual as usize]),
+                ReadPart::Qual => None,
             }
         } else {
             None
@@ -573,10 +958,153 @@ impl<'a> MutReadPair<'a> {
     }
 }
 
+/// Trimming applied to a single read before or after it was loaded into a
+/// `ReadPair`. The ranges are the bases removed, in the coordinates of the
+/// original, untrimmed read.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct ReadTrim {
+    /// Bases trimmed as adapter, e.g. the `trim_range` of an adapter `TrimResult`
+    pub adapter: Option<RpRange>,
+    /// Bases trimmed for low quality
+    pub quality: Option<RpRange>,
+    /// Bases hard clipped from the read, e.g. by a maximum read length
+    pub hard_clip: Option<RpRange>,
+    /// Bases trimmed as a poly-G tail, which two-color sequencers call where there
+    /// is no signal
+    #[serde(default)]
+    pub poly_g: Option<RpRange>,
+}
+
+impl ReadTrim {
+    /// True if no trimming was recorded
+    pub fn is_empty(&self) -> bool {
+        self.adapter.is_none()
+            && self.quality.is_none()
+            && self.hard_clip.is_none()
+            && self.poly_g.is_none()
+    }
+
+    /// Move the recorded ranges to read `which`
+    fn relabel(&mut self, which: WhichRead) {
+        for range in [
+            &mut self.adapter,
+            &mut self.quality,
+            &mut self.hard_clip,
+            &mut self.poly_g,
+        ]
+        .iter_mut()
+        .filter_map(|r| r.as_mut())
+        {
+            *range = RpRange::new(which, range.offset(), range.len());
+        }
+    }
+}
+
+/// Record of the trimming of each read of a `ReadPair`, so that the original reads
+/// can be reconstructed when emitting BAM tags or regenerating FASTQ files.
+/// See [`TrimmedReadPair`](struct.TrimmedReadPair.html).
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct TrimProvenance {
+    reads: [ReadTrim; 4],
+}
+
+impl TrimProvenance {
+    /// The trimming of read `which`
+    pub fn read(&self, which: WhichRead) -> &ReadTrim {
+        &self.reads[which as usize]
+    }
+
+    /// Mutable access to the trimming of read `which`
+    pub fn read_mut(&mut self, which: WhichRead) -> &mut ReadTrim {
+        &mut self.reads[which as usize]
+    }
+
+    /// Move the trimming of read `order[i]` to read `i`, as `ReadPair::relabel` does
+    /// for the reads
+    fn permute(&mut self, order: [WhichRead; 4]) {
+        let reads = self.reads;
+        for (i, &from) in order.iter().enumerate() {
+            let mut trim = reads[from as usize];
+            trim.relabel(WhichRead::from(i));
+            self.reads[i] = trim;
+        }
+    }
+}
+
+/// A `ReadPair` along with the record of the trimming of its reads. The record is
+/// kept next to the read pair rather than in it, so that the serialized form, the
+/// size and the ordering of `ReadPair` don't depend on it.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::read_pair::{ReadPair, RpRange, TrimmedReadPair, WhichRead};
+/// use fastq_set::OwnedRecord;
+/// let rec = OwnedRecord {
+///     head: b"read".to_vec(),
+///     seq: b"ACGTACGT".to_vec(),
+///     qual: b"IIIIIIII".to_vec(),
+///     sep: None,
+/// };
+/// let mut rp = TrimmedReadPair::new(ReadPair::new([Some(rec), None, None, None]));
+/// rp.trim_mut(WhichRead::R1).adapter = Some(RpRange::new(WhichRead::R1, 6, Some(2)));
+/// assert_eq!(rp.trims.read(WhichRead::R1).adapter.unwrap().offset(), 6);
+/// assert!(rp.trims.read(WhichRead::R2).is_empty());
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TrimmedReadPair {
+    pub read_pair: ReadPair,
+    pub trims: TrimProvenance,
+}
+
+impl TrimmedReadPair {
+    /// A read pair without any trimming recorded
+    pub fn new(read_pair: ReadPair) -> Self {
+        TrimmedReadPair {
+            read_pair,
+            trims: TrimProvenance::default(),
+        }
+    }
+
+    /// Mutable access to the trimming record of read `which`
+    pub fn trim_mut(&mut self, which: WhichRead) -> &mut ReadTrim {
+        self.trims.read_mut(which)
+    }
+
+    /// Exchange reads `a` and `b` along with their trimming records
+    pub fn swap(&mut self, a: WhichRead, b: WhichRead) {
+        let mut order = WhichRead::read_types();
+        order.swap(a as usize, b as usize);
+        self.read_pair.permute(order);
+        self.trims.permute(order);
+    }
+
+    /// Reorder the reads along with their trimming records, as `ReadPair::relabel` does
+    pub fn relabel(&mut self, order: [WhichRead; 4]) -> Result<(), Error> {
+        self.read_pair.relabel(order)?;
+        self.trims.permute(order);
+        Ok(())
+    }
+
+    /// Length of read `which` before it was hard clipped, i.e. the end of its
+    /// `hard_clip` range, or the length of the read if it was not clipped.
+    /// `None` if the read is not present, or if the clipped length is unknown.
+    pub fn untrimmed_len(&self, which: WhichRead) -> Option<usize> {
+        let len = self.read_pair.len(which)?;
+        match self.trims.read(which).hard_clip {
+            Some(clip) => clip.len().map(|clip_len| clip.offset() + clip_len),
+            None => Some(len),
+        }
+    }
+}
+
 /// Container for all read data from a single Illumina cluster. Faithfully represents
 /// the FASTQ data from all available reads, if available.
 /// Generally should be created by a `ReadPairIter`.
-#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct ReadPair {
     offsets: [ReadOffset; 4],
 
@@ -585,6 +1113,164 @@ pub struct ReadPair {
     data: Bytes,
 }
 
+/// Limits applied to the reads as a `ReadPair` is constructed. Bases beyond the
+/// maximum length of a read are dropped, e.g. to bound memory when only the
+/// first 90bp of R2 are used downstream.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::read_pair::{ReadPair, ReadPairConfig, WhichRead};
+/// use fastq_set::OwnedRecord;
+/// let rec = || OwnedRecord {
+///     head: b"read".to_vec(),
+///     seq: b"ACGTACGT".to_vec(),
+///     qual: b"IIIIIIII".to_vec(),
+///     sep: None,
+/// };
+/// let config = ReadPairConfig::default().max_len(WhichRead::R2, 5);
+/// let rp = ReadPair::with_config([Some(rec()), Some(rec()), None, None], &config);
+/// assert_eq!(rp.len(WhichRead::R1), Some(8));
+/// assert_eq!(rp.len(WhichRead::R2), Some(5));
+/// ```
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadPairConfig {
+    /// Maximum number of bases kept of each read, in the order R1, R2, I1, I2.
+    /// `None` keeps the full read.
+    pub max_len: [Option<usize>; 4],
+}
+
+impl ReadPairConfig {
+    /// Keep at most `len` bases of read `which`
+    pub fn max_len(mut self, which: WhichRead, len: usize) -> Self {
+        self.max_len[which as usize] = Some(len);
+        self
+    }
+
+    /// Number of bases kept of a read `which` that is `len` bases long
+    pub fn kept_len(&self, which: WhichRead, len: usize) -> usize {
+        match self.max_len[which as usize] {
+            Some(max_len) => len.min(max_len),
+            None => len,
+        }
+    }
+}
+
+/// A type implementing the fastq Record trait for handling trimming
+pub(crate) struct TrimRecord<'a, R: Record> {
+    inner: &'a R,
+    trim: usize,
+}
+
+impl<'a, R: Record> TrimRecord<'a, R> {
+    pub(crate) fn new(inner: &'a R, trim: usize) -> Self {
+        let trim = trim.min(inner.seq().len());
+        TrimRecord { inner, trim }
+    }
+}
+
+impl<'a, R: Record> Record for TrimRecord<'a, R> {
+    fn seq(&self) -> &[u8] {
+        &self.inner.seq()[..self.trim]
+    }
+    fn qual(&self) -> &[u8] {
+        &self.inner.qual()[..self.trim]
+    }
+    fn head(&self) -> &[u8] {
+        self.inner.head()
+    }
+    fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<usize> {
+        let mut written = 0;
+        // TODO(lhepler): the fundamental impl in fastq here is busted, write may not write all
+        // bytes...
+        written += writer.write(b"@")?;
+        written += writer.write(self.head())?;
+        written += writer.write(b"\n")?;
+        written += writer.write(self.seq())?;
+        written += writer.write(b"\n+\n")?;
+        written += writer.write(self.qual())?;
+        written += writer.write(b"\n")?;
+        Ok(written)
+    }
+}
+
+/// Maximum number of bytes of a sequence or quality string shown by the
+/// `Debug` and `Display` implementations of `ReadPair`.
+const MAX_DISPLAY_LEN: usize = 32;
+
+/// Formats a byte string as text, truncated to `MAX_DISPLAY_LEN` bytes with
+/// the number of omitted bytes appended.
+struct Truncated<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Truncated<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.len() > MAX_DISPLAY_LEN {
+            let shown = String::from_utf8_lossy(&self.0[..MAX_DISPLAY_LEN]);
+            write!(f, "{}...(+{})", shown, self.0.len() - MAX_DISPLAY_LEN)
+        } else {
+            write!(f, "{}", String::from_utf8_lossy(self.0))
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Truncated<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+struct ReadDebug<'a> {
+    rp: &'a ReadPair,
+    which: WhichRead,
+}
+
+impl<'a> fmt::Debug for ReadDebug<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let get = |part| Truncated(self.rp.get(self.which, part).unwrap());
+        let mut s = f.debug_struct("Read");
+        s.field("header", &String::from_utf8_lossy(get(ReadPart::Header).0))
+            .field("seq", &get(ReadPart::Seq));
+        // The quality string is missing if it was dropped
+        match self.rp.get(self.which, ReadPart::Qual) {
+            Some(qual) => s.field("qual", &Truncated(qual)),
+            None => s.field("qual", &None::<()>),
+        };
+        s.finish()
+    }
+}
+
+/// Shows the full header and truncated sequence and quality strings of
+/// each read present in the `ReadPair`.
+impl fmt::Debug for ReadPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("ReadPair");
+        for &which in WhichRead::read_types().iter() {
+            if self.offsets[which as usize].exists {
+                s.field(&format!("{:?}", which), &ReadDebug { rp: self, which });
+            }
+        }
+        s.finish()
+    }
+}
+
+/// Single line summary: the header of the first available read, followed by
+/// the truncated sequence of each read, e.g. `@name read1:ACGT index1:TTAG`
+impl fmt::Display for ReadPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for &which in WhichRead::read_types().iter() {
+            if let Some(seq) = self.get(which, ReadPart::Seq) {
+                if first {
+                    let header = self.get(which, ReadPart::Header).unwrap();
+                    write!(f, "@{}", String::from_utf8_lossy(header))?;
+                    first = false;
+                }
+                write!(f, " {}:{}", which, Truncated(seq))?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl ReadPair {
     #[inline]
     /// Get a ReadPart `part` from a read `which` in this cluster
@@ -593,8 +1279,12 @@ impl ReadPair {
             let w = self.offsets[which as usize];
             match part {
                 ReadPart::Header => Some(&self.data[w.start as usize..w.head as usize]),
+                ReadPart::HeaderComment => Some(header_comment(
+                    &self.data[w.start as usize..w.head as usize],
+                )),
                 ReadPart::Seq => Some(&self.data[w.head as usize..w.seq as usize]),
-                ReadPart::Qual => Some(&self.data[w.seq as usize..w.qual as usize]),
+                ReadPart::Qual if w.has_qual() => Some(&self.data[w.seq as usize..w.qual as usize]),
+                ReadPart::Qual => None,
             }
         } else {
             None
@@ -608,6 +1298,22 @@ impl ReadPair {
         read.and_then(|r| rp_range.slice(r))
     }
 
+    /// Get a view of the sequence and quality in `rp_range`, without copying.
+    /// Returns `None` if the read is not present or the range is not contained in it.
+    /// The quality of the view is empty if the quality string of the read was not retained.
+    pub fn view(&self, rp_range: RpRange) -> Option<RpView<'_>> {
+        let seq = self.get_range(rp_range, ReadPart::Seq)?;
+        let qual = match self.get(rp_range.read(), ReadPart::Qual) {
+            Some(qual) => rp_range.slice(qual)?,
+            None => &[],
+        };
+        Some(RpView {
+            range: RpRange::new(rp_range.read(), rp_range.offset(), Some(seq.len())),
+            seq,
+            qual,
+        })
+    }
+
     pub fn check_range(&self, range: &RpRange, region_name: &str) -> Result<(), Error> {
         let req_len = range.offset() + range.len().unwrap_or(0);
 
@@ -638,6 +1344,8 @@ impl ReadPair {
         }
     }
 
+    /// Copy the reads into `OwnedRecord`s. Reads whose quality string was not
+    /// retained get an empty quality string.
     pub fn to_owned_record(&self) -> HashMap<WhichRead, OwnedRecord> {
         let mut result = HashMap::new();
         for &which in WhichRead::read_types().iter() {
@@ -647,7 +1355,7 @@ impl ReadPair {
                     head: self.data[w.start as usize..w.head as usize].to_vec(),
                     seq: self.data[w.head as usize..w.seq as usize].to_vec(),
                     sep: None,
-                    qual: self.data[w.seq as usize..w.qual as usize].to_vec(),
+                    qual: self.get(which, ReadPart::Qual).unwrap_or(&[]).to_vec(),
                 };
                 result.insert(which, rec);
             }
@@ -655,14 +1363,316 @@ impl ReadPair {
         result
     }
 
+    /// Iterate over the bases of read `which` along with their phred quality
+    /// scores (with the ASCII offset removed). Returns `None` if the read is not present.
+    pub fn iter_base_qual(&self, which: WhichRead) -> Option<impl Iterator<Item = (u8, u8)> + '_> {
+        let seq = self.get(which, ReadPart::Seq)?;
+        let qual = self.get(which, ReadPart::Qual)?;
+        Some(
+            seq.iter()
+                .zip(qual.iter())
+                .map(|(&b, &q)| (b, q.saturating_sub(ILLUMINA_QUAL_OFFSET))),
+        )
+    }
+
+    /// Sequence of read `which` with the bases with a phred quality below `min_qual`
+    /// replaced by `N`. Returns `None` if the read is not present.
+    pub fn masked_seq(&self, which: WhichRead, min_qual: u8) -> Option<Vec<u8>> {
+        let mut seq = self.get(which, ReadPart::Seq)?.to_vec();
+        mask_bases_below(&mut seq, self.get(which, ReadPart::Qual)?, min_qual);
+        Some(seq)
+    }
+
+    /// Edit the sequence and quality string of read `which` in place by calling
+    /// `f(seq, qual)`, returning its result. The read lengths cannot change, so
+    /// the offsets of the reads stay valid. Because the data of a `ReadPair` may
+    /// share a buffer with other `ReadPair`s, each call copies the data once, so
+    /// several edits to the same read are best made in a single call.
+    /// `qual` is empty if the quality string of the read was not retained.
+    /// Returns an error if the read is not present.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{ReadPair, ReadPart, WhichRead};
+    /// use fastq_set::utils::mask_bases_below;
+    /// use fastq_set::OwnedRecord;
+    /// let rec = OwnedRecord {
+    ///     head: b"read".to_vec(),
+    ///     seq: b"ACGT".to_vec(),
+    ///     qual: b"I#II".to_vec(),
+    ///     sep: None,
+    /// };
+    /// let mut rp = ReadPair::new([Some(rec), None, None, None]);
+    /// let masked = rp
+    ///     .edit_read(WhichRead::R1, |seq, qual| mask_bases_below(seq, qual, 20))
+    ///     .unwrap();
+    /// assert_eq!(masked, 1);
+    /// assert_eq!(rp.get(WhichRead::R1, ReadPart::Seq).unwrap(), b"ANGT");
+    /// ```
+    pub fn edit_read<F, T>(&mut self, which: WhichRead, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut [u8], &mut [u8]) -> T,
+    {
+        let w = self.offsets[which as usize];
+        if !w.exists {
+            return Err(format_err!("Read {} is not present.", which));
+        }
+        let mut data = BytesMut::from(&self.data[..]);
+        let (seq, qual) = data[w.head as usize..w.end()].split_at_mut((w.seq - w.head) as usize);
+        let result = f(seq, qual);
+        self.data = data.freeze();
+        Ok(result)
+    }
+
+    /// Overwrite the sequence of read `which` with `seq`, which must have the
+    /// same length as the read.
+    pub fn set_seq(&mut self, which: WhichRead, seq: &[u8]) -> Result<(), Error> {
+        self.check_same_len(which, seq.len())?;
+        self.edit_read(which, |s, _| s.copy_from_slice(seq))
+    }
+
+    /// Overwrite the quality string of read `which` with `qual`, which must have
+    /// the same length as the read. Returns an error if the quality string of
+    /// the read was not retained.
+    pub fn set_qual(&mut self, which: WhichRead, qual: &[u8]) -> Result<(), Error> {
+        self.check_same_len(which, qual.len())?;
+        self.check_has_qual(which)?;
+        self.edit_read(which, |_, q| q.copy_from_slice(qual))
+    }
+
+    /// Replace the header of read `which` with `header`, which excludes the leading `@`.
+    /// Unlike the sequence, the header can change length.
+    pub fn set_header(&mut self, which: WhichRead, header: &[u8]) -> Result<(), Error> {
+        let mut headers = [None; 4];
+        headers[which as usize] = Some(header);
+        self.set_headers(headers)
+    }
+
+    /// Replace the headers of the reads given in `headers`, in the order R1, R2, I1,
+    /// I2, copying the data of the read pair once rather than once per header as
+    /// repeated calls to `set_header` would. Reads set to `None` keep their header.
+    pub fn set_headers(&mut self, headers: [Option<&[u8]>; 4]) -> Result<(), Error> {
+        let mut total_len = self.data.len();
+        for (i, (w, header)) in self.offsets.iter().zip(headers.iter()).enumerate() {
+            if let Some(header) = header {
+                if !w.exists {
+                    return Err(format_err!("Read {} is not present.", WhichRead::from(i)));
+                }
+                total_len = total_len + header.len() - (w.head - w.start) as usize;
+            }
+        }
+        if total_len > MAX_READ_PAIR_BYTES {
+            return Err(format_err!(
+                "FASTQ data of a read pair exceeds the maximum supported size of {} bytes \
+                 with the new headers. Got {} bytes.",
+                MAX_READ_PAIR_BYTES,
+                total_len
+            ));
+        }
+
+        let mut data = BytesMut::with_capacity(total_len);
+        for (offset, header) in self.offsets.iter_mut().zip(headers.iter()) {
+            let w = *offset;
+            if !w.exists {
+                continue;
+            }
+            let start = data.len() as u16;
+            match header {
+                Some(header) => data.extend_from_slice(header),
+                None => data.extend_from_slice(&self.data[w.start as usize..w.head as usize]),
+            }
+            let head = data.len() as u16;
+            data.extend_from_slice(&self.data[w.head as usize..w.end()]);
+            let seq = head + (w.seq - w.head);
+            *offset = ReadOffset {
+                exists: true,
+                start,
+                head,
+                seq,
+                qual: if w.has_qual() {
+                    seq + (w.qual - w.seq)
+                } else {
+                    0
+                },
+            };
+        }
+        self.data = data.freeze();
+        Ok(())
+    }
+
+    fn check_has_qual(&self, which: WhichRead) -> Result<(), Error> {
+        let w = self.offsets[which as usize];
+        if w.exists && !w.has_qual() {
+            Err(format_err!(
+                "The quality string of Read {} was not retained.",
+                which
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_same_len(&self, which: WhichRead, len: usize) -> Result<(), Error> {
+        match self.len(which) {
+            Some(l) if l == len => Ok(()),
+            Some(l) => Err(format_err!(
+                "Cannot replace the {} bp Read {} with {} bases.",
+                l,
+                which,
+                len
+            )),
+            None => Err(format_err!("Read {} is not present.", which)),
+        }
+    }
+
+    /// Replace the bases of the sequence in `range` with `base`, e.g. `b'N'` to
+    /// hard-mask adapter bases. An open ended range is masked up to the end of the read.
+    /// Returns an error if the range is not contained in the read.
+    pub fn mask_range(&mut self, range: RpRange, base: u8) -> Result<(), Error> {
+        self.check_range(&range, "Masked range")?;
+        self.edit_read(range.read(), |seq, _| {
+            let start = range.offset();
+            let end = range.len().map_or(seq.len(), |l| start + l);
+            for b in &mut seq[start..end] {
+                *b = base;
+            }
+        })
+    }
+
+    /// Exchange reads `a` and `b`, e.g. to fix a run where R1 and R2 were swapped at
+    /// demultiplexing time.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{ReadPair, ReadPart, WhichRead};
+    /// use fastq_set::OwnedRecord;
+    /// let rec = |seq: &[u8]| OwnedRecord {
+    ///     head: b"read".to_vec(),
+    ///     seq: seq.to_vec(),
+    ///     qual: vec![b'I'; seq.len()],
+    ///     sep: None,
+    /// };
+    /// let mut rp = ReadPair::new([Some(rec(b"ACGT")), Some(rec(b"GGCCTT")), None, None]);
+    /// rp.swap(WhichRead::R1, WhichRead::R2);
+    /// assert_eq!(rp.get(WhichRead::R1, ReadPart::Seq).unwrap(), b"GGCCTT");
+    /// assert_eq!(rp.get(WhichRead::R2, ReadPart::Seq).unwrap(), b"ACGT");
+    /// ```
+    pub fn swap(&mut self, a: WhichRead, b: WhichRead) {
+        let mut order = WhichRead::read_types();
+        order.swap(a as usize, b as usize);
+        self.permute(order);
+    }
+
+    /// Reorder the reads so that read `order[i]` becomes read `i`, e.g. an `order` of
+    /// `[I1, R2, R1, I2]` moves a barcode delivered as I1 into R1, and R1 into I1.
+    ///
+    /// # Errors
+    /// * If `order` isn't a permutation of the four reads
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{ReadPair, ReadPart, WhichRead};
+    /// use fastq_set::OwnedRecord;
+    /// let rec = |seq: &[u8]| OwnedRecord {
+    ///     head: b"read".to_vec(),
+    ///     seq: seq.to_vec(),
+    ///     qual: vec![b'I'; seq.len()],
+    ///     sep: None,
+    /// };
+    /// let mut rp = ReadPair::new([Some(rec(b"ACGT")), Some(rec(b"GGCCTT")), Some(rec(b"AAAA")), None]);
+    /// rp.relabel([WhichRead::I1, WhichRead::R2, WhichRead::R1, WhichRead::I2]).unwrap();
+    /// assert_eq!(rp.get(WhichRead::R1, ReadPart::Seq).unwrap(), b"AAAA");
+    /// assert_eq!(rp.get(WhichRead::I1, ReadPart::Seq).unwrap(), b"ACGT");
+    /// assert!(rp.relabel([WhichRead::R1; 4]).is_err());
+    /// ```
+    pub fn relabel(&mut self, order: [WhichRead; 4]) -> Result<(), Error> {
+        for &which in &WhichRead::read_types() {
+            if !order.contains(&which) {
+                return Err(format_err!(
+                    "Read order {:?} is not a permutation of the reads: {} is missing",
+                    order,
+                    which
+                ));
+            }
+        }
+        self.permute(order);
+        Ok(())
+    }
+
+    fn permute(&mut self, order: [WhichRead; 4]) {
+        let offsets = self.offsets;
+        for (i, &from) in order.iter().enumerate() {
+            self.offsets[i] = offsets[from as usize];
+        }
+    }
+
+    /// Detect an artifactual poly-G tail at the 3' end of read `which`. On two-color
+    /// instruments (NovaSeq, NextSeq) the absence of signal is called as G, so reads
+    /// running past the end of a short insert end in a run of Gs, on both R1 and R2.
+    /// Like fastp, the tail may contain one mismatch per 8 bases. Returns the range
+    /// of the tail, or `None` if the read is missing or has no tail of at least
+    /// `min_len` bases.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{ReadPair, RpRange, WhichRead};
+    /// use fastq_set::OwnedRecord;
+    /// let rec = |seq: &[u8]| OwnedRecord {
+    ///     head: b"read".to_vec(),
+    ///     seq: seq.to_vec(),
+    ///     qual: vec![b'I'; seq.len()],
+    ///     sep: None,
+    /// };
+    /// let rp = ReadPair::new([Some(rec(b"ACGTACGTGGGGGGGGGTGG")), Some(rec(b"ACGTACGTGG")), None, None]);
+    /// let tail = rp.detect_polyg_suffix(WhichRead::R1, 10).unwrap();
+    /// assert_eq!(tail, RpRange::new(WhichRead::R1, 8, Some(12)));
+    /// assert_eq!(rp.detect_polyg_suffix(WhichRead::R2, 10), None);
+    /// ```
+    pub fn detect_polyg_suffix(&self, which: WhichRead, min_len: usize) -> Option<RpRange> {
+        let seq = self.get(which, ReadPart::Seq)?;
+        let mut tail_len = 0;
+        let mut mismatches = 0;
+        // Gs since the last mismatch. A lone G past a mismatch doesn't extend the tail.
+        let mut run = 0;
+        for (i, &b) in seq.iter().rev().enumerate() {
+            if b.eq_ignore_ascii_case(&b'G') {
+                run += 1;
+                if mismatches == 0 || run >= 2 {
+                    tail_len = i + 1;
+                }
+            } else {
+                run = 0;
+                mismatches += 1;
+                if mismatches > 1.max((i + 1) / 8) {
+                    break;
+                }
+            }
+        }
+        // Even with a min_len of 0, a read not ending in G has no tail
+        if tail_len == 0 || tail_len < min_len {
+            return None;
+        }
+        Some(RpRange::new(which, seq.len() - tail_len, Some(tail_len)))
+    }
+
+    /// Number of bytes held by the read pair outside of the `ReadPair` struct itself,
+    /// for memory accounting with a [`MemoryTracker`](../memory_tracker/struct.MemoryTracker.html).
+    /// With `ReadPairStorage::SharedBuffer` the FASTQ data lives in a buffer shared
+    /// with other read pairs, which is only freed once all of them are dropped.
+    pub fn heap_bytes(&self) -> usize {
+        self.data.len()
+    }
+
     /// Read length of the selected read.
     pub fn len(&self, which: WhichRead) -> Option<usize> {
         self.offsets[which as usize].seq_len()
     }
 
     /// Write read selected by `which` in FASTQ format to `writer`.
-    /// This method will silently do nothing if the selected read doesn't exist.
+    /// This method will silently do nothing if the selected read doesn't exist,
+    /// and returns an error if the quality string of the read was not retained.
     pub fn write_fastq<W: Write>(&self, which: WhichRead, writer: &mut W) -> Result<(), Error> {
+        self.check_has_qual(which)?;
         if self.offsets[which as usize].exists {
             let head = self.get(which, ReadPart::Header).unwrap();
             writer.write_all(b"@")?;
@@ -681,18 +1691,364 @@ impl ReadPair {
         Ok(())
     }
 
+    /// Render the reads in `reads` as FASTQ records, in the given order. Reads that
+    /// don't exist in the read pair are skipped. If `ranges` contains a range on a
+    /// read, only the sequence and quality in that range are written. Each of `tags`
+    /// is appended to every header as a SAM-style `XX:Z:<value>` field.
+    /// Returns an error if a range is not contained in its read, or if the quality
+    /// string of a read was not retained.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{ReadPair, RpRange, WhichRead};
+    /// use fastq_set::OwnedRecord;
+    /// let rec = |seq: &[u8]| OwnedRecord {
+    ///     head: b"read".to_vec(),
+    ///     seq: seq.to_vec(),
+    ///     qual: vec![b'I'; seq.len()],
+    ///     sep: None,
+    /// };
+    /// let rp = ReadPair::new([Some(rec(b"ACGTACGT")), Some(rec(b"TTTT")), None, None]);
+    /// let fastq = rp
+    ///     .to_fastq_records(
+    ///         &[WhichRead::R1, WhichRead::R2, WhichRead::I1],
+    ///         &[RpRange::new(WhichRead::R1, 2, Some(4))],
+    ///         &[(*b"BC", b"AACC")],
+    ///     )
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     fastq,
+    ///     b"@read BC:Z:AACC\nGTAC\n+\nIIII\n@read BC:Z:AACC\nTTTT\n+\nIIII\n".to_vec()
+    /// );
+    /// ```
+    pub fn to_fastq_records(
+        &self,
+        reads: &[WhichRead],
+        ranges: &[RpRange],
+        tags: &[([u8; 2], &[u8])],
+    ) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        for &which in reads {
+            let len = match self.len(which) {
+                Some(len) => len,
+                None => continue,
+            };
+            self.check_has_qual(which)?;
+            let range = ranges
+                .iter()
+                .find(|r| r.read() == which)
+                .copied()
+                .unwrap_or_else(|| RpRange::new(which, 0, Some(len)));
+            let view = self.view(range).ok_or_else(|| {
+                format_err!(
+                    "Range {:?} is not contained in {}, which is {} bp long.",
+                    range,
+                    which,
+                    len
+                )
+            })?;
+
+            out.push(b'@');
+            out.extend_from_slice(self.get(which, ReadPart::Header).unwrap());
+            for (tag, value) in tags {
+                out.push(b' ');
+                out.extend_from_slice(tag);
+                out.extend_from_slice(b":Z:");
+                out.extend_from_slice(value);
+            }
+            out.push(b'\n');
+            out.extend_from_slice(view.seq());
+            out.extend_from_slice(b"\n+\n");
+            out.extend_from_slice(view.qual());
+            out.push(b'\n');
+        }
+        Ok(out)
+    }
+
+    /// Describe how `other` differs from `self`, read by read and part by part.
+    /// Returns an empty vector if the two read pairs hold identical FASTQ data.
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::read_pair::{Difference, ReadPair, ReadPart, WhichRead};
+    /// use fastq_set::OwnedRecord;
+    /// let rec = |seq: &[u8]| OwnedRecord {
+    ///     head: b"read".to_vec(),
+    ///     seq: seq.to_vec(),
+    ///     qual: vec![b'I'; seq.len()],
+    ///     sep: None,
+    /// };
+    /// let a = ReadPair::new([Some(rec(b"ACGTACGT")), None, None, None]);
+    /// let b = ReadPair::new([Some(rec(b"ACTTACGT")), None, None, None]);
+    /// assert!(a.diff(&a).is_empty());
+    /// assert_eq!(
+    ///     a.diff(&b),
+    ///     vec![Difference::Bytes { read: WhichRead::R1, part: ReadPart::Seq, range: 2..3 }]
+    /// );
+    /// ```
+    pub fn diff(&self, other: &ReadPair) -> Vec<Difference> {
+        let mut diffs = Vec::new();
+        for &read in WhichRead::read_types().iter() {
+            match (
+                self.offsets[read as usize].exists,
+                other.offsets[read as usize].exists,
+            ) {
+                (true, true) => {
+                    for &part in [ReadPart::Header, ReadPart::Seq, ReadPart::Qual].iter() {
+                        // a quality string that was not retained compares as empty
+                        let a = self.get(read, part).unwrap_or(&[]);
+                        let b = other.get(read, part).unwrap_or(&[]);
+                        Self::diff_bytes(read, part, a, b, &mut diffs);
+                    }
+                }
+                (false, false) => {}
+                (in_self, _) => diffs.push(Difference::Presence { read, in_self }),
+            }
+        }
+        diffs
+    }
+
+    fn diff_bytes(
+        read: WhichRead,
+        part: ReadPart,
+        a: &[u8],
+        b: &[u8],
+        diffs: &mut Vec<Difference>,
+    ) {
+        let mut run_start = None;
+        for (i, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+            match (x == y, run_start) {
+                (false, None) => run_start = Some(i),
+                (true, Some(start)) => {
+                    diffs.push(Difference::Bytes {
+                        read,
+                        part,
+                        range: start..i,
+                    });
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = run_start {
+            diffs.push(Difference::Bytes {
+                read,
+                part,
+                range: start..a.len().min(b.len()),
+            });
+        }
+        if a.len() != b.len() {
+            diffs.push(Difference::Length {
+                read,
+                part,
+                self_len: a.len(),
+                other_len: b.len(),
+            });
+        }
+    }
+
+    /// The sequence of the alignable regions of the first and second read. A
+    /// region that is missing or not contained in its read is empty.
+    pub fn alignable_sequence(&self, ranges: AlignableRanges) -> (&[u8], &[u8]) {
+        (
+            self.alignable_part(ranges.r1, ReadPart::Seq),
+            self.alignable_part(ranges.r2, ReadPart::Seq),
+        )
+    }
+
+    /// The quality scores of the alignable regions of the first and second read.
+    pub fn alignable_quals(&self, ranges: AlignableRanges) -> (&[u8], &[u8]) {
+        (
+            self.alignable_part(ranges.r1, ReadPart::Qual),
+            self.alignable_part(ranges.r2, ReadPart::Qual),
+        )
+    }
+
+    fn alignable_part(&self, range: Option<RpRange>, part: ReadPart) -> &[u8] {
+        range.and_then(|r| self.get_range(r, part)).unwrap_or(&[])
+    }
+
     /// WARNING: DO NOT USE THIS FUNCTION IF YOU ARE STREAMING FASTQ DATA
     /// This function is intended for testing and illustration purposes
     /// only. Use `ReadPairIter` if you are iterating over a fastq.
+    ///
+    /// # Panics
+    /// * If the records exceed `MAX_READ_PAIR_BYTES`. Use `try_new` to get an
+    ///   error instead.
     pub fn new<R: Record>(rr: [Option<R>; 4]) -> ReadPair {
         let mut buffer = BytesMut::with_capacity(4096);
         MutReadPair::new(&mut buffer, &rr).freeze()
     }
+
+    /// Like `new`, returning an error if the records exceed `MAX_READ_PAIR_BYTES`.
+    pub fn try_new<R: Record>(rr: [Option<R>; 4]) -> Result<ReadPair, Error> {
+        let mut buffer = BytesMut::with_capacity(4096);
+        Ok(MutReadPair::try_new(&mut buffer, &rr)?.freeze())
+    }
+
+    /// Like `new`, truncating the reads to the maximum lengths in `config`.
+    /// Use `ReadPairIter::config` to apply a config to reads from FASTQ files.
+    pub fn with_config<R: Record>(rr: [Option<R>; 4], config: &ReadPairConfig) -> ReadPair {
+        let mut buffer = BytesMut::with_capacity(4096);
+        let mut rp = MutReadPair::empty(&mut buffer);
+        for (rec, &which) in rr.iter().zip(WhichRead::read_types().iter()) {
+            if let Some(rec) = rec {
+                let tr = TrimRecord::new(rec, config.kept_len(which, rec.seq().len()));
+                if let Err(e) = rp.push_read(&tr, which) {
+                    panic!("{}", e);
+                }
+            }
+        }
+        rp.freeze()
+    }
+}
+
+/// The alignable regions of a read pair, i.e. what is left of the two primary
+/// reads after barcodes, UMIs and adapters are trimmed. Processed read types can
+/// store these ranges alongside the `ReadPair` rather than slicing the reads
+/// themselves, and implement `AlignableReadPair` with
+/// [`ReadPair::alignable_sequence`](struct.ReadPair.html#method.alignable_sequence)
+/// and [`ReadPair::alignable_quals`](struct.ReadPair.html#method.alignable_quals).
+///
+/// # Example
+/// ```rust
+/// use fastq_set::read_pair::{AlignableRanges, ReadPair, RpRange, WhichRead};
+/// use fastq_set::OwnedRecord;
+/// let rec = |seq: &[u8]| OwnedRecord {
+///     head: b"read".to_vec(),
+///     seq: seq.to_vec(),
+///     qual: vec![b'I'; seq.len()],
+///     sep: None,
+/// };
+/// let rp = ReadPair::new([Some(rec(b"ACGTACGTTTGG")), Some(rec(b"CCAA")), None, None]);
+/// // 8bp barcode at the start of R1, last base of R2 trimmed
+/// let ranges = AlignableRanges {
+///     r1: Some(RpRange::new(WhichRead::R1, 8, None)),
+///     r2: Some(RpRange::new(WhichRead::R2, 0, Some(3))),
+/// };
+/// assert_eq!(rp.alignable_sequence(ranges), (&b"TTGG"[..], &b"CCA"[..]));
+/// ```
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AlignableRanges {
+    pub r1: Option<RpRange>,
+    pub r2: Option<RpRange>,
+}
+
+impl AlignableRanges {
+    /// The whole of R1, and the whole of R2 if `paired` is true
+    pub fn full(paired: bool) -> Self {
+        AlignableRanges {
+            r1: Some(RpRange::new(WhichRead::R1, 0, None)),
+            r2: if paired {
+                Some(RpRange::new(WhichRead::R2, 0, None))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// A region of a `ReadPair`, such as a barcode, UMI or insert, created by
+/// [`ReadPair::view`](struct.ReadPair.html#method.view). Dereferences to the
+/// sequence of the region; the quality string is available from `qual()`.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::read_pair::{ReadPair, RpRange, WhichRead};
+/// use fastq_set::OwnedRecord;
+/// let rec = OwnedRecord {
+///     head: b"read".to_vec(),
+///     seq: b"ACGTACGTTTGGCCAA".to_vec(),
+///     qual: b"IIIIIIII########".to_vec(),
+///     sep: None,
+/// };
+/// let rp = ReadPair::new([Some(rec), None, None, None]);
+/// let insert = rp.view(RpRange::new(WhichRead::R1, 8, None)).unwrap();
+/// assert_eq!(&*insert, b"TTGGCCAA");
+/// assert_eq!(insert.qual(), b"########");
+/// let tail = insert.sub_view(4, None).unwrap();
+/// assert_eq!(&*tail, b"CCAA");
+/// assert_eq!(tail.range(), RpRange::new(WhichRead::R1, 12, Some(4)));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RpView<'a> {
+    range: RpRange,
+    seq: &'a [u8],
+    qual: &'a [u8],
+}
+
+impl<'a> RpView<'a> {
+    /// Range of the view in the `ReadPair`. The length is always set.
+    pub fn range(&self) -> RpRange {
+        self.range
+    }
+
+    pub fn read(&self) -> WhichRead {
+        self.range.read()
+    }
+
+    pub fn seq(&self) -> &'a [u8] {
+        self.seq
+    }
+
+    /// Quality string of the view, which is empty if the quality string
+    /// of the read was not retained.
+    pub fn qual(&self) -> &'a [u8] {
+        self.qual
+    }
+
+    /// View of the `len` bases starting at `offset` within this view, or of all
+    /// the bases after `offset` if `len` is `None`. Returns `None` if the region
+    /// extends beyond the view.
+    pub fn sub_view(&self, offset: usize, len: Option<usize>) -> Option<RpView<'a>> {
+        let sub = RpRange::new(self.read(), offset, len);
+        let seq = sub.slice(self.seq)?;
+        let qual = if self.qual.is_empty() {
+            self.qual
+        } else {
+            sub.slice(self.qual)?
+        };
+        Some(RpView {
+            range: RpRange::new(self.read(), self.range.offset() + offset, Some(seq.len())),
+            seq,
+            qual,
+        })
+    }
+
+    /// Split the view into two views at position `mid`.
+    ///
+    /// # Panics
+    /// * If `mid` is larger than the length of the view
+    pub fn split_at(&self, mid: usize) -> (RpView<'a>, RpView<'a>) {
+        assert!(mid <= self.seq.len(), "split position is beyond the view");
+        (
+            self.sub_view(0, Some(mid)).unwrap(),
+            self.sub_view(mid, None).unwrap(),
+        )
+    }
+
+    /// Iterate over the bases of the view along with their phred quality
+    /// scores (with the ASCII offset removed).
+    pub fn iter_base_qual(&self) -> impl Iterator<Item = (u8, u8)> + 'a {
+        self.seq
+            .iter()
+            .zip(self.qual.iter())
+            .map(|(&b, &q)| (b, q.saturating_sub(ILLUMINA_QUAL_OFFSET)))
+    }
+}
+
+impl<'a> ops::Deref for RpView<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.seq
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::read_pair_iter::ReadPairIter;
     use proptest::arbitrary::any;
     use proptest::proptest;
     use proptest::strategy::Strategy;
@@ -872,6 +2228,63 @@ mod tests {
         }
     }
 
+    fn covers(range: Option<RpRange>, pos: usize) -> bool {
+        match range {
+            Some(r) => pos >= r.offset() && r.end().into_iter().all(|e| pos < e),
+            None => false,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_test_rprange_set_algebra(
+            offset in 0..1000usize,
+            len in 0..1001usize,
+            other_offset in 0..1000usize,
+            other_len in 0..1001usize,
+            other_read in 0..2usize,
+            mid in 0..1000usize,
+        ) {
+            // A length of 1000 stands for an open ended range
+            let len = Some(len).filter(|&l| l < 1000);
+            let other_len = Some(other_len).filter(|&l| l < 1000);
+            let range = RpRange::new(WhichRead::R1, offset, len);
+            let other = RpRange::new(WhichRead::from(other_read), other_offset, other_len);
+            let same_read = other.read() == range.read();
+
+            let (before, after) = range.subtract(other);
+            let mut all_contained = true;
+            for pos in 0..2100 {
+                let in_other = same_read && covers(Some(other), pos);
+                assert_eq!(
+                    covers(Some(range), pos) && !in_other,
+                    covers(before, pos) || covers(after, pos)
+                );
+                assert!(!(covers(before, pos) && covers(after, pos)));
+                all_contained &= !in_other || covers(Some(range), pos);
+            }
+            for piece in before.iter().chain(after.iter()) {
+                assert!(range.contains(*piece));
+                assert_ne!(piece.len(), Some(0));
+            }
+            // positions are only checked up to 2100, and empty ranges cover no positions
+            if (other.len().is_some() || range.len().is_none()) && other.len() != Some(0) {
+                assert_eq!(range.contains(other), same_read && all_contained);
+            }
+
+            if len.into_iter().all(|l| mid <= l) {
+                let (first, second) = range.split_at(mid);
+                assert_eq!(first.offset(), offset);
+                assert_eq!(first.len(), Some(mid));
+                assert_eq!(second.offset(), offset + mid);
+                assert_eq!(second.len(), len.map(|l| l - mid));
+                let mut shifted = second;
+                shifted.shift(-(mid as isize));
+                assert_eq!(shifted.offset(), offset);
+            }
+        }
+    }
+
     proptest! {
         #[test]
         fn prop_test_readpair_get(
@@ -897,6 +2310,705 @@ mod tests {
         }
     }
 
+    fn owned_record(head: &[u8], seq: &[u8], qual: &[u8]) -> OwnedRecord {
+        OwnedRecord {
+            head: head.to_vec(),
+            seq: seq.to_vec(),
+            qual: qual.to_vec(),
+            sep: None,
+        }
+    }
+
+    #[test]
+    fn test_base_qual() {
+        let rp = ReadPair::new([
+            Some(owned_record(b"r", b"ACGTA", b"I#5?!")),
+            None,
+            None,
+            None,
+        ]);
+        let pairs: Vec<_> = rp.iter_base_qual(WhichRead::R1).unwrap().collect();
+        assert_eq!(
+            pairs,
+            vec![(b'A', 40), (b'C', 2), (b'G', 20), (b'T', 30), (b'A', 0)]
+        );
+        assert!(rp.iter_base_qual(WhichRead::R2).is_none());
+        assert_eq!(rp.masked_seq(WhichRead::R1, 20).unwrap(), b"ANGTN".to_vec());
+        assert_eq!(rp.masked_seq(WhichRead::R1, 0).unwrap(), b"ACGTA".to_vec());
+        assert_eq!(rp.masked_seq(WhichRead::R1, 41).unwrap(), b"NNNNN".to_vec());
+        assert!(rp.masked_seq(WhichRead::I1, 20).is_none());
+    }
+
+    #[test]
+    fn test_rp_view() {
+        let rp = ReadPair::new([
+            Some(owned_record(b"r", b"ACGTACGT", b"ABCDEFGH")),
+            None,
+            None,
+            None,
+        ]);
+        let bc = rp.view(RpRange::new(WhichRead::R1, 0, Some(4))).unwrap();
+        assert_eq!(&*bc, b"ACGT");
+        assert_eq!(bc.qual(), b"ABCD");
+        assert_eq!(bc.len(), 4);
+        assert_eq!(bc.read(), WhichRead::R1);
+
+        let rest = rp.view(RpRange::new(WhichRead::R1, 2, None)).unwrap();
+        assert_eq!(rest.range(), RpRange::new(WhichRead::R1, 2, Some(6)));
+        let (umi, insert) = rest.split_at(2);
+        assert_eq!((&*umi, umi.qual()), (&b"GT"[..], &b"CD"[..]));
+        assert_eq!((&*insert, insert.qual()), (&b"ACGT"[..], &b"EFGH"[..]));
+        assert_eq!(insert.range(), RpRange::new(WhichRead::R1, 4, Some(4)));
+        assert_eq!(
+            rp.view(insert.range()).unwrap().seq(),
+            rp.get_range(insert.range(), ReadPart::Seq).unwrap()
+        );
+        assert_eq!(insert.sub_view(1, Some(2)).unwrap().seq(), b"CG");
+        assert_eq!(insert.sub_view(4, None).unwrap().len(), 0);
+        assert!(insert.sub_view(2, Some(3)).is_none());
+        assert!(insert.sub_view(5, None).is_none());
+        assert_eq!(
+            bc.iter_base_qual().collect::<Vec<_>>(),
+            vec![(b'A', 32), (b'C', 33), (b'G', 34), (b'T', 35)]
+        );
+
+        assert!(rp.view(RpRange::new(WhichRead::R1, 4, Some(5))).is_none());
+        assert!(rp.view(RpRange::new(WhichRead::R2, 0, None)).is_none());
+    }
+
+    #[test]
+    fn test_swap_relabel() {
+        let rp = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            Some("tests/read_pair_iter/good-I1.fastq"),
+            None,
+            true,
+        )
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+
+        let mut swapped = rp.clone();
+        swapped.swap(WhichRead::R1, WhichRead::R2);
+        for &(a, b) in &[
+            (WhichRead::R1, WhichRead::R2),
+            (WhichRead::R2, WhichRead::R1),
+            (WhichRead::I1, WhichRead::I1),
+        ] {
+            for &part in &[ReadPart::Header, ReadPart::Seq, ReadPart::Qual] {
+                assert_eq!(swapped.get(a, part), rp.get(b, part));
+            }
+        }
+        assert_eq!(swapped.get(WhichRead::I2, ReadPart::Seq), None);
+
+        // Trimming records move with their reads
+        let mut trimmed = TrimmedReadPair::new(rp.clone());
+        trimmed.trim_mut(WhichRead::R2).adapter = Some(RpRange::new(WhichRead::R2, 10, None));
+        trimmed.swap(WhichRead::R1, WhichRead::R2);
+        assert_eq!(trimmed.read_pair, swapped);
+        assert_eq!(
+            trimmed.trims.read(WhichRead::R1).adapter,
+            Some(RpRange::new(WhichRead::R1, 10, None))
+        );
+        assert!(trimmed.trims.read(WhichRead::R2).is_empty());
+        trimmed
+            .relabel([WhichRead::R2, WhichRead::R1, WhichRead::I1, WhichRead::I2])
+            .unwrap();
+        assert_eq!(trimmed.read_pair, rp);
+        assert!(trimmed.trims.read(WhichRead::R1).is_empty());
+
+        // Swapping back restores the original reads
+        swapped.swap(WhichRead::R2, WhichRead::R1);
+        assert_eq!(swapped, rp);
+
+        // Swapping a read with itself is a no-op
+        let mut same = rp.clone();
+        same.swap(WhichRead::I1, WhichRead::I1);
+        assert_eq!(same, rp);
+
+        let order = [WhichRead::I2, WhichRead::I1, WhichRead::R1, WhichRead::R2];
+        let mut relabeled = rp.clone();
+        relabeled.relabel(order).unwrap();
+        for (i, &from) in order.iter().enumerate() {
+            assert_eq!(
+                relabeled.get(WhichRead::from(i), ReadPart::Seq),
+                rp.get(from, ReadPart::Seq)
+            );
+        }
+        assert!(relabeled.relabel(WhichRead::read_types()).is_ok());
+        let bad = [WhichRead::R1, WhichRead::R2, WhichRead::I1, WhichRead::I1];
+        assert!(relabeled.relabel(bad).is_err());
+    }
+
+    #[test]
+    fn test_untrimmed_len() {
+        let mut it = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            Some("tests/read_pair_iter/good-I1.fastq"),
+            None,
+            true,
+        )
+        .unwrap()
+        .trim_length(WhichRead::R2, Some(10))
+        .with_trims();
+        let mut rp = it.next().unwrap().unwrap();
+        let untrimmed = rp.untrimmed_len(WhichRead::R2).unwrap();
+        assert!(untrimmed > 10);
+        assert_eq!(
+            rp.trims.read(WhichRead::R2).hard_clip,
+            Some(RpRange::new(WhichRead::R2, 10, Some(untrimmed - 10)))
+        );
+        assert_eq!(
+            rp.untrimmed_len(WhichRead::R1),
+            rp.read_pair.len(WhichRead::R1)
+        );
+        assert_eq!(rp.untrimmed_len(WhichRead::I2), None);
+
+        // The headers are left as they are
+        rp.read_pair.set_header(WhichRead::R2, b"renamed").unwrap();
+        assert_eq!(rp.untrimmed_len(WhichRead::R2), Some(untrimmed));
+        let mut fastq = Vec::new();
+        rp.read_pair.write_fastq(WhichRead::R2, &mut fastq).unwrap();
+        assert!(fastq.starts_with(b"@renamed\n"));
+    }
+
+    #[test]
+    fn test_set_header() {
+        let rp = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            Some("tests/read_pair_iter/good-I1.fastq"),
+            None,
+            true,
+        )
+        .unwrap()
+        .drop_qual(WhichRead::I1)
+        .next()
+        .unwrap()
+        .unwrap();
+
+        let mut renamed = rp.clone();
+        renamed.set_header(WhichRead::R2, b"r 4:N:0:0").unwrap();
+        renamed
+            .set_header(WhichRead::I1, b"a longer header than before")
+            .unwrap();
+        assert_eq!(
+            renamed.get(WhichRead::R2, ReadPart::Header).unwrap(),
+            b"r 4:N:0:0"
+        );
+        assert_eq!(
+            renamed.get(WhichRead::I1, ReadPart::Header).unwrap(),
+            b"a longer header than before"
+        );
+        assert_eq!(
+            renamed.get(WhichRead::R1, ReadPart::Header),
+            rp.get(WhichRead::R1, ReadPart::Header)
+        );
+        for &which in &[WhichRead::R1, WhichRead::R2, WhichRead::I1] {
+            for &part in &[ReadPart::Seq, ReadPart::Qual] {
+                assert_eq!(renamed.get(which, part), rp.get(which, part));
+            }
+        }
+        assert_eq!(renamed.get(WhichRead::I1, ReadPart::Qual), None);
+        assert!(renamed.set_header(WhichRead::I2, b"r").is_err());
+        assert!(renamed
+            .set_header(WhichRead::R1, &vec![b'r'; MAX_READ_PAIR_BYTES])
+            .is_err());
+
+        // Several headers replaced at once
+        let mut batch = rp.clone();
+        batch
+            .set_headers([
+                None,
+                Some(b"r 4:N:0:0"),
+                Some(b"a longer header than before"),
+                None,
+            ])
+            .unwrap();
+        assert_eq!(batch, renamed);
+        assert!(batch
+            .set_headers([Some(b"r"), None, None, Some(b"r")])
+            .is_err());
+        assert_eq!(batch, renamed);
+    }
+
+    #[test]
+    fn test_detect_polyg_suffix() {
+        let rp = |seq: &[u8]| {
+            let rec = OwnedRecord {
+                head: b"r".to_vec(),
+                seq: seq.to_vec(),
+                qual: vec![b'I'; seq.len()],
+                sep: None,
+            };
+            ReadPair::new([None, Some(rec), None, None])
+        };
+        let tail = |seq: &[u8], min_len| {
+            rp(seq)
+                .detect_polyg_suffix(WhichRead::R2, min_len)
+                .map(|r| r.len().unwrap())
+        };
+        assert_eq!(tail(b"ACGTGGGGGGGGGG", 5), Some(10));
+        assert_eq!(tail(b"ACGTGGGGGGGGGG", 11), None);
+        // Trailing mismatch
+        assert_eq!(tail(b"ACGTGGGGGGGGGA", 5), Some(10));
+        // One mismatch per 8 bases
+        let long = [
+            &b"ACGTACGTAC"[..],
+            &[b'G'; 7],
+            b"T",
+            &[b'G'; 7],
+            b"A",
+            &[b'G'; 8],
+        ]
+        .concat();
+        assert_eq!(tail(&long, 10), Some(24));
+        assert_eq!(tail(b"ACGTGGTTGGGG", 3), Some(4));
+        assert_eq!(tail(b"gggggg", 6), Some(6));
+        assert_eq!(tail(b"ACGTACGTAC", 0), None);
+        assert_eq!(tail(b"", 0), None);
+        assert!(rp(b"GGGGGG")
+            .detect_polyg_suffix(WhichRead::R1, 1)
+            .is_none());
+
+        // The range can be used to trim the read
+        let read = rp(b"ACGTGGGGGGGGGG");
+        let range = read.detect_polyg_suffix(WhichRead::R2, 5).unwrap();
+        let mut insert = RpRange::new(WhichRead::R2, 0, read.len(WhichRead::R2));
+        insert.trim(WhichEnd::ThreePrime, range.len().unwrap());
+        assert_eq!(read.get_range(insert, ReadPart::Seq).unwrap(), b"ACGT");
+    }
+
+    #[test]
+    fn test_heap_bytes() {
+        let rec = OwnedRecord {
+            head: b"r".to_vec(),
+            seq: b"ACGT".to_vec(),
+            qual: b"IIII".to_vec(),
+            sep: None,
+        };
+        let rp = ReadPair::new([Some(rec), None, None, None]);
+        assert_eq!(rp.heap_bytes(), 9);
+    }
+
+    #[test]
+    fn test_trim_provenance() {
+        use crate::adapter_trimmer::quality_trim;
+
+        let rec = OwnedRecord {
+            head: b"r".to_vec(),
+            seq: b"ACGTACGTAC".to_vec(),
+            qual: b"IIIIIIII##".to_vec(),
+            sep: None,
+        };
+        let mut rp = TrimmedReadPair::new(ReadPair::new([Some(rec), None, None, None]));
+        let original = rp.clone();
+        assert_eq!(rp.trims, TrimProvenance::default());
+
+        let qual = rp.read_pair.get(WhichRead::R1, ReadPart::Qual).unwrap();
+        let trim = quality_trim(qual, 20).unwrap();
+        rp.trim_mut(WhichRead::R1).quality = Some(trim.trim_rp_range(WhichRead::R1));
+        rp.trim_mut(WhichRead::R1).hard_clip = Some(RpRange::new(WhichRead::R1, 10, Some(40)));
+        assert_ne!(rp, original);
+        assert_eq!(rp.read_pair, original.read_pair);
+        let read_trim = *rp.trims.read(WhichRead::R1);
+        assert_eq!(
+            read_trim.quality,
+            Some(RpRange::new(WhichRead::R1, 8, Some(2)))
+        );
+        assert_eq!(read_trim.adapter, None);
+        assert!(!read_trim.is_empty());
+        assert!(rp.trims.read(WhichRead::I1).is_empty());
+
+        // The record survives editing and serialization
+        rp.read_pair
+            .mask_range(RpRange::new(WhichRead::R1, 8, None), b'N')
+            .unwrap();
+        let encoded = bincode::serialize(&rp).unwrap();
+        let decoded: TrimmedReadPair = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, rp);
+        assert_eq!(decoded.trims.read(WhichRead::R1), &read_trim);
+
+        // The serialized form of a ReadPair doesn't change: 4 offsets of a bool
+        // and 4 u16s, followed by the length and bytes of the data
+        let encoded = bincode::serialize(&rp.read_pair).unwrap();
+        assert_eq!(
+            bincode::serialized_size(&rp.read_pair).unwrap(),
+            (4 * 9 + 8 + rp.read_pair.data.len()) as u64
+        );
+        let decoded: ReadPair = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, rp.read_pair);
+    }
+
+    #[test]
+    fn test_alignable_ranges() {
+        use crate::metric_utils::MinAlignableLength;
+        use crate::AlignableReadPair;
+
+        struct DnaRead {
+            read: ReadPair,
+            ranges: AlignableRanges,
+        }
+        impl AlignableReadPair for DnaRead {
+            fn header(&self) -> &[u8] {
+                self.read.get(WhichRead::R1, ReadPart::Header).unwrap()
+            }
+            fn alignable_sequence(&self) -> (&[u8], &[u8]) {
+                self.read.alignable_sequence(self.ranges)
+            }
+            fn alignable_quals(&self) -> (&[u8], &[u8]) {
+                self.read.alignable_quals(self.ranges)
+            }
+        }
+
+        let rec = |head: &[u8], seq: &[u8], qual: &[u8]| OwnedRecord {
+            head: head.to_vec(),
+            seq: seq.to_vec(),
+            qual: qual.to_vec(),
+            sep: None,
+        };
+        let read = ReadPair::new([
+            Some(rec(b"r1", b"ACGTACGTTTGG", b"IIIIIIII####")),
+            Some(rec(b"r2", b"CCAA", b"FF:#")),
+            None,
+            None,
+        ]);
+        let mut dna = DnaRead {
+            read,
+            ranges: AlignableRanges::full(true),
+        };
+        assert_eq!(dna.header(), b"r1");
+        assert_eq!(
+            dna.alignable_sequence(),
+            (&b"ACGTACGTTTGG"[..], &b"CCAA"[..])
+        );
+
+        dna.ranges.r1 = Some(RpRange::new(WhichRead::R1, 8, None));
+        dna.ranges.r2 = Some(RpRange::new(WhichRead::R2, 1, Some(2)));
+        assert_eq!(dna.alignable_sequence(), (&b"TTGG"[..], &b"CA"[..]));
+        assert_eq!(dna.alignable_quals(), (&b"####"[..], &b"F:"[..]));
+
+        let mut filter = MinAlignableLength::new(3, true);
+        assert!(!filter.check(&dna));
+
+        // Missing or out of range regions are empty
+        dna.ranges = AlignableRanges::full(false);
+        assert_eq!(dna.alignable_sequence().1, b"");
+        dna.ranges.r1 = Some(RpRange::new(WhichRead::R1, 20, None));
+        assert_eq!(dna.alignable_quals().0, b"");
+        dna.ranges.r1 = Some(RpRange::new(WhichRead::I1, 0, None));
+        assert_eq!(dna.alignable_sequence().0, b"");
+    }
+
+    #[test]
+    fn test_rprange_new_checked() {
+        let rec = |seq: &[u8]| OwnedRecord {
+            head: b"r".to_vec(),
+            seq: seq.to_vec(),
+            qual: vec![b'F'; seq.len()],
+            sep: None,
+        };
+        let rp = ReadPair::new([Some(rec(b"ACGTACGT")), None, Some(rec(b"")), None]);
+
+        for &(offset, len) in &[
+            (0, None),
+            (0, Some(8)),
+            (8, None),
+            (8, Some(0)),
+            (3, Some(2)),
+        ] {
+            let range = RpRange::new_checked(WhichRead::R1, offset, len, &rp).unwrap();
+            assert_eq!(range, RpRange::new(WhichRead::R1, offset, len));
+            assert!(rp.check_range(&range, "Range").is_ok());
+            assert!(rp.get_range(range, ReadPart::Seq).is_some());
+        }
+        assert!(RpRange::new_checked(WhichRead::I1, 0, None, &rp).is_ok());
+
+        assert_eq!(
+            RpRange::new_checked(WhichRead::R1, 9, None, &rp),
+            Err(RpRangeError::OutOfBounds {
+                read: WhichRead::R1,
+                start: 9,
+                end: 9,
+                read_len: 8
+            })
+        );
+        assert_eq!(
+            RpRange::new_checked(WhichRead::I1, 0, Some(1), &rp),
+            Err(RpRangeError::OutOfBounds {
+                read: WhichRead::I1,
+                start: 0,
+                end: 1,
+                read_len: 0
+            })
+        );
+        assert_eq!(
+            RpRange::new_checked(WhichRead::I2, 0, Some(1), &rp),
+            Err(RpRangeError::MissingRead {
+                read: WhichRead::I2
+            })
+        );
+        assert_eq!(
+            RpRange::new_checked(WhichRead::R1, 1 << 15, None, &rp),
+            Err(RpRangeError::TooLarge {
+                read: WhichRead::R1,
+                offset: 1 << 15,
+                len: None
+            })
+        );
+        let err = RpRange::new_checked(WhichRead::R1, 0, Some(0x7FFF), &rp).unwrap_err();
+        assert!(err.to_string().contains("can't be represented"));
+
+        // try_new accepts exactly the values `new` does not panic on
+        for &(offset, len) in &[
+            (0x7FFF, None),
+            (0, Some(0x7FFE)),
+            (1 << 15, None),
+            (0, Some(0x7FFF)),
+        ] {
+            let ok = offset < (1 << 15) && len.into_iter().all(|l| l < 0x7FFF);
+            assert_eq!(RpRange::try_new(WhichRead::R2, offset, len).is_ok(), ok);
+        }
+
+        let range = RpRange::new(WhichRead::R1, 6, Some(3));
+        assert_eq!(
+            range.check_against(&rp),
+            Err(RpRangeError::OutOfBounds {
+                read: WhichRead::R1,
+                start: 6,
+                end: 9,
+                read_len: 8
+            })
+        );
+        assert!(rp.check_range(&range, "Range").is_err());
+        let mut shifted = range;
+        shifted.shift(-6);
+        assert_eq!(shifted.check_against(&rp), Ok(()));
+    }
+
+    #[test]
+    fn test_to_fastq_records() {
+        let rec = |head: &[u8], seq: &[u8]| OwnedRecord {
+            head: head.to_vec(),
+            seq: seq.to_vec(),
+            qual: vec![b'F'; seq.len()],
+            sep: None,
+        };
+        let rp = ReadPair::new([
+            Some(rec(b"r 1:N:0:0", b"ACGTACGT")),
+            Some(rec(b"r 2:N:0:0", b"GGCC")),
+            None,
+            Some(rec(b"r 4:N:0:0", b"TTAA")),
+        ]);
+
+        // Without ranges or tags the records are the same as `write_fastq`
+        let mut expected = Vec::new();
+        for &which in &[WhichRead::I2, WhichRead::R1] {
+            rp.write_fastq(which, &mut expected).unwrap();
+        }
+        let all = rp
+            .to_fastq_records(&[WhichRead::I2, WhichRead::I1, WhichRead::R1], &[], &[])
+            .unwrap();
+        assert_eq!(all, expected);
+
+        let fastq = rp
+            .to_fastq_records(
+                &[WhichRead::R1, WhichRead::R2],
+                &[
+                    RpRange::new(WhichRead::R1, 4, None),
+                    RpRange::new(WhichRead::I2, 0, Some(1)),
+                ],
+                &[(*b"BC", b"TTAA"), (*b"RX", b"GG")],
+            )
+            .unwrap();
+        assert_eq!(
+            fastq,
+            b"@r 1:N:0:0 BC:Z:TTAA RX:Z:GG\nACGT\n+\nFFFF\n@r 2:N:0:0 BC:Z:TTAA RX:Z:GG\nGGCC\n+\nFFFF\n"
+                .to_vec()
+        );
+        assert!(rp.to_fastq_records(&[], &[], &[]).unwrap().is_empty());
+
+        let err = rp
+            .to_fastq_records(
+                &[WhichRead::R2],
+                &[RpRange::new(WhichRead::R2, 2, Some(4))],
+                &[],
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("is not contained in read2"));
+
+        let mut buf = BytesMut::new();
+        let mut mrp = MutReadPair::empty(&mut buf);
+        mrp.push_read(&rec(b"r", b"ACGT"), WhichRead::R1).unwrap();
+        mrp.drop_qual(WhichRead::R1);
+        assert!(mrp
+            .freeze()
+            .to_fastq_records(&[WhichRead::R1], &[], &[])
+            .is_err());
+    }
+
+    #[test]
+    fn test_edit_read() {
+        let mut buffer = BytesMut::with_capacity(4096);
+        let r1 = owned_record(b"a", b"ACGTACGT", b"IIIIIIII");
+        let r2 = owned_record(b"b", b"GGGG", b"IIII");
+        let mut rp = MutReadPair::new(&mut buffer, &[Some(r1), Some(r2), None, None]).freeze();
+        // a second read pair sharing the buffer must not be affected by the edits
+        let other = MutReadPair::new(
+            &mut buffer,
+            &[Some(owned_record(b"c", b"TTTT", b"IIII")), None, None, None],
+        )
+        .freeze();
+        let original = rp.clone();
+
+        rp.set_seq(WhichRead::R2, b"CCCA").unwrap();
+        rp.set_qual(WhichRead::R2, b"#I#I").unwrap();
+        rp.mask_range(RpRange::new(WhichRead::R1, 6, None), b'N')
+            .unwrap();
+        rp.mask_range(RpRange::new(WhichRead::R1, 1, Some(2)), b'N')
+            .unwrap();
+        assert_eq!(rp.get(WhichRead::R1, ReadPart::Seq).unwrap(), b"ANNTACNN");
+        assert_eq!(rp.get(WhichRead::R1, ReadPart::Header).unwrap(), b"a");
+        assert_eq!(rp.get(WhichRead::R2, ReadPart::Seq).unwrap(), b"CCCA");
+        assert_eq!(rp.get(WhichRead::R2, ReadPart::Qual).unwrap(), b"#I#I");
+        assert_eq!(original.get(WhichRead::R2, ReadPart::Seq).unwrap(), b"GGGG");
+        assert_eq!(other.get(WhichRead::R1, ReadPart::Seq).unwrap(), b"TTTT");
+
+        assert!(rp.set_seq(WhichRead::R2, b"CCC").is_err());
+        assert!(rp.set_qual(WhichRead::I1, b"").is_err());
+        assert!(rp
+            .mask_range(RpRange::new(WhichRead::R1, 6, Some(3)), b'N')
+            .is_err());
+        assert!(rp.edit_read(WhichRead::I2, |_, _| ()).is_err());
+        assert_eq!(rp.get(WhichRead::R1, ReadPart::Seq).unwrap(), b"ANNTACNN");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_mask_bases_below_mismatched_len() {
+        mask_bases_below(&mut b"ACGT".to_vec(), b"III", 20);
+    }
+
+    #[test]
+    fn test_push_read_too_long() {
+        let mut buffer = BytesMut::with_capacity(4096);
+        let mut rp = MutReadPair::empty(&mut buffer);
+        let seq = vec![b'A'; MAX_READ_PAIR_BYTES / 3];
+        let rec = owned_record(b"r", &seq, &seq);
+        rp.push_read(&rec, WhichRead::R1).unwrap();
+        assert!(rp.push_read(&rec, WhichRead::R2).is_err());
+        let rp = rp.freeze();
+        assert_eq!(rp.len(WhichRead::R1), Some(seq.len()));
+        assert_eq!(rp.len(WhichRead::R2), None);
+
+        let mut buffer = BytesMut::new();
+        let rec2 = owned_record(b"r", &seq, &seq);
+        let rr = [Some(rec), Some(rec2), None, None];
+        assert!(MutReadPair::try_new(&mut buffer, &rr).is_err());
+        assert!(ReadPair::try_new(rr).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_read_pair_new_too_long() {
+        let seq = vec![b'A'; MAX_READ_PAIR_BYTES / 3];
+        let rec = owned_record(b"r", &seq, &seq);
+        let rec2 = owned_record(b"r", &seq, &seq);
+        ReadPair::new([Some(rec), Some(rec2), None, None]);
+    }
+
+    #[test]
+    fn test_readpair_diff() {
+        let a = ReadPair::new([
+            Some(owned_record(b"r", b"ACGTACGT", b"IIIIIIII")),
+            Some(owned_record(b"r", b"GGGG", b"IIII")),
+            None,
+            None,
+        ]);
+        assert!(a.diff(&a.clone()).is_empty());
+
+        let b = ReadPair::new([
+            Some(owned_record(b"r", b"TCGTACCC", b"IIIIIIII")),
+            None,
+            None,
+            Some(owned_record(b"r", b"AAAA", b"IIII")),
+        ]);
+        assert_eq!(
+            a.diff(&b),
+            vec![
+                Difference::Bytes {
+                    read: WhichRead::R1,
+                    part: ReadPart::Seq,
+                    range: 0..1
+                },
+                Difference::Bytes {
+                    read: WhichRead::R1,
+                    part: ReadPart::Seq,
+                    range: 6..8
+                },
+                Difference::Presence {
+                    read: WhichRead::R2,
+                    in_self: true
+                },
+                Difference::Presence {
+                    read: WhichRead::I2,
+                    in_self: false
+                },
+            ]
+        );
+
+        let c = ReadPair::new([
+            Some(owned_record(b"read", b"ACGTACGT", b"IIII#III")),
+            Some(owned_record(b"r", b"GGGG", b"IIII")),
+            None,
+            None,
+        ]);
+        assert_eq!(
+            a.diff(&c),
+            vec![
+                Difference::Length {
+                    read: WhichRead::R1,
+                    part: ReadPart::Header,
+                    self_len: 1,
+                    other_len: 4
+                },
+                Difference::Bytes {
+                    read: WhichRead::R1,
+                    part: ReadPart::Qual,
+                    range: 4..5
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_readpair_fmt() {
+        let long_seq = [b'A'; 40];
+        let rp = ReadPair::new([
+            Some(owned_record(b"name 1:N", &long_seq, &[b'I'; 40])),
+            None,
+            Some(owned_record(b"name 2:N", b"ACGT", b"IIII")),
+            None,
+        ]);
+        assert_eq!(
+            format!("{}", rp),
+            "@name 1:N read1:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA...(+8) index1:ACGT"
+        );
+        assert_eq!(
+            format!("{:?}", rp),
+            "ReadPair { R1: Read { header: \"name 1:N\", \
+             seq: \"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA...(+8)\", \
+             qual: \"IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII...(+8)\" }, \
+             I1: Read { header: \"name 2:N\", seq: \"ACGT\", qual: \"IIII\" } }"
+        );
+        let empty = ReadPair::new::<OwnedRecord>([None, None, None, None]);
+        assert_eq!(format!("{}", empty), "");
+        assert_eq!(format!("{:?}", empty), "ReadPair");
+    }
+
     #[test]
     #[should_panic]
     fn test_rprange_intersect_panic() {
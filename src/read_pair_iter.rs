@@ -5,30 +5,57 @@
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
-use crate::read_pair::{MutReadPair, ReadPair, ReadPairStorage, ReadPart, WhichRead};
+use crate::block_gz::{self, BgzfReader, BGZF_HEADER_LEN};
+use crate::metric_utils::ILLUMINA_QUAL_OFFSET;
+use crate::read_names::read_name;
+use crate::read_pair::{
+    MutReadPair, ReadPair, ReadPairConfig, ReadPairStorage, ReadPart, RpRange, TrimProvenance,
+    TrimRecord, TrimmedReadPair, WhichRead,
+};
+use crate::utils::{LZ4_MAGIC, XZ_MAGIC, ZSTD_MAGIC};
 use fastq::{self, Record, RecordRefIter};
 
-use bytes::{BufMut, BytesMut};
+use bytes::BytesMut;
 
 use std::io::ErrorKind;
-use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
 
 use failure::Backtrace;
 use failure::Fail;
 
+use failure::{format_err, Error};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use rand::distributions::{Distribution, Uniform};
 use rand::SeedableRng;
 use rand_xorshift::XorShiftRng;
 
 const GZ_BUF_SIZE: usize = 1 << 16;
 
+/// Number of threads decompressing each BGZF input, unless set with
+/// `decompression_threads`. A single thread decompresses on the thread reading records.
+const BGZF_THREADS: usize = 1;
+
+/// Errors reading FASTQ files. `FastqFormat` and `Io` are `non_exhaustive` so
+/// that more context can be attached to them: create format errors with
+/// `FastqError::format` or `FastqError::record_format`, and read the context
+/// with `record` and `suggestion`.
 #[derive(Fail, Debug)]
 pub enum FastqError {
     #[fail(display = "{}: file: {:?}, line: {}", message, file, line)]
+    #[non_exhaustive]
     FastqFormat {
         message: String,
         line: usize,
+        /// Index of the record where the error occurred, if known
+        record: Option<usize>,
         file: PathBuf,
+        /// How the user can fix the error, if known
+        suggestion: Option<Suggestion>,
         backtrace: Backtrace,
     },
     #[fail(display = "Error opening FASTQ file '{:?}': {}", file, source)]
@@ -41,28 +68,173 @@ pub enum FastqError {
         display = "IO error in FASTQ file '{:?}', line: {}: {}",
         file, line, source
     )]
+    #[non_exhaustive]
     Io {
         source: io::Error,
         file: PathBuf,
         line: usize,
+        /// Index of the record where the error occurred, if known
+        record: Option<usize>,
         backtrace: Backtrace,
     },
 }
 
+/// How the user can fix a `FastqError`, attached to the error where it is found
+/// and reported by [`FastqError::diagnostic`](enum.FastqError.html#method.diagnostic)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Suggestion {
+    HeaderMismatch,
+    RecordCount,
+    Interleaved,
+    InvalidBase,
+    NotFastq,
+    Gzip,
+    Lz4,
+    Zstd,
+    Xz,
+}
+
+impl Suggestion {
+    pub fn text(self) -> &'static str {
+        match self {
+            Suggestion::HeaderMismatch => "Check that the R1, R2, I1 and I2 files come from the same sequencing run and are listed in a consistent order.",
+            Suggestion::RecordCount => "The FASTQ files contain different numbers of records. Check whether one of them is truncated or missing data.",
+            Suggestion::Interleaved => "Check whether the FASTQ file is really interleaved, or if it was truncated.",
+            Suggestion::InvalidBase => "The sequence line contains invalid characters. Check that the file is a valid FASTQ file.",
+            Suggestion::NotFastq => "Check that the path refers to a FASTQ file, compressed with one of the supported formats.",
+            Suggestion::Gzip => "Check that the file is fully transferred and decompresses with gunzip -t.",
+            Suggestion::Lz4 => "Check that the file is fully transferred and decompresses with lz4 -t.",
+            Suggestion::Zstd => "Check that the file is fully transferred and decompresses with zstd -t.",
+            Suggestion::Xz => "Check that the file is fully transferred and decompresses with xz -t.",
+        }
+    }
+}
+
 impl FastqError {
+    /// A format error at line `line` of the file at `path`
     pub fn format(message: String, path: impl AsRef<Path>, line: usize) -> FastqError {
         FastqError::FastqFormat {
             message,
             line,
+            record: None,
             file: path.as_ref().to_path_buf(),
+            suggestion: None,
             backtrace: Backtrace::new(),
         }
     }
+
+    /// A format error in the FASTQ record with index `record`, which starts at line
+    /// `4 * record` of the file
+    pub fn record_format(message: String, path: impl AsRef<Path>, record: usize) -> FastqError {
+        FastqError::FastqFormat {
+            message,
+            line: 4 * record,
+            record: Some(record),
+            file: path.as_ref().to_path_buf(),
+            suggestion: None,
+            backtrace: Backtrace::new(),
+        }
+    }
+
+    /// Attach a suggestion of how to fix a format error
+    pub fn suggest(mut self, hint: Suggestion) -> FastqError {
+        if let FastqError::FastqFormat { suggestion, .. } = &mut self {
+            *suggestion = Some(hint);
+        }
+        self
+    }
+
+    /// Index of the FASTQ record where the error occurred, if known
+    pub fn record(&self) -> Option<usize> {
+        match self {
+            FastqError::FastqFormat { record, .. } | FastqError::Io { record, .. } => *record,
+            FastqError::Open { .. } => None,
+        }
+    }
+
+    /// How to fix a format error, if known
+    pub fn suggestion(&self) -> Option<Suggestion> {
+        match self {
+            FastqError::FastqFormat { suggestion, .. } => *suggestion,
+            _ => None,
+        }
+    }
+
+    /// A machine-readable description of the error, for orchestration layers that
+    /// need to surface the location of the problem and a possible fix to the user.
+    pub fn diagnostic(&self) -> FastqDiagnostic {
+        let (code, message, file, line, record) = match self {
+            FastqError::FastqFormat {
+                message,
+                file,
+                line,
+                record,
+                ..
+            } => ("fastq_format", message.clone(), file, Some(*line), *record),
+            FastqError::Open { source, file, .. } => ("open", source.to_string(), file, None, None),
+            FastqError::Io {
+                source,
+                file,
+                line,
+                record,
+                ..
+            } => ("io", source.to_string(), file, Some(*line), *record),
+        };
+        FastqDiagnostic {
+            code: code.to_string(),
+            message,
+            file: file.clone(),
+            line,
+            record,
+            suggestion: self.suggestion_text().map(String::from),
+        }
+    }
+
+    /// The diagnostic as a JSON string. See [`diagnostic`](#method.diagnostic).
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.diagnostic()).unwrap()
+    }
+
+    fn suggestion_text(&self) -> Option<&'static str> {
+        match self {
+            FastqError::FastqFormat { suggestion, .. } => suggestion.map(Suggestion::text),
+            FastqError::Open { source, .. } => match source.kind() {
+                ErrorKind::NotFound => {
+                    Some("Check that the FASTQ path is correct and the file exists.")
+                }
+                ErrorKind::PermissionDenied => {
+                    Some("Check that the FASTQ file is readable by the current user.")
+                }
+                _ => None,
+            },
+            FastqError::Io { source, .. } => match source.kind() {
+                ErrorKind::UnexpectedEof => Some(
+                    "The file ended unexpectedly. It may be truncated; try transferring it again.",
+                ),
+                _ => None,
+            },
+        }
+    }
 }
 
-trait FileIoError<T> {
+/// Machine-readable summary of a `FastqError`, see [`FastqError::diagnostic`](enum.FastqError.html#method.diagnostic).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FastqDiagnostic {
+    /// Kind of error: `fastq_format`, `open` or `io`
+    pub code: String,
+    pub message: String,
+    pub file: PathBuf,
+    /// Line of the FASTQ file where the error occurred, if known
+    pub line: Option<usize>,
+    /// Index of the FASTQ record where the error occurred, if known
+    pub record: Option<usize>,
+    pub suggestion: Option<String>,
+}
+
+pub(crate) trait FileIoError<T> {
     fn open_err(self, path: impl AsRef<Path>) -> Result<T, FastqError>;
-    fn fastq_err(self, path: impl AsRef<Path>, line: usize) -> Result<T, FastqError>;
+    /// Convert an error reading the FASTQ record with index `record`
+    fn fastq_err(self, path: impl AsRef<Path>, record: usize) -> Result<T, FastqError>;
 }
 
 impl<T> FileIoError<T> for Result<T, std::io::Error> {
@@ -80,17 +252,23 @@ impl<T> FileIoError<T> for Result<T, std::io::Error> {
         }
     }
 
-    fn fastq_err(self, path: impl AsRef<Path>, line: usize) -> Result<T, FastqError> {
+    fn fastq_err(self, path: impl AsRef<Path>, record: usize) -> Result<T, FastqError> {
         match self {
             Ok(v) => Ok(v),
             Err(e) => {
                 match e.kind() {
                     // convert InvalidData into a FastqFormat error
                     ErrorKind::InvalidData => {
+                        let suggestion = e
+                            .get_ref()
+                            .and_then(|inner| inner.downcast_ref::<DecodeError>())
+                            .map(|d| d.suggestion);
                         let e = FastqError::FastqFormat {
                             message: e.to_string(),
-                            line,
+                            line: 4 * record,
+                            record: Some(record),
                             file: path.as_ref().to_path_buf(),
+                            suggestion,
                             backtrace: Backtrace::new(),
                         };
                         Err(e)
@@ -101,7 +279,8 @@ impl<T> FileIoError<T> for Result<T, std::io::Error> {
                         let e = FastqError::Io {
                             source: e,
                             file: path.as_ref().to_path_buf(),
-                            line,
+                            line: 4 * record,
+                            record: Some(record),
                             backtrace: Backtrace::new(),
                         };
                         Err(e)
@@ -112,9 +291,61 @@ impl<T> FileIoError<T> for Result<T, std::io::Error> {
     }
 }
 
+/// Invalid data found by a decompressor, with the suggestion of how to check the file
+#[derive(Debug)]
+struct DecodeError {
+    source: io::Error,
+    suggestion: Suggestion,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Wrap the decompressing reader `inner` to attach `suggestion` to its errors
+fn hinted<R: Read>(inner: R, suggestion: Suggestion) -> HintedDecoder<R> {
+    HintedDecoder { inner, suggestion }
+}
+
+/// Decompressing reader attaching `suggestion` to the errors of invalid data, which
+/// some decompressors report as invalid input
+struct HintedDecoder<R> {
+    inner: R,
+    suggestion: Suggestion,
+}
+
+impl<R: Read> Read for HintedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            if e.kind() == ErrorKind::InvalidData || e.kind() == ErrorKind::InvalidInput {
+                let suggestion = self.suggestion;
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    DecodeError {
+                        source: e,
+                        suggestion,
+                    },
+                )
+            } else {
+                e
+            }
+        })
+    }
+}
+
 /// A set of corresponding FASTQ representing the different read components from a set of flowcell 'clusters'
 /// All reads are optional except for R1. For an interleaved R1/R2 file, set the filename in the `r1` field,
-/// and set `r1_interleaved = true`.
+/// and set `r1_interleaved = true`. An interleaved file can also hold the index reads, with 3 or 4
+/// records per read pair, which must be given with
+/// [`ReadPairIter::interleave`](struct.ReadPairIter.html#method.interleave).
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct InputFastqs {
     pub r1: String,
@@ -148,62 +379,514 @@ impl InputFastqs {
 
 const BUF_SIZE: usize = 4096 * 4;
 
-/// A type implementing the fastq Record trait for handling trimming
-struct TrimRecord<'a, R: Record> {
-    inner: &'a R,
-    trim: usize,
-}
-
-impl<'a, 'b, R: Record> TrimRecord<'a, R> {
-    fn new(inner: &'a R, trim: usize) -> Self {
-        let trim = trim.min(inner.seq().len());
-        TrimRecord { inner, trim }
-    }
-}
-
-impl<'a, R: Record> Record for TrimRecord<'a, R> {
-    fn seq(&self) -> &[u8] {
-        &self.inner.seq()[..self.trim]
-    }
-    fn qual(&self) -> &[u8] {
-        &self.inner.qual()[..self.trim]
-    }
-    fn head(&self) -> &[u8] {
-        self.inner.head()
-    }
-    fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<usize> {
-        let mut written = 0;
-        // TODO(lhepler): the fundamental impl in fastq here is busted, write may not write all
-        // bytes...
-        written += writer.write(b"@")?;
-        written += writer.write(self.head())?;
-        written += writer.write(b"\n")?;
-        written += writer.write(self.seq())?;
-        written += writer.write(b"\n+\n")?;
-        written += writer.write(self.qual())?;
-        written += writer.write(b"\n")?;
-        Ok(written)
-    }
-}
-
 /// Read sequencing data from a parallel set of FASTQ files.
 /// Illumina sequencers typically emit a parallel set of FASTQ files, with one file
 /// for each read component taken by the sequencer. Up to 4 reads are possible (R1, R2, I1, and I2).
 /// The reader supports any combination of R1/R2/I1/I2 read files,
-/// as well as an interleaved R1/R2 file. Supports plain or gzipped FASTQ files, which
-/// will be detected based on the filename extension.
+/// as well as an interleaved R1/R2 file, optionally interleaving the index reads too.
+/// Supports plain or gzipped FASTQ files, which will be detected based on the filename extension.
 pub struct ReadPairIter {
+    // Readers of the input files, until the parsers are created on the first read
+    readers: [Option<Box<dyn BufRead + Send>>; 4],
     iters: [Option<RecordRefIter<Box<dyn BufRead + Send>>>; 4],
     paths: [Option<PathBuf>; 4],
-    // Each input file can interleave up to 2 -- declare those here
-    r1_interleaved: bool,
+    // The reads stored in consecutive records of the R1 file
+    r1_reads: Vec<WhichRead>,
     buffer: BytesMut,
+    buffer_size: usize,
     rand: XorShiftRng,
     uniform: Uniform<f64>,
     subsample_rate: f64,
     storage: ReadPairStorage,
     records_read: [usize; 4],
-    read_lengths: [usize; 4],
+    config: ReadPairConfig,
+    dropped_bases: [u64; 4],
+    last_trims: TrimProvenance,
+    qual_offsets: [u8; 4],
+    drop_qual: [bool; 4],
+    source_id: u32,
+    last_record: u64,
+    last_offset: Option<u64>,
+    io: [Option<Arc<IoCounters>>; 4],
+    time_io: bool,
+    advance_nanos: [u64; 4],
+    malformed_policy: MalformedRecordPolicy,
+    allow_truncated: bool,
+    // Shared with the `RecordFilter` of each file
+    filters: [Option<Arc<FilterState>>; 4],
+    malformed_records: u64,
+    malformed_headers: Vec<String>,
+    truncated: bool,
+    // Uncompressed bytes of the records read from each file
+    bytes_read: [u64; 4],
+    // Shared with the `BgzfReader` of each BGZF file
+    bgzf_threads: Arc<AtomicUsize>,
+    reject_empty: bool,
+    check_read_names: bool,
+    // Name of the first record of the current read pair, when checking read names
+    name_buf: Vec<u8>,
+}
+
+/// I/O statistics for one input FASTQ of a `ReadPairIter`, reported by
+/// [`ReadPairIter::io_stats`](struct.ReadPairIter.html#method.io_stats).
+/// A large `read_time` relative to the other times indicates a storage-bound run,
+/// while large `decompress_time` or `parse_time` indicate a CPU-bound run.
+/// The times are only measured once enabled with
+/// [`ReadPairIter::time_io`](struct.ReadPairIter.html#method.time_io), and are zero otherwise.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoStats {
+    /// Bytes read from the file, before decompression
+    pub compressed_bytes: u64,
+    /// Bytes of FASTQ data after decompression
+    pub decompressed_bytes: u64,
+    /// Time spent waiting on the file or reader
+    pub read_time: Duration,
+    /// Time spent decompressing the data, excluding `read_time`
+    pub decompress_time: Duration,
+    /// Time spent parsing FASTQ records, excluding `read_time` and `decompress_time`
+    pub parse_time: Duration,
+}
+
+impl IoStats {
+    /// Combine the statistics of two files, e.g. of the same read component in
+    /// different chunks.
+    pub fn merge(&mut self, other: &IoStats) {
+        self.compressed_bytes += other.compressed_bytes;
+        self.decompressed_bytes += other.decompressed_bytes;
+        self.read_time += other.read_time;
+        self.decompress_time += other.decompress_time;
+        self.parse_time += other.parse_time;
+    }
+
+    /// Ratio of decompressed to compressed bytes, or 1.0 if nothing was read
+    pub fn compression_ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            1.0
+        } else {
+            self.decompressed_bytes as f64 / self.compressed_bytes as f64
+        }
+    }
+}
+
+/// Byte and time counters shared with the readers of one input file. The raw
+/// counters track reads from the file and the decoded counters track reads from
+/// the decompressor, which include the time spent in reads from the file. Times are
+/// only measured if `timed` is set.
+#[derive(Default)]
+pub(crate) struct IoCounters {
+    raw_bytes: AtomicU64,
+    raw_nanos: AtomicU64,
+    decoded_bytes: AtomicU64,
+    decoded_nanos: AtomicU64,
+    timed: AtomicBool,
+}
+
+/// Byte counters of the input files of a `ReadPairIter`, which can still be read
+/// after the iterator is moved to a background thread
+#[derive(Clone)]
+pub(crate) struct IoBytes([Option<Arc<IoCounters>>; 4]);
+
+impl IoBytes {
+    /// Total bytes read from the files, and of FASTQ data after decompression
+    pub(crate) fn totals(&self) -> (u64, u64) {
+        let mut totals = (0, 0);
+        for c in self.0.iter().flatten() {
+            totals.0 += c.raw_bytes.load(Ordering::Relaxed);
+            totals.1 += c.decoded_bytes.load(Ordering::Relaxed);
+        }
+        totals
+    }
+}
+
+impl IoCounters {
+    /// Bytes read from the file, before decompression
+    pub(crate) fn raw_bytes(&self) -> u64 {
+        self.raw_bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Reader updating the raw or decoded `IoCounters` of a file
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    counters: Arc<IoCounters>,
+    decoded: bool,
+}
+
+impl<R> CountingReader<R> {
+    /// Count the bytes read from `inner`, e.g. a file before decompression, as
+    /// raw bytes in `counters`
+    pub(crate) fn raw(inner: R, counters: Arc<IoCounters>) -> Self {
+        CountingReader {
+            inner,
+            counters,
+            decoded: false,
+        }
+    }
+
+    fn start(&self) -> Option<Instant> {
+        if self.counters.timed.load(Ordering::Relaxed) {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    fn add_time(&self, start: Option<Instant>) {
+        if let Some(start) = start {
+            let n = if self.decoded {
+                &self.counters.decoded_nanos
+            } else {
+                &self.counters.raw_nanos
+            };
+            n.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn add_bytes(&self, bytes: usize) {
+        let b = if self.decoded {
+            &self.counters.decoded_bytes
+        } else {
+            &self.counters.raw_bytes
+        };
+        b.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = self.start();
+        let n = self.inner.read(buf)?;
+        self.add_time(start);
+        self.add_bytes(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let start = self.start();
+        if start.is_some() {
+            self.inner.fill_buf()?;
+            self.add_time(start);
+        }
+        // bytes are counted when they are consumed
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.add_bytes(amt);
+    }
+}
+
+/// How a `ReadPairIter` handles a malformed FASTQ record, such as a record without
+/// the leading `@`, with a quality string that doesn't match the length of the
+/// sequence, or with bases other than `ACGTN`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MalformedRecordPolicy {
+    /// Return an error
+    Fail,
+    /// Skip the read pair containing the malformed record and continue
+    Skip,
+    /// End the iteration before the read pair containing the malformed record,
+    /// as if the input files ended there
+    Truncate,
+}
+
+/// Header of the records replacing malformed records, followed by the start of the
+/// first line of the malformed record
+const MALFORMED_HEADER: &[u8] = b"\x01MALFORMED\x01 ";
+
+/// Number of headers of malformed records kept by a `ReadPairIter`
+const MAX_MALFORMED_HEADERS: usize = 10;
+
+/// Number of bytes of a FASTQ record with a bare `+` separator line and `\n` line
+/// endings: the `@`, `+` and four newlines around the header, sequence and quality
+fn record_len(rec: &impl Record) -> usize {
+    rec.head().len() + rec.seq().len() + rec.qual().len() + 6
+}
+
+/// The truncation found by the `RecordFilter` of a file
+#[derive(Default)]
+struct FilterState {
+    truncated: AtomicBool,
+    truncated_bytes: AtomicU64,
+}
+
+/// Reader going over the FASTQ records of a file, only placed in front of the FASTQ
+/// parser when checking records or allowing truncated files. When checking
+/// records, each malformed record is replaced with a record with an empty sequence
+/// and a `MALFORMED_HEADER` header, so that the FASTQ parser can continue after it.
+/// When allowing truncated files, an incomplete last record, or a decoder error for
+/// data ending unexpectedly, ends the data after the last complete record.
+struct RecordFilter<R> {
+    inner: R,
+    state: Arc<FilterState>,
+    check_records: bool,
+    allow_truncated: bool,
+    lines: VecDeque<Vec<u8>>,
+    eof: bool,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> RecordFilter<R> {
+    fn new(inner: R, state: Arc<FilterState>, check_records: bool, allow_truncated: bool) -> Self {
+        RecordFilter {
+            inner,
+            state,
+            check_records,
+            allow_truncated,
+            lines: VecDeque::new(),
+            eof: false,
+            out: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Read lines until there are `n` lines ahead, or the end of the input
+    fn fill_lines(&mut self, n: usize) -> io::Result<()> {
+        while !self.eof && self.lines.len() < n {
+            let mut line = Vec::new();
+            match self.inner.read_until(b'\n', &mut line) {
+                Ok(0) => self.eof = true,
+                Ok(_) => self.lines.push_back(line),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && self.allow_truncated => {
+                    self.eof = true;
+                    self.state.truncated.store(true, Ordering::Relaxed);
+                    if !line.is_empty() {
+                        self.lines.push_back(line);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the next record, or the record replacing it if it is malformed, to `out`
+    fn next_record(&mut self) -> io::Result<()> {
+        fn trim(line: &[u8]) -> &[u8] {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            line.strip_suffix(b"\r").unwrap_or(line)
+        }
+        let marker = |lines: &VecDeque<Vec<u8>>, i: usize, c: u8| {
+            lines.get(i).and_then(|l| l.first()) == Some(&c)
+        };
+
+        self.fill_lines(4)?;
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        let complete = self.lines.len() == 4
+            && (self.lines[3].ends_with(b"\n")
+                || trim(&self.lines[3]).len() >= trim(&self.lines[1]).len());
+        if self.eof && !complete && self.allow_truncated {
+            let bytes: usize = self.lines.drain(..).map(|l| l.len()).sum();
+            self.state.truncated.store(true, Ordering::Relaxed);
+            self.state
+                .truncated_bytes
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        let aligned = marker(&self.lines, 2, b'+');
+        if !self.check_records
+            || (self.lines.len() == 4
+                && aligned
+                && marker(&self.lines, 0, b'@')
+                && trim(&self.lines[1]).len() == trim(&self.lines[3]).len())
+        {
+            for mut line in self.lines.drain(..) {
+                if !line.ends_with(b"\n") {
+                    line.push(b'\n');
+                }
+                self.out.extend_from_slice(&line);
+            }
+            return Ok(());
+        }
+
+        let first = self.lines.pop_front().unwrap();
+        if aligned {
+            // The record has 4 lines, drop the other 3
+            self.lines.drain(..self.lines.len().min(3));
+        } else {
+            // Lines are missing, drop lines up to the next header
+            loop {
+                self.fill_lines(3)?;
+                if self.lines.is_empty()
+                    || (marker(&self.lines, 0, b'@') && marker(&self.lines, 2, b'+'))
+                {
+                    break;
+                }
+                self.lines.pop_front();
+            }
+        }
+
+        let first = trim(&first);
+        let first = first.strip_prefix(b"@").unwrap_or(first);
+        self.out.push(b'@');
+        self.out.extend_from_slice(MALFORMED_HEADER);
+        self.out.extend_from_slice(&first[..first.len().min(200)]);
+        self.out.extend_from_slice(b"\n\n+\n\n");
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for RecordFilter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = self.fill_buf()?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for RecordFilter<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.out.len() {
+            self.out.clear();
+            self.pos = 0;
+            while self.out.len() < 32 * 1024 {
+                let len = self.out.len();
+                self.next_record()?;
+                if self.out.len() == len {
+                    break;
+                }
+            }
+        }
+        Ok(&self.out[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// Location of a read pair in its input FASTQs: the index of the record (or of the
+/// interleaved pair of records) within the files, counting records skipped by
+/// subsampling. `source` identifies the set of input files and is assigned by the
+/// caller with [`ReadPairIter::source_id`](struct.ReadPairIter.html#method.source_id).
+///
+/// The provenance is not stored in the `ReadPair`, to keep read pairs compact. It is
+/// returned along with each read pair by
+/// [`ReadPairIter::with_provenance`](struct.ReadPairIter.html#method.with_provenance),
+/// and has to be carried alongside the read pair by downstream stages that need it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Provenance {
+    pub source: u32,
+    pub record: u64,
+    /// Offset of the first record of the read pair in the uncompressed data of the
+    /// R1 file, as reported by [`ReadPairIter::tell`](struct.ReadPairIter.html#method.tell).
+    /// `None` once a malformed record has been skipped, which makes the offsets inexact.
+    #[serde(default)]
+    pub offset: Option<u64>,
+}
+
+/// Position of a `ReadPairIter` in its input FASTQs, returned by
+/// [`ReadPairIter::tell`](struct.ReadPairIter.html#method.tell), from which the
+/// iteration can be resumed with
+/// [`ReadPairIter::resume_from`](struct.ReadPairIter.html#method.resume_from).
+/// It can be stored as JSON to checkpoint a long-running job.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReadPairOffsets {
+    /// Offset of the next record in the uncompressed data of each input file,
+    /// in the order R1, R2, I1, I2
+    pub offsets: [Option<u64>; 4],
+    /// Number of records read from each input file
+    pub records: [u64; 4],
+    /// The reads stored in consecutive records of the R1 file
+    pub interleaved_reads: Vec<WhichRead>,
+}
+
+/// Iterator over read pairs along with their `Provenance`,
+/// created by [`ReadPairIter::with_provenance`](struct.ReadPairIter.html#method.with_provenance).
+pub struct ProvenanceIter {
+    inner: ReadPairIter,
+}
+
+impl ProvenanceIter {
+    /// The underlying `ReadPairIter`
+    pub fn get_ref(&self) -> &ReadPairIter {
+        &self.inner
+    }
+}
+
+impl Iterator for ProvenanceIter {
+    type Item = Result<(Provenance, ReadPair), FastqError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let inner = &mut self.inner;
+        inner.next().map(|r| r.map(|rp| (inner.provenance(), rp)))
+    }
+}
+
+/// Iterator over read pairs along with the bases removed by the maximum read
+/// lengths, created by [`ReadPairIter::with_trims`](struct.ReadPairIter.html#method.with_trims).
+pub struct TrimmedReadPairIter {
+    inner: ReadPairIter,
+}
+
+impl TrimmedReadPairIter {
+    /// The underlying `ReadPairIter`
+    pub fn get_ref(&self) -> &ReadPairIter {
+        &self.inner
+    }
+}
+
+impl Iterator for TrimmedReadPairIter {
+    type Item = Result<TrimmedReadPair, FastqError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let inner = &mut self.inner;
+        inner.next().map(|r| {
+            r.map(|read_pair| TrimmedReadPair {
+                read_pair,
+                trims: inner.trims(),
+            })
+        })
+    }
+}
+
+/// Iterator over batches of read pairs sharing a buffer,
+/// created by [`ReadPairIter::batches`](struct.ReadPairIter.html#method.batches).
+pub struct ReadPairBatches {
+    inner: ReadPairIter,
+    batch_size: usize,
+}
+
+impl Iterator for ReadPairBatches {
+    type Item = Result<Vec<ReadPair>, FastqError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Start each batch in a fresh buffer, so the batch does not keep
+        // the tail of the previous batch's buffer alive.
+        self.inner.buffer = BytesMut::with_capacity(self.inner.buffer_size);
+
+        let mut batch = Vec::with_capacity(self.batch_size);
+        let mut bytes = 0;
+        while batch.len() < self.batch_size {
+            match self.inner.next() {
+                Some(Ok(rp)) => {
+                    bytes += rp.heap_bytes();
+                    batch.push(rp);
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None => break,
+            }
+        }
+        if batch.is_empty() {
+            return None;
+        }
+
+        // Leave some headroom for batches with longer reads
+        self.inner.buffer_size = (bytes + bytes / 8).max(BUF_SIZE);
+        Some(Ok(batch))
+    }
 }
 
 impl ReadPairIter {
@@ -219,39 +902,90 @@ impl ReadPairIter {
         )
     }
 
-    /// Open a FASTQ file that is uncompressed, gzipped compressed, or lz4 compressed.
+    /// Open a FASTQ file that is uncompressed, or gzip, lz4, zstd or xz compressed.
     /// The extension of the file is ignored & the filetype is determined by looking
     /// for magic bytes at the of the file
     fn open_fastq(p: impl AsRef<Path>) -> Result<Box<dyn BufRead + Send>, FastqError> {
         let p = p.as_ref();
+        let file = std::fs::File::open(p).open_err(p)?;
+        Self::decode_fastq(file, p)
+    }
 
-        let mut file = std::fs::File::open(p).fastq_err(p, 0)?;
+    /// Wrap a reader of FASTQ data that is uncompressed, or gzip, lz4, zstd or xz
+    /// compressed into a `BufRead` of the uncompressed data. The compression is determined
+    /// by looking for magic bytes at the start of the stream. `p` is only used in error messages.
+    /// Gzip data may consist of several concatenated members.
+    pub(crate) fn decode_fastq<R: Read + Send + 'static>(
+        reader: R,
+        p: &Path,
+    ) -> Result<Box<dyn BufRead + Send>, FastqError> {
+        Self::decode_fastq_threads(reader, p, &Arc::new(AtomicUsize::new(BGZF_THREADS)))
+    }
 
-        let mut buf = vec![0u8; 4];
-        file.read_exact(&mut buf[..]).fastq_err(p, 0)?;
-        file.seek(std::io::SeekFrom::Start(0)).fastq_err(p, 0)?;
+    /// Like `decode_fastq`, decompressing BGZF data on the number of threads in
+    /// `bgzf_threads`
+    fn decode_fastq_threads<R: Read + Send + 'static>(
+        reader: R,
+        p: &Path,
+        bgzf_threads: &Arc<AtomicUsize>,
+    ) -> Result<Box<dyn BufRead + Send>, FastqError> {
+        // Read enough of the stream to detect the compression, as a single read can
+        // return fewer bytes than the magic numbers
+        let mut reader = reader;
+        let mut magic = Vec::with_capacity(BGZF_HEADER_LEN);
+        (&mut reader)
+            .take(BGZF_HEADER_LEN as u64)
+            .read_to_end(&mut magic)
+            .fastq_err(p, 0)?;
+        let buf = &magic[..];
+        let reader =
+            BufReader::with_capacity(32 * 1024, io::Cursor::new(buf.to_vec()).chain(reader));
+
+        if buf.is_empty() {
+            // An empty chunk, e.g. from a demultiplexer without reads for a sample
+            return Ok(Box::new(reader));
+        }
+        if buf.len() < 4 {
+            let e = io::Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer");
+            return Err(e).fastq_err(p, 0);
+        }
 
-        if &buf[0..2] == &[0x1F, 0x8B] {
-            let gz = flate2::read::MultiGzDecoder::new(file);
-            let buf_reader = BufReader::with_capacity(GZ_BUF_SIZE, gz);
+        if BgzfReader::<R>::is_bgzf(buf) {
+            let bgzf = BgzfReader::with_shared_threads(reader, bgzf_threads.clone());
+            let buf_reader = BufReader::with_capacity(GZ_BUF_SIZE, hinted(bgzf, Suggestion::Gzip));
             Ok(Box::new(buf_reader))
-        } else if &buf[0..4] == &[0x04, 0x22, 0x4D, 0x18] {
-            let lz = lz4::Decoder::new(file).fastq_err(p, 0)?;
-            let buf_reader = BufReader::with_capacity(GZ_BUF_SIZE, lz);
+        } else if buf[0..2] == [0x1F, 0x8B] {
+            let gz = flate2::bufread::MultiGzDecoder::new(reader);
+            let buf_reader = BufReader::with_capacity(GZ_BUF_SIZE, hinted(gz, Suggestion::Gzip));
             Ok(Box::new(buf_reader))
-        } else if buf[0] == b'@' {
-            let buf_reader = BufReader::with_capacity(32 * 1024, file);
+        } else if buf.starts_with(&LZ4_MAGIC) {
+            let lz = lz4::Decoder::new(reader).fastq_err(p, 0)?;
+            let buf_reader = BufReader::with_capacity(GZ_BUF_SIZE, hinted(lz, Suggestion::Lz4));
+            Ok(Box::new(buf_reader))
+        } else if buf.starts_with(&ZSTD_MAGIC) {
+            let zstd = zstd::Decoder::with_buffer(reader).fastq_err(p, 0)?;
+            let buf_reader = BufReader::with_capacity(GZ_BUF_SIZE, hinted(zstd, Suggestion::Zstd));
             Ok(Box::new(buf_reader))
+        } else if buf.starts_with(&XZ_MAGIC) {
+            let xz = xz2::bufread::XzDecoder::new_multi_decoder(reader);
+            let buf_reader = BufReader::with_capacity(GZ_BUF_SIZE, hinted(xz, Suggestion::Xz));
+            Ok(Box::new(buf_reader))
+        } else if buf[0] == b'@' {
+            Ok(Box::new(reader))
         } else {
             let msg =
-            "FASTQ file does not appear to be valid. Input FASTQ file must be gzip or lz4 compressed, or must begin with the '@' symbol".to_string();
-            let e = FastqError::format(msg, p, 0);
+            "FASTQ file does not appear to be valid. Input FASTQ file must be gzip, lz4, zstd or xz compressed, or must begin with the '@' symbol".to_string();
+            let e = FastqError::format(msg, p, 0).suggest(Suggestion::NotFastq);
             Err(e)
         }
     }
 
     /// Open a (possibly gzip or lz4 compressed) FASTQ file & read some records to confirm the format looks good.
-    fn open_fastq_confirm_fmt(p: impl AsRef<Path>) -> Result<Box<dyn BufRead + Send>, FastqError> {
+    fn open_fastq_confirm_fmt(
+        p: impl AsRef<Path>,
+        counters: &Arc<IoCounters>,
+        bgzf_threads: &Arc<AtomicUsize>,
+    ) -> Result<Box<dyn BufRead + Send>, FastqError> {
         let p = p.as_ref();
         let reader = Self::open_fastq(p)?;
         let parser = fastq::Parser::new(reader);
@@ -261,7 +995,7 @@ impl ReadPairIter {
         let mut iter = parser.ref_iter();
 
         for rec in 0..10 {
-            iter.advance().fastq_err(p, rec * 4)?;
+            iter.advance().fastq_err(p, rec)?;
             let rec = iter.get();
             if rec.is_none() {
                 break;
@@ -269,7 +1003,25 @@ impl ReadPairIter {
         }
 
         // re-open file so we re-read the initial records
-        Self::open_fastq(p)
+        let file = std::fs::File::open(p).open_err(p)?;
+        Self::decode_counted(file, p, counters, bgzf_threads)
+    }
+
+    /// Decode a reader with `decode_fastq_threads`, tracking the I/O of the reader
+    /// and of the decoder in `counters`.
+    fn decode_counted<R: Read + Send + 'static>(
+        reader: R,
+        p: &Path,
+        counters: &Arc<IoCounters>,
+        bgzf_threads: &Arc<AtomicUsize>,
+    ) -> Result<Box<dyn BufRead + Send>, FastqError> {
+        let raw = CountingReader::raw(reader, counters.clone());
+        let decoded = Self::decode_fastq_threads(raw, p, bgzf_threads)?;
+        Ok(Box::new(CountingReader {
+            inner: decoded,
+            counters: counters.clone(),
+            decoded: true,
+        }))
     }
 
     /// Open a `ReadPairIter` given of FASTQ files.
@@ -282,41 +1034,270 @@ impl ReadPairIter {
         i2: Option<P>,
         r1_interleaved: bool,
     ) -> Result<ReadPairIter, FastqError> {
-        let mut iters = [None, None, None, None];
+        let mut readers = [None, None, None, None];
         let mut paths = [None, None, None, None];
+        let mut io = [None, None, None, None];
+
+        let r1_reads = if r1_interleaved {
+            vec![WhichRead::R1, WhichRead::R2]
+        } else {
+            vec![WhichRead::R1]
+        };
+        let bgzf_threads = Arc::new(AtomicUsize::new(BGZF_THREADS));
 
         for (idx, r) in [r1, r2, i1, i2].iter().enumerate() {
             if let Some(ref p) = *r {
-                let rdr = Self::open_fastq_confirm_fmt(p)?;
-                let parser = fastq::Parser::new(rdr);
-                iters[idx] = Some(parser.ref_iter());
+                let counters = Arc::new(IoCounters::default());
+                readers[idx] = Some(Self::open_fastq_confirm_fmt(p, &counters, &bgzf_threads)?);
                 paths[idx] = Some(p.as_ref().to_path_buf());
+                io[idx] = Some(counters);
             }
         }
 
+        Self::from_parts(readers, paths, io, r1_reads, bgzf_threads)
+    }
+
+    /// Open a `ReadPairIter` over readers supplying FASTQ data for the available
+    /// read components, in the order R1, R2, I1, I2. Each reader can supply uncompressed,
+    /// gzip or lz4 compressed data, which is detected from the first bytes of the stream.
+    /// Unlike `new()`, no records are read up front to validate the format, so format
+    /// errors are reported by the iterator. Errors refer to the readers by the name of
+    /// the read component (e.g. `read1`) in place of a file path.
+    /// For interleaved R1/R2 data, set `readers[1] = None`, and set
+    /// `r1_interleaved = true`.
+    pub fn from_readers<R: Read + Send + 'static>(
+        mut readers: [Option<R>; 4],
+        r1_interleaved: bool,
+    ) -> Result<ReadPairIter, FastqError> {
+        let mut decoded = [None, None, None, None];
+        let mut paths = [None, None, None, None];
+        let mut io = [None, None, None, None];
+
+        let r1_reads = if r1_interleaved {
+            vec![WhichRead::R1, WhichRead::R2]
+        } else {
+            vec![WhichRead::R1]
+        };
+        let bgzf_threads = Arc::new(AtomicUsize::new(BGZF_THREADS));
+
+        for (idx, r) in readers.iter_mut().enumerate() {
+            if let Some(r) = r.take() {
+                let name = PathBuf::from(WhichRead::read_types()[idx].to_string());
+                let counters = Arc::new(IoCounters::default());
+                decoded[idx] = Some(Self::decode_counted(r, &name, &counters, &bgzf_threads)?);
+                paths[idx] = Some(name);
+                io[idx] = Some(counters);
+            }
+        }
+
+        Self::from_parts(decoded, paths, io, r1_reads, bgzf_threads)
+    }
+
+    /// Detect the reads interleaved in the FASTQ file `p` from the records at its start,
+    /// for use with [`interleave`](#method.interleave). Consecutive records with the
+    /// same read name make up a read pair. With 2 records per read pair, the records are
+    /// R1 and R2. With 3 or 4 records per read pair, the read numbers in the headers
+    /// (`1:N:0:...`) are taken to give the order in which the reads were sequenced, R1
+    /// first, then I1 and I2, then R2, which is the numbering of BCL_PROCESSOR files.
+    ///
+    /// Returns an error if the layout is ambiguous: the read pairs at the start of the
+    /// file have different numbers of records, or 3 or 4 records lack distinct read
+    /// numbers, or are stored in the order of their read numbers, which is the order
+    /// of the cycles with the numbering above but also the R1, R2, I1, I2 order with a
+    /// numbering following the file layout. Use `interleave` with the layout of the file
+    /// in these cases.
+    pub fn detect_interleaved_reads(p: impl AsRef<Path>) -> Result<Vec<WhichRead>, FastqError> {
+        let p = p.as_ref();
+        let mut iter = fastq::Parser::new(Self::open_fastq(p)?).ref_iter();
+        // Two read pairs of up to 4 records, and the record after them
+        let mut headers = Vec::new();
+        while headers.len() < 9 {
+            iter.advance().fastq_err(p, headers.len())?;
+            match iter.get() {
+                Some(rec) => headers.push(rec.head().to_vec()),
+                None => break,
+            }
+        }
+        let ambiguous = |reason: &str| {
+            let msg = format!(
+                "Cannot detect the reads interleaved in the FASTQ file: {}",
+                reason
+            );
+            Err(FastqError::format(msg, p, 0).suggest(Suggestion::Interleaved))
+        };
+        if headers.is_empty() {
+            return ambiguous("the file has no records");
+        }
+
+        let group_len = |start: usize| {
+            headers[start..]
+                .iter()
+                .take_while(|h| read_name(h) == read_name(&headers[start]))
+                .count()
+        };
+        let group = group_len(0);
+        if group < 2 {
+            return ambiguous("consecutive records have different read names");
+        } else if group > 4 {
+            return ambiguous("more than 4 consecutive records have the same read name");
+        }
+        if headers.len() > group {
+            // The second read pair can be incomplete if the file ends within it
+            let next = group_len(group);
+            if next > group || (next < group && group + next < headers.len()) {
+                return ambiguous("read pairs have different numbers of records");
+            }
+        }
+        if group == 2 {
+            return Ok(vec![WhichRead::R1, WhichRead::R2]);
+        }
+
+        // The read number is the first field of the comment
+        let read_numbers: Option<Vec<u32>> = headers[..group]
+            .iter()
+            .map(|h| {
+                let comment = h.splitn(2, |&c| c == b' ').nth(1)?;
+                let number = comment.split(|&c| c == b':').next()?;
+                std::str::from_utf8(number).ok()?.trim().parse().ok()
+            })
+            .collect();
+        let numbers = match read_numbers {
+            Some(n) if (1..n.len()).all(|i| !n[..i].contains(&n[i])) => n,
+            _ => return ambiguous("the records of a read pair lack distinct read numbers"),
+        };
+        if numbers.windows(2).all(|w| w[0] < w[1]) {
+            return ambiguous("the records of a read pair are in the order of their read numbers");
+        }
+
+        let mut order: Vec<usize> = (0..group).collect();
+        order.sort_by_key(|&i| numbers[i]);
+        let mut by_cycle = vec![WhichRead::R1, WhichRead::I1, WhichRead::I2];
+        by_cycle.truncate(group - 1);
+        by_cycle.push(WhichRead::R2);
+        let mut reads = vec![WhichRead::R1; group];
+        for (rank, &i) in order.iter().enumerate() {
+            reads[i] = by_cycle[rank];
+        }
+        Ok(reads)
+    }
+
+    /// Check that the reads interleaved in the R1 file are distinct, start with R1,
+    /// and aren't also given as separate files.
+    fn check_interleaved(
+        r1_reads: &[WhichRead],
+        paths: &[Option<PathBuf>; 4],
+    ) -> Result<(), FastqError> {
+        let path = paths.iter().flatten().next().unwrap();
+        let distinct = (1..r1_reads.len()).all(|i| !r1_reads[..i].contains(&r1_reads[i]));
+        if r1_reads.first() != Some(&WhichRead::R1) || !distinct {
+            let msg = format!(
+                "Interleaved reads must be distinct and start with R1, got {:?}",
+                r1_reads
+            );
+            return Err(FastqError::format(msg, path, 0));
+        }
+        if let Some(&which) = r1_reads[1..].iter().find(|&&w| paths[w as usize].is_some()) {
+            let msg = format!(
+                "Interleaved FASTQ file contains {} records, but {} was also given as a separate file",
+                which, which
+            );
+            return Err(FastqError::format(msg, path, 0));
+        }
+        Ok(())
+    }
+
+    fn from_parts(
+        readers: [Option<Box<dyn BufRead + Send>>; 4],
+        paths: [Option<PathBuf>; 4],
+        io: [Option<Arc<IoCounters>>; 4],
+        r1_reads: Vec<WhichRead>,
+        bgzf_threads: Arc<AtomicUsize>,
+    ) -> Result<ReadPairIter, FastqError> {
+        Self::check_interleaved(&r1_reads, &paths)?;
         let buffer = BytesMut::with_capacity(BUF_SIZE);
+        let mut filters = [None, None, None, None];
+        for (filter, reader) in filters.iter_mut().zip(readers.iter()) {
+            if reader.is_some() {
+                *filter = Some(Arc::new(FilterState::default()));
+            }
+        }
 
         Ok(ReadPairIter {
+            readers,
             paths,
-            iters,
-            r1_interleaved,
+            iters: [None, None, None, None],
+            r1_reads,
             buffer,
+            buffer_size: BUF_SIZE,
             rand: XorShiftRng::seed_from_u64(0),
             uniform: Uniform::new(0.0, 1.0),
             subsample_rate: 1.0,
             storage: ReadPairStorage::default(),
             records_read: [0; 4],
-            read_lengths: [std::usize::MAX; 4],
+            config: ReadPairConfig::default(),
+            dropped_bases: [0; 4],
+            last_trims: TrimProvenance::default(),
+            qual_offsets: [ILLUMINA_QUAL_OFFSET; 4],
+            drop_qual: [false; 4],
+            source_id: 0,
+            last_record: 0,
+            last_offset: None,
+            io,
+            time_io: false,
+            advance_nanos: [0; 4],
+            malformed_policy: MalformedRecordPolicy::Fail,
+            allow_truncated: false,
+            filters,
+            malformed_records: 0,
+            malformed_headers: Vec::new(),
+            truncated: false,
+            bytes_read: [0; 4],
+            bgzf_threads,
+            reject_empty: false,
+            check_read_names: false,
+            name_buf: Vec::new(),
         })
     }
 
-    pub fn illumina_r1_trim_length(mut self, r1_length: Option<usize>) -> Self {
-        self.read_lengths[WhichRead::R1 as usize] = r1_length.unwrap_or(std::usize::MAX);
-        self
+    /// The reads stored in consecutive records of the R1 file, e.g. `[R1, R2]` for an
+    /// interleaved R1/R2 file, or `[R1]` if the file is not interleaved.
+    pub fn interleaved_reads(&self) -> &[WhichRead] {
+        &self.r1_reads
+    }
+
+    /// Read `reads` from consecutive records of the R1 file, e.g. `[R1, R2, I1, I2]`
+    /// for a file interleaving all the reads of a read pair. The layout of a file can be
+    /// found with [`detect_interleaved_reads`](#method.detect_interleaved_reads).
+    /// Returns an error if `reads` doesn't start with R1, repeats a read, or has a
+    /// read that is also given as a separate file, or if records were already read.
+    pub fn interleave(mut self, reads: &[WhichRead]) -> Result<Self, FastqError> {
+        Self::check_interleaved(reads, &self.paths)?;
+        if self.records_read.iter().any(|&n| n > 0) {
+            let msg = "Cannot change the interleaved reads after reading records".to_string();
+            return Err(FastqError::format(
+                msg,
+                self.paths.iter().flatten().next().unwrap(),
+                0,
+            ));
+        }
+        self.r1_reads = reads.to_vec();
+        Ok(self)
+    }
+
+    pub fn illumina_r1_trim_length(self, r1_length: Option<usize>) -> Self {
+        self.trim_length(WhichRead::R1, r1_length)
     }
 
-    pub fn illumina_r2_trim_length(mut self, r2_length: Option<usize>) -> Self {
-        self.read_lengths[WhichRead::R2 as usize] = r2_length.unwrap_or(std::usize::MAX);
+    pub fn illumina_r2_trim_length(self, r2_length: Option<usize>) -> Self {
+        self.trim_length(WhichRead::R2, r2_length)
+    }
+
+    /// Only keep the first `length` bases of read `which`, e.g. to use 50bp of R2
+    /// for counting. Reads are truncated as they are parsed, so the trimmed bases
+    /// don't take up memory or space in serialized read pairs. `None` keeps the
+    /// full read.
+    pub fn trim_length(mut self, which: WhichRead, length: Option<usize>) -> Self {
+        self.config.max_len[which as usize] = length;
         self
     }
 
@@ -335,100 +1316,560 @@ impl ReadPairIter {
         self
     }
 
+    /// ASCII offset of the quality scores in each input file, in the order R1, R2, I1, I2.
+    /// For interleaved input the R1 offset applies to both reads. Quality strings
+    /// from files with an offset other than 33, such as legacy phred+64 data, are
+    /// converted to phred+33, so that reads from inputs with mixed encodings have
+    /// consistent quality values. Defaults to 33 for all files. Returns an error
+    /// if an offset is below 33.
+    pub fn quality_offsets(mut self, offsets: [u8; 4]) -> Result<Self, FastqError> {
+        if let Some(idx) = offsets.iter().position(|&o| o < ILLUMINA_QUAL_OFFSET) {
+            let msg = format!(
+                "Quality offset {} of {} is below {}",
+                offsets[idx],
+                WhichRead::read_types()[idx],
+                ILLUMINA_QUAL_OFFSET
+            );
+            let path = self.paths.iter().flatten().next().unwrap();
+            return Err(FastqError::format(msg, path, 0));
+        }
+        self.qual_offsets = offsets;
+        Ok(self)
+    }
+
+    /// Don't keep the quality string of read `which`, to save memory when the
+    /// qualities of that read are never used. `ReadPair::get(which, ReadPart::Qual)`
+    /// returns `None` for the read, and the read can't be written to a FASTQ file.
+    pub fn drop_qual(mut self, which: WhichRead) -> Self {
+        self.drop_qual[which as usize] = true;
+        self
+    }
+
+    /// Identifier of the input files reported in the `Provenance` of each read pair.
+    /// Defaults to 0.
+    pub fn source_id(mut self, source_id: u32) -> Self {
+        self.source_id = source_id;
+        self
+    }
+
+    /// How to handle malformed FASTQ records, so that a single corrupt record can be
+    /// skipped rather than aborting a long run. Defaults to `MalformedRecordPolicy::Fail`.
+    /// Malformed records among the first records of each file are still reported
+    /// as errors by `new()`, which validates them when opening the files. The
+    /// policy applies from the first read pair, so it can't be changed after that.
+    pub fn malformed_record_policy(mut self, policy: MalformedRecordPolicy) -> Self {
+        self.malformed_policy = policy;
+        self
+    }
+
+    /// End the iteration cleanly at the last complete read pair when an input file
+    /// is truncated, e.g. by a sequencer crash, rather than returning an error. A file
+    /// is truncated if its last record is incomplete, or if its compressed data ends
+    /// unexpectedly. The truncation of each file is reported by `truncated_bytes`.
+    /// Like other format errors, truncation within the first records of a file is
+    /// still reported as an error by `new()`. Like `malformed_record_policy`, this
+    /// can't be changed once the first read pair has been read.
+    pub fn allow_truncated(mut self, allow: bool) -> Self {
+        self.allow_truncated = allow;
+        self
+    }
+
+    /// Measure the time spent reading, decompressing and parsing each input file,
+    /// reported in `io_stats`. Timing every read adds overhead to the iteration, so
+    /// it is off by default.
+    pub fn time_io(mut self, time: bool) -> Self {
+        self.time_io = time;
+        for counters in self.io.iter().flatten() {
+            counters.timed.store(time, Ordering::Relaxed);
+        }
+        self
+    }
+
+    /// Number of threads decompressing each BGZF input file. Defaults to 1, which
+    /// decompresses on the thread reading the records, as for other compressions.
+    /// With more threads, each BGZF file gets a pool of threads that decompress
+    /// ahead of the records being read.
+    pub fn decompression_threads(self, threads: usize) -> Self {
+        self.bgzf_threads.store(threads.max(1), Ordering::Relaxed);
+        self
+    }
+
+    /// Check that the records read from each file at the same position have the same
+    /// read name, ignoring the comment after the first space or tab and a `/1` to `/4`
+    /// read number suffix, and return an error naming the read pair index and the
+    /// mismatched names otherwise. Unlike the check of the headers that is always
+    /// made, this covers the read pairs skipped by subsampling and compares the
+    /// complete names, so that files that got out of sync are never silently paired.
+    pub fn check_read_names(mut self, check: bool) -> Self {
+        self.check_read_names = check;
+        self
+    }
+
+    /// Return an error if the input files contain no records, rather than ending the
+    /// iteration without read pairs. Empty files, including empty gzip files, are
+    /// produced legitimately by demultiplexers for samples without reads, so they
+    /// are accepted by default.
+    pub fn reject_empty(mut self, reject: bool) -> Self {
+        self.reject_empty = reject;
+        self
+    }
+
+    /// For each input file in the order R1, R2, I1, I2, the number of bytes of
+    /// uncompressed data after the last complete record of the file, if the file was
+    /// found to be truncated with `allow_truncated`. Data that could not be decompressed
+    /// is not counted, so the count is 0 if the compressed data of a file ends at a
+    /// record boundary.
+    pub fn truncated_bytes(&self) -> [Option<u64>; 4] {
+        let mut bytes = [None; 4];
+        for (b, filter) in bytes.iter_mut().zip(self.filters.iter()) {
+            if let Some(f) = filter {
+                if f.truncated.load(Ordering::Relaxed) {
+                    *b = Some(f.truncated_bytes.load(Ordering::Relaxed));
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Number of read pairs containing a malformed record so far, which were
+    /// skipped or ended the iteration according to the `MalformedRecordPolicy`
+    pub fn malformed_records(&self) -> u64 {
+        self.malformed_records
+    }
+
+    /// The headers of the first malformed records, or the start of their first line
+    /// if it isn't a header
+    pub fn malformed_headers(&self) -> &[String] {
+        &self.malformed_headers
+    }
+
+    /// Iterate over batches of up to `batch_size` read pairs. The read pairs use
+    /// `ReadPairStorage::SharedBuffer`, and each batch is read into a single buffer
+    /// sized from the number of bytes in the previous batch, so that a batch
+    /// usually costs one allocation rather than one per read pair.
+    ///
+    /// # Panics
+    /// * If `batch_size` is 0
+    pub fn batches(self, batch_size: usize) -> ReadPairBatches {
+        assert!(batch_size > 0, "batch_size must be positive");
+        ReadPairBatches {
+            inner: self.storage(ReadPairStorage::SharedBuffer),
+            batch_size,
+        }
+    }
+
+    /// Iterate over the read pairs along with their `Provenance`
+    pub fn with_provenance(self) -> ProvenanceIter {
+        ProvenanceIter { inner: self }
+    }
+
+    /// Iterate over the read pairs along with the bases removed from each read
+    /// by the maximum read lengths, recorded as the `hard_clip` of its
+    /// `TrimProvenance`, so that the full length of the reads is available from
+    /// `TrimmedReadPair::untrimmed_len`.
+    pub fn with_trims(self) -> TrimmedReadPairIter {
+        TrimmedReadPairIter { inner: self }
+    }
+
+    /// The bases removed by the maximum read lengths from the last read pair
+    /// returned by the iterator
+    pub fn trims(&self) -> TrimProvenance {
+        self.last_trims
+    }
+
+    /// The `Provenance` of the last read pair returned by the iterator
+    pub fn provenance(&self) -> Provenance {
+        Provenance {
+            source: self.source_id,
+            record: self.last_record,
+            offset: self.last_offset,
+        }
+    }
+
+    /// Paths of the input files for each read component, or the name of the read
+    /// component for iterators created by `from_readers()`.
+    pub fn paths(&self) -> &[Option<PathBuf>; 4] {
+        &self.paths
+    }
+
+    /// I/O statistics of the input file for each read component, covering all
+    /// the records read so far. For interleaved input the statistics of the
+    /// interleaved file are reported for R1. The times are zero unless `time_io`
+    /// is enabled.
+    pub fn io_stats(&self) -> [Option<IoStats>; 4] {
+        let mut stats = [None; 4];
+        for (idx, counters) in self.io.iter().enumerate() {
+            if let Some(c) = counters {
+                let raw_nanos = c.raw_nanos.load(Ordering::Relaxed);
+                let decoded_nanos = c.decoded_nanos.load(Ordering::Relaxed).max(raw_nanos);
+                let advance_nanos = self.advance_nanos[idx].max(decoded_nanos);
+                stats[idx] = Some(IoStats {
+                    compressed_bytes: c.raw_bytes.load(Ordering::Relaxed),
+                    decompressed_bytes: c.decoded_bytes.load(Ordering::Relaxed),
+                    read_time: Duration::from_nanos(raw_nanos),
+                    decompress_time: Duration::from_nanos(decoded_nanos - raw_nanos),
+                    parse_time: Duration::from_nanos(advance_nanos - decoded_nanos),
+                });
+            }
+        }
+        stats
+    }
+
+    /// The position of the iterator in its input files, to resume the iteration
+    /// after the last read pair returned with `resume_from`. The offsets are only
+    /// exact if no malformed record was skipped, since malformed records are
+    /// replaced before they are parsed, and if the records have a bare `+`
+    /// separator line and `\n` line endings, as the offsets are computed from the
+    /// lengths of the record fields. The state of the subsampling random number
+    /// generator is not part of the position.
+    pub fn tell(&self) -> ReadPairOffsets {
+        let mut offsets = [None; 4];
+        let mut records = [0; 4];
+        for idx in 0..4 {
+            if self.paths[idx].is_some() {
+                offsets[idx] = Some(self.bytes_read[idx]);
+                records[idx] = self.records_read[idx] as u64;
+            }
+        }
+        ReadPairOffsets {
+            offsets,
+            records,
+            interleaved_reads: self.r1_reads.clone(),
+        }
+    }
+
+    /// Open a `ReadPairIter` over `input_fastqs` starting at `offsets`, as returned by
+    /// `tell()` on an iterator over the same files. BGZF and uncompressed files are
+    /// opened at the offset directly, using the member sizes in BGZF files, while
+    /// files with other compressions are decompressed from the start up to the offset.
+    /// Settings such as trim lengths or the subsample rate are not part of `offsets`
+    /// and must be set again.
+    pub fn resume_from(
+        input_fastqs: &InputFastqs,
+        offsets: &ReadPairOffsets,
+    ) -> Result<ReadPairIter, FastqError> {
+        let files = [
+            Some(&input_fastqs.r1),
+            input_fastqs.r2.as_ref(),
+            input_fastqs.i1.as_ref(),
+            input_fastqs.i2.as_ref(),
+        ];
+
+        let mut readers = [None, None, None, None];
+        let mut paths = [None, None, None, None];
+        let mut io = [None, None, None, None];
+        let bgzf_threads = Arc::new(AtomicUsize::new(BGZF_THREADS));
+
+        for (idx, file) in files.iter().enumerate() {
+            match (file, offsets.offsets[idx]) {
+                (Some(p), Some(offset)) => {
+                    let p = Path::new(p);
+                    let counters = Arc::new(IoCounters::default());
+                    readers[idx] = Some(Self::open_fastq_at(p, offset, &counters, &bgzf_threads)?);
+                    paths[idx] = Some(p.to_path_buf());
+                    io[idx] = Some(counters);
+                }
+                (None, None) => {}
+                _ => {
+                    let msg = format!(
+                        "The offsets to resume from don't match the input files: {} {}",
+                        WhichRead::read_types()[idx],
+                        if file.is_some() {
+                            "is given but has no offset"
+                        } else {
+                            "has an offset but is not given"
+                        }
+                    );
+                    return Err(FastqError::format(msg, &input_fastqs.r1, 0));
+                }
+            }
+        }
+
+        let mut iter = Self::from_parts(
+            readers,
+            paths,
+            io,
+            offsets.interleaved_reads.clone(),
+            bgzf_threads,
+        )?;
+        for idx in 0..4 {
+            iter.records_read[idx] = offsets.records[idx] as usize;
+            iter.bytes_read[idx] = offsets.offsets[idx].unwrap_or(0);
+        }
+        Ok(iter)
+    }
+
+    /// Open the FASTQ file `p` at `offset` in its uncompressed data, tracking the
+    /// I/O in `counters`.
+    fn open_fastq_at(
+        p: &Path,
+        offset: u64,
+        counters: &Arc<IoCounters>,
+        bgzf_threads: &Arc<AtomicUsize>,
+    ) -> Result<Box<dyn BufRead + Send>, FastqError> {
+        let mut file = std::fs::File::open(p).open_err(p)?;
+        let mut header = Vec::with_capacity(BGZF_HEADER_LEN);
+        (&mut file)
+            .take(BGZF_HEADER_LEN as u64)
+            .read_to_end(&mut header)
+            .fastq_err(p, 0)?;
+
+        let decoded: Box<dyn BufRead + Send> = if BgzfReader::<io::Empty>::is_bgzf(&header) {
+            file.seek(SeekFrom::Start(0)).fastq_err(p, 0)?;
+            let skip = block_gz::bgzf_seek(&mut file, offset).fastq_err(p, 0)?;
+            let raw = CountingReader {
+                inner: file,
+                counters: counters.clone(),
+                decoded: false,
+            };
+            let bgzf = BgzfReader::with_shared_threads(raw, bgzf_threads.clone());
+            let mut rdr = BufReader::with_capacity(GZ_BUF_SIZE, bgzf);
+            Self::skip_bytes(&mut rdr, skip, p)?;
+            Box::new(rdr)
+        } else if header.starts_with(b"@") {
+            file.seek(SeekFrom::Start(offset)).fastq_err(p, 0)?;
+            let raw = CountingReader {
+                inner: file,
+                counters: counters.clone(),
+                decoded: false,
+            };
+            Box::new(BufReader::with_capacity(32 * 1024, raw))
+        } else {
+            file.seek(SeekFrom::Start(0)).fastq_err(p, 0)?;
+            let mut rdr = Self::decode_counted(file, p, counters, bgzf_threads)?;
+            Self::skip_bytes(&mut rdr, offset, p)?;
+            return Ok(rdr);
+        };
+        Ok(Box::new(CountingReader {
+            inner: decoded,
+            counters: counters.clone(),
+            decoded: true,
+        }))
+    }
+
+    /// Skip `bytes` bytes of `rdr`, failing if it ends first
+    fn skip_bytes(rdr: &mut dyn BufRead, bytes: u64, p: &Path) -> Result<(), FastqError> {
+        let skipped = io::copy(&mut rdr.take(bytes), &mut io::sink()).fastq_err(p, 0)?;
+        if skipped < bytes {
+            let msg = format!(
+                "FASTQ file is shorter than the offset {} to resume from",
+                bytes
+            );
+            return Err(FastqError::format(msg, p, 0));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn io_bytes(&self) -> IoBytes {
+        IoBytes(self.io.clone())
+    }
+
+    /// Number of bases removed from each read so far by the maximum read
+    /// lengths set with `trim_length`, in the order R1, R2, I1, I2. These are the
+    /// `hard_clip` lengths recorded in `trims`, summed over all sampled read pairs.
+    pub fn dropped_bases(&self) -> [u64; 4] {
+        self.dropped_bases
+    }
+
+    /// Create the FASTQ parsers of the input files on the first read, once the
+    /// settings are known. The readers are only wrapped in a `RecordFilter` when
+    /// checking records or allowing truncated files, so that other iterations
+    /// parse the decoded data directly.
+    fn start_parsers(&mut self) {
+        let check_records = self.malformed_policy != MalformedRecordPolicy::Fail;
+        let allow_truncated = self.allow_truncated;
+        let inputs = self
+            .readers
+            .iter_mut()
+            .zip(self.iters.iter_mut())
+            .zip(self.filters.iter());
+        for ((reader, iter), filter) in inputs {
+            if let Some(rdr) = reader.take() {
+                let rdr: Box<dyn BufRead + Send> = if check_records || allow_truncated {
+                    let state = filter.clone().unwrap();
+                    Box::new(RecordFilter::new(
+                        rdr,
+                        state,
+                        check_records,
+                        allow_truncated,
+                    ))
+                } else {
+                    rdr
+                };
+                *iter = Some(fastq::Parser::new(rdr).ref_iter());
+            }
+        }
+    }
+
     fn get_next(&mut self) -> Result<Option<ReadPair>, FastqError> {
         // Recycle the buffer if it's almost full.
-        if self.buffer.remaining_mut() < 512 {
-            self.buffer = BytesMut::with_capacity(BUF_SIZE)
+        if self.buffer.capacity() - self.buffer.len() < 512 {
+            self.buffer = BytesMut::with_capacity(self.buffer_size)
+        }
+
+        if self.truncated {
+            return Ok(None);
         }
+        self.start_parsers();
 
         // need these local reference to avoid borrow checker problem
         let paths = &self.paths;
         let rec_num = &mut self.records_read;
-
-        let mut rp = MutReadPair::empty(&mut self.buffer).storage(self.storage);
+        let advance_nanos = &mut self.advance_nanos;
+        let time_io = self.time_io;
 
         loop {
+            // Drop the reads of a skipped read pair
+            self.buffer.clear();
+            let mut rp = MutReadPair::empty(&mut self.buffer).storage(self.storage);
+            let mut trims = TrimProvenance::default();
+
             let sample = self.uniform.sample(&mut self.rand) < self.subsample_rate;
+            let pair_index = rec_num[0] / self.r1_reads.len();
+            let offset = self.bytes_read[0];
+            // Header of the first malformed record of the read pair
+            let mut malformed = None;
+            // Read and file of the first record of the read pair, whose name is in `name_buf`
+            let mut first_name: Option<(WhichRead, usize)> = None;
 
             // Track which reader was the first to finish.
             let mut iter_ended = [false; 4];
 
             for (idx, iter_opt) in self.iters.iter_mut().enumerate() {
                 if let Some(ref mut iter) = *iter_opt {
-                    iter.advance()
-                        .fastq_err(paths[idx].as_ref().unwrap(), rec_num[idx] * 4)?;
+                    // The R1 file can interleave several reads
+                    let reads = if idx == 0 {
+                        &self.r1_reads[..]
+                    } else {
+                        &WhichRead::read_types()[idx..=idx]
+                    };
+
+                    for (k, &which) in reads.iter().enumerate() {
+                        let res = if time_io {
+                            let start = Instant::now();
+                            let res = iter.advance();
+                            advance_nanos[idx] += start.elapsed().as_nanos() as u64;
+                            res
+                        } else {
+                            iter.advance()
+                        };
+                        res.fastq_err(paths[idx].as_ref().unwrap(), rec_num[idx])?;
 
-                    {
                         let record = iter.get();
-                        // track which reader finished
-                        if record.is_none() {
-                            iter_ended[idx] = true;
+                        if let Some(ref r) = record {
+                            self.bytes_read[idx] += record_len(r) as u64;
                         }
-
-                        // Check for non-ACGTN characters
-                        if let Some(ref rec) = record {
-                            if !fastq::Record::validate_dnan(rec) {
-                                let msg =
-                                    "FASTQ contains sequence base with character other than [ACGTN].".to_string();
-                                let e = FastqError::format(
-                                    msg,
-                                    paths[idx].as_ref().unwrap(),
-                                    rec_num[idx] * 4,
-                                );
-                                return Err(e);
-                            }
-                        }
-
-                        if sample {
-                            let which = WhichRead::read_types()[idx];
-                            let read_length = self.read_lengths[which as usize];
-                            record.map(|r| {
-                                let tr = TrimRecord::new(&r, read_length);
-                                rp.push_read(&tr, which)
-                            });
-                        }
-
-                        rec_num[idx] += 1;
-                    }
-
-                    // If R1 is interleaved, read another entry
-                    // and store it as R2
-                    if idx == 0 && self.r1_interleaved && !iter_ended[idx] {
-                        iter.advance()
-                            .fastq_err(paths[idx].as_ref().unwrap(), (rec_num[idx] + 1) * 4)?;
-                        let record = iter.get();
                         if record.is_none() {
-                            // We should only hit this if the FASTQ has an odd
-                            // number of records. Throw an error
-                            let msg = "Input FASTQ file was input as interleaved R1 and R2, but contains an odd number of records".to_string();
-                            let e = FastqError::format(
+                            if k == 0 {
+                                // track which reader finished
+                                iter_ended[idx] = true;
+                                break;
+                            }
+                            let filter = self.filters[idx].as_ref().unwrap();
+                            if filter.truncated.load(Ordering::Relaxed) {
+                                // The interleaved file was truncated within a read pair
+                                self.truncated = true;
+                                return Ok(None);
+                            }
+                            // We should only hit this if the number of records of an
+                            // interleaved FASTQ is not a multiple of the reads it holds.
+                            // Throw an error
+                            let msg = if reads.len() == 2 {
+                                "Input FASTQ file was input as interleaved R1 and R2, but contains an odd number of records".to_string()
+                            } else {
+                                format!("Input FASTQ file was input as interleaving {} reads, but its number of records is not a multiple of {}", reads.len(), reads.len())
+                            };
+                            let e = FastqError::record_format(
                                 msg,
-                                self.paths[idx].as_ref().unwrap(),
-                                rec_num[idx] * 4,
-                            );
+                                paths[idx].as_ref().unwrap(),
+                                rec_num[idx],
+                            )
+                            .suggest(Suggestion::Interleaved);
                             return Err(e);
                         }
 
                         // Check for non-ACGTN characters
                         if let Some(ref rec) = record {
-                            if !fastq::Record::validate_dnan(rec) {
-                                let msg = "FASTQ contains sequence base with character other than [ACGTN].";
-                                let e = FastqError::format(
-                                    msg.to_string(),
-                                    paths[idx].as_ref().unwrap(),
-                                    rec_num[idx] * 4,
-                                );
-                                return Err(e);
+                            let bad_header = if let Some(h) =
+                                rec.head().strip_prefix(MALFORMED_HEADER)
+                            {
+                                Some(h)
+                            } else if !fastq::Record::validate_dnan(rec) {
+                                if self.malformed_policy == MalformedRecordPolicy::Fail {
+                                    let msg =
+                                        "FASTQ contains sequence base with character other than [ACGTN].".to_string();
+                                    let e = FastqError::record_format(
+                                        msg,
+                                        paths[idx].as_ref().unwrap(),
+                                        rec_num[idx],
+                                    )
+                                    .suggest(Suggestion::InvalidBase);
+                                    return Err(e);
+                                }
+                                Some(rec.head())
+                            } else {
+                                None
+                            };
+                            if let (None, Some(h)) = (&malformed, bad_header) {
+                                malformed = Some(String::from_utf8_lossy(h).into_owned());
+                            }
+
+                            if self.check_read_names && bad_header.is_none() {
+                                let name = read_name(rec.head());
+                                match first_name {
+                                    None => {
+                                        self.name_buf.clear();
+                                        self.name_buf.extend_from_slice(name);
+                                        first_name = Some((which, idx));
+                                    }
+                                    Some((first, first_idx)) if name != &self.name_buf[..] => {
+                                        let msg = format!(
+                                            "Read names are out of sync at read pair {}: {} record {:?} in {:?} but {} record {:?}",
+                                            pair_index,
+                                            first,
+                                            String::from_utf8_lossy(&self.name_buf),
+                                            paths[first_idx].as_ref().unwrap(),
+                                            which,
+                                            String::from_utf8_lossy(name),
+                                        );
+                                        let e = FastqError::record_format(
+                                            msg,
+                                            paths[idx].as_ref().unwrap(),
+                                            rec_num[idx],
+                                        )
+                                        .suggest(Suggestion::HeaderMismatch);
+                                        return Err(e);
+                                    }
+                                    Some(_) => {}
+                                }
                             }
                         }
 
-                        if sample {
-                            let which = WhichRead::read_types()[idx + 1];
-                            let read_length = self.read_lengths[which as usize];
-                            record.map(|r| {
-                                let tr = TrimRecord::new(&r, read_length);
-                                rp.push_read(&tr, which)
-                            });
+                        if let (true, Some(r), None) = (sample, record, &malformed) {
+                            let kept = self.config.kept_len(which, r.seq().len());
+                            let dropped = r.seq().len() - kept;
+                            if dropped > 0 {
+                                // Open-ended if the read is too long to record its length
+                                trims.read_mut(which).hard_clip =
+                                    RpRange::try_new(which, kept, Some(dropped))
+                                        .or_else(|_| RpRange::try_new(which, kept, None))
+                                        .ok();
+                                self.dropped_bases[which as usize] += dropped as u64;
+                            }
+                            let tr = TrimRecord::new(&r, kept);
+                            let qual_offset = self.qual_offsets[idx];
+                            rp.push_read(&tr, which)
+                                .and_then(|_| rp.normalize_qual(which, qual_offset))
+                                .map_err(|e| {
+                                    FastqError::record_format(
+                                        e.to_string(),
+                                        paths[idx].as_ref().unwrap(),
+                                        rec_num[idx],
+                                    )
+                                })?;
+                            if self.drop_qual[which as usize] {
+                                rp.drop_qual(which);
+                            }
                         }
 
                         rec_num[idx] += 1;
@@ -436,6 +1877,18 @@ impl ReadPairIter {
                 }
             }
 
+            if let Some(header) = malformed {
+                self.malformed_records += 1;
+                if self.malformed_headers.len() < MAX_MALFORMED_HEADERS {
+                    self.malformed_headers.push(header);
+                }
+                if self.malformed_policy == MalformedRecordPolicy::Truncate {
+                    self.truncated = true;
+                    return Ok(None);
+                }
+                continue;
+            }
+
             // check that headers of all reads match
             let mut header_slices = Vec::with_capacity(4);
 
@@ -443,24 +1896,33 @@ impl ReadPairIter {
             for w in 0..4 {
                 if let Some(header) = rp.get(which[w], ReadPart::Header) {
                     let prefix = header.split(|x| *x == b' ' || *x == b'/').next();
-                    header_slices.push((w, prefix));
+                    // The file and the index of the record holding the read, which
+                    // is in the R1 file for interleaved reads
+                    let (file, record) = match self.r1_reads.iter().position(|&r| r == which[w]) {
+                        Some(k) => (0, rec_num[0] - self.r1_reads.len() + k),
+                        None => (w, rec_num[w] - 1),
+                    };
+                    header_slices.push((file, record, prefix));
                 }
             }
 
             if !header_slices.is_empty() {
-                for i in 1..header_slices.len() {
-                    if header_slices[i].1 != header_slices[0].1 {
-                        let msg = format!("FASTQ header mismatch detected at line {} of input files {:?} and {:?}",
-                                rec_num[0] * 4,
-                                self.paths[header_slices[0].0].as_ref().unwrap(),
-                                self.paths[header_slices[i].0].as_ref().unwrap()
+                let (first_file, first_record, first_prefix) = header_slices[0];
+                for &(file, record, prefix) in &header_slices[1..] {
+                    if prefix != first_prefix {
+                        let msg = format!("FASTQ header mismatch detected at line {} of input file {:?} and line {} of input file {:?}",
+                                first_record * 4,
+                                self.paths[first_file].as_ref().unwrap(),
+                                record * 4,
+                                self.paths[file].as_ref().unwrap()
                             );
 
-                        let e = FastqError::format(
+                        let e = FastqError::record_format(
                             msg,
-                            self.paths[header_slices[0].0].as_ref().unwrap(),
-                            rec_num[i] * 4,
-                        );
+                            self.paths[first_file].as_ref().unwrap(),
+                            first_record,
+                        )
+                        .suggest(Suggestion::HeaderMismatch);
                         return Err(e);
                     }
                 }
@@ -474,36 +1936,279 @@ impl ReadPairIter {
                     .zip(self.paths.iter())
                     .any(|(ended, path)| path.is_some() && !ended);
 
-                if any_not_complete {
+                let truncated = self
+                    .filters
+                    .iter()
+                    .flatten()
+                    .any(|f| f.truncated.load(Ordering::Relaxed));
+                if any_not_complete && !truncated {
                     // Index of a finished iterator
                     let ended_index = iter_ended.iter().enumerate().find(|(_, v)| **v).unwrap().0;
 
                     let msg = "Input FASTQ file ended prematurely";
                     let path = self.paths[ended_index].as_ref().unwrap();
-                    let e = FastqError::format(msg.to_string(), path, rec_num[ended_index] * 4);
+                    let e = FastqError::record_format(msg.to_string(), path, rec_num[ended_index])
+                        .suggest(Suggestion::RecordCount);
                     return Err(e);
+                } else if self.reject_empty && !truncated && rec_num.iter().all(|&n| n == 0) {
+                    let msg = "Input FASTQ file contains no records";
+                    return Err(FastqError::format(
+                        msg.to_string(),
+                        paths.iter().flatten().next().unwrap(),
+                        0,
+                    ));
                 } else {
+                    // Don't read on from the files that didn't end at a truncated file
+                    self.truncated = truncated;
                     return Ok(None);
                 }
             }
-
-            if sample {
-                return Ok(Some(rp.freeze()));
+
+            if sample {
+                self.last_trims = trims;
+                self.last_record = pair_index as u64;
+                self.last_offset = if self.malformed_records == 0 {
+                    Some(offset)
+                } else {
+                    None
+                };
+                return Ok(Some(rp.freeze()));
+            }
+        }
+    }
+}
+
+impl Iterator for ReadPairIter {
+    type Item = Result<ReadPair, FastqError>;
+
+    /// Iterate over ReadPair objects
+    fn next(&mut self) -> Option<Result<ReadPair, FastqError>> {
+        // Convert Result<Option<_>, Error> to Option<Result<_, Error>>
+        match self.get_next() {
+            Ok(Some(v)) => Some(Ok(v)),
+            Ok(None) => None,
+            Err(v) => Some(Err(v)),
+        }
+    }
+}
+
+/// Re-read specific read pairs from their source FASTQs, given their `Provenance`.
+/// `sources` maps the `source` of each provenance to the FASTQ files it was read from.
+/// The read pairs are returned in the order of `provenance`. If every file of a source
+/// has a block index (see the [`block_gz`](../block_gz/index.html) module), only the
+/// blocks holding the requested records are decompressed. Otherwise the source is read
+/// sequentially up to the last requested record, so this is meant for fetching a
+/// limited number of records for debugging or targeted re-processing.
+pub fn fetch_records<S: BuildHasher>(
+    sources: &HashMap<u32, InputFastqs, S>,
+    provenance: &[Provenance],
+) -> Result<Vec<ReadPair>, Error> {
+    let mut by_source: BTreeMap<u32, Vec<(u64, usize)>> = BTreeMap::new();
+    for (idx, p) in provenance.iter().enumerate() {
+        by_source.entry(p.source).or_default().push((p.record, idx));
+    }
+
+    let mut result = vec![None; provenance.len()];
+    for (source, mut wanted) in by_source {
+        let fastqs = sources
+            .get(&source)
+            .ok_or_else(|| format_err!("No FASTQ files given for source {}", source))?;
+        wanted.sort_unstable();
+        let mut wanted = wanted.into_iter().peekable();
+        let not_found =
+            |record| format_err!("Record {} not found in FASTQ files {:?}", record, fastqs);
+
+        match block_indexes(fastqs) {
+            Some(indexes) => {
+                let blocks = &indexes[0].2.blocks;
+                while let Some(&(record, _)) = wanted.peek() {
+                    let block = blocks
+                        .iter()
+                        .position(|b| record < b.first_read_pair + b.read_pairs)
+                        .ok_or_else(|| not_found(record))?;
+                    let first = blocks[block].first_read_pair;
+                    let mut readers = [None, None, None, None];
+                    for (idx, path, index) in &indexes {
+                        readers[*idx] = Some(index.block_reader(path, block)?);
+                    }
+                    let iter = ReadPairIter::from_readers(readers, fastqs.r1_interleaved)?
+                        .with_provenance()
+                        .map(|rp| rp.map(|(prov, rp)| (first + prov.record, rp)));
+                    take_wanted(iter, &mut wanted, &mut result)?;
+
+                    let end = first + blocks[block].read_pairs;
+                    if let Some(&(record, _)) = wanted.peek().filter(|(r, _)| *r < end) {
+                        return Err(not_found(record));
+                    }
+                }
+            }
+            None => {
+                let iter = ReadPairIter::from_fastq_files(fastqs)?
+                    .with_provenance()
+                    .map(|rp| rp.map(|(prov, rp)| (prov.record, rp)));
+                take_wanted(iter, &mut wanted, &mut result)?;
+                if let Some((record, _)) = wanted.next() {
+                    return Err(not_found(record));
+                }
+            }
+        }
+    }
+    Ok(result.into_iter().map(Option::unwrap).collect())
+}
+
+/// The block index of each file of `fastqs`, along with its read component and path,
+/// if every file has one and the blocks of all the files hold the same read pairs
+fn block_indexes(fastqs: &InputFastqs) -> Option<Vec<(usize, &str, block_gz::BlockIndex)>> {
+    let paths = [
+        Some(&fastqs.r1),
+        fastqs.r2.as_ref(),
+        fastqs.i1.as_ref(),
+        fastqs.i2.as_ref(),
+    ];
+    let mut indexes = Vec::new();
+    for (idx, path) in paths.iter().enumerate() {
+        if let Some(path) = path {
+            let index = block_gz::BlockIndex::read(path).ok()?;
+            indexes.push((idx, path.as_str(), index));
+        }
+    }
+    let same_blocks = |index: &block_gz::BlockIndex| {
+        index.blocks.len() == indexes[0].2.blocks.len()
+            && index.blocks.iter().zip(&indexes[0].2.blocks).all(|(a, b)| {
+                a.first_read_pair == b.first_read_pair && a.read_pairs == b.read_pairs
+            })
+    };
+    if indexes.iter().all(|(_, _, index)| same_blocks(index)) {
+        Some(indexes)
+    } else {
+        None
+    }
+}
+
+/// Store the read pairs of `iter` that are `wanted`, given as sorted (record, index in
+/// `result`) pairs, until the iterator ends or no more records are wanted
+fn take_wanted(
+    iter: impl Iterator<Item = Result<(u64, ReadPair), FastqError>>,
+    wanted: &mut std::iter::Peekable<std::vec::IntoIter<(u64, usize)>>,
+    result: &mut [Option<ReadPair>],
+) -> Result<(), Error> {
+    for rp in iter {
+        let (record, rp) = rp?;
+        while let Some(&(_, idx)) = wanted.peek().filter(|(r, _)| *r == record) {
+            result[idx] = Some(rp.clone());
+            wanted.next();
+        }
+        if wanted.peek().is_none() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// How `sample_reads` picks reads from the input FASTQs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SampleStrategy {
+    /// The first reads of the files
+    Head,
+    /// Reads evenly spaced over the files, at least `stride` reads apart. The
+    /// spacing doubles as more of the files is read, so the sample covers all the
+    /// reads read.
+    Strided { stride: usize },
+    /// A uniform random sample of the reads of the files, computed by reservoir
+    /// sampling.
+    Reservoir { seed: u64 },
+}
+
+/// Most read pairs allocated up front by `sample_reads`, which can be asked for more
+/// reads than the files hold
+const MAX_SAMPLE_CAPACITY: usize = 1 << 16;
+
+/// Sample up to `n` read pairs from `inputs`. Sampling reads spread across the files
+/// avoids biasing detection of the chemistry or quality toward the first tiles of
+/// the flowcell, at the cost of reading more of the file than the `Head` strategy.
+/// If `time_limit` is set, the files are only read until the time limit expires,
+/// and the sample is drawn from the reads seen so far.
+/// The reads are returned in the order in which they appear in the files.
+pub fn sample_reads(
+    inputs: &InputFastqs,
+    n: usize,
+    strategy: SampleStrategy,
+    time_limit: Option<Duration>,
+) -> Result<Vec<ReadPair>, FastqError> {
+    let iter = ReadPairIter::from_fastq_files(inputs)?;
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let start = Instant::now();
+    let expired = || {
+        time_limit
+            .filter(|&limit| start.elapsed() >= limit)
+            .is_some()
+    };
+    let capacity = n.min(MAX_SAMPLE_CAPACITY);
+
+    match strategy {
+        SampleStrategy::Head => {
+            let mut reads = Vec::with_capacity(capacity);
+            for rp in iter {
+                reads.push(rp?);
+                if reads.len() == n || expired() {
+                    break;
+                }
+            }
+            Ok(reads)
+        }
+        SampleStrategy::Strided { stride } => {
+            // Keep every `step`-th read. Once 2n reads are kept, drop every other
+            // one and double the step, so the kept reads span all the reads read.
+            let mut step = stride.max(1);
+            let mut kept = Vec::with_capacity(capacity);
+            for (i, rp) in iter.enumerate() {
+                let rp = rp?;
+                if i % step == 0 {
+                    kept.push(rp);
+                    if kept.len() >= n.saturating_mul(2) {
+                        kept = kept.into_iter().step_by(2).collect();
+                        step *= 2;
+                    }
+                }
+                if expired() {
+                    break;
+                }
+            }
+            // Take n evenly spaced reads of the fewer than 2n kept reads, from the
+            // first to the last
+            let len = kept.len();
+            if len <= n {
+                return Ok(kept);
             }
+            let picks: Vec<usize> = (0..n).map(|j| j * (len - 1) / (n - 1).max(1)).collect();
+            Ok(kept
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| picks.binary_search(i).is_ok())
+                .map(|(_, rp)| rp)
+                .collect())
         }
-    }
-}
-
-impl Iterator for ReadPairIter {
-    type Item = Result<ReadPair, FastqError>;
-
-    /// Iterate over ReadPair objects
-    fn next(&mut self) -> Option<Result<ReadPair, FastqError>> {
-        // Convert Result<Option<_>, Error> to Option<Result<_, Error>>
-        match self.get_next() {
-            Ok(Some(v)) => Some(Ok(v)),
-            Ok(None) => None,
-            Err(v) => Some(Err(v)),
+        SampleStrategy::Reservoir { seed } => {
+            let mut rng = XorShiftRng::seed_from_u64(seed);
+            let mut reservoir = Vec::with_capacity(capacity);
+            for (i, rp) in iter.with_provenance().enumerate() {
+                let (prov, rp) = rp?;
+                if i < n {
+                    reservoir.push((prov.record, rp));
+                } else {
+                    let j = Uniform::new(0, i + 1).sample(&mut rng);
+                    if j < n {
+                        reservoir[j] = (prov.record, rp);
+                    }
+                }
+                if expired() {
+                    break;
+                }
+            }
+            reservoir.sort_by_key(|(record, _)| *record);
+            Ok(reservoir.into_iter().map(|(_, rp)| rp).collect())
         }
     }
 }
@@ -512,7 +2217,7 @@ type BackgroundReadPairIter =
     crate::background_iterator::BackgroundIterator<Result<ReadPair, FastqError>>;
 
 pub(crate) enum AnyReadPairIter {
-    Direct(ReadPairIter),
+    Direct(Box<ReadPairIter>),
     Background(BackgroundReadPairIter),
 }
 
@@ -532,6 +2237,7 @@ impl Iterator for AnyReadPairIter {
 #[cfg(test)]
 mod test_read_pair_iter {
     use super::*;
+    use crate::read_pair::RpRange;
     use file_diff::diff_files;
     use std::fs::File;
     use std::io::Write;
@@ -663,6 +2369,1046 @@ mod test_read_pair_iter {
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn test_empty_chunks() {
+        use crate::block_gz::{BlockConfig, BlockFormat, BlockGzWriter};
+        use flate2::write::GzEncoder;
+
+        let empty_gz = GzEncoder::new(Vec::new(), flate2::Compression::default())
+            .finish()
+            .unwrap();
+        let empty_bgzf = BlockGzWriter::new(Vec::new(), BlockConfig::new(BlockFormat::Bgzf, 1), 2)
+            .unwrap()
+            .finish()
+            .unwrap()
+            .0;
+        let paths = ["tests/empty-R1.fastq", "tests/empty-R2.fastq.gz"];
+        std::fs::write(paths[0], b"").unwrap();
+        std::fs::write(paths[1], &empty_gz).unwrap();
+
+        for data in &[Vec::new(), empty_gz, empty_bgzf] {
+            let mut it = ReadPairIter::from_readers(
+                [Some(io::Cursor::new(data.clone())), None, None, None],
+                true,
+            )
+            .unwrap();
+            assert!(it.next().is_none());
+
+            let mut it = ReadPairIter::from_readers(
+                [Some(io::Cursor::new(data.clone())), None, None, None],
+                true,
+            )
+            .unwrap()
+            .reject_empty(true);
+            assert!(it.next().unwrap().is_err());
+        }
+
+        let it = ReadPairIter::new(Some(paths[0]), Some(paths[1]), None, None, false).unwrap();
+        assert_eq!(it.count(), 0);
+        let mut it = ReadPairIter::new(Some(paths[0]), Some(paths[1]), None, None, false)
+            .unwrap()
+            .reject_empty(true);
+        let err = it.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("no records"), "{}", err);
+
+        // Empty chunks of a single file are still mismatched with the other files
+        let it = ReadPairIter::new(
+            Some(paths[0]),
+            None,
+            Some("tests/read_pair_iter/good-I1.fastq"),
+            None,
+            false,
+        )
+        .unwrap();
+        let res: Result<Vec<ReadPair>, FastqError> = it.collect();
+        assert!(res.is_err());
+
+        // Non-empty files are unaffected
+        let it = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap()
+        .reject_empty(true);
+        assert!(it.collect::<Result<Vec<_>, _>>().is_ok());
+
+        for p in &paths {
+            std::fs::remove_file(p).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_check_read_names() {
+        let records = |names: &[&str], read: u32| {
+            let data: String = names
+                .iter()
+                .map(|n| format!("@{} {}:N:0:0\nACGT\n+\nIIII\n", n, read))
+                .collect();
+            io::Cursor::new(data.into_bytes())
+        };
+        let r1 = ["a/1", "b/1", "c/1", "d/1"];
+        let open = |r2: &[&str]| {
+            ReadPairIter::from_readers(
+                [Some(records(&r1, 1)), Some(records(r2, 2)), None, None],
+                false,
+            )
+            .unwrap()
+            .check_read_names(true)
+        };
+
+        // Read number suffixes and comments are ignored
+        assert_eq!(open(&["a/2", "b/2", "c", "d/2"]).count(), 4);
+
+        let err = open(&["a/2", "b/2", "x/2", "d/2"])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("read pair 2"), "{}", err);
+        assert!(err.contains("\"c\"") && err.contains("\"x\""), "{}", err);
+
+        // Read pairs skipped by subsampling are checked too
+        let res: Result<Vec<_>, _> = open(&["a/2", "x/2", "c/2", "d/2"])
+            .subsample_rate(0.0)
+            .collect();
+        assert!(res.is_err());
+        let res: Result<Vec<_>, _> = open(&["a/2", "x/2", "c/2", "d/2"])
+            .check_read_names(false)
+            .subsample_rate(0.0)
+            .collect();
+        assert!(res.unwrap().is_empty());
+
+        // Only the part before the first '/' is compared by the default check
+        let r2 = ["a/2", "b/2", "c/x", "d/2"];
+        let mut it = open(&r2);
+        assert!(it.nth(2).unwrap().is_err());
+        let it = open(&r2).check_read_names(false);
+        assert_eq!(it.count(), 4);
+
+        // Mismatched names in an interleaved file are reported as errors
+        let interleaved = records(&["a/1", "a/2", "b/1", "x/2"], 1);
+        for &check in &[false, true] {
+            let res: Result<Vec<_>, _> =
+                ReadPairIter::from_readers([Some(interleaved.clone()), None, None, None], true)
+                    .unwrap()
+                    .check_read_names(check)
+                    .collect();
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn test_from_readers() {
+        let ra = std::fs::read("tests/read_pair_iter/good-RA.fastq").unwrap();
+        let gz = std::fs::read("tests/read_pair_iter/good-gzipped-RA.fastq.gz").unwrap();
+        let lz4 = std::fs::read("tests/read_pair_iter/good-lz4-RA.fastq.lz4").unwrap();
+        let expected: Vec<ReadPair> = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+        // Concatenated gzip members, e.g. from `cat a.fastq.gz b.fastq.gz`
+        let multi_gz = [&gz[..], &gz[..]].concat();
+        let it =
+            ReadPairIter::from_readers([Some(io::Cursor::new(multi_gz)), None, None, None], true)
+                .unwrap();
+        let res: Vec<ReadPair> = it.collect::<Result<_, _>>().unwrap();
+        assert_eq!(res, [&expected[..], &expected[..]].concat());
+
+        // zstd and xz, including concatenated frames and streams
+        let zst = zstd::encode_all(&ra[..], 3).unwrap();
+        let mut xz = Vec::new();
+        xz2::read::XzEncoder::new(&ra[..], 6)
+            .read_to_end(&mut xz)
+            .unwrap();
+        for data in [zst, xz].iter() {
+            let concat = [&data[..], &data[..]].concat();
+            let it =
+                ReadPairIter::from_readers([Some(io::Cursor::new(concat)), None, None, None], true)
+                    .unwrap();
+            let res: Vec<ReadPair> = it.collect::<Result<_, _>>().unwrap();
+            assert_eq!(res, [&expected[..], &expected[..]].concat());
+        }
+
+        for data in [ra, gz, lz4].iter() {
+            let data = io::Cursor::new(data.clone());
+            let it = ReadPairIter::from_readers([Some(data), None, None, None], true).unwrap();
+            let res: Vec<ReadPair> = it.collect::<Result<_, _>>().unwrap();
+            assert_eq!(res, expected);
+        }
+
+        // Readers returning fewer bytes than the magic numbers per read, e.g. pipes
+        struct OneByte(io::Cursor<Vec<u8>>);
+        impl Read for OneByte {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = buf.len().min(1);
+                self.0.read(&mut buf[..len])
+            }
+        }
+        let gz = std::fs::read("tests/read_pair_iter/good-gzipped-RA.fastq.gz").unwrap();
+        let data = OneByte(io::Cursor::new(gz));
+        let it = ReadPairIter::from_readers([Some(data), None, None, None], true).unwrap();
+        let res: Vec<ReadPair> = it.collect::<Result<_, _>>().unwrap();
+        assert_eq!(res, expected);
+        let short = OneByte(io::Cursor::new(b"@r".to_vec()));
+        assert!(ReadPairIter::from_readers([Some(short), None, None, None], true).is_err());
+
+        let it = ReadPairIter::from_readers(
+            [Some(io::Cursor::new(b"garbage".to_vec())), None, None, None],
+            false,
+        );
+        assert!(it.is_err());
+
+        let truncated = b"@read\nACGT\n+\nII".to_vec();
+        let it =
+            ReadPairIter::from_readers([Some(io::Cursor::new(truncated)), None, None, None], false)
+                .unwrap();
+        let res: Result<Vec<ReadPair>, FastqError> = it.collect();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_malformed_records() {
+        fn fastq(read: u32, bad: &[(usize, &str)]) -> io::Cursor<Vec<u8>> {
+            let mut data = String::new();
+            for i in 0..5 {
+                match bad.iter().find(|b| b.0 == i) {
+                    Some(b) => data.push_str(b.1),
+                    None => data.push_str(&format!("@r{} {}:N:0:0\nACGT\n+\nIIII\n", i, read)),
+                }
+            }
+            io::Cursor::new(data.into_bytes())
+        }
+        fn names(reads: &[ReadPair]) -> Vec<&[u8]> {
+            reads
+                .iter()
+                .map(|rp| &rp.get(WhichRead::R2, ReadPart::Header).unwrap()[..2])
+                .collect()
+        }
+        let short_qual = (2, "@r2 1:N:0:0\nACGT\n+\nII\n");
+        let bad_marker = (1, "r1 4:N:0:0\nACGT\n+\nIIII\n");
+        let missing_line = (3, "@r3 1:N:0:0\nACGT\nIIII\n");
+        let bad_base = (4, "@r4 4:N:0:0\nACXT\n+\nIIII\n");
+
+        for &bad in [short_qual, missing_line].iter() {
+            let it = ReadPairIter::from_readers(
+                [Some(fastq(1, &[bad])), Some(fastq(4, &[])), None, None],
+                false,
+            )
+            .unwrap();
+            let res: Result<Vec<ReadPair>, FastqError> = it.collect();
+            assert!(res.is_err());
+        }
+        let it = ReadPairIter::from_readers(
+            [Some(fastq(1, &[])), Some(fastq(4, &[bad_base])), None, None],
+            false,
+        )
+        .unwrap();
+        let res: Result<Vec<ReadPair>, FastqError> = it.collect();
+        assert!(res.is_err());
+
+        let mut it = ReadPairIter::from_readers(
+            [
+                Some(fastq(1, &[short_qual, missing_line])),
+                Some(fastq(4, &[])),
+                None,
+                None,
+            ],
+            false,
+        )
+        .unwrap()
+        .malformed_record_policy(MalformedRecordPolicy::Skip);
+        let reads: Vec<ReadPair> = it.by_ref().collect::<Result<_, _>>().unwrap();
+        assert_eq!(names(&reads), [b"r0", b"r1", b"r4"]);
+        assert_eq!(it.malformed_records(), 2);
+        assert_eq!(it.malformed_headers(), ["r2 1:N:0:0", "r3 1:N:0:0"]);
+
+        let mut it = ReadPairIter::from_readers(
+            [
+                Some(fastq(1, &[])),
+                Some(fastq(4, &[bad_marker, bad_base])),
+                None,
+                None,
+            ],
+            false,
+        )
+        .unwrap()
+        .malformed_record_policy(MalformedRecordPolicy::Skip);
+        let reads: Vec<ReadPair> = it.by_ref().collect::<Result<_, _>>().unwrap();
+        assert_eq!(names(&reads), [b"r0", b"r2", b"r3"]);
+        assert_eq!(it.malformed_headers(), ["r1 4:N:0:0", "r4 4:N:0:0"]);
+
+        let mut it = ReadPairIter::from_readers(
+            [
+                Some(fastq(1, &[short_qual])),
+                Some(fastq(4, &[])),
+                None,
+                None,
+            ],
+            false,
+        )
+        .unwrap()
+        .malformed_record_policy(MalformedRecordPolicy::Truncate);
+        let reads: Vec<ReadPair> = it.by_ref().collect::<Result<_, _>>().unwrap();
+        assert_eq!(names(&reads), [b"r0", b"r1"]);
+        assert_eq!(it.malformed_records(), 1);
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn test_allow_truncated() {
+        let expected: Vec<ReadPair> = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+        let gz = std::fs::read("tests/read_pair_iter/good-gzipped-RA.fastq.gz").unwrap();
+        let truncated = || io::Cursor::new(gz[..gz.len() * 2 / 3].to_vec());
+
+        let it = ReadPairIter::from_readers([Some(truncated()), None, None, None], true).unwrap();
+        let res: Result<Vec<ReadPair>, FastqError> = it.collect();
+        assert!(res.is_err());
+
+        let mut it = ReadPairIter::from_readers([Some(truncated()), None, None, None], true)
+            .unwrap()
+            .allow_truncated(true);
+        let reads: Vec<ReadPair> = it.by_ref().collect::<Result<_, _>>().unwrap();
+        assert!(!reads.is_empty() && reads.len() < expected.len());
+        assert_eq!(reads, &expected[..reads.len()]);
+        assert!(it.truncated_bytes()[0].is_some());
+
+        // The read pairs end at the last complete record of the truncated file
+        let records = |read: u32, n: usize| {
+            let data: String = (0..n)
+                .map(|i| format!("@r{} {}:N:0:0\nACGT\n+\nIIII\n", i, read))
+                .collect();
+            data.into_bytes()
+        };
+        let mut r1 = records(1, 4);
+        r1.extend_from_slice(b"@r4 1:N:0:0\nAC");
+        let readers = [
+            Some(io::Cursor::new(r1)),
+            Some(io::Cursor::new(records(4, 5))),
+            None,
+            None,
+        ];
+        let mut it = ReadPairIter::from_readers(readers.clone(), false)
+            .unwrap()
+            .allow_truncated(true);
+        assert_eq!(it.by_ref().count(), 4);
+        assert_eq!(it.truncated_bytes(), [Some(14), None, None, None]);
+        assert!(it.next().is_none());
+
+        let it = ReadPairIter::from_readers(readers, false).unwrap();
+        let res: Result<Vec<ReadPair>, FastqError> = it.collect();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_resume_from() {
+        use crate::block_gz::{BlockConfig, BlockFormat, BlockGzWriter};
+
+        let bgzf_path = "tests/resume_from-RA.fastq.gz";
+        let mut writer =
+            BlockGzWriter::new(Vec::new(), BlockConfig::new(BlockFormat::Bgzf, 1), 2).unwrap();
+        writer
+            .write_all(&std::fs::read("tests/read_pair_iter/good-RA.fastq").unwrap())
+            .unwrap();
+        std::fs::write(bgzf_path, writer.finish().unwrap().0).unwrap();
+
+        for r1 in &[
+            "tests/read_pair_iter/good-RA.fastq",
+            "tests/read_pair_iter/good-gzipped-RA.fastq.gz",
+            bgzf_path,
+        ] {
+            let input = InputFastqs {
+                r1: r1.to_string(),
+                r2: None,
+                i1: Some("tests/read_pair_iter/good-I1.fastq".to_string()),
+                i2: None,
+                r1_interleaved: true,
+            };
+            let expected: Vec<ReadPair> = ReadPairIter::from_fastq_files(&input)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert!(expected.len() > 3);
+
+            let mut it = ReadPairIter::from_fastq_files(&input).unwrap();
+            assert_eq!(it.by_ref().take(3).count(), 3);
+            let offsets = it.tell();
+            assert_eq!(offsets.records, [6, 0, 3, 0]);
+            assert_eq!(offsets.offsets[1], None);
+
+            // Round trip through a checkpoint
+            let json = serde_json::to_string(&offsets).unwrap();
+            let offsets: ReadPairOffsets = serde_json::from_str(&json).unwrap();
+
+            let mut it = ReadPairIter::resume_from(&input, &offsets)
+                .unwrap()
+                .decompression_threads(2);
+            let rest: Vec<ReadPair> = it.by_ref().collect::<Result<_, _>>().unwrap();
+            assert_eq!(rest, &expected[3..], "{}", r1);
+            assert_eq!(it.provenance().record, expected.len() as u64 - 1);
+
+            // The files must match the offsets
+            let mut mismatched = input.clone();
+            mismatched.i1 = None;
+            assert!(ReadPairIter::resume_from(&mismatched, &offsets).is_err());
+        }
+
+        std::fs::remove_file(bgzf_path).unwrap();
+    }
+
+    #[test]
+    fn test_interleaved_reads() {
+        fn records(path: &str) -> Vec<Vec<u8>> {
+            let data = std::fs::read(path).unwrap();
+            let lines: Vec<_> = data.split_inclusive(|&c| c == b'\n').collect();
+            lines.chunks(4).map(|r| r.concat()).collect()
+        }
+        let ra = records("tests/read_pair_iter/good-RA.fastq");
+        let i1 = records("tests/read_pair_iter/good-I1.fastq");
+        let i2 = records("tests/read_pair_iter/good-I2.fastq");
+        let expected: Vec<ReadPair> = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            Some("tests/read_pair_iter/good-I1.fastq"),
+            Some("tests/read_pair_iter/good-I2.fastq"),
+            true,
+        )
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+        let read = |data: Vec<u8>, i1: Option<Vec<u8>>| {
+            ReadPairIter::from_readers(
+                [
+                    Some(io::Cursor::new(data)),
+                    None,
+                    i1.map(io::Cursor::new),
+                    None,
+                ],
+                true,
+            )
+            .unwrap()
+        };
+        let path = "test_interleaved_reads.fastq";
+        let detect = |data: &[u8]| {
+            std::fs::write(path, data).unwrap();
+            ReadPairIter::detect_interleaved_reads(path)
+        };
+        let interleave = |data: Vec<u8>, i1: Option<Vec<u8>>, reads: &[WhichRead]| {
+            read(data, i1).interleave(reads)
+        };
+        let all_reads = [WhichRead::R1, WhichRead::R2, WhichRead::I1, WhichRead::I2];
+
+        // 4-way interleaved, in the order R1, R2, I1, I2 of the read numbers 1, 4, 2, 3
+        let four_way: Vec<u8> = (0..i1.len())
+            .flat_map(|i| vec![&ra[2 * i], &ra[2 * i + 1], &i1[i], &i2[i]])
+            .flatten()
+            .cloned()
+            .collect();
+        assert_eq!(detect(&four_way).unwrap(), &all_reads);
+        let it = interleave(four_way.clone(), None, &all_reads).unwrap();
+        assert_eq!(it.interleaved_reads(), &all_reads);
+        let res: Vec<ReadPair> = it.collect::<Result<_, _>>().unwrap();
+        assert_eq!(res, expected);
+
+        // RA + I1 + I2
+        assert_eq!(
+            detect(&ra.concat()).unwrap(),
+            &[WhichRead::R1, WhichRead::R2]
+        );
+        let it = read(ra.concat(), Some(i1.concat()));
+        assert_eq!(it.interleaved_reads(), &[WhichRead::R1, WhichRead::R2]);
+
+        // In the order of the sequencing cycles, the read numbers are ascending, which
+        // is ambiguous, so the layout must be given
+        let cycle_reads = [WhichRead::R1, WhichRead::I1, WhichRead::I2, WhichRead::R2];
+        let cycles: Vec<u8> = (0..i1.len())
+            .flat_map(|i| vec![&ra[2 * i], &i1[i], &i2[i], &ra[2 * i + 1]])
+            .flatten()
+            .cloned()
+            .collect();
+        assert!(detect(&cycles).is_err());
+        let it = interleave(cycles, None, &cycle_reads).unwrap();
+        assert_eq!(it.interleaved_reads(), &cycle_reads);
+        // The reads are stored in a different order, so compare them one by one
+        let res: Vec<ReadPair> = it.collect::<Result<_, _>>().unwrap();
+        assert_eq!(res.len(), expected.len());
+        for (r, e) in res.iter().zip(&expected) {
+            for &which in WhichRead::read_types().iter() {
+                for &part in &[ReadPart::Header, ReadPart::Seq, ReadPart::Qual] {
+                    assert_eq!(r.get(which, part), e.get(which, part));
+                }
+            }
+        }
+
+        // Without read numbers, 3 records can't be detected
+        let strip = |r: &Vec<u8>| {
+            let space = r.iter().position(|&c| c == b' ').unwrap();
+            let eol = r.iter().position(|&c| c == b'\n').unwrap();
+            [&r[..space], &r[eol..]].concat()
+        };
+        let three_way: Vec<u8> = (0..i1.len())
+            .flat_map(|i| vec![strip(&ra[2 * i]), strip(&ra[2 * i + 1]), strip(&i1[i])])
+            .flatten()
+            .collect();
+        assert!(detect(&three_way).is_err());
+        let three_reads = [WhichRead::R1, WhichRead::R2, WhichRead::I1];
+        let it = interleave(three_way, None, &three_reads).unwrap();
+        let res: Vec<ReadPair> = it.collect::<Result<_, _>>().unwrap();
+        assert_eq!(res.len(), expected.len());
+        for (r, e) in res.iter().zip(&expected) {
+            for &which in &three_reads {
+                assert_eq!(r.get(which, ReadPart::Seq), e.get(which, ReadPart::Seq));
+            }
+            assert_eq!(r.get(WhichRead::I2, ReadPart::Seq), None);
+        }
+
+        // Read pairs with different numbers of records
+        let uneven = [&ra[0], &ra[1], &i1[0], &ra[2], &ra[3], &ra[4], &ra[5]];
+        let uneven: Vec<u8> = uneven.iter().flat_map(|r| r.iter()).cloned().collect();
+        assert!(detect(&uneven).is_err());
+        assert!(detect(b"").is_err());
+        std::fs::remove_file(path).unwrap();
+
+        // Invalid layouts, and I1 both interleaved and in its own file
+        assert!(interleave(four_way.clone(), None, &[WhichRead::R2, WhichRead::R1]).is_err());
+        assert!(interleave(four_way.clone(), None, &[WhichRead::R1, WhichRead::R1]).is_err());
+        assert!(interleave(four_way.clone(), Some(i1.concat()), &all_reads).is_err());
+
+        // An incomplete read pair at the end of the file, reported at the line of the
+        // missing record
+        let truncated = four_way[..four_way.len() - i2[0].len()].to_vec();
+        let res: Result<Vec<ReadPair>, FastqError> =
+            interleave(truncated, None, &all_reads).unwrap().collect();
+        match res {
+            Err(FastqError::FastqFormat { line, .. }) => {
+                assert_eq!(line, 4 * (4 * i1.len() - 1))
+            }
+            r => panic!("unexpected result {:?}", r.map(|r| r.len())),
+        }
+    }
+
+    #[test]
+    fn test_provenance() {
+        let open = || {
+            ReadPairIter::new(
+                Some("tests/read_pair_iter/good-RA.fastq"),
+                None,
+                Some("tests/read_pair_iter/good-I1.fastq"),
+                Some("tests/read_pair_iter/good-I2.fastq"),
+                true,
+            )
+            .unwrap()
+        };
+        let all: Vec<ReadPair> = open().map(|r| r.unwrap()).collect();
+        let sampled: Vec<(Provenance, ReadPair)> = open()
+            .subsample_rate(0.5)
+            .seed(1)
+            .source_id(7)
+            .with_provenance()
+            .map(|r| r.unwrap())
+            .collect();
+        assert!(!sampled.is_empty() && sampled.len() < all.len());
+        for (prov, rp) in &sampled {
+            assert_eq!(prov.source, 7);
+            assert_eq!(&all[prov.record as usize], rp);
+        }
+        assert!(sampled.windows(2).all(|w| w[0].0.record < w[1].0.record));
+
+        // The offset locates the header of the R1 read in the R1 file
+        let ra = std::fs::read("tests/read_pair_iter/good-RA.fastq").unwrap();
+        for (prov, rp) in &sampled {
+            let offset = prov.offset.unwrap() as usize;
+            let header = rp.get(WhichRead::R1, ReadPart::Header).unwrap();
+            assert_eq!(ra[offset], b'@');
+            assert!(ra[offset + 1..].starts_with(header));
+        }
+        let json = serde_json::to_string(&sampled[0].0).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Provenance>(&json).unwrap(),
+            sampled[0].0
+        );
+        let old: Provenance = serde_json::from_str(r#"{"source":7,"record":0}"#).unwrap();
+        assert_eq!(old.offset, None);
+
+        let it = open().with_provenance();
+        assert_eq!(
+            it.get_ref().paths()[2],
+            Some(PathBuf::from("tests/read_pair_iter/good-I1.fastq"))
+        );
+        let last = it.last().unwrap().unwrap();
+        assert_eq!(last.0.record as usize, all.len() - 1);
+    }
+
+    #[test]
+    fn test_batches() {
+        let open = || {
+            ReadPairIter::new(
+                Some("tests/read_pair_iter/vdj_micro_50k.fastq"),
+                None,
+                None,
+                None,
+                true,
+            )
+            .unwrap()
+        };
+        let all: Vec<ReadPair> = open().map(|r| r.unwrap()).collect();
+        let batches: Vec<Vec<ReadPair>> = open().batches(1000).map(|b| b.unwrap()).collect();
+        assert!(batches.len() > 2);
+        assert!(!batches.last().unwrap().is_empty());
+        assert!(batches[..batches.len() - 1].iter().all(|b| b.len() == 1000));
+        assert_eq!(batches.concat(), all);
+
+        // after the first batch, the reads of a batch are contiguous in one buffer
+        for batch in &batches[1..] {
+            for w in batch.windows(2) {
+                let prev = w[0].get(WhichRead::R2, ReadPart::Qual).unwrap();
+                let next = w[1].get(WhichRead::R1, ReadPart::Header).unwrap();
+                assert_eq!(prev.as_ptr_range().end, next.as_ptr());
+            }
+        }
+    }
+
+    #[test]
+    fn test_drop_qual() {
+        let open = || {
+            ReadPairIter::new(
+                Some("tests/read_pair_iter/good-RA.fastq"),
+                None,
+                Some("tests/read_pair_iter/good-I1.fastq"),
+                None,
+                true,
+            )
+            .unwrap()
+        };
+        let all: Vec<ReadPair> = open().map(|r| r.unwrap()).collect();
+        let dropped: Vec<ReadPair> = open()
+            .drop_qual(WhichRead::R2)
+            .drop_qual(WhichRead::I1)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(all.len(), dropped.len());
+        for (a, d) in all.iter().zip(&dropped) {
+            assert!(d.heap_bytes() < a.heap_bytes());
+            for &which in WhichRead::read_types().iter() {
+                for &part in &[ReadPart::Header, ReadPart::Seq] {
+                    assert_eq!(a.get(which, part), d.get(which, part));
+                }
+            }
+            assert_eq!(
+                a.get(WhichRead::R1, ReadPart::Qual),
+                d.get(WhichRead::R1, ReadPart::Qual)
+            );
+            assert!(d.get(WhichRead::R2, ReadPart::Qual).is_none());
+            assert!(d.get(WhichRead::I1, ReadPart::Qual).is_none());
+            let debug = format!("{:?}", d);
+            assert!(debug.contains("qual: None"), "{}", debug);
+            assert!(d.iter_base_qual(WhichRead::R2).is_none());
+            let view = d.view(RpRange::new(WhichRead::R2, 2, Some(4))).unwrap();
+            assert_eq!(
+                view.seq(),
+                &a.get(WhichRead::R2, ReadPart::Seq).unwrap()[2..6]
+            );
+            assert!(view.qual().is_empty() && view.sub_view(1, None).unwrap().qual().is_empty());
+
+            let mut w = Vec::new();
+            d.write_fastq(WhichRead::R1, &mut w).unwrap();
+            assert!(d.write_fastq(WhichRead::R2, &mut w).is_err());
+            assert_eq!(d.to_owned_record()[&WhichRead::R2].qual, Vec::<u8>::new());
+            let mut edited = d.clone();
+            assert!(edited
+                .set_qual(WhichRead::I1, &vec![b'I'; d.len(WhichRead::I1).unwrap()])
+                .is_err());
+            edited
+                .mask_range(RpRange::new(WhichRead::R2, 0, Some(1)), b'N')
+                .unwrap();
+            assert_eq!(edited.get(WhichRead::R2, ReadPart::Seq).unwrap()[0], b'N');
+        }
+    }
+
+    #[test]
+    fn test_quality_offsets() {
+        let r1 = b"@r1\nACGT\n+\nIII#\n".to_vec();
+        let r2 = b"@r1\nACGT\n+\nhhhB\n".to_vec();
+        let open = |r2: Vec<u8>| {
+            ReadPairIter::from_readers(
+                [
+                    Some(io::Cursor::new(r1.clone())),
+                    Some(io::Cursor::new(r2)),
+                    None,
+                    None,
+                ],
+                false,
+            )
+            .unwrap()
+            .quality_offsets([33, 64, 33, 33])
+            .unwrap()
+        };
+
+        let rp = open(r2).next().unwrap().unwrap();
+        assert_eq!(rp.get(WhichRead::R1, ReadPart::Qual).unwrap(), b"III#");
+        assert_eq!(rp.get(WhichRead::R2, ReadPart::Qual).unwrap(), b"III#");
+
+        // phred+33 data declared as phred+64
+        let err = open(r1.clone()).next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("below the quality offset"));
+
+        // Quality values beyond the range of phred+33 once converted
+        let err = open(b"@r1\nACGT\n+\nhhh\xff\n".to_vec())
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("above the range of the quality offset"));
+
+        // Offsets below 33 are rejected
+        let it = ReadPairIter::from_readers([Some(io::Cursor::new(r1)), None, None, None], false)
+            .unwrap();
+        assert!(it.quality_offsets([33, 0, 33, 33]).is_err());
+    }
+
+    #[test]
+    fn test_trim_length() {
+        let open = || {
+            ReadPairIter::new(
+                Some("tests/read_pair_iter/good-RA.fastq"),
+                None,
+                Some("tests/read_pair_iter/good-I1.fastq"),
+                None,
+                true,
+            )
+            .unwrap()
+            .trim_length(WhichRead::R2, Some(10))
+            .trim_length(WhichRead::I1, Some(4))
+        };
+
+        let full: Vec<_> = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            Some("tests/read_pair_iter/good-I1.fastq"),
+            None,
+            true,
+        )
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+
+        for (rp, full) in open().zip(&full) {
+            let rp = rp.unwrap();
+            assert_eq!(rp.len(WhichRead::R1), full.len(WhichRead::R1));
+            assert_eq!(rp.len(WhichRead::R2), Some(10));
+            assert_eq!(rp.len(WhichRead::I1), Some(4));
+            assert_eq!(rp.get(WhichRead::R2, ReadPart::Qual).unwrap().len(), 10);
+            assert_eq!(
+                rp.get(WhichRead::R2, ReadPart::Header),
+                full.get(WhichRead::R2, ReadPart::Header)
+            );
+        }
+
+        let n = open()
+            .with_trims()
+            .zip(&full)
+            .map(|(rp, full)| {
+                let rp = rp.unwrap();
+                assert_eq!(rp.read_pair.len(WhichRead::R2), Some(10));
+                assert_eq!(
+                    rp.read_pair.get(WhichRead::R2, ReadPart::Header),
+                    full.get(WhichRead::R2, ReadPart::Header)
+                );
+                assert!(rp.trims.read(WhichRead::R1).is_empty());
+                assert_eq!(rp.untrimmed_len(WhichRead::R2), full.len(WhichRead::R2));
+                assert_eq!(rp.untrimmed_len(WhichRead::I1), full.len(WhichRead::I1));
+                assert_eq!(rp.untrimmed_len(WhichRead::R1), full.len(WhichRead::R1));
+                assert_eq!(rp.untrimmed_len(WhichRead::I2), None);
+            })
+            .count();
+        assert_eq!(n, full.len());
+
+        // Same truncation as ReadPair::with_config, counting the dropped bases
+        let config = ReadPairConfig::default()
+            .max_len(WhichRead::R2, 10)
+            .max_len(WhichRead::I1, 4);
+        let mut it = open()
+            .trim_length(WhichRead::R2, Some(10))
+            .trim_length(WhichRead::I1, Some(4));
+        for (rp, full) in (&mut it).zip(&full) {
+            let rp = rp.unwrap();
+            let mut recs = full.to_owned_record();
+            let recs = [
+                recs.remove(&WhichRead::R1),
+                recs.remove(&WhichRead::R2),
+                recs.remove(&WhichRead::I1),
+                recs.remove(&WhichRead::I2),
+            ];
+            assert_eq!(rp, ReadPair::with_config(recs, &config));
+        }
+        let dropped = |which| {
+            full.iter()
+                .map(|rp| rp.len(which).unwrap() - config.kept_len(which, rp.len(which).unwrap()))
+                .sum::<usize>() as u64
+        };
+        assert_eq!(
+            it.dropped_bases(),
+            [0, dropped(WhichRead::R2), dropped(WhichRead::I1), 0]
+        );
+        assert!(it.dropped_bases()[1] > 0);
+    }
+
+    #[test]
+    fn test_io_stats() {
+        let plain_path = "tests/read_pair_iter/good-RA.fastq";
+        let plain_size = std::fs::metadata(plain_path).unwrap().len();
+        let gz_path = "tests/read_pair_iter/good-gzipped-RA.fastq.gz";
+        let gz_size = std::fs::metadata(gz_path).unwrap().len();
+
+        let mut it = ReadPairIter::new(Some(plain_path), None, None, None, true).unwrap();
+        assert_eq!(it.io_stats()[0].unwrap().decompressed_bytes, 0);
+        for r in &mut it {
+            r.unwrap();
+        }
+        let stats = it.io_stats();
+        assert!(stats[1].is_none() && stats[2].is_none());
+        let plain = stats[0].unwrap();
+        assert_eq!(plain.compressed_bytes, plain_size);
+        assert_eq!(plain.decompressed_bytes, plain_size);
+
+        let file = std::fs::File::open(gz_path).unwrap();
+        let mut it = ReadPairIter::from_readers([Some(file), None, None, None], true).unwrap();
+        for r in &mut it {
+            r.unwrap();
+        }
+        let gz = it.io_stats()[0].unwrap();
+        assert_eq!(gz.compressed_bytes, gz_size);
+        assert_eq!(gz.decompressed_bytes, plain_size);
+        assert!(gz.compression_ratio() > 1.0);
+
+        let mut total = plain;
+        total.merge(&gz);
+        assert_eq!(total.decompressed_bytes, 2 * plain_size);
+        assert_eq!(total.read_time, plain.read_time + gz.read_time);
+
+        // Times are only measured when enabled
+        assert_eq!(gz.read_time, Duration::default());
+        assert_eq!(gz.parse_time, Duration::default());
+        let file = std::fs::File::open(gz_path).unwrap();
+        let mut it = ReadPairIter::from_readers([Some(file), None, None, None], true)
+            .unwrap()
+            .time_io(true);
+        for r in &mut it {
+            r.unwrap();
+        }
+        let timed = it.io_stats()[0].unwrap();
+        assert_eq!(timed.decompressed_bytes, plain_size);
+        assert!(timed.read_time + timed.decompress_time > Duration::default());
+        assert!(timed.parse_time > Duration::default());
+    }
+
+    #[test]
+    fn test_fetch_records() {
+        let fastqs = InputFastqs {
+            r1: "tests/read_pair_iter/good-RA.fastq".to_string(),
+            r2: None,
+            i1: Some("tests/read_pair_iter/good-I1.fastq".to_string()),
+            i2: None,
+            r1_interleaved: true,
+        };
+        let all: Vec<ReadPair> = ReadPairIter::from_fastq_files(&fastqs)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        let mut sources = HashMap::new();
+        sources.insert(3, fastqs);
+        let prov = |record| Provenance {
+            source: 3,
+            record,
+            offset: None,
+        };
+
+        let wanted = [prov(5), prov(0), prov(5), prov(all.len() as u64 - 1)];
+        let fetched = fetch_records(&sources, &wanted).unwrap();
+        assert_eq!(fetched.len(), 4);
+        assert_eq!(fetched[0], all[5]);
+        assert_eq!(fetched[1], all[0]);
+        assert_eq!(fetched[2], all[5]);
+        assert_eq!(fetched[3], all[all.len() - 1]);
+        assert!(fetch_records(&sources, &[]).unwrap().is_empty());
+
+        assert!(fetch_records(&sources, &[prov(all.len() as u64)]).is_err());
+        let unknown = Provenance {
+            source: 0,
+            record: 0,
+            offset: None,
+        };
+        assert!(fetch_records(&sources, &[unknown]).is_err());
+    }
+
+    #[test]
+    fn test_fetch_records_block_index() {
+        use crate::block_gz::{BlockConfig, BlockFormat, BlockIndex};
+        use crate::read_pair_writer::ReadPairWriter;
+
+        let fastqs = InputFastqs {
+            r1: "tests/read_pair_iter/good-RA.fastq".to_string(),
+            r2: None,
+            i1: Some("tests/read_pair_iter/good-I1.fastq".to_string()),
+            i2: None,
+            r1_interleaved: true,
+        };
+        let all: Vec<ReadPair> = ReadPairIter::from_fastq_files(&fastqs)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        let blocks = InputFastqs {
+            r1: "tests/fetch_records_RA.fastq.gz".to_string(),
+            i1: Some("tests/fetch_records_I1.fastq.gz".to_string()),
+            ..fastqs
+        };
+        {
+            let config = BlockConfig::new(BlockFormat::Bgzf, 3);
+            let mut writer = ReadPairWriter::with_blocks(&blocks, config).unwrap();
+            for rp in &all {
+                writer.write(rp).unwrap();
+            }
+        }
+
+        // Overwrite the first block of R1, which is never read when seeking to the
+        // records of the later blocks
+        let index = BlockIndex::read(&blocks.r1).unwrap();
+        let mut data = std::fs::read(&blocks.r1).unwrap();
+        let first_len = index.blocks[0].compressed_len as usize;
+        data[..first_len].iter_mut().for_each(|b| *b = 0);
+        std::fs::write(&blocks.r1, &data).unwrap();
+
+        let mut sources = HashMap::new();
+        sources.insert(0, blocks.clone());
+        let prov = |record| Provenance {
+            source: 0,
+            record,
+            offset: None,
+        };
+        let last = all.len() as u64 - 1;
+        let fetched = fetch_records(&sources, &[prov(last), prov(3), prov(7), prov(4)]);
+        let res = fetch_records(&sources, &[prov(0)]);
+        let missing = fetch_records(&sources, &[prov(all.len() as u64)]);
+        for path in &[&blocks.r1, blocks.i1.as_ref().unwrap()] {
+            std::fs::remove_file(path).unwrap();
+            std::fs::remove_file(BlockIndex::path_for(path)).unwrap();
+        }
+
+        assert_eq!(
+            fetched.unwrap(),
+            vec![
+                all[last as usize].clone(),
+                all[3].clone(),
+                all[7].clone(),
+                all[4].clone()
+            ]
+        );
+        assert!(res.is_err());
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_sample_reads() {
+        let fastqs = InputFastqs {
+            r1: "tests/read_pair_iter/good-RA.fastq".to_string(),
+            r2: None,
+            i1: None,
+            i2: None,
+            r1_interleaved: true,
+        };
+        let all: Vec<ReadPair> = ReadPairIter::from_fastq_files(&fastqs)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        let n = all.len() / 4;
+
+        let head = sample_reads(&fastqs, n, SampleStrategy::Head, None).unwrap();
+        assert_eq!(head, &all[..n]);
+
+        let strided =
+            sample_reads(&fastqs, n, SampleStrategy::Strided { stride: 3 }, None).unwrap();
+        assert_eq!(strided, vec![all[0].clone(), all[6].clone()]);
+        // The spacing grows to cover the whole file
+        let strided =
+            sample_reads(&fastqs, n, SampleStrategy::Strided { stride: 1 }, None).unwrap();
+        assert_eq!(strided, vec![all[0].clone(), all[4].clone()]);
+        let strided =
+            sample_reads(&fastqs, 3, SampleStrategy::Strided { stride: 1 }, None).unwrap();
+        assert_eq!(
+            strided,
+            vec![all[0].clone(), all[2].clone(), all[6].clone()]
+        );
+
+        let reservoir = SampleStrategy::Reservoir { seed: 0 };
+        let sampled = sample_reads(&fastqs, n, reservoir, None).unwrap();
+        assert_eq!(sampled.len(), n);
+        assert_ne!(sampled, head);
+        assert_eq!(sampled, sample_reads(&fastqs, n, reservoir, None).unwrap());
+        // Reads are in file order
+        let positions: Vec<_> = sampled
+            .iter()
+            .map(|rp| all.iter().position(|a| a == rp).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+
+        let everything = sample_reads(&fastqs, 2 * all.len(), reservoir, None).unwrap();
+        assert_eq!(everything, all);
+        let huge = sample_reads(&fastqs, usize::MAX, SampleStrategy::Head, None).unwrap();
+        assert_eq!(huge, all);
+
+        // The time limit applies to every strategy
+        let limit = Some(Duration::from_secs(0));
+        for &strategy in &[
+            SampleStrategy::Head,
+            SampleStrategy::Strided { stride: 1 },
+            reservoir,
+        ] {
+            assert_eq!(
+                sample_reads(&fastqs, n, strategy, limit).unwrap(),
+                &all[..1]
+            );
+            assert!(sample_reads(&fastqs, 0, strategy, limit)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn test_read_pair_too_long() {
+        let seq = vec![b'A'; 40_000];
+        let mut data = Vec::new();
+        for _ in 0..2 {
+            data.extend_from_slice(b"@read\n");
+            data.extend_from_slice(&seq);
+            data.extend_from_slice(b"\n+\n");
+            data.extend(seq.iter().map(|_| b'I'));
+            data.extend_from_slice(b"\n");
+        }
+        let it = ReadPairIter::from_readers([Some(io::Cursor::new(data)), None, None, None], true)
+            .unwrap();
+        let res: Result<Vec<ReadPair>, FastqError> = it.collect();
+        assert!(res.is_err());
+    }
+
     #[test]
     fn test_missing_pair() {
         let it = ReadPairIter::new(
@@ -757,6 +3503,122 @@ mod test_read_pair_iter {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_mismatched_interleaved_header() {
+        let open = |ra: &'static [u8], i1: &'static [u8]| {
+            ReadPairIter::from_readers([Some(ra), None, Some(i1), None], true)
+                .unwrap()
+                .collect::<Result<Vec<ReadPair>, FastqError>>()
+        };
+        let ra = b"@a\nAC\n+\nII\n@a\nAC\n+\nII\n@b\nAC\n+\nII\n@c\nAC\n+\nII\n";
+        let i1 = b"@a\nAC\n+\nII\n@b\nAC\n+\nII\n";
+
+        // The R2 record of the second read pair is the 4th record of the R1 file,
+        // which has no R2 file of its own
+        let err = open(ra, i1).unwrap_err();
+        let diag = err.diagnostic();
+        assert_eq!(diag.file, PathBuf::from("read1"));
+        assert_eq!(diag.line, Some(8));
+        assert!(diag
+            .message
+            .contains("line 8 of input file \"read1\" and line 12 of input file \"read1\""));
+
+        // The I1 record of the second read pair is the 2nd record of the I1 file
+        let ra = b"@a\nAC\n+\nII\n@a\nAC\n+\nII\n@b\nAC\n+\nII\n@b\nAC\n+\nII\n";
+        let i1 = b"@a\nAC\n+\nII\n@c\nAC\n+\nII\n";
+        let diag = open(ra, i1).unwrap_err().diagnostic();
+        assert_eq!(diag.line, Some(8));
+        assert!(diag.message.contains("line 4 of input file \"index1\""));
+    }
+
+    #[test]
+    fn test_error_diagnostic() {
+        let it = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            Some("tests/read_pair_iter/bad-header-I1.fastq"),
+            Some("tests/read_pair_iter/good-I2.fastq"),
+            true,
+        )
+        .unwrap();
+
+        let err = it
+            .collect::<Result<Vec<ReadPair>, FastqError>>()
+            .unwrap_err();
+        let diag = err.diagnostic();
+        assert_eq!(diag.code, "fastq_format");
+        assert_eq!(
+            diag.file,
+            PathBuf::from("tests/read_pair_iter/good-RA.fastq")
+        );
+        assert_eq!(diag.record, diag.line.map(|l| l / 4));
+        assert_eq!(diag.record, err.record());
+        assert_eq!(err.suggestion(), Some(Suggestion::HeaderMismatch));
+        assert!(diag.suggestion.is_some());
+        let json: FastqDiagnostic = serde_json::from_str(&err.to_json()).unwrap();
+        assert_eq!(json, diag);
+
+        let err = ReadPairIter::new(
+            Some("tests/read_pair_iter/missing-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .err()
+        .unwrap();
+        let diag = err.diagnostic();
+        assert_eq!(diag.code, "open");
+        assert_eq!(diag.line, None);
+        assert!(diag.suggestion.is_some());
+        assert_eq!(err.record(), None);
+        assert_eq!(err.suggestion(), None);
+
+        // Corrupt compressed data gets the suggestion of its format
+        let mut gz = std::fs::read("tests/read_pair_iter/good-gzipped-RA.fastq.gz").unwrap();
+        let mid = gz.len() / 2;
+        gz[mid..mid + 16].iter_mut().for_each(|b| *b = 0xff);
+        let err = ReadPairIter::from_readers([Some(io::Cursor::new(gz)), None, None, None], true)
+            .unwrap()
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(
+            err.diagnostic().suggestion.as_deref(),
+            Some(Suggestion::Gzip.text())
+        );
+
+        // Errors that are not about a record have no record, and suggestions don't
+        // depend on the wording of the message
+        let err = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap()
+        .quality_offsets([20; 4])
+        .err()
+        .unwrap();
+        assert_eq!(err.diagnostic().record, None);
+        let mut it = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap();
+        it.next();
+        let diag = it
+            .interleave(&[WhichRead::R1, WhichRead::R2])
+            .err()
+            .unwrap()
+            .diagnostic();
+        assert!(diag.message.contains("interleaved"));
+        assert_eq!(diag.suggestion, None);
+    }
+
     #[test]
     fn test_mismatched_fastq_error() {
         let it = ReadPairIter::new(
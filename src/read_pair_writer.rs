@@ -1,12 +1,14 @@
 //! Write `ReadPair` objects to a set of FASTQ files.
 
-use failure::{Error, ResultExt};
+use failure::{format_err, Error, ResultExt};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
+use crate::block_gz::{BlockConfig, BlockGzWriter};
 use crate::read_pair::{ReadPair, WhichRead};
 use crate::read_pair_iter::InputFastqs;
-use crate::utils;
+use crate::read_pair_iter::ReadPairIter;
+use crate::utils::{self, Codec, Compression, FinishWrite};
 
 /// Read sequencing data from a parallel set of FASTQ files.
 /// Illumina sequencers typically emit a parallel set of FASTQ files, with one file
@@ -15,7 +17,7 @@ use crate::utils;
 /// as well as an interleaved R1/R2 file. Supports plain or gzipped FASTQ files, which
 /// will be detected based on the filename extension.
 pub struct ReadPairWriter {
-    writers: [Option<Box<dyn Write>>; 4],
+    writers: [Option<Box<dyn FinishWrite>>; 4],
     paths: [Option<PathBuf>; 4],
     // Each input file can interleave up to 2 -- declare those here
     r1_interleaved: bool,
@@ -43,13 +45,87 @@ impl ReadPairWriter {
         i1: Option<P>,
         i2: Option<P>,
         r1_interleaved: bool,
+    ) -> Result<ReadPairWriter, Error> {
+        Self::open([r1, r2, i1, i2], r1_interleaved, None)
+    }
+
+    /// Open a `ReadPairWriter` for a set of FASTQ files using the given compression,
+    /// irrespective of the file extensions.
+    pub fn with_compression(
+        output_fastqs: &InputFastqs,
+        compression: Compression,
+    ) -> Result<ReadPairWriter, Error> {
+        Self::with_codec(output_fastqs, &compression)
+    }
+
+    /// Open a `ReadPairWriter` for a set of FASTQ files encoded with `codec`, e.g.
+    /// `&Gzip { level: 6 }`, irrespective of the file extensions.
+    pub fn with_codec(
+        output_fastqs: &InputFastqs,
+        codec: &dyn Codec,
+    ) -> Result<ReadPairWriter, Error> {
+        Self::open(
+            [
+                Some(&output_fastqs.r1),
+                output_fastqs.r2.as_ref(),
+                output_fastqs.i1.as_ref(),
+                output_fastqs.i2.as_ref(),
+            ],
+            output_fastqs.r1_interleaved,
+            Some(codec),
+        )
+    }
+
+    /// Open a `ReadPairWriter` writing gzip files split into blocks of
+    /// `config.read_pairs_per_block` read pairs, each with a sidecar index of
+    /// the blocks. See the [`block_gz`](../block_gz/index.html) module.
+    /// The blocks of all the files contain the same read pairs.
+    pub fn with_blocks(
+        output_fastqs: &InputFastqs,
+        config: BlockConfig,
+    ) -> Result<ReadPairWriter, Error> {
+        let files = [
+            Some(&output_fastqs.r1),
+            output_fastqs.r2.as_ref(),
+            output_fastqs.i1.as_ref(),
+            output_fastqs.i2.as_ref(),
+        ];
+        let mut writers: [Option<Box<dyn FinishWrite>>; 4] = [None, None, None, None];
+        let mut paths = [None, None, None, None];
+
+        for (idx, r) in files.iter().enumerate() {
+            if let Some(p) = *r {
+                let records = if idx == 0 && output_fastqs.r1_interleaved {
+                    2
+                } else {
+                    1
+                };
+                writers[idx] = Some(Box::new(BlockGzWriter::create(p, config, records)?));
+                paths[idx] = Some(PathBuf::from(p));
+            }
+        }
+
+        Ok(ReadPairWriter {
+            paths,
+            writers,
+            r1_interleaved: output_fastqs.r1_interleaved,
+        })
+    }
+
+    fn open<P: AsRef<Path>>(
+        files: [Option<P>; 4],
+        r1_interleaved: bool,
+        codec: Option<&dyn Codec>,
     ) -> Result<ReadPairWriter, Error> {
         let mut writers = [None, None, None, None];
         let mut paths = [None, None, None, None];
 
-        for (idx, r) in [r1, r2, i1, i2].iter().enumerate() {
+        for (idx, r) in files.iter().enumerate() {
             if let Some(ref p) = *r {
-                let wtr = utils::write_with_gz(p)?;
+                let wtr = match codec {
+                    Some(c) => utils::write_with_codec(p, c)?,
+                    None => utils::write_with_gz(p)?,
+                };
                 writers[idx] = Some(wtr);
                 paths[idx] = Some(p.as_ref().to_path_buf());
             }
@@ -83,4 +159,284 @@ impl ReadPairWriter {
 
         Ok(())
     }
+
+    /// Write `rec` with `tags` appended to the header of each read as SAM-style
+    /// `XX:Z:<value>` fields, e.g. the corrected barcode and UMI as `CB` and `UB`.
+    /// Tools that consume FASTQ, such as aligners that copy the header comment
+    /// into their output (`bwa mem -C`, `minimap2 -y`), can pick the tags up, and
+    /// `illumina_header_info::header_tag` reads them back.
+    pub fn write_with_tags(
+        &mut self,
+        rec: &ReadPair,
+        tags: &[([u8; 2], &[u8])],
+    ) -> Result<(), Error> {
+        let paths = &self.paths;
+
+        for (idx, writer_opt) in self.writers.iter_mut().enumerate() {
+            if let Some(ref mut writer) = *writer_opt {
+                let which = WhichRead::read_types()[idx];
+                let reads: &[WhichRead] = if which == WhichRead::R1 && self.r1_interleaved {
+                    &[WhichRead::R1, WhichRead::R2]
+                } else {
+                    &[which]
+                };
+                let records = rec.to_fastq_records(reads, &[], tags)?;
+                writer.write_all(&records).with_context(|_| {
+                    format!("error writing fastq record to file: {:?}", paths[idx])
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flush buffered data to the output files. Compressed output is only
+    /// complete once the writer is finished or dropped.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let paths = &self.paths;
+        for (idx, writer) in self.writers.iter_mut().enumerate() {
+            if let Some(writer) = writer {
+                writer
+                    .flush()
+                    .with_context(|_| format!("error flushing fastq file: {:?}", paths[idx]))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush buffered data and complete the output files, e.g. by writing the
+    /// trailer of compressed files, reporting any error. Dropping the writer
+    /// also completes the files, but ignores errors. No read pairs can be written
+    /// afterwards.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        let paths = &self.paths;
+        for (idx, writer) in self.writers.iter_mut().enumerate() {
+            if let Some(mut writer) = writer.take() {
+                writer
+                    .finish()
+                    .with_context(|_| format!("error finishing fastq file: {:?}", paths[idx]))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copy a set of FASTQ files to `output`. When each output file name implies the
+/// same compression as the corresponding input file, the files are copied byte for
+/// byte without decompressing and recompressing them. Otherwise the reads are
+/// re-encoded according to the output file extensions.
+///
+/// `input` and `output` must contain the same read components.
+pub fn copy_raw(input: &InputFastqs, output: &InputFastqs) -> Result<(), Error> {
+    let files = |f: &InputFastqs| [Some(f.r1.clone()), f.r2.clone(), f.i1.clone(), f.i2.clone()];
+    let pairs: Vec<_> = files(input)
+        .iter()
+        .cloned()
+        .zip(files(output).iter().cloned())
+        .collect();
+    if input.r1_interleaved != output.r1_interleaved
+        || pairs.iter().any(|(i, o)| i.is_some() != o.is_some())
+    {
+        return Err(format_err!(
+            "Cannot copy FASTQ files {:?} to {:?} containing different read components",
+            input,
+            output
+        ));
+    }
+
+    let mut raw = true;
+    for (src, dst) in &pairs {
+        if let (Some(src), Some(dst)) = (src, dst) {
+            let compression = Compression::detect(src)
+                .with_context(|_| format!("error opening fastq file: {:?}", src))?;
+            raw &= compression == Compression::from_extension(dst);
+        }
+    }
+
+    if raw {
+        for (src, dst) in pairs {
+            if let (Some(src), Some(dst)) = (src, dst) {
+                std::fs::copy(&src, &dst)
+                    .with_context(|_| format!("error copying fastq file {:?} to {:?}", src, dst))?;
+            }
+        }
+    } else {
+        let mut writer = ReadPairWriter::from_fastq_files(output)?;
+        for rp in ReadPairIter::from_fastq_files(input)? {
+            writer.write(&rp?)?;
+        }
+        writer.finish()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::illumina_header_info::header_tag;
+    use crate::read_pair::ReadPart;
+    use file_diff::diff_files;
+    use std::fs::File;
+
+    fn fastqs(r1: &str, i1: Option<&str>) -> InputFastqs {
+        InputFastqs {
+            r1: r1.to_string(),
+            r2: None,
+            i1: i1.map(String::from),
+            i2: None,
+            r1_interleaved: true,
+        }
+    }
+
+    #[test]
+    fn test_copy_raw() -> Result<(), Error> {
+        let input = fastqs(
+            "tests/read_pair_iter/good-RA.fastq",
+            Some("tests/read_pair_iter/good-I1.fastq"),
+        );
+        let output = fastqs("tests/copy_raw_RA.fastq", Some("tests/copy_raw_I1.fastq"));
+        copy_raw(&input, &output)?;
+        assert!(diff_files(
+            &mut File::open(&input.r1)?,
+            &mut File::open(&output.r1)?
+        ));
+
+        // Decompressed from gzip, and with the wrong extension
+        let gz = fastqs("tests/read_pair_iter/good-gzipped-RA.fastq.gz", None);
+        let plain = fastqs("tests/copy_raw_gz_RA.fastq", None);
+        copy_raw(&gz, &plain)?;
+        assert!(diff_files(
+            &mut File::open(&input.r1)?,
+            &mut File::open(&plain.r1)?
+        ));
+
+        // Re-encoded from plain to lz4
+        let lz4 = fastqs(
+            "tests/copy_raw_RA.fastq.lz4",
+            Some("tests/copy_raw_I1.fastq.lz4"),
+        );
+        copy_raw(&input, &lz4)?;
+        assert_eq!(Compression::detect(&lz4.r1)?, Compression::Lz4);
+        assert_eq!(
+            ReadPairIter::from_fastq_files(&lz4)?.collect::<Result<Vec<_>, _>>()?,
+            ReadPairIter::from_fastq_files(&input)?.collect::<Result<Vec<_>, _>>()?
+        );
+
+        assert!(copy_raw(&gz, &output).is_err());
+        for f in &[
+            &output.r1,
+            output.i1.as_ref().unwrap(),
+            &plain.r1,
+            &lz4.r1,
+            lz4.i1.as_ref().unwrap(),
+        ] {
+            std::fs::remove_file(f)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_with_tags() -> Result<(), Error> {
+        let input = fastqs(
+            "tests/read_pair_iter/good-RA.fastq",
+            Some("tests/read_pair_iter/good-I1.fastq"),
+        );
+        let output = fastqs("tests/with_tags_RA.fastq", Some("tests/with_tags_I1.fastq"));
+        let reads: Vec<ReadPair> =
+            ReadPairIter::from_fastq_files(&input)?.collect::<Result<_, _>>()?;
+        {
+            let mut writer = ReadPairWriter::from_fastq_files(&output)?;
+            for rp in &reads {
+                let bc = rp.get(WhichRead::I1, ReadPart::Seq).unwrap();
+                writer.write_with_tags(rp, &[(*b"CB", bc), (*b"UB", b"ACGT")])?;
+            }
+        }
+        let written: Vec<ReadPair> =
+            ReadPairIter::from_fastq_files(&output)?.collect::<Result<_, _>>()?;
+        std::fs::remove_file(&output.r1)?;
+        std::fs::remove_file(output.i1.as_ref().unwrap())?;
+
+        assert_eq!(written.len(), reads.len());
+        for (w, r) in written.iter().zip(&reads) {
+            for &which in &[WhichRead::R1, WhichRead::R2, WhichRead::I1] {
+                let header = w.get(which, ReadPart::Header).unwrap();
+                let bc = r.get(WhichRead::I1, ReadPart::Seq);
+                assert_eq!(header_tag(header, b"CB"), bc);
+                assert_eq!(header_tag(header, b"UB"), Some(&b"ACGT"[..]));
+                assert!(header.starts_with(r.get(which, ReadPart::Header).unwrap()));
+                assert_eq!(w.get(which, ReadPart::Seq), r.get(which, ReadPart::Seq));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_compression() -> Result<(), Error> {
+        let input = fastqs("tests/read_pair_iter/good-RA.fastq", None);
+        let output = fastqs("tests/with_compression_RA.fastq", None);
+        let reads: Vec<ReadPair> =
+            ReadPairIter::from_fastq_files(&input)?.collect::<Result<_, _>>()?;
+        {
+            let mut writer = ReadPairWriter::with_compression(&output, Compression::Gzip)?;
+            for rp in &reads {
+                writer.write(rp)?;
+            }
+        }
+        assert_eq!(Compression::detect(&output.r1)?, Compression::Gzip);
+        let written: Vec<ReadPair> =
+            ReadPairIter::from_fastq_files(&output)?.collect::<Result<_, _>>()?;
+        std::fs::remove_file(&output.r1)?;
+        assert_eq!(written, reads);
+
+        // lz4, zstd and xz output, and gzip at a higher level
+        let lz4 = fastqs("tests/with_compression_RA.fastq.lz4", None);
+        let gz = fastqs("tests/with_compression_RA.fastq.gz", None);
+        let zst = fastqs("tests/with_compression_RA.fastq.zst", None);
+        let xz = fastqs("tests/with_compression_RA.fastq.xz", None);
+        for &(out, codec) in &[
+            (&lz4, &Compression::Lz4 as &dyn Codec),
+            (&gz, &utils::Gzip { level: 9 }),
+            (&lz4, &utils::Lz4 { level: 9 }),
+            (&zst, &Compression::Zstd),
+            (&xz, &Compression::Xz),
+        ] {
+            {
+                let mut writer = ReadPairWriter::with_codec(out, codec)?;
+                for rp in &reads {
+                    writer.write(rp)?;
+                }
+            }
+            let written: Vec<ReadPair> =
+                ReadPairIter::from_fastq_files(out)?.collect::<Result<_, _>>()?;
+            assert_eq!(written, reads);
+        }
+        assert_eq!(Compression::detect(&lz4.r1)?, Compression::Lz4);
+        assert_eq!(Compression::detect(&zst.r1)?, Compression::Zstd);
+        assert_eq!(Compression::detect(&xz.r1)?, Compression::Xz);
+        assert_eq!(Compression::from_extension(&zst.r1), Compression::Zstd);
+        assert_eq!(Compression::from_extension(&xz.r1), Compression::Xz);
+        for out in &[&lz4, &gz, &zst, &xz] {
+            std::fs::remove_file(&out.r1)?;
+        }
+        // An invalid level leaves an existing file untouched
+        std::fs::write(&output.r1, b"existing")?;
+        assert!(ReadPairWriter::with_codec(&output, &utils::Gzip { level: 10 }).is_err());
+        assert!(ReadPairWriter::with_codec(&output, &utils::Lz4 { level: 17 }).is_err());
+        assert_eq!(std::fs::read(&output.r1)?, b"existing");
+        std::fs::remove_file(&output.r1)?;
+
+        // The extension of the file selects lz4 compression
+        {
+            let mut writer = ReadPairWriter::from_fastq_files(&lz4)?;
+            for rp in &reads {
+                writer.write(rp)?;
+            }
+        }
+        assert_eq!(Compression::detect(&lz4.r1)?, Compression::Lz4);
+        let written: Vec<ReadPair> =
+            ReadPairIter::from_fastq_files(&lz4)?.collect::<Result<_, _>>()?;
+        std::fs::remove_file(&lz4.r1)?;
+        assert_eq!(written, reads);
+        Ok(())
+    }
 }
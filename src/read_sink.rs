@@ -0,0 +1,324 @@
+//! A common interface for destinations of reads, so that code producing reads
+//! can write to FASTQ files, in-memory collections or arbitrary callbacks
+//! without knowing which one it is writing to.
+
+use failure::Error;
+use std::io::Write;
+
+use crate::read_pair::ReadPair;
+use crate::read_pair_writer::ReadPairWriter;
+
+/// A destination for reads of type `T`.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::read_sink::{sink_fn, ReadSink};
+/// use failure::Error;
+///
+/// fn write_even<S: ReadSink<u32>>(sink: &mut S) -> Result<(), Error> {
+///     for i in 0..10 {
+///         if i % 2 == 0 {
+///             sink.write(&i)?;
+///         }
+///     }
+///     sink.finish()
+/// }
+///
+/// let mut reads = Vec::new();
+/// write_even(&mut reads).unwrap();
+/// assert_eq!(reads, vec![0, 2, 4, 6, 8]);
+///
+/// let mut total = 0;
+/// write_even(&mut sink_fn(|i: &u32| {
+///     total += i;
+///     Ok(())
+/// }))
+/// .unwrap();
+/// assert_eq!(total, 20);
+/// ```
+pub trait ReadSink<T> {
+    /// Write a single read
+    fn write(&mut self, read: &T) -> Result<(), Error>;
+
+    /// Flush any buffered reads once all the reads have been written.
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<T, S: ReadSink<T> + ?Sized> ReadSink<T> for &mut S {
+    fn write(&mut self, read: &T) -> Result<(), Error> {
+        (**self).write(read)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        (**self).finish()
+    }
+}
+
+impl<T, S: ReadSink<T> + ?Sized> ReadSink<T> for Box<S> {
+    fn write(&mut self, read: &T) -> Result<(), Error> {
+        (**self).write(read)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        (**self).finish()
+    }
+}
+
+/// Collect the reads in memory
+impl<T: Clone> ReadSink<T> for Vec<T> {
+    fn write(&mut self, read: &T) -> Result<(), Error> {
+        self.push(read.clone());
+        Ok(())
+    }
+}
+
+impl ReadSink<ReadPair> for ReadPairWriter {
+    fn write(&mut self, read: &ReadPair) -> Result<(), Error> {
+        ReadPairWriter::write(self, read)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        ReadPairWriter::finish(self)
+    }
+}
+
+/// A sink passing each read to a closure, created by [`sink_fn`](fn.sink_fn.html).
+pub struct FnSink<F> {
+    f: F,
+}
+
+/// Create a sink which calls `f` on each read
+pub fn sink_fn<T, F>(f: F) -> FnSink<F>
+where
+    F: FnMut(&T) -> Result<(), Error>,
+{
+    FnSink { f }
+}
+
+impl<T, F> ReadSink<T> for FnSink<F>
+where
+    F: FnMut(&T) -> Result<(), Error>,
+{
+    fn write(&mut self, read: &T) -> Result<(), Error> {
+        (self.f)(read)
+    }
+}
+
+/// A sink writing every read to two sinks, created by [`tee`](fn.tee.html).
+pub struct Tee<A, B> {
+    first: A,
+    second: B,
+}
+
+/// Create a sink which writes every read to both `first` and `second`.
+/// Tees can be nested to write to more than two sinks.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::read_sink::{tee, write_all, ReadSink};
+/// let mut all = Vec::new();
+/// let mut count = 0;
+/// let mut sink = tee(&mut all, fastq_set::read_sink::sink_fn(|_: &u8| {
+///     count += 1;
+///     Ok(())
+/// }));
+/// write_all(&mut sink, b"ACGT".iter().map(|&b| Ok(b))).unwrap();
+/// drop(sink);
+/// assert_eq!(all, b"ACGT".to_vec());
+/// assert_eq!(count, 4);
+/// ```
+pub fn tee<A, B>(first: A, second: B) -> Tee<A, B> {
+    Tee { first, second }
+}
+
+impl<A, B> Tee<A, B> {
+    /// Recover the two sinks
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<T, A: ReadSink<T>, B: ReadSink<T>> ReadSink<T> for Tee<A, B> {
+    fn write(&mut self, read: &T) -> Result<(), Error> {
+        self.first.write(read)?;
+        self.second.write(read)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.first.finish()?;
+        self.second.finish()
+    }
+}
+
+/// A sink routing each read to one of two sinks, created by [`route_by`](fn.route_by.html).
+pub struct Route<F, A, B> {
+    predicate: F,
+    matching: A,
+    other: B,
+}
+
+/// Create a sink which writes the reads for which `predicate` is true to `matching`
+/// and all other reads to `other`, e.g. to separate reads with a valid barcode from
+/// the rest. Routes can be nested to split reads into more than two sinks.
+///
+/// # Example
+/// ```rust
+/// use fastq_set::read_sink::{route_by, write_all};
+/// let mut even = Vec::new();
+/// let mut odd = Vec::new();
+/// let mut sink = route_by(|i: &u32| i % 2 == 0, &mut even, &mut odd);
+/// write_all(&mut sink, (0..5).map(Ok)).unwrap();
+/// drop(sink);
+/// assert_eq!(even, vec![0, 2, 4]);
+/// assert_eq!(odd, vec![1, 3]);
+/// ```
+pub fn route_by<T, F, A, B>(predicate: F, matching: A, other: B) -> Route<F, A, B>
+where
+    F: FnMut(&T) -> bool,
+{
+    Route {
+        predicate,
+        matching,
+        other,
+    }
+}
+
+impl<F, A, B> Route<F, A, B> {
+    /// Recover the sinks for matching and other reads
+    pub fn into_inner(self) -> (A, B) {
+        (self.matching, self.other)
+    }
+}
+
+impl<T, F, A, B> ReadSink<T> for Route<F, A, B>
+where
+    F: FnMut(&T) -> bool,
+    A: ReadSink<T>,
+    B: ReadSink<T>,
+{
+    fn write(&mut self, read: &T) -> Result<(), Error> {
+        if (self.predicate)(read) {
+            self.matching.write(read)
+        } else {
+            self.other.write(read)
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.matching.finish()?;
+        self.other.finish()
+    }
+}
+
+/// Write all the reads from `reads` to `sink` and finish the sink.
+/// Returns the number of reads written.
+pub fn write_all<T, S, I>(sink: &mut S, reads: I) -> Result<usize, Error>
+where
+    S: ReadSink<T> + ?Sized,
+    I: IntoIterator<Item = Result<T, Error>>,
+{
+    let mut n = 0;
+    for read in reads {
+        sink.write(&read?)?;
+        n += 1;
+    }
+    sink.finish()?;
+    Ok(n)
+}
+
+/// Discards all the reads
+impl<T> ReadSink<T> for std::io::Sink {
+    fn write(&mut self, _read: &T) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(self.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair_iter::{InputFastqs, ReadPairIter};
+
+    #[test]
+    fn test_write_all() -> Result<(), Error> {
+        let input = InputFastqs {
+            r1: "tests/read_pair_iter/good-RA.fastq".to_string(),
+            r2: None,
+            i1: None,
+            i2: None,
+            r1_interleaved: true,
+        };
+        let read = || {
+            ReadPairIter::from_fastq_files(&input)
+                .unwrap()
+                .map(|r| r.map_err(Error::from))
+        };
+
+        // The compressed files are complete once the sink is finished, before it is dropped
+        let output = InputFastqs {
+            r1: "tests/read_sink_R1.fastq.gz".to_string(),
+            r2: Some("tests/read_sink_R2.fastq.zst".to_string()),
+            ..input.clone()
+        };
+        let mut sinks: Vec<Box<dyn ReadSink<ReadPair>>> = vec![
+            Box::new(ReadPairWriter::from_fastq_files(&InputFastqs {
+                r1_interleaved: false,
+                ..output.clone()
+            })?),
+            Box::new(Vec::new()),
+            Box::new(std::io::sink()),
+        ];
+        let n = write_all(&mut sinks[0], read())?;
+        assert_eq!(write_all(&mut sinks[1], read())?, n);
+        assert_eq!(write_all(&mut sinks[2], read())?, n);
+
+        let mut written = Vec::new();
+        write_all(
+            &mut written,
+            ReadPairIter::from_fastq_files(&InputFastqs {
+                r1_interleaved: false,
+                ..output.clone()
+            })?
+            .map(|r| r.map_err(Error::from)),
+        )?;
+        std::fs::remove_file(&output.r1)?;
+        std::fs::remove_file(output.r2.unwrap())?;
+        let original: Vec<ReadPair> = read().collect::<Result<_, _>>()?;
+        assert_eq!(written, original);
+        Ok(())
+    }
+
+    #[test]
+    fn test_combinators() -> Result<(), Error> {
+        // Split into three sinks, while also keeping every read
+        let mut sink = tee(
+            Vec::new(),
+            route_by(
+                |i: &u32| *i < 3,
+                Vec::new(),
+                route_by(|i: &u32| *i >= 5, Vec::new(), Vec::new()),
+            ),
+        );
+        write_all(&mut sink, (0..8).map(Ok))?;
+        let (all, route) = sink.into_inner();
+        let (low, route) = route.into_inner();
+        let (high, mid) = route.into_inner();
+        assert_eq!(all, (0..8).collect::<Vec<_>>());
+        assert_eq!(low, vec![0, 1, 2]);
+        assert_eq!(high, vec![5, 6, 7]);
+        assert_eq!(mid, vec![3, 4]);
+
+        // Errors from either sink are reported
+        let mut failing = tee(
+            Vec::new(),
+            sink_fn(|_: &u32| Err(failure::format_err!("full"))),
+        );
+        assert!(failing.write(&1).is_err());
+        Ok(())
+    }
+}
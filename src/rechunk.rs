@@ -0,0 +1,351 @@
+//! Merge and split sets of FASTQ files into chunks of similar numbers of read
+//! pairs, so that a pipeline can normalize the very uneven chunks coming out of
+//! demultiplexing before scattering work over them.
+//!
+//! When every input was written as block gzip files with sidecar indexes (see the
+//! [`block_gz`](../block_gz/index.html) module), the chunks are ranges of blocks of
+//! the inputs and no data is copied. Otherwise the reads are rewritten into new
+//! files of exactly the target number of read pairs.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::read_pair_iter::InputFastqs;
+//! use fastq_set::rechunk::rechunk;
+//! let input = InputFastqs {
+//!     r1: "tests/read_pair_iter/good-RA.fastq".to_string(),
+//!     r2: None,
+//!     i1: None,
+//!     i2: None,
+//!     r1_interleaved: true,
+//! };
+//! let dir = std::env::temp_dir().join("fastq_set_rechunk_doctest");
+//! std::fs::create_dir_all(&dir).unwrap();
+//! let chunks = rechunk(&[input.clone(), input], 5, &dir).unwrap();
+//! let sizes: Vec<_> = chunks.iter().map(|c| c.read_pairs()).collect();
+//! assert_eq!(sizes, vec![5, 5, 5, 1]);
+//! assert_eq!(chunks[1].iter().count(), 5);
+//! std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+
+use crate::block_gz::BlockIndex;
+use crate::read_pair::ReadPair;
+use crate::read_pair_iter::{InputFastqs, ReadPairIter};
+use crate::read_pair_writer::ReadPairWriter;
+use failure::{format_err, Error};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::path::Path;
+
+/// Part of a chunk: a set of FASTQ files, or a range of blocks of a set of block
+/// gzip files.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkPiece {
+    pub fastqs: InputFastqs,
+    /// The blocks of the files making up the piece, or `None` for the whole files
+    pub blocks: Option<Range<usize>>,
+    pub read_pairs: u64,
+}
+
+impl ChunkPiece {
+    /// Read the read pairs of the piece
+    pub fn open(&self) -> Result<ReadPairIter, Error> {
+        let blocks = match &self.blocks {
+            Some(blocks) => blocks.clone(),
+            None => return Ok(ReadPairIter::from_fastq_files(&self.fastqs)?),
+        };
+        let mut readers = [None, None, None, None];
+        for (idx, path) in files(&self.fastqs).iter().enumerate() {
+            if let Some(path) = path {
+                let index = BlockIndex::read(path)?;
+                readers[idx] = Some(index.blocks_reader(path, blocks.clone())?);
+            }
+        }
+        Ok(ReadPairIter::from_readers(
+            readers,
+            self.fastqs.r1_interleaved,
+        )?)
+    }
+}
+
+/// A chunk of read pairs, made of consecutive pieces of the inputs.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub pieces: Vec<ChunkPiece>,
+}
+
+impl Chunk {
+    /// Number of read pairs in the chunk
+    pub fn read_pairs(&self) -> u64 {
+        self.pieces.iter().map(|p| p.read_pairs).sum()
+    }
+
+    /// Read the read pairs of the chunk, opening the pieces in turn
+    pub fn iter(&self) -> impl Iterator<Item = Result<ReadPair, Error>> + '_ {
+        self.pieces.iter().flat_map(
+            |piece| -> Box<dyn Iterator<Item = Result<ReadPair, Error>>> {
+                match piece.open() {
+                    Ok(iter) => Box::new(iter.map(|rp| rp.map_err(Error::from))),
+                    Err(e) => Box::new(std::iter::once(Err(e))),
+                }
+            },
+        )
+    }
+}
+
+fn files(fastqs: &InputFastqs) -> [Option<&String>; 4] {
+    [
+        Some(&fastqs.r1),
+        fastqs.r2.as_ref(),
+        fastqs.i1.as_ref(),
+        fastqs.i2.as_ref(),
+    ]
+}
+
+/// Split the read pairs of `inputs`, in order, into chunks of about
+/// `target_reads_per_chunk` read pairs. All the inputs must contain the same read
+/// components.
+///
+/// If all the files of all the inputs have block indexes, the chunks are ranges of
+/// whole blocks: each chunk ends with the first block that brings it to at least
+/// `target_reads_per_chunk` read pairs. Otherwise the read pairs are rewritten
+/// into gzipped FASTQ files in `out_dir`, named `chunk<n>_R1.fastq.gz` etc., each
+/// holding exactly `target_reads_per_chunk` read pairs. In both cases the last
+/// chunk holds the remaining read pairs.
+///
+/// # Panics
+/// * If `target_reads_per_chunk` is 0
+pub fn rechunk(
+    inputs: &[InputFastqs],
+    target_reads_per_chunk: usize,
+    out_dir: impl AsRef<Path>,
+) -> Result<Vec<Chunk>, Error> {
+    assert!(
+        target_reads_per_chunk > 0,
+        "Chunks must hold at least one read pair"
+    );
+    let layout = |f: &InputFastqs| {
+        let present: Vec<_> = files(f).iter().map(Option::is_some).collect();
+        (present, f.r1_interleaved)
+    };
+    if let Some(other) = inputs.iter().find(|f| layout(f) != layout(&inputs[0])) {
+        return Err(format_err!(
+            "Cannot rechunk FASTQ files {:?} and {:?} containing different read components",
+            inputs[0],
+            other
+        ));
+    }
+
+    match block_indexes(inputs) {
+        Some(indexes) => Ok(rechunk_blocks(
+            inputs,
+            &indexes,
+            target_reads_per_chunk as u64,
+        )),
+        None => rewrite(inputs, target_reads_per_chunk, out_dir.as_ref()),
+    }
+}
+
+/// The block index of each input, if every file of every input has an index, and
+/// the files of each input have the same blocks.
+fn block_indexes(inputs: &[InputFastqs]) -> Option<Vec<BlockIndex>> {
+    let mut indexes = Vec::new();
+    for input in inputs {
+        let mut input_indexes = Vec::new();
+        for path in files(input).iter().flatten() {
+            input_indexes.push(BlockIndex::read(path).ok()?);
+        }
+        let read_pairs = |index: &BlockIndex| {
+            index
+                .blocks
+                .iter()
+                .map(|b| b.read_pairs)
+                .collect::<Vec<_>>()
+        };
+        if input_indexes
+            .iter()
+            .any(|index| read_pairs(index) != read_pairs(&input_indexes[0]))
+        {
+            return None;
+        }
+        indexes.push(input_indexes.swap_remove(0));
+    }
+    Some(indexes)
+}
+
+fn rechunk_blocks(inputs: &[InputFastqs], indexes: &[BlockIndex], target: u64) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut pieces: Vec<ChunkPiece> = Vec::new();
+    let mut read_pairs = 0;
+    for (input, index) in inputs.iter().zip(indexes) {
+        let mut start = 0;
+        for (i, block) in index.blocks.iter().enumerate() {
+            read_pairs += block.read_pairs;
+            if read_pairs >= target || i + 1 == index.blocks.len() {
+                pieces.push(ChunkPiece {
+                    fastqs: input.clone(),
+                    blocks: Some(start..i + 1),
+                    read_pairs: index.blocks[start..=i].iter().map(|b| b.read_pairs).sum(),
+                });
+                start = i + 1;
+            }
+            if read_pairs >= target {
+                chunks.push(Chunk {
+                    pieces: std::mem::take(&mut pieces),
+                });
+                read_pairs = 0;
+            }
+        }
+    }
+    if !pieces.is_empty() {
+        chunks.push(Chunk { pieces });
+    }
+    chunks
+}
+
+fn rewrite(inputs: &[InputFastqs], target: usize, out_dir: &Path) -> Result<Vec<Chunk>, Error> {
+    let path = |chunk: usize, read: &str| {
+        let p = out_dir.join(format!("chunk{}_{}.fastq.gz", chunk, read));
+        p.to_string_lossy().to_string()
+    };
+    let output = |chunk: usize| InputFastqs {
+        r1: path(chunk, "R1"),
+        r2: inputs[0].r2.as_ref().map(|_| path(chunk, "R2")),
+        i1: inputs[0].i1.as_ref().map(|_| path(chunk, "I1")),
+        i2: inputs[0].i2.as_ref().map(|_| path(chunk, "I2")),
+        r1_interleaved: inputs[0].r1_interleaved,
+    };
+
+    let mut chunks = Vec::new();
+    let mut writer: Option<ReadPairWriter> = None;
+    let mut read_pairs = 0;
+    for input in inputs {
+        for rp in ReadPairIter::from_fastq_files(input)? {
+            let rp = rp?;
+            if writer.is_none() {
+                writer = Some(ReadPairWriter::from_fastq_files(&output(chunks.len()))?);
+            }
+            writer.as_mut().unwrap().write(&rp)?;
+            read_pairs += 1;
+            if read_pairs == target {
+                let fastqs = output(chunks.len());
+                finish_chunk(&mut chunks, writer.take(), fastqs, target)?;
+                read_pairs = 0;
+            }
+        }
+    }
+    if writer.is_some() {
+        let fastqs = output(chunks.len());
+        finish_chunk(&mut chunks, writer, fastqs, read_pairs)?;
+    }
+    Ok(chunks)
+}
+
+fn finish_chunk(
+    chunks: &mut Vec<Chunk>,
+    writer: Option<ReadPairWriter>,
+    fastqs: InputFastqs,
+    read_pairs: usize,
+) -> Result<(), Error> {
+    if let Some(mut writer) = writer {
+        writer.finish()?;
+    }
+    chunks.push(Chunk {
+        pieces: vec![ChunkPiece {
+            fastqs,
+            blocks: None,
+            read_pairs: read_pairs as u64,
+        }],
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_gz::{BlockConfig, BlockFormat};
+
+    fn fastqs(r1: &str) -> InputFastqs {
+        InputFastqs {
+            r1: r1.to_string(),
+            r2: None,
+            i1: None,
+            i2: None,
+            r1_interleaved: true,
+        }
+    }
+
+    fn all_reads(input: &InputFastqs) -> Result<Vec<ReadPair>, Error> {
+        Ok(ReadPairIter::from_fastq_files(input)?.collect::<Result<_, _>>()?)
+    }
+
+    fn chunk_reads(chunks: &[Chunk]) -> Result<Vec<ReadPair>, Error> {
+        chunks.iter().flat_map(|c| c.iter()).collect()
+    }
+
+    #[test]
+    fn test_rechunk_blocks() -> Result<(), Error> {
+        let reads = all_reads(&fastqs("tests/read_pair_iter/vdj_micro_50k.fastq"))?;
+        let dir = Path::new("tests/rechunk_blocks");
+        std::fs::create_dir_all(dir)?;
+        let a = fastqs("tests/rechunk_blocks/a.fastq.gz");
+        let b = fastqs("tests/rechunk_blocks/b.fastq.gz");
+        for (input, range, block) in &[(&a, 0..1000, 100), (&b, 1000..1230, 30)] {
+            let config = BlockConfig::new(BlockFormat::MultiMember, *block);
+            let mut writer = ReadPairWriter::with_blocks(input, config)?;
+            for rp in &reads[range.clone()] {
+                writer.write(rp)?;
+            }
+        }
+
+        let chunks = rechunk(&[a.clone(), b.clone()], 250, dir)?;
+        let sizes: Vec<_> = chunks.iter().map(|c| c.read_pairs()).collect();
+        assert_eq!(sizes, vec![300, 300, 300, 250, 80]);
+        assert_eq!(chunks[3].pieces.len(), 2);
+        assert_eq!(chunks[3].pieces[0].blocks, Some(9..10));
+        assert_eq!(chunks[3].pieces[1].blocks, Some(0..5));
+        assert_eq!(chunk_reads(&chunks)?, &reads[..1230]);
+        for chunk in &chunks {
+            assert_eq!(chunk.iter().count() as u64, chunk.read_pairs());
+        }
+
+        // Without an index, the reads are rewritten
+        std::fs::remove_file(BlockIndex::path_for(&b.r1))?;
+        let chunks = rechunk(&[a, b], 500, dir)?;
+        let sizes: Vec<_> = chunks.iter().map(|c| c.read_pairs()).collect();
+        assert_eq!(sizes, vec![500, 500, 230]);
+        assert!(chunks.iter().all(|c| c.pieces[0].blocks.is_none()));
+        assert_eq!(chunk_reads(&chunks)?, &reads[..1230]);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rechunk_rewrite() -> Result<(), Error> {
+        let dir = Path::new("tests/rechunk_rewrite");
+        std::fs::create_dir_all(dir)?;
+        let inputs = [
+            fastqs("tests/read_pair_iter/good-RA.fastq"),
+            fastqs("tests/read_pair_iter/good-gzipped-RA.fastq.gz"),
+        ];
+        let reads = [all_reads(&inputs[0])?, all_reads(&inputs[1])?].concat();
+        let chunks = rechunk(&inputs, 8, dir)?;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(
+            chunks[1].pieces[0].fastqs.r1,
+            "tests/rechunk_rewrite/chunk1_R1.fastq.gz"
+        );
+        assert_eq!(chunk_reads(&chunks)?, reads);
+
+        let chunks = rechunk(&inputs, 100, dir)?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].read_pairs(), 16);
+        assert!(rechunk(&inputs[..0], 100, dir)?.is_empty());
+
+        let mut other = inputs[0].clone();
+        other.i1 = Some("tests/read_pair_iter/good-I1.fastq".to_string());
+        assert!(rechunk(&[inputs[0].clone(), other], 100, dir).is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+//! Extraction of cell barcodes and UMIs from reads using the regex patterns of
+//! `umi_tools extract --extract-method=regex`. Named groups `cell_<n>`, `umi_<n>`
+//! and `discard_<n>` select the cell barcode, UMI and discarded parts of the read.
+//! Groups of the same kind are concatenated in the order of `<n>`, and the bases
+//! that are not part of any group remain in the read.
+//!
+//! Patterns made up only of fixed-length groups, like `(?P<cell_1>.{16})(?P<umi_1>.{12})`,
+//! are compiled into a fixed layout that is extracted without running a regex.
+//! The fuzzy matching syntax of the Python `regex` module (e.g. `{s<=1}`) is not supported.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::regex_extract::RegexExtractor;
+//! let extractor = RegexExtractor::new("(?P<cell_1>.{4})(?P<discard_1>TT)(?P<umi_1>.{3})").unwrap();
+//! let read = b"ACGTTTGGCAAAAA";
+//! let extraction = extractor.extract(read).unwrap();
+//! assert_eq!(extraction.cell(read), b"ACGT");
+//! assert_eq!(extraction.umi(read), b"GGC");
+//! assert_eq!(extraction.remaining(read), b"AAAAA");
+//! assert!(extractor.extract(b"ACGTAAGGCAAAAA").is_none());
+//! ```
+
+use crate::read_pair::{ReadPair, ReadPart, RpRange, WhichRead};
+use failure::{format_err, Error};
+use regex::bytes::Regex;
+use std::ops::Range;
+
+/// The kind of a named group of an extraction pattern
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GroupKind {
+    Cell,
+    Umi,
+    Discard,
+}
+
+impl GroupKind {
+    fn parse(name: &str) -> Option<(GroupKind, u32)> {
+        let (kind, index) = if let Some(i) = name.strip_prefix("cell_") {
+            (GroupKind::Cell, i)
+        } else if let Some(i) = name.strip_prefix("umi_") {
+            (GroupKind::Umi, i)
+        } else if let Some(i) = name.strip_prefix("discard_") {
+            (GroupKind::Discard, i)
+        } else {
+            return None;
+        };
+        Some((kind, index.parse().ok()?))
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    /// Lengths of consecutive groups anchored at the start of the read
+    Fixed(Vec<usize>),
+    Regex(Regex),
+}
+
+/// A compiled umi_tools extraction pattern. See the [module documentation](index.html).
+#[derive(Clone, Debug)]
+pub struct RegexExtractor {
+    matcher: Matcher,
+    // Kind and capture group index of each named group, ordered by kind and `<n>`
+    groups: Vec<(GroupKind, usize)>,
+}
+
+/// The parts of a read matched by a `RegexExtractor`. The ranges of each kind
+/// are in the order of their group numbers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Extraction {
+    pub cell: Vec<Range<usize>>,
+    pub umi: Vec<Range<usize>>,
+    pub discard: Vec<Range<usize>>,
+}
+
+impl RegexExtractor {
+    /// Compile an umi_tools extraction pattern. The pattern is anchored at the
+    /// start of the read, like `re.match` in Python. Returns an error if the
+    /// pattern is not a valid regex, if it has named groups other than
+    /// `cell_<n>`, `umi_<n>` and `discard_<n>`, or if it has no cell or UMI group.
+    pub fn new(pattern: &str) -> Result<RegexExtractor, Error> {
+        let regex = Regex::new(&format!("^(?-u:{})", pattern))
+            .map_err(|e| format_err!("Invalid extraction pattern '{}': {}", pattern, e))?;
+
+        let mut named = Vec::new();
+        for (idx, name) in regex.capture_names().enumerate() {
+            if let Some(name) = name {
+                let (kind, n) = GroupKind::parse(name).ok_or_else(|| {
+                    format_err!(
+                        "Invalid group name '{}' in extraction pattern '{}'. Expected \
+                         cell_<n>, umi_<n> or discard_<n>.",
+                        name,
+                        pattern
+                    )
+                })?;
+                named.push((kind, n, idx));
+            }
+        }
+        if !named.iter().any(|g| g.0 != GroupKind::Discard) {
+            return Err(format_err!(
+                "Extraction pattern '{}' has no cell_<n> or umi_<n> group.",
+                pattern
+            ));
+        }
+        named.sort();
+        let groups = named.iter().map(|&(kind, _, idx)| (kind, idx)).collect();
+
+        let matcher = match fixed_lengths(pattern) {
+            Some(lengths) => Matcher::Fixed(lengths),
+            None => Matcher::Regex(regex),
+        };
+        Ok(RegexExtractor { matcher, groups })
+    }
+
+    /// Whether the pattern was compiled into a fixed layout
+    pub fn is_fixed(&self) -> bool {
+        matches!(self.matcher, Matcher::Fixed(_))
+    }
+
+    /// Match the pattern against `seq`. Returns `None` if it doesn't match.
+    pub fn extract(&self, seq: &[u8]) -> Option<Extraction> {
+        let mut ranges = Vec::new();
+        match self.matcher {
+            Matcher::Fixed(ref lengths) => {
+                if lengths.iter().sum::<usize>() > seq.len() {
+                    return None;
+                }
+                // Capture group 0 is the whole match
+                ranges.push(None);
+                let mut start = 0;
+                for &len in lengths {
+                    ranges.push(Some(start..start + len));
+                    start += len;
+                }
+            }
+            Matcher::Regex(ref regex) => {
+                let caps = regex.captures(seq)?;
+                ranges.extend(caps.iter().map(|m| m.map(|m| m.range())));
+            }
+        }
+
+        let mut extraction = Extraction::default();
+        for &(kind, idx) in &self.groups {
+            // Groups inside an optional part of the pattern may not participate
+            if let Some(range) = ranges[idx].clone() {
+                match kind {
+                    GroupKind::Cell => extraction.cell.push(range),
+                    GroupKind::Umi => extraction.umi.push(range),
+                    GroupKind::Discard => extraction.discard.push(range),
+                }
+            }
+        }
+        Some(extraction)
+    }
+
+    /// Match the pattern against read `which` of `read_pair`. Returns `None` if
+    /// the read is missing or the pattern doesn't match.
+    pub fn extract_read(&self, read_pair: &ReadPair, which: WhichRead) -> Option<Extraction> {
+        self.extract(read_pair.get(which, ReadPart::Seq)?)
+    }
+}
+
+impl Extraction {
+    /// The concatenated cell barcode groups of `seq`, which must be the matched sequence
+    pub fn cell(&self, seq: &[u8]) -> Vec<u8> {
+        concat(&self.cell, seq)
+    }
+
+    /// The concatenated UMI groups of `seq`
+    pub fn umi(&self, seq: &[u8]) -> Vec<u8> {
+        concat(&self.umi, seq)
+    }
+
+    /// The bases of `seq` outside of all the groups, which umi_tools keeps as the
+    /// read sequence. Also applies to the quality string of the read.
+    pub fn remaining(&self, seq: &[u8]) -> Vec<u8> {
+        let mut removed: Vec<_> = self
+            .cell
+            .iter()
+            .chain(&self.umi)
+            .chain(&self.discard)
+            .cloned()
+            .collect();
+        removed.sort_by_key(|r| r.start);
+        let mut result = Vec::with_capacity(seq.len());
+        let mut pos = 0;
+        for r in removed {
+            if r.start > pos {
+                result.extend_from_slice(&seq[pos..r.start]);
+            }
+            pos = pos.max(r.end);
+        }
+        result.extend_from_slice(&seq[pos.min(seq.len())..]);
+        result
+    }
+
+    /// The ranges of the cell barcode groups as `RpRange`s on read `which`
+    pub fn cell_ranges(&self, which: WhichRead) -> Vec<RpRange> {
+        to_rp_ranges(&self.cell, which)
+    }
+
+    /// The ranges of the UMI groups as `RpRange`s on read `which`
+    pub fn umi_ranges(&self, which: WhichRead) -> Vec<RpRange> {
+        to_rp_ranges(&self.umi, which)
+    }
+}
+
+fn concat(ranges: &[Range<usize>], seq: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    for r in ranges {
+        result.extend_from_slice(&seq[r.clone()]);
+    }
+    result
+}
+
+fn to_rp_ranges(ranges: &[Range<usize>], which: WhichRead) -> Vec<RpRange> {
+    ranges
+        .iter()
+        .map(|r| RpRange::new(which, r.start, Some(r.len())))
+        .collect()
+}
+
+/// The group lengths of a pattern made up only of named groups of the form
+/// `(?P<name>.{n})`, or `None` for any other pattern.
+fn fixed_lengths(pattern: &str) -> Option<Vec<usize>> {
+    let mut lengths = Vec::new();
+    let mut rest = pattern;
+    while !rest.is_empty() {
+        let group = rest.strip_prefix("(?P<")?;
+        let end = group.find('>')?;
+        let body = group[end + 1..].strip_prefix(".{")?;
+        let close = body.find("})")?;
+        lengths.push(body[..close].parse().ok()?);
+        rest = &body[close + 2..];
+    }
+    Some(lengths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastq::OwnedRecord;
+
+    #[test]
+    fn test_fixed_layout() {
+        let pattern = "(?P<cell_1>.{16})(?P<umi_1>.{12})";
+        let extractor = RegexExtractor::new(pattern).unwrap();
+        assert!(extractor.is_fixed());
+        let regex = RegexExtractor {
+            matcher: Matcher::Regex(Regex::new(&format!("^(?-u:{})", pattern)).unwrap()),
+            groups: extractor.groups.clone(),
+        };
+
+        let read = b"AAAACCCCGGGGTTTTACGTACGTACGTNNNN";
+        for e in &[&extractor, &regex] {
+            let extraction = e.extract(read).unwrap();
+            assert_eq!(extraction.cell, vec![0..16]);
+            assert_eq!(extraction.umi, vec![16..28]);
+            assert_eq!(extraction.remaining(read), b"NNNN");
+            assert!(e.extract(&read[..27]).is_none());
+            assert_eq!(e.extract(&read[..28]).unwrap().remaining(&read[..28]), b"");
+        }
+
+        assert!(!RegexExtractor::new("(?P<cell_1>.{16})TT(?P<umi_1>.{12})")
+            .unwrap()
+            .is_fixed());
+        assert_eq!(fixed_lengths("(?P<a>.{2})(?P<b>.{x})"), None);
+        assert_eq!(fixed_lengths("(?P<a>.{2})(?P<b>.{3})"), Some(vec![2, 3]));
+    }
+
+    #[test]
+    fn test_group_order() {
+        // Groups are concatenated in the order of their numbers
+        let extractor =
+            RegexExtractor::new("(?P<umi_2>.{2})(?P<cell_2>.{3})(?P<cell_1>.{2})(?P<umi_1>.{1})")
+                .unwrap();
+        let read = b"AACCCGGTACGT";
+        let extraction = extractor.extract(read).unwrap();
+        assert_eq!(extraction.cell(read), b"GGCCC");
+        assert_eq!(extraction.umi(read), b"TAA");
+        assert_eq!(extraction.remaining(read), b"ACGT");
+        assert_eq!(
+            extraction.cell_ranges(WhichRead::R1),
+            vec![
+                RpRange::new(WhichRead::R1, 5, Some(2)),
+                RpRange::new(WhichRead::R1, 2, Some(3))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_regex_pattern() {
+        // Linker between the two parts of the barcode, and a UMI followed by a poly-T
+        let extractor = RegexExtractor::new(
+            "(?P<cell_1>.{8,10})(?P<discard_1>GAGTGATTGCTTGTGACGCCTT)(?P<cell_2>.{8})(?P<umi_1>.{6})T{3}.*",
+        )
+        .unwrap();
+        let read = b"ACGTACGTAGAGTGATTGCTTGTGACGCCTTCCCCGGGGAACCTTTTTTTTTTACGT";
+        let extraction = extractor.extract(read).unwrap();
+        assert_eq!(extraction.cell(read), b"ACGTACGTACCCCGGGG");
+        assert_eq!(extraction.umi(read), b"AACCTT");
+        assert_eq!(extraction.discard, vec![9..31]);
+        assert_eq!(extraction.remaining(read), b"TTTTTTTTACGT");
+        assert!(extractor.extract(&read[3..]).is_none());
+
+        let rec = OwnedRecord {
+            head: b"r".to_vec(),
+            seq: read.to_vec(),
+            qual: vec![b'I'; read.len()],
+            sep: None,
+        };
+        let rp = ReadPair::new([None, Some(rec), None, None]);
+        assert_eq!(extractor.extract_read(&rp, WhichRead::R2), Some(extraction));
+        assert_eq!(extractor.extract_read(&rp, WhichRead::R1), None);
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for &pattern in &[
+            "(?P<cell_1>.{16}",
+            "(?P<barcode>.{16})(?P<umi_1>.{12})",
+            "(?P<cell_x>.{16})",
+            "(?P<discard_1>.{16})",
+            ".{16}",
+        ] {
+            assert!(RegexExtractor::new(pattern).is_err(), "{}", pattern);
+        }
+    }
+}
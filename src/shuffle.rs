@@ -0,0 +1,281 @@
+//! Seeded external shuffle of processed reads, for consumers such as aligners or
+//! model training that are sensitive to the order of their input (e.g. reads sorted
+//! by flowcell position).
+//!
+//! Items are read in runs of a bounded number of items, each run is shuffled in
+//! memory and spilled to a file, and the runs are then merged by drawing the next
+//! item from a run chosen with probability proportional to its number of remaining
+//! items. This gives a uniformly random order of all the items while keeping at most
+//! one run in memory. The order only depends on the seed, the run length and the
+//! order of the input, so it is reproducible.
+//!
+//! Runs are stored as JSON lines in a directory given by the caller, and removed
+//! when the shuffled iterator is dropped. Input that fits in a single run is
+//! shuffled in memory without writing any file.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::shuffle::ExternalShuffle;
+//! let dir = std::env::temp_dir().join("fastq_set_shuffle_doctest");
+//! std::fs::create_dir_all(&dir).unwrap();
+//! let items = (0..100u32).map(Ok::<_, failure::Error>);
+//! let shuffled: Vec<u32> = ExternalShuffle::new(&dir, 42)
+//!     .run_len(16)
+//!     .shuffle(items)
+//!     .unwrap()
+//!     .collect::<Result<_, _>>()
+//!     .unwrap();
+//! assert_eq!(shuffled.len(), 100);
+//! assert_ne!(shuffled, (0..100).collect::<Vec<_>>());
+//! std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+
+use failure::{Error, ResultExt};
+use rand::distributions::{Distribution, Uniform};
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_xorshift::XorShiftRng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Default maximum number of items held in memory
+const DEFAULT_RUN_LEN: usize = 500_000;
+
+/// Configuration of an external shuffle
+#[derive(Clone, Debug)]
+pub struct ExternalShuffle {
+    dir: PathBuf,
+    seed: u64,
+    run_len: usize,
+}
+
+impl ExternalShuffle {
+    /// Shuffle with the random seed `seed`, spilling runs to files in `dir`, which
+    /// must exist and must not be shared by concurrent shuffles.
+    pub fn new(dir: impl AsRef<Path>, seed: u64) -> Self {
+        ExternalShuffle {
+            dir: dir.as_ref().to_path_buf(),
+            seed,
+            run_len: DEFAULT_RUN_LEN,
+        }
+    }
+
+    /// Maximum number of items held in memory. Defaults to 500,000. The shuffled
+    /// order depends on the run length, so it must be kept the same to reproduce
+    /// an order.
+    ///
+    /// # Panics
+    /// * If `run_len` is 0
+    pub fn run_len(mut self, run_len: usize) -> Self {
+        assert!(run_len > 0, "run_len must be positive");
+        self.run_len = run_len;
+        self
+    }
+
+    /// Shuffle `items`. The items are all read, and runs are written to disk, before
+    /// the first shuffled item is returned.
+    pub fn shuffle<T, I, E>(&self, items: I) -> Result<Shuffled<T>, Error>
+    where
+        T: Serialize + DeserializeOwned,
+        I: IntoIterator<Item = Result<T, E>>,
+        Error: From<E>,
+    {
+        let mut rng = XorShiftRng::seed_from_u64(self.seed);
+        let mut runs = Vec::new();
+        let mut buf = Vec::with_capacity(self.run_len.min(DEFAULT_RUN_LEN));
+        for item in items {
+            buf.push(item?);
+            if buf.len() == self.run_len {
+                runs.push(self.write_run(runs.len(), &mut buf, &mut rng)?);
+            }
+        }
+
+        if runs.is_empty() {
+            buf.shuffle(&mut rng);
+            let remaining = buf.len() as u64;
+            return Ok(Shuffled {
+                memory: buf.into_iter(),
+                runs,
+                remaining,
+                rng,
+            });
+        }
+        if !buf.is_empty() {
+            runs.push(self.write_run(runs.len(), &mut buf, &mut rng)?);
+        }
+
+        let remaining = runs.iter().map(|r| r.remaining).sum();
+        Ok(Shuffled {
+            memory: Vec::new().into_iter(),
+            runs,
+            remaining,
+            rng,
+        })
+    }
+
+    /// Shuffle `buf` and write it to the file of run `index`, leaving `buf` empty
+    fn write_run<T: Serialize + DeserializeOwned>(
+        &self,
+        index: usize,
+        buf: &mut Vec<T>,
+        rng: &mut XorShiftRng,
+    ) -> Result<Run<T>, Error> {
+        buf.shuffle(rng);
+        let path = self.dir.join(format!("shuffle_run{}.json", index));
+        let context = || format!("error writing shuffle run {:?}", path);
+        let mut writer = BufWriter::new(File::create(&path).with_context(|_| context())?);
+        for item in buf.iter() {
+            serde_json::to_writer(&mut writer, item).with_context(|_| context())?;
+            writer.write_all(b"\n").with_context(|_| context())?;
+        }
+        writer.flush().with_context(|_| context())?;
+
+        let remaining = buf.len() as u64;
+        buf.clear();
+        let file = File::open(&path).with_context(|_| context())?;
+        Ok(Run {
+            items: serde_json::Deserializer::from_reader(BufReader::new(file)).into_iter(),
+            path,
+            remaining,
+        })
+    }
+}
+
+/// A shuffled run spilled to disk
+struct Run<T> {
+    path: PathBuf,
+    items: serde_json::StreamDeserializer<'static, serde_json::de::IoRead<BufReader<File>>, T>,
+    remaining: u64,
+}
+
+/// Iterator over shuffled items, created by `ExternalShuffle::shuffle`. The files of
+/// the runs are removed when it is dropped.
+pub struct Shuffled<T> {
+    memory: std::vec::IntoIter<T>,
+    runs: Vec<Run<T>>,
+    remaining: u64,
+    rng: XorShiftRng,
+}
+
+impl<T> Shuffled<T> {
+    /// Number of items left
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for Shuffled<T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        if self.runs.is_empty() {
+            return self.memory.next().map(Ok);
+        }
+
+        let mut pick = Uniform::new(0, self.remaining + 1).sample(&mut self.rng);
+        let run = self
+            .runs
+            .iter_mut()
+            .find(|r| {
+                if pick < r.remaining {
+                    true
+                } else {
+                    pick -= r.remaining;
+                    false
+                }
+            })
+            .unwrap();
+        run.remaining -= 1;
+        let path = &run.path;
+        Some(match run.items.next() {
+            Some(item) => item
+                .with_context(|_| format!("error reading shuffle run {:?}", path))
+                .map_err(Error::from),
+            None => Err(failure::format_err!(
+                "shuffle run {:?} ended prematurely",
+                path
+            )),
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl<T> Drop for Shuffled<T> {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = std::fs::remove_file(&run.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_pair::ReadPair;
+    use crate::read_pair_iter::ReadPairIter;
+
+    fn shuffle(dir: &Path, seed: u64, run_len: usize, n: u32) -> Vec<u32> {
+        ExternalShuffle::new(dir, seed)
+            .run_len(run_len)
+            .shuffle((0..n).map(Ok::<_, Error>))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_shuffle() -> Result<(), Error> {
+        let dir = Path::new("tests/shuffle");
+        std::fs::create_dir_all(dir)?;
+
+        let shuffled = shuffle(dir, 1, 64, 1000);
+        assert_ne!(shuffled, (0..1000).collect::<Vec<_>>());
+        let mut sorted = shuffled.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..1000).collect::<Vec<_>>());
+
+        // The order is reproducible, and the run files are removed
+        assert_eq!(shuffle(dir, 1, 64, 1000), shuffled);
+        assert_ne!(shuffle(dir, 2, 64, 1000), shuffled);
+        assert_eq!(std::fs::read_dir(dir)?.count(), 0);
+
+        // Items from late runs are spread over the output
+        let first_half = shuffled[..500].iter().filter(|&&i| i >= 500).count();
+        assert!(first_half > 200 && first_half < 300);
+
+        // In memory
+        let shuffled = shuffle(dir, 1, 1000, 1000);
+        assert_ne!(shuffled, (0..1000).collect::<Vec<_>>());
+        assert_eq!(std::fs::read_dir(dir)?.count(), 0);
+
+        let reads: Vec<ReadPair> = ReadPairIter::new(
+            Some("tests/read_pair_iter/good-RA.fastq"),
+            None,
+            None,
+            None,
+            true,
+        )?
+        .collect::<Result<_, _>>()?;
+        let mut shuffled: Vec<ReadPair> = ExternalShuffle::new(dir, 0)
+            .run_len(3)
+            .shuffle(reads.clone().into_iter().map(Ok::<_, Error>))?
+            .collect::<Result<_, _>>()?;
+        shuffled.sort();
+        let mut sorted = reads;
+        sorted.sort();
+        assert_eq!(shuffled, sorted);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
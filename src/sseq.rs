@@ -3,10 +3,14 @@
 //! Sized, stack-allocated container for a short DNA sequence.
 
 use crate::array::{ArrayContent, ByteArray};
+use crate::metric_utils::ILLUMINA_QUAL_OFFSET;
+use crate::squality::SQualityGen;
+use crate::utils::mask_bases_below;
 use std::iter::Iterator;
 use std::str;
 
 const UPPER_ACGTN: &[u8; 5] = b"ACGTN";
+const LOWER_ACGTN: &[u8; 5] = b"acgtn";
 const N_BASE_INDEX: usize = 4;
 
 #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
@@ -52,6 +56,30 @@ impl<const N: usize> SSeqGen<N> {
         self.as_mut_bytes()
     }
 
+    /// Iterate over the bases of this sequence along with the phred quality scores
+    /// (with the ASCII offset removed) in `qual`.
+    ///
+    /// # Panics
+    /// * If `qual` has a different length
+    pub fn iter_base_qual<'a>(
+        &'a self,
+        qual: &'a SQualityGen<N>,
+    ) -> impl Iterator<Item = (u8, u8)> + 'a {
+        assert_eq!(self.len(), qual.len());
+        self.iter()
+            .zip(qual.iter())
+            .map(|(&b, &q)| (b, q - ILLUMINA_QUAL_OFFSET))
+    }
+
+    /// Replace the bases with a phred quality below `min_qual` in `qual` with `N`.
+    /// Returns the number of bases that were masked.
+    ///
+    /// # Panics
+    /// * If `qual` has a different length
+    pub fn mask_bases_below(&mut self, qual: &SQualityGen<N>, min_qual: u8) -> usize {
+        mask_bases_below(self.as_mut_bytes(), qual.as_bytes(), min_qual)
+    }
+
     /// Returns true if this sequence contains an N.
     pub fn has_n(&self) -> bool {
         self.iter().any(|&c| c == b'N' || c == b'n')
@@ -99,6 +127,103 @@ impl<const N: usize> SSeqGen<N> {
     pub fn one_hamming_iter(self, opt: HammingIterOpt) -> SSeqOneHammingIter<N> {
         SSeqOneHammingIter::new(self, opt)
     }
+
+    /// Create a new sequence from the given byte slice, handling lowercase
+    /// (soft-masked) "acgtn" bases according to `policy`. The returned `SoftMask`
+    /// records the positions that were lowercase in the input, and is only
+    /// populated with `LowercasePolicy::PreserveWithMask`.
+    ///
+    /// # Panics
+    /// * If the input contains characters other than "ACGTN" after applying the policy
+    /// * If the input exceeds the capacity of the sequence
+    ///
+    /// # Example
+    /// ```rust
+    /// use fastq_set::sseq::{LowercasePolicy, SSeq};
+    /// let (seq, mask) = SSeq::from_bytes_with_policy(b"ACgtN", LowercasePolicy::PreserveWithMask);
+    /// assert_eq!(seq.seq(), b"ACGTN");
+    /// assert_eq!(mask.positions().collect::<Vec<_>>(), vec![2, 3]);
+    /// ```
+    pub fn from_bytes_with_policy(src: &[u8], policy: LowercasePolicy) -> (Self, SoftMask) {
+        let mut mask = SoftMask::default();
+        let seq = match policy {
+            LowercasePolicy::Reject => Self::from_bytes(src),
+            LowercasePolicy::Uppercase | LowercasePolicy::PreserveWithMask => {
+                Self::from_iter(src.iter().enumerate().map(|(i, &c)| {
+                    if LOWER_ACGTN.contains(&c) {
+                        if policy == LowercasePolicy::PreserveWithMask {
+                            mask.set(i);
+                        }
+                        c.to_ascii_uppercase()
+                    } else {
+                        c
+                    }
+                }))
+            }
+        };
+        (seq, mask)
+    }
+}
+
+/// How lowercase (soft-masked) "acgtn" bases are handled when creating
+/// an `SSeqGen` with `from_bytes_with_policy()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LowercasePolicy {
+    /// Panic on lowercase bases, identical to `from_bytes()`
+    Reject,
+    /// Convert lowercase bases to uppercase and discard the masking
+    Uppercase,
+    /// Convert lowercase bases to uppercase and record the masked
+    /// positions in a `SoftMask`
+    PreserveWithMask,
+}
+
+/// Number of positions in a `SoftMask`, enough for any `SSeqGen` since its
+/// length is stored in a `u8`
+const SOFT_MASK_LEN: usize = 256;
+
+/// Positions of soft-masked (lowercase) bases in a sequence, stored as a bitmask.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Hash)]
+pub struct SoftMask {
+    bits: [u64; SOFT_MASK_LEN / 64],
+}
+
+impl SoftMask {
+    fn set(&mut self, pos: usize) {
+        // Positions beyond the mask can't be in a sequence, and are rejected
+        // by the capacity check when the sequence is built
+        if pos < SOFT_MASK_LEN {
+            self.bits[pos / 64] |= 1 << (pos % 64);
+        }
+    }
+
+    /// Returns true if the base at `pos` was soft-masked.
+    pub fn is_masked(&self, pos: usize) -> bool {
+        pos < SOFT_MASK_LEN && (self.bits[pos / 64] >> (pos % 64)) & 1 == 1
+    }
+
+    /// Returns true if no base was soft-masked.
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&b| b == 0)
+    }
+
+    /// Number of soft-masked bases.
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// Iterate over the soft-masked positions in increasing order.
+    pub fn positions(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..SOFT_MASK_LEN).filter(move |&pos| self.is_masked(pos))
+    }
+
+    /// Lowercase the soft-masked positions of `seq`, restoring the original input.
+    pub fn apply(&self, seq: &mut [u8]) {
+        let len = seq.len();
+        for pos in self.positions().take_while(|&pos| pos < len) {
+            seq[pos] = seq[pos].to_ascii_lowercase();
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -348,6 +473,19 @@ mod sseq_test {
         assert!(!SSeq::from_bytes(b"ACGT").has_n());
     }
 
+    #[test]
+    fn test_base_qual() {
+        use crate::squality::SQuality;
+        let mut seq = SSeq::from_bytes(b"ACGT");
+        let qual = SQuality::from_bytes(b"I#5?");
+        assert_equal(
+            seq.iter_base_qual(&qual),
+            vec![(b'A', 40), (b'C', 2), (b'G', 20), (b'T', 30)],
+        );
+        assert_eq!(seq.mask_bases_below(&qual, 30), 2);
+        assert_eq!(seq, SSeq::from_bytes(b"ANNT"));
+    }
+
     #[test]
     fn test_is_homopolymer() {
         assert!(SSeq::from_bytes(b"AAAA").is_homopolymer());
@@ -391,6 +529,64 @@ mod sseq_test {
         );
     }
 
+    #[test]
+    fn test_from_bytes_with_policy() {
+        let (seq, mask) = SSeq::from_bytes_with_policy(b"ACGT", LowercasePolicy::Reject);
+        assert_eq!(seq.seq(), b"ACGT");
+        assert!(mask.is_empty());
+
+        let (seq, mask) = SSeq::from_bytes_with_policy(b"acGTn", LowercasePolicy::Uppercase);
+        assert_eq!(seq.seq(), b"ACGTN");
+        assert!(mask.is_empty());
+
+        let (seq, mask) = SSeq::from_bytes_with_policy(b"acGTn", LowercasePolicy::PreserveWithMask);
+        assert_eq!(seq.seq(), b"ACGTN");
+        assert_eq!(mask.count(), 3);
+        assert!(mask.is_masked(0) && mask.is_masked(1) && mask.is_masked(4));
+        assert!(!mask.is_masked(2));
+
+        let mut restored = seq.seq().to_vec();
+        mask.apply(&mut restored);
+        assert_eq!(restored, b"acGTn");
+
+        // Masked positions beyond 64 bases
+        let mut long = vec![b'A'; 200];
+        long[70] = b'c';
+        long[199] = b'n';
+        let (seq, mask) =
+            SSeqGen::<200>::from_bytes_with_policy(&long, LowercasePolicy::PreserveWithMask);
+        assert_eq!(mask.positions().collect::<Vec<_>>(), vec![70, 199]);
+        let mut restored = seq.seq().to_vec();
+        mask.apply(&mut restored);
+        assert_eq!(restored, long);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_from_bytes_with_policy_reject() {
+        let _ = SSeq::from_bytes_with_policy(b"acgt", LowercasePolicy::Reject);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_from_bytes_with_policy_invalid() {
+        let _ = SSeq::from_bytes_with_policy(b"acgx", LowercasePolicy::Uppercase);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_test_lowercase_policy(
+            ref seq in "[ACGTNacgtn]{0, 23}",
+        ) {
+            let (sseq, mask) = SSeq::from_bytes_with_policy(seq.as_bytes(), LowercasePolicy::PreserveWithMask);
+            let upper = seq.to_ascii_uppercase();
+            prop_assert_eq!(sseq.seq(), upper.as_bytes());
+            let mut restored = sseq.seq().to_vec();
+            mask.apply(&mut restored);
+            prop_assert_eq!(restored, seq.as_bytes().to_vec());
+        }
+    }
+
     #[test]
     fn test_from_iter() {
         let seq = SSeq::from_bytes(b"ACGT");
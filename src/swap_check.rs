@@ -0,0 +1,289 @@
+//! Pre-flight check for R1 and R2 FASTQs that were swapped, e.g. by a mislabeled
+//! sample sheet or a copy with the wrong names. The cell barcodes of swapped files
+//! are read from the wrong read, so almost none of them are on the barcode
+//! whitelist. The check compares the fraction of a sample of reads with a barcode
+//! on the whitelist to the fraction found when reading the barcode from the other
+//! read, and suspects a swap if the swapped reads match the whitelist far more
+//! often. The whitelist is given as the barcode sequences, as for
+//! [`UndeterminedRescue`](../undetermined_rescue/struct.UndeterminedRescue.html).
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::read_pair::{ReadPair, RpRange, WhichRead};
+//! use fastq_set::swap_check::SwapCheck;
+//! use fastq_set::OwnedRecord;
+//! let rec = |seq: &[u8]| OwnedRecord {
+//!     head: b"read".to_vec(),
+//!     seq: seq.to_vec(),
+//!     qual: vec![b'I'; seq.len()],
+//!     sep: None,
+//! };
+//! // The barcode is expected at the start of R1, but found at the start of R2
+//! let reads = vec![
+//!     ReadPair::new([Some(rec(b"TTTTTTTTTTTT")), Some(rec(b"AAAACCCCGGGG")), None, None]),
+//!     ReadPair::new([Some(rec(b"GGGGGGGGGGGG")), Some(rec(b"GGGGTTTTAAAA")), None, None]),
+//! ];
+//! let whitelist = vec![b"AAAACCCC".to_vec(), b"GGGGTTTT".to_vec()];
+//! let check = SwapCheck::new(RpRange::new(WhichRead::R1, 0, Some(8)), whitelist);
+//! let report = check.check(&reads);
+//! assert_eq!(report.hit_rate(), 0.0);
+//! assert_eq!(report.swapped_hit_rate(), 1.0);
+//! assert!(report.is_swapped());
+//! ```
+
+use crate::read_pair::{ReadPair, ReadPart, RpRange, WhichRead};
+use crate::read_pair_iter::{sample_reads, InputFastqs, SampleStrategy};
+use failure::{format_err, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// How many times more often the swapped reads must hit the whitelist for a swap
+/// to be suspected
+const SWAP_FOLD: f64 = 5.0;
+
+/// Lowest fraction of the swapped reads hitting the whitelist for a swap to be
+/// suspected, so that a sample of reads with hardly any valid barcode either way
+/// isn't taken for a swap
+const MIN_SWAPPED_HIT_RATE: f64 = 0.1;
+
+/// Spacing of the reads sampled by `check_fastqs`, so the sample covers more than
+/// the first tiles of the flowcell
+const SAMPLE_STRIDE: usize = 100;
+
+/// Checks reads for swapped R1 and R2 files by their barcode whitelist hit rate
+pub struct SwapCheck {
+    barcode: RpRange,
+    whitelist: HashSet<Vec<u8>>,
+    auto_correct: bool,
+}
+
+impl SwapCheck {
+    /// Check the barcodes found at `barcode` of the read pairs against the
+    /// barcodes of `whitelist`.
+    ///
+    /// # Panics
+    /// * If `barcode` isn't in R1 or R2
+    pub fn new(barcode: RpRange, whitelist: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        assert!(
+            matches!(barcode.read(), WhichRead::R1 | WhichRead::R2),
+            "The barcode must be in R1 or R2 to check for swapped reads, found {}",
+            barcode.read()
+        );
+        SwapCheck {
+            barcode,
+            whitelist: whitelist
+                .into_iter()
+                .map(|bc| bc.to_ascii_uppercase())
+                .collect(),
+            auto_correct: false,
+        }
+    }
+
+    /// Return the R1 and R2 files swapped from `check_fastqs` when a swap is
+    /// suspected, rather than only reporting it.
+    pub fn auto_correct(mut self, auto_correct: bool) -> Self {
+        self.auto_correct = auto_correct;
+        self
+    }
+
+    /// The barcode range in the other primary read
+    fn swapped_barcode(&self) -> RpRange {
+        let read = match self.barcode.read() {
+            WhichRead::R1 => WhichRead::R2,
+            _ => WhichRead::R1,
+        };
+        RpRange::new(read, self.barcode.offset(), self.barcode.len())
+    }
+
+    fn is_hit(&self, read: &ReadPair, range: RpRange) -> bool {
+        read.get_range(range, ReadPart::Seq)
+            .map(|bc| self.whitelist.contains(&bc.to_ascii_uppercase()))
+            .unwrap_or(false)
+    }
+
+    /// Count the whitelist hits of `reads`, with the barcode read from its
+    /// expected read and from the other read.
+    pub fn check<'a>(&self, reads: impl IntoIterator<Item = &'a ReadPair>) -> SwapReport {
+        let swapped = self.swapped_barcode();
+        let mut report = SwapReport::default();
+        for read in reads {
+            report.num_reads += 1;
+            report.hits += self.is_hit(read, self.barcode) as u64;
+            report.swapped_hits += self.is_hit(read, swapped) as u64;
+        }
+        report
+    }
+
+    /// Check a sample of up to `num_reads` reads of `fastqs`. Returns the FASTQs
+    /// to process along with the report: `fastqs` itself, or `fastqs` with the R1
+    /// and R2 files swapped if a swap is suspected and `auto_correct` is set.
+    ///
+    /// # Errors
+    /// * If the FASTQs can't be read
+    /// * If a swap is suspected and `auto_correct` is set, but R1 and R2 are
+    ///   interleaved in a single file or R2 is missing, so the files can't be swapped
+    pub fn check_fastqs(
+        &self,
+        fastqs: &InputFastqs,
+        num_reads: usize,
+    ) -> Result<(InputFastqs, SwapReport), Error> {
+        let strategy = SampleStrategy::Strided {
+            stride: SAMPLE_STRIDE,
+        };
+        let reads = sample_reads(fastqs, num_reads, strategy, None)?;
+        let report = self.check(&reads);
+
+        let mut fastqs = fastqs.clone();
+        if self.auto_correct && report.is_swapped() {
+            fastqs = match (fastqs.r2, fastqs.r1_interleaved) {
+                (Some(r2), false) => InputFastqs {
+                    r2: Some(fastqs.r1),
+                    r1: r2,
+                    ..fastqs
+                },
+                _ => {
+                    return Err(format_err!(
+                        "The R1 and R2 reads of {} appear to be swapped, but can't be \
+                         swapped back as they are not in separate files",
+                        fastqs.r1
+                    ))
+                }
+            };
+        }
+        Ok((fastqs, report))
+    }
+}
+
+/// The number of reads with a barcode on the whitelist, with the barcode read
+/// from its expected read and from the other read
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SwapReport {
+    num_reads: u64,
+    hits: u64,
+    swapped_hits: u64,
+}
+
+impl SwapReport {
+    pub fn num_reads(&self) -> u64 {
+        self.num_reads
+    }
+
+    fn rate(&self, count: u64) -> f64 {
+        if self.num_reads == 0 {
+            0.0
+        } else {
+            count as f64 / self.num_reads as f64
+        }
+    }
+
+    /// Fraction of the reads with a barcode on the whitelist
+    pub fn hit_rate(&self) -> f64 {
+        self.rate(self.hits)
+    }
+
+    /// Fraction of the reads with a barcode on the whitelist, reading the barcode
+    /// from the other read
+    pub fn swapped_hit_rate(&self) -> f64 {
+        self.rate(self.swapped_hits)
+    }
+
+    /// Returns true if the reads hit the whitelist much more often with R1 and R2
+    /// swapped, which suggests that the files were swapped.
+    pub fn is_swapped(&self) -> bool {
+        self.swapped_hit_rate() >= MIN_SWAPPED_HIT_RATE
+            && self.swapped_hits as f64 > SWAP_FOLD * self.hits as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OwnedRecord;
+
+    fn read(r1: &[u8], r2: Option<&[u8]>) -> ReadPair {
+        let rec = |seq: &[u8]| OwnedRecord {
+            head: b"read".to_vec(),
+            seq: seq.to_vec(),
+            qual: vec![b'I'; seq.len()],
+            sep: None,
+        };
+        ReadPair::new([Some(rec(r1)), r2.map(rec), None, None])
+    }
+
+    #[test]
+    fn test_check() {
+        let whitelist = vec![b"AAAA".to_vec(), b"cccc".to_vec()];
+        let check = SwapCheck::new(RpRange::new(WhichRead::R2, 2, Some(4)), whitelist);
+        let mut reads = vec![
+            read(b"TTAAAATT", Some(b"GGGGGGGG")),
+            read(b"TTccccTT", Some(b"GGGGGGGG")),
+            read(b"TTGGGGTT", Some(b"TTAAAATT")),
+            read(b"TTGGGGTT", None),
+        ];
+        let report = check.check(&reads);
+        assert_eq!(report.num_reads(), 4);
+        assert_eq!(report.hit_rate(), 0.25);
+        assert_eq!(report.swapped_hit_rate(), 0.5);
+        assert!(!report.is_swapped());
+
+        reads.extend((0..8).map(|_| read(b"TTAAAATT", Some(b"GGGG"))));
+        let report = check.check(&reads);
+        assert!(report.is_swapped());
+
+        // Too few hits either way
+        let reads: Vec<_> = (0..20)
+            .map(|i| {
+                if i == 0 {
+                    read(b"TTAAAATT", None)
+                } else {
+                    read(b"GGGGGGGG", None)
+                }
+            })
+            .collect();
+        let report = check.check(&reads);
+        assert_eq!(report.hit_rate(), 0.0);
+        assert!(!report.is_swapped());
+        assert!(!SwapReport::default().is_swapped());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_index_read_barcode() {
+        SwapCheck::new(RpRange::new(WhichRead::I1, 0, Some(8)), vec![]);
+    }
+
+    #[test]
+    fn test_check_fastqs() -> Result<(), Error> {
+        let fastqs = InputFastqs {
+            r1: "tests/read_pair_iter/csi-1376-R1.fastq".to_string(),
+            r2: Some("tests/read_pair_iter/csi-1376-R2.fastq".to_string()),
+            i1: None,
+            i2: None,
+            r1_interleaved: false,
+        };
+        // Use the start of R2 of the reads as the whitelist of R1 barcodes
+        let range = RpRange::new(WhichRead::R1, 0, Some(16));
+        let reads = sample_reads(&fastqs, 100, SampleStrategy::Head, None)?;
+        let whitelist: Vec<_> = reads
+            .iter()
+            .map(|rp| rp.get(WhichRead::R2, ReadPart::Seq).unwrap()[..16].to_vec())
+            .collect();
+
+        let check = SwapCheck::new(range, whitelist.clone());
+        let (checked, report) = check.check_fastqs(&fastqs, 100)?;
+        assert!(report.is_swapped());
+        assert_eq!(checked, fastqs);
+
+        let check = SwapCheck::new(range, whitelist).auto_correct(true);
+        let (swapped, _) = check.check_fastqs(&fastqs, 100)?;
+        assert_eq!(swapped.r1, "tests/read_pair_iter/csi-1376-R2.fastq");
+        assert_eq!(
+            swapped.r2.as_deref(),
+            Some("tests/read_pair_iter/csi-1376-R1.fastq")
+        );
+        let (_, report) = check.check_fastqs(&swapped, 100)?;
+        assert!(!report.is_swapped());
+        assert!(report.hit_rate() > 0.5);
+        Ok(())
+    }
+}
@@ -0,0 +1,410 @@
+//! Rescue of reads from the Undetermined FASTQs of a demultiplexing run. Reads
+//! end up in Undetermined when a sequencing error in their sample index keeps
+//! the demultiplexer from assigning them. A read whose sample index is within
+//! one mismatch of a single known sample, and whose cell barcode is on the
+//! barcode whitelist, is very likely a genuine read of that sample, and can be
+//! written to that sample's outputs.
+//!
+//! # Example
+//! ```rust
+//! use fastq_set::read_pair::{ReadPair, RpRange, WhichRead};
+//! use fastq_set::undetermined_rescue::{RescueOutcome, UndeterminedRescue};
+//! use fastq_set::OwnedRecord;
+//! let rec = |seq: &[u8]| OwnedRecord {
+//!     head: b"read".to_vec(),
+//!     seq: seq.to_vec(),
+//!     qual: vec![b'I'; seq.len()],
+//!     sep: None,
+//! };
+//! let read = |barcode: &[u8], index: &[u8]| {
+//!     ReadPair::new([Some(rec(barcode)), None, Some(rec(index)), None])
+//! };
+//!
+//! let whitelist = vec![b"ACGTACGT".to_vec()];
+//! let mut rescue = UndeterminedRescue::new(RpRange::new(WhichRead::R1, 0, Some(8)), whitelist);
+//! rescue.add_sample_index_set("pbmc", "SI-3A-A1").unwrap();
+//!
+//! let reads = vec![
+//!     read(b"ACGTACGTTTTT", b"AAACGGCC"),
+//!     read(b"TTTTACGTTTTT", b"AAACGGCC"),
+//!     read(b"ACGTACGTTTTT", b"AAACTTCC"),
+//! ];
+//! assert_eq!(rescue.classify(&reads[0]), RescueOutcome::Rescued(0));
+//!
+//! let mut rescued = vec![Vec::new()];
+//! let report = rescue.rescue(reads.into_iter().map(Ok), &mut rescued).unwrap();
+//! assert_eq!(rescued[0].len(), 1);
+//! assert_eq!(report.rescued("pbmc"), 1);
+//! assert_eq!(report.barcode_not_in_whitelist(), 1);
+//! assert_eq!(report.no_sample(), 1);
+//! assert!((report.rescue_rate() - 1.0 / 3.0).abs() < 1e-9);
+//! ```
+
+use crate::read_pair::{ReadPair, ReadPart, RpRange, WhichRead};
+use crate::read_sink::ReadSink;
+use crate::sample_index_map::SAMPLE_INDEX_MAP;
+use failure::{format_err, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Marks index sequences equally close to more than one sample
+const AMBIGUOUS: usize = usize::MAX;
+
+/// The result of trying to rescue a read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RescueOutcome {
+    /// The read belongs to the sample at this position in `samples()`
+    Rescued(usize),
+    /// The sample index read or the barcode is missing from the read
+    MissingRead,
+    /// The sample index is more than one mismatch from every sample
+    NoSample,
+    /// The sample index is equally close to several samples
+    AmbiguousSample,
+    /// The sample index matches a sample, but the barcode is not on the whitelist
+    BarcodeNotInWhitelist,
+}
+
+/// Assigns reads from the Undetermined FASTQs to the samples of a run.
+/// A sample index matching one sample exactly is preferred over one mismatch
+/// from another sample.
+pub struct UndeterminedRescue {
+    index_read: WhichRead,
+    barcode: RpRange,
+    whitelist: HashSet<Vec<u8>>,
+    names: Vec<String>,
+    // Sample index sequence -> (mismatches, sample)
+    indices: HashMap<Vec<u8>, (usize, usize)>,
+    index_lens: Vec<usize>,
+}
+
+impl UndeterminedRescue {
+    /// Create a rescue with no samples, reading the cell barcode from `barcode`
+    /// and the sample index from I1.
+    pub fn new(barcode: RpRange, whitelist: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        UndeterminedRescue {
+            index_read: WhichRead::I1,
+            barcode,
+            whitelist: whitelist.into_iter().collect(),
+            names: Vec::new(),
+            indices: HashMap::new(),
+            index_lens: Vec::new(),
+        }
+    }
+
+    /// Read the sample index from `which` rather than I1. Only the first bases of
+    /// the read, up to the length of the sample index, are compared.
+    pub fn index_read(mut self, which: WhichRead) -> Self {
+        self.index_read = which;
+        self
+    }
+
+    /// Add a sample with the given sample index sequences. Adding several sets of
+    /// sequences with the same name pools them.
+    pub fn add_sample(&mut self, name: impl ToString, indices: &[&[u8]]) {
+        let name = name.to_string();
+        let sample = match self.names.iter().position(|n| *n == name) {
+            Some(sample) => sample,
+            None => {
+                self.names.push(name);
+                self.names.len() - 1
+            }
+        };
+        for index in indices {
+            let index = index.to_ascii_uppercase();
+            if !self.index_lens.contains(&index.len()) {
+                self.index_lens.push(index.len());
+            }
+            for pos in 0..index.len() {
+                for &base in b"ACGTN" {
+                    if base != index[pos] {
+                        let mut neighbor = index.clone();
+                        neighbor[pos] = base;
+                        self.insert_index(neighbor, 1, sample);
+                    }
+                }
+            }
+            self.insert_index(index, 0, sample);
+        }
+    }
+
+    /// Add a sample using the sequences of a 10x sample index set, e.g. "SI-GA-A1".
+    pub fn add_sample_index_set(&mut self, name: impl ToString, set: &str) -> Result<(), Error> {
+        let indices = SAMPLE_INDEX_MAP
+            .get(set)
+            .ok_or_else(|| format_err!("Unknown sample index set {}", set))?;
+        let indices: Vec<&[u8]> = indices.iter().map(|s| s.as_bytes()).collect();
+        self.add_sample(name, &indices);
+        Ok(())
+    }
+
+    fn insert_index(&mut self, seq: Vec<u8>, mismatches: usize, sample: usize) {
+        match self.indices.entry(seq) {
+            Entry::Vacant(e) => {
+                e.insert((mismatches, sample));
+            }
+            Entry::Occupied(mut e) => {
+                let (m, s) = *e.get();
+                if mismatches < m {
+                    e.insert((mismatches, sample));
+                } else if mismatches == m && s != sample {
+                    e.insert((m, AMBIGUOUS));
+                }
+            }
+        }
+    }
+
+    /// Names of the samples, in the order they were added
+    pub fn samples(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Decide whether `read` can be rescued, and into which sample.
+    pub fn classify(&self, read: &ReadPair) -> RescueOutcome {
+        let (index, barcode) = match (
+            read.get(self.index_read, ReadPart::Seq),
+            read.get_range(self.barcode, ReadPart::Seq),
+        ) {
+            (Some(index), Some(barcode)) => (index, barcode),
+            _ => return RescueOutcome::MissingRead,
+        };
+
+        let mut best: Option<(usize, usize)> = None;
+        for &len in &self.index_lens {
+            if let Some(&(m, s)) = index.get(..len).and_then(|i| self.indices.get(i)) {
+                best = match best {
+                    Some((best_m, best_s)) if best_m < m || (best_m == m && best_s == s) => best,
+                    Some((best_m, _)) if best_m == m => Some((m, AMBIGUOUS)),
+                    _ => Some((m, s)),
+                };
+            }
+        }
+        match best {
+            None => RescueOutcome::NoSample,
+            Some((_, AMBIGUOUS)) => RescueOutcome::AmbiguousSample,
+            Some((_, sample)) => {
+                if self.whitelist.contains(barcode) {
+                    RescueOutcome::Rescued(sample)
+                } else {
+                    RescueOutcome::BarcodeNotInWhitelist
+                }
+            }
+        }
+    }
+
+    /// Write each rescued read from `reads` to the sink of its sample, and finish
+    /// the sinks. `sinks` has one sink per sample, in the order of `samples()`.
+    pub fn rescue<I, S>(&self, reads: I, sinks: &mut [S]) -> Result<RescueReport, Error>
+    where
+        I: IntoIterator<Item = Result<ReadPair, Error>>,
+        S: ReadSink<ReadPair>,
+    {
+        if sinks.len() != self.names.len() {
+            return Err(format_err!(
+                "Expected one sink for each of the {} samples, found {} sinks",
+                self.names.len(),
+                sinks.len()
+            ));
+        }
+        let mut report = RescueReport {
+            rescued: self.names.iter().map(|name| (name.clone(), 0)).collect(),
+            ..RescueReport::default()
+        };
+        for read in reads {
+            let read = read?;
+            report.num_reads += 1;
+            match self.classify(&read) {
+                RescueOutcome::Rescued(sample) => {
+                    sinks[sample].write(&read)?;
+                    *report.rescued.get_mut(&self.names[sample]).unwrap() += 1;
+                }
+                RescueOutcome::MissingRead => report.missing_read += 1,
+                RescueOutcome::NoSample => report.no_sample += 1,
+                RescueOutcome::AmbiguousSample => report.ambiguous_sample += 1,
+                RescueOutcome::BarcodeNotInWhitelist => report.barcode_not_in_whitelist += 1,
+            }
+        }
+        for sink in sinks {
+            sink.finish()?;
+        }
+        Ok(report)
+    }
+}
+
+/// Number of Undetermined reads rescued into each sample, and the reasons the
+/// other reads were not rescued.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RescueReport {
+    num_reads: u64,
+    rescued: BTreeMap<String, u64>,
+    missing_read: u64,
+    no_sample: u64,
+    ambiguous_sample: u64,
+    barcode_not_in_whitelist: u64,
+}
+
+impl RescueReport {
+    /// Combine the report from another chunk
+    pub fn merge(&mut self, other: &RescueReport) {
+        self.num_reads += other.num_reads;
+        for (name, &count) in &other.rescued {
+            *self.rescued.entry(name.clone()).or_insert(0) += count;
+        }
+        self.missing_read += other.missing_read;
+        self.no_sample += other.no_sample;
+        self.ambiguous_sample += other.ambiguous_sample;
+        self.barcode_not_in_whitelist += other.barcode_not_in_whitelist;
+    }
+
+    /// Total number of Undetermined reads examined
+    pub fn num_reads(&self) -> u64 {
+        self.num_reads
+    }
+
+    /// Number of reads rescued into the sample `name`
+    pub fn rescued(&self, name: &str) -> u64 {
+        self.rescued.get(name).cloned().unwrap_or(0)
+    }
+
+    /// Number of reads rescued into any sample
+    pub fn num_rescued(&self) -> u64 {
+        self.rescued.values().sum()
+    }
+
+    /// Fraction of the examined reads rescued into any sample
+    pub fn rescue_rate(&self) -> f64 {
+        if self.num_reads == 0 {
+            0.0
+        } else {
+            self.num_rescued() as f64 / self.num_reads as f64
+        }
+    }
+
+    /// Number of reads missing the sample index read or the barcode
+    pub fn missing_read(&self) -> u64 {
+        self.missing_read
+    }
+
+    /// Number of reads with a sample index more than one mismatch from every sample
+    pub fn no_sample(&self) -> u64 {
+        self.no_sample
+    }
+
+    /// Number of reads with a sample index equally close to several samples
+    pub fn ambiguous_sample(&self) -> u64 {
+        self.ambiguous_sample
+    }
+
+    /// Number of reads matching a sample, with a barcode not on the whitelist
+    pub fn barcode_not_in_whitelist(&self) -> u64 {
+        self.barcode_not_in_whitelist
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OwnedRecord;
+
+    fn read(barcode: &[u8], index: Option<&[u8]>) -> ReadPair {
+        let rec = |seq: &[u8]| OwnedRecord {
+            head: b"read".to_vec(),
+            seq: seq.to_vec(),
+            qual: vec![b'I'; seq.len()],
+            sep: None,
+        };
+        ReadPair::new([Some(rec(barcode)), None, index.map(rec), None])
+    }
+
+    #[test]
+    fn test_classify() {
+        let whitelist = vec![b"AAAACCCC".to_vec(), b"GGGGTTTT".to_vec()];
+        let mut rescue =
+            UndeterminedRescue::new(RpRange::new(WhichRead::R1, 0, Some(8)), whitelist);
+        rescue.add_sample("a", &[b"AAAAAAAA", b"CCCCCCCC"]);
+        rescue.add_sample("b", &[b"AAAAAAAC"]);
+        rescue.add_sample("a", &[b"GGGGGGGG"]);
+        assert_eq!(rescue.samples(), &["a".to_string(), "b".to_string()]);
+
+        let bc = b"AAAACCCCTTTTTTTT";
+        let classify = |barcode: &[u8], index: &[u8]| rescue.classify(&read(barcode, Some(index)));
+        assert_eq!(classify(bc, b"AAAAAAAA"), RescueOutcome::Rescued(0));
+        assert_eq!(classify(bc, b"CCCCCCCA"), RescueOutcome::Rescued(0));
+        assert_eq!(classify(bc, b"GGGGNGGG"), RescueOutcome::Rescued(0));
+        // An exact match beats a mismatch to another sample
+        assert_eq!(classify(bc, b"AAAAAAAC"), RescueOutcome::Rescued(1));
+        assert_eq!(classify(bc, b"AAAAAAAG"), RescueOutcome::AmbiguousSample);
+        assert_eq!(classify(bc, b"CCCCCCAA"), RescueOutcome::NoSample);
+        // Index reads longer than the sample index are compared on their prefix
+        assert_eq!(classify(bc, b"CCCCCCCCAT"), RescueOutcome::Rescued(0));
+        assert_eq!(classify(bc, b"CCCC"), RescueOutcome::NoSample);
+        assert_eq!(
+            classify(b"GGGGTTTT", b"AAAAAAAA"),
+            RescueOutcome::Rescued(0)
+        );
+        assert_eq!(
+            classify(b"GGGGTTTC", b"AAAAAAAA"),
+            RescueOutcome::BarcodeNotInWhitelist
+        );
+        assert_eq!(classify(b"GGGG", b"AAAAAAAA"), RescueOutcome::MissingRead);
+        assert_eq!(rescue.classify(&read(bc, None)), RescueOutcome::MissingRead);
+
+        // Index read from I2
+        let rescue = UndeterminedRescue::new(RpRange::new(WhichRead::R1, 0, Some(8)), vec![])
+            .index_read(WhichRead::I2);
+        assert_eq!(
+            rescue.classify(&read(bc, Some(b"AAAAAAAA"))),
+            RescueOutcome::MissingRead
+        );
+    }
+
+    #[test]
+    fn test_rescue() -> Result<(), Error> {
+        let whitelist = vec![b"AAAACCCC".to_vec()];
+        let mut rescue =
+            UndeterminedRescue::new(RpRange::new(WhichRead::R1, 0, Some(8)), whitelist);
+        rescue.add_sample_index_set("a", "SI-3A-A1")?;
+        rescue.add_sample_index_set("b", "SI-3A-A2")?;
+        assert!(rescue.add_sample_index_set("c", "SI-NOPE").is_err());
+
+        let bc: &[u8] = b"AAAACCCCGG";
+        let reads = vec![
+            read(bc, Some(b"AAACGGCC")),
+            read(bc, Some(b"TTGTAAGA")),
+            read(bc, Some(b"AGCCCTTA")),
+            read(bc, Some(b"AGCCCAAA")),
+            read(b"AAAACCCAGG", Some(b"AGCCCTTA")),
+            read(bc, None),
+        ];
+        let mut sinks = vec![Vec::new(), Vec::new()];
+        let report = rescue.rescue(reads.clone().into_iter().map(Ok), &mut sinks)?;
+        assert_eq!(sinks[0], reads[0..2].to_vec());
+        assert_eq!(sinks[1], reads[2..3].to_vec());
+        assert_eq!(report.num_reads(), 6);
+        assert_eq!(report.rescued("a"), 2);
+        assert_eq!(report.rescued("b"), 1);
+        assert_eq!(report.num_rescued(), 3);
+        assert_eq!(report.rescue_rate(), 0.5);
+        assert_eq!(report.no_sample(), 1);
+        assert_eq!(report.barcode_not_in_whitelist(), 1);
+        assert_eq!(report.missing_read(), 1);
+        assert_eq!(report.ambiguous_sample(), 0);
+
+        let mut merged = report.clone();
+        merged.merge(&report);
+        assert_eq!(merged.num_reads(), 12);
+        assert_eq!(merged.rescued("a"), 4);
+        assert_eq!(merged.rescue_rate(), 0.5);
+        let json = serde_json::to_string(&merged)?;
+        assert_eq!(serde_json::from_str::<RescueReport>(&json)?, merged);
+        assert_eq!(RescueReport::default().rescue_rate(), 0.0);
+
+        // One sink is needed per sample
+        let mut sinks = vec![Vec::new()];
+        assert!(rescue
+            .rescue(reads.into_iter().map(Ok), &mut sinks)
+            .is_err());
+        Ok(())
+    }
+}
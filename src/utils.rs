@@ -4,31 +4,315 @@
 
 use std::boxed::Box;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, Read, Write};
 use std::path::Path;
+use std::time::Duration;
 
-use failure::Error;
+use crate::metric_utils::ILLUMINA_QUAL_OFFSET;
+use failure::{format_err, Error};
 use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
 
 const GZ_BUF_SIZE: usize = 1 << 22;
 
-/// Open a (possibly gzipped) file into a BufReader.
-pub(crate) fn write_with_gz<P: AsRef<Path>>(p: P) -> Result<Box<dyn Write>, Error> {
+pub(crate) const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4D, 0x18];
+pub(crate) const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+pub(crate) const XZ_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+
+// Default levels of the zstd and xz command line tools
+const ZSTD_LEVEL: i32 = 3;
+const XZ_LEVEL: u32 = 6;
+
+/// Compression format of a FASTQ file
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    Lz4,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// The compression implied by the extension of `p`: `.gz`, `.lz4`, `.zst` or
+    /// `.xz`, and `Plain` otherwise.
+    pub fn from_extension(p: impl AsRef<Path>) -> Compression {
+        match p.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("lz4") => Compression::Lz4,
+            Some("zst") => Compression::Zstd,
+            Some("xz") => Compression::Xz,
+            _ => Compression::Plain,
+        }
+    }
+
+    /// The actual compression of the file at `p`, determined from its first bytes
+    /// regardless of its extension.
+    pub fn detect(p: impl AsRef<Path>) -> std::io::Result<Compression> {
+        let mut magic = Vec::with_capacity(XZ_MAGIC.len());
+        File::open(p)?
+            .take(XZ_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        if magic.starts_with(&[0x1F, 0x8B]) {
+            Ok(Compression::Gzip)
+        } else if magic.starts_with(&LZ4_MAGIC) {
+            Ok(Compression::Lz4)
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            Ok(Compression::Zstd)
+        } else if magic.starts_with(&XZ_MAGIC) {
+            Ok(Compression::Xz)
+        } else {
+            Ok(Compression::Plain)
+        }
+    }
+}
+
+/// A compression format used to encode output files. `Compression` selects a
+/// format with its default settings, while `Gzip` and `Lz4` also set the
+/// compression level.
+pub trait Codec {
+    /// Check the settings of the codec, e.g. before creating the file to encode.
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Wrap `writer` in a (buffered) encoder. The compressed stream is completed
+    /// by `FinishWrite::finish`, or when the returned writer is dropped, ignoring
+    /// any error.
+    fn encoder(&self, writer: Box<dyn Write>) -> Result<Box<dyn FinishWrite>, Error>;
+}
+
+/// A writer whose output is only complete once it is finished, such as a
+/// compressed stream that ends with a trailer.
+pub trait FinishWrite: Write {
+    /// Flush the buffered data and complete the output. Nothing should be
+    /// written afterwards.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl<W: FinishWrite> FinishWrite for BufWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().finish()
+    }
+}
+
+/// The file written by an encoder, which only needs to be flushed
+impl FinishWrite for Box<dyn Write> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl<W: Write> FinishWrite for GzEncoder<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().flush()
+    }
+}
+
+impl<W: Write> FinishWrite for xz2::write::XzEncoder<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().flush()
+    }
+}
+
+impl Codec for Compression {
+    fn encoder(&self, writer: Box<dyn Write>) -> Result<Box<dyn FinishWrite>, Error> {
+        match self {
+            Compression::Plain => Ok(Box::new(BufWriter::with_capacity(32 * 1024, writer))),
+            Compression::Gzip => Gzip::default().encoder(writer),
+            Compression::Lz4 => Lz4::default().encoder(writer),
+            Compression::Zstd => {
+                let zstd = ZstdWriter {
+                    encoder: zstd::Encoder::new(writer, ZSTD_LEVEL)?,
+                };
+                Ok(Box::new(BufWriter::with_capacity(GZ_BUF_SIZE, zstd)))
+            }
+            Compression::Xz => {
+                let xz = xz2::write::XzEncoder::new(writer, XZ_LEVEL);
+                Ok(Box::new(BufWriter::with_capacity(GZ_BUF_SIZE, xz)))
+            }
+        }
+    }
+}
+
+/// Gzip compression with a level from 0 (none) to 9 (best).
+/// The default level is 1, favoring speed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Gzip {
+    pub level: u32,
+}
+
+impl Default for Gzip {
+    fn default() -> Self {
+        Gzip {
+            level: flate2::Compression::fast().level(),
+        }
+    }
+}
+
+impl Codec for Gzip {
+    fn validate(&self) -> Result<(), Error> {
+        if self.level > 9 {
+            return Err(format_err!(
+                "Invalid gzip compression level {}, must be between 0 and 9",
+                self.level
+            ));
+        }
+        Ok(())
+    }
+
+    fn encoder(&self, writer: Box<dyn Write>) -> Result<Box<dyn FinishWrite>, Error> {
+        self.validate()?;
+        let gz = GzEncoder::new(writer, flate2::Compression::new(self.level));
+        Ok(Box::new(BufWriter::with_capacity(GZ_BUF_SIZE, gz)))
+    }
+}
+
+/// LZ4 frame compression with a level from 0 (fastest) to 16 (best).
+/// The default level is 0.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Lz4 {
+    pub level: u32,
+}
+
+impl Codec for Lz4 {
+    fn validate(&self) -> Result<(), Error> {
+        if self.level > 16 {
+            return Err(format_err!(
+                "Invalid lz4 compression level {}, must be between 0 and 16",
+                self.level
+            ));
+        }
+        Ok(())
+    }
+
+    fn encoder(&self, writer: Box<dyn Write>) -> Result<Box<dyn FinishWrite>, Error> {
+        self.validate()?;
+        let encoder = lz4::EncoderBuilder::new().level(self.level).build(writer)?;
+        let lz = Lz4Writer {
+            encoder: Some(encoder),
+        };
+        Ok(Box::new(BufWriter::with_capacity(GZ_BUF_SIZE, lz)))
+    }
+}
+
+/// Writes the end of the lz4 frame when dropped, which `lz4::Encoder` leaves to
+/// an explicit call to `finish()`.
+struct Lz4Writer<W: Write> {
+    encoder: Option<lz4::Encoder<W>>,
+}
+
+impl<W: Write> Write for Lz4Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.encoder.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.encoder.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> FinishWrite for Lz4Writer<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        match self.encoder.take() {
+            Some(encoder) => {
+                let (mut w, result) = encoder.finish();
+                result?;
+                w.flush()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for Lz4Writer<W> {
+    fn drop(&mut self) {
+        // Like the gzip encoder, errors when finishing on drop are ignored
+        let _ = FinishWrite::finish(self);
+    }
+}
+
+/// Writes the end of the zstd frame when dropped, unless it was finished
+struct ZstdWriter<W: Write> {
+    encoder: zstd::Encoder<'static, W>,
+}
+
+impl<W: Write> Write for ZstdWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl<W: Write> FinishWrite for ZstdWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.encoder.do_finish()?;
+        self.encoder.get_mut().flush()
+    }
+}
+
+impl<W: Write> Drop for ZstdWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.encoder.do_finish();
+    }
+}
+
+/// Open a file for writing, compressed as implied by its extension.
+pub(crate) fn write_with_gz<P: AsRef<Path>>(p: P) -> Result<Box<dyn FinishWrite>, Error> {
+    write_with_codec(&p, &Compression::from_extension(&p))
+}
+
+/// Open a file for writing with the given codec, irrespective of its extension.
+/// The settings of the codec are checked before the file is created.
+pub(crate) fn write_with_codec<P: AsRef<Path>>(
+    p: P,
+    codec: &dyn Codec,
+) -> Result<Box<dyn FinishWrite>, Error> {
+    codec.validate()?;
     let w = File::create(p.as_ref())?;
+    codec.encoder(Box::new(w))
+}
 
-    let ext = p.as_ref().extension().unwrap();
-
-    if ext == "gz" {
-        let gz = GzEncoder::new(w, flate2::Compression::fast());
-        let buf_writer = BufWriter::with_capacity(GZ_BUF_SIZE, gz);
-        Ok(Box::new(buf_writer))
-    // disabling lz4 for now -- need to check on how to ensure all reads are flushed on drop.
-    // } else if ext == "lz4" {
-    //    let lz = lz4::Encoder::new(w)?;
-    //    let buf_writer = BufWriter::with_capacity(GZ_BUF_SIZE, lz);
-    //    Ok(Box::new(buf_writer))
-    } else {
-        let buf_writer = BufWriter::with_capacity(32 * 1024, w);
-        Ok(Box::new(buf_writer))
+/// Replace the bases of `seq` whose phred quality in the (phred+33 encoded) `qual`
+/// is below `min_qual` with `N`. Returns the number of bases that were masked.
+///
+/// # Panics
+/// * If `seq` and `qual` have different lengths
+///
+/// # Example
+/// ```rust
+/// use fastq_set::utils::mask_bases_below;
+/// let mut seq = b"ACGT".to_vec();
+/// assert_eq!(mask_bases_below(&mut seq, b"I#5I", 20), 1);
+/// assert_eq!(seq, b"ANGT".to_vec());
+/// ```
+pub fn mask_bases_below(seq: &mut [u8], qual: &[u8], min_qual: u8) -> usize {
+    assert_eq!(
+        seq.len(),
+        qual.len(),
+        "Sequence and quality string have different lengths"
+    );
+    let threshold = min_qual.saturating_add(ILLUMINA_QUAL_OFFSET);
+    let mut masked = 0;
+    for (b, &q) in seq.iter_mut().zip(qual.iter()) {
+        if q < threshold {
+            *b = b'N';
+            masked += 1;
+        }
     }
+    masked
+}
+
+/// CPU time used by the calling thread, from `/proc/thread-self/schedstat`, or
+/// `None` on platforms without it
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/thread-self/schedstat").ok()?;
+    let nanos = stat.split_whitespace().next()?.parse().ok()?;
+    Some(Duration::from_nanos(nanos))
 }